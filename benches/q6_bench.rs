@@ -1,6 +1,8 @@
-use std::time::Instant;
+use std::{sync::Arc, time::Instant};
 
-use wsql::{engine::QueryEngine, executor::QueryExecutor, gpu::Gpu};
+use wsql::{
+    dataset::DatasetTable, engine::QueryEngine, executor::QueryExecutor, gpu::Gpu, storage::Storage,
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -8,24 +10,17 @@ async fn main() -> anyhow::Result<()> {
     let executor = QueryExecutor::new(gpu);
     let engine = QueryEngine::new(executor);
 
-    let dal_builder = opendal::services::Fs::default().root("benches");
-    let op = opendal::Operator::new(dal_builder)
-        .expect("Buffer")
-        .finish();
+    // Any table location, e.g. s3://bucket/lineitem.parquet with the AWS_*
+    // variables set. The file is streamed with range reads while the GPU
+    // works on the batches already decoded. Only the 4 columns Q6 reads are
+    // fetched and decoded.
+    let location = std::env::var("WSQL_LINEITEM")
+        .unwrap_or_else(|_| "benches/data/lineitem.parquet".to_string());
+    let table = DatasetTable::open_with(&Storage::default(), &location).await?;
 
-    let file_path = "data/lineitem.parquet";
-    let buffer = op.read(file_path).await.expect("Unable to create buffer");
-
-    let reader =
-        parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(buffer.to_bytes())
-            .unwrap()
-            .with_batch_size(65536)
-            .build()
-            .unwrap();
-
-    let json_plan = std::fs::read_to_string("benches/queries/tpch_q6.json").unwrap();
+    let plan = wsql::sub::parse_plan(&std::fs::read("benches/queries/tpch_q6.json")?)?;
     let start = Instant::now();
-    let result = engine.run(reader, &json_plan).await?;
+    let result = engine.run_table(Arc::new(table), &plan).await?;
     let duration = start.elapsed();
 
     println!("Query time: {:?}", duration);
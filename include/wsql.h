@@ -0,0 +1,90 @@
+// C API of the wsql GPU query engine, see src/ffi.rs.
+//
+// Tables are passed in and results returned through the Arrow C stream
+// interface (https://arrow.apache.org/docs/format/CStreamInterface.html).
+// Functions returning int give 0 on success and -1 on failure, with the
+// message from wsql_last_error. An engine is not safe to use from more than
+// one thread at a time.
+
+#ifndef WSQL_H
+#define WSQL_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#ifndef ARROW_C_DATA_INTERFACE
+#define ARROW_C_DATA_INTERFACE
+
+#define ARROW_FLAG_DICTIONARY_ORDERED 1
+#define ARROW_FLAG_NULLABLE 2
+#define ARROW_FLAG_MAP_KEYS_SORTED 4
+
+struct ArrowSchema {
+  const char* format;
+  const char* name;
+  const char* metadata;
+  int64_t flags;
+  int64_t n_children;
+  struct ArrowSchema** children;
+  struct ArrowSchema* dictionary;
+  void (*release)(struct ArrowSchema*);
+  void* private_data;
+};
+
+struct ArrowArray {
+  int64_t length;
+  int64_t null_count;
+  int64_t offset;
+  int64_t n_buffers;
+  int64_t n_children;
+  const void** buffers;
+  struct ArrowArray** children;
+  struct ArrowArray* dictionary;
+  void (*release)(struct ArrowArray*);
+  void* private_data;
+};
+
+#endif  // ARROW_C_DATA_INTERFACE
+
+#ifndef ARROW_C_STREAM_INTERFACE
+#define ARROW_C_STREAM_INTERFACE
+
+struct ArrowArrayStream {
+  int (*get_schema)(struct ArrowArrayStream*, struct ArrowSchema* out);
+  int (*get_next)(struct ArrowArrayStream*, struct ArrowArray* out);
+  const char* (*get_last_error)(struct ArrowArrayStream*);
+  void (*release)(struct ArrowArrayStream*);
+  void* private_data;
+};
+
+#endif  // ARROW_C_STREAM_INTERFACE
+
+typedef struct WsqlEngine WsqlEngine;
+
+// Opens the GPU and an engine without tables, NULL on failure
+WsqlEngine* wsql_open(void);
+void wsql_close(WsqlEngine* engine);
+
+// Registers the batches of `stream` as table `name`, taking ownership of the
+// stream. Its buffers are kept without copying.
+int wsql_register_stream(WsqlEngine* engine, const char* name, struct ArrowArrayStream* stream);
+int wsql_register_parquet(WsqlEngine* engine, const char* name, const char* path);
+
+// Run a Substrait plan (JSON or binary protobuf) or a SQL SELECT over the
+// registered tables. The result is written to `out`, which the caller releases.
+int wsql_execute_substrait(WsqlEngine* engine, const uint8_t* plan, size_t len,
+                           struct ArrowArrayStream* out);
+int wsql_execute_sql(WsqlEngine* engine, const char* sql, struct ArrowArrayStream* out);
+
+// Message of the last failure on this thread, or NULL
+const char* wsql_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif  // WSQL_H
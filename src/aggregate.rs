@@ -0,0 +1,327 @@
+use std::{collections::HashMap, sync::Arc};
+
+use arrow::{
+    array::{
+        Array, ArrayRef, AsArray, Float32Array, Float64Array, Int32Array, Int64Array, StructArray,
+    },
+    datatypes::{DataType, Field, Fields, Float64Type, Int64Type, Schema},
+    record_batch::RecordBatch,
+    row::{OwnedRow, RowConverter, SortField},
+};
+
+use crate::{distinct, executor::QueryExecutor, sub};
+
+// Hash aggregation over every grouping set in one pass. The GPU hash set
+// finds the groups of each batch, the measures accumulate per group on the CPU.
+pub struct GroupedAggregate {
+    key_fields: Vec<Field>,
+    measures: Vec<sub::Measure>,
+    sets: Vec<GroupingSet>,
+}
+
+struct GroupingSet {
+    // Positions of the set's keys among all the keys
+    keys: Vec<usize>,
+    converter: RowConverter,
+    groups: HashMap<OwnedRow, usize>,
+    // Key of every group, the empty set has a single group without one
+    rows: Vec<OwnedRow>,
+    accumulators: Vec<Accumulator>,
+}
+
+// One entry per group
+#[derive(Default)]
+struct Accumulator {
+    sums: Vec<f64>,
+    counts: Vec<u64>,
+    // DISTINCT measures keep every value of the group with its numeric value
+    distinct: Vec<HashMap<OwnedRow, f64>>,
+}
+
+impl GroupedAggregate {
+    pub fn new(key_fields: Vec<Field>, aggregation: &sub::Aggregation) -> anyhow::Result<Self> {
+        let mut sets = Vec::new();
+        for keys in &aggregation.groupings {
+            let fields = keys
+                .iter()
+                .map(|k| SortField::new(key_fields[*k].data_type().clone()))
+                .collect();
+            sets.push(GroupingSet {
+                keys: keys.clone(),
+                converter: RowConverter::new(fields)?,
+                groups: HashMap::new(),
+                rows: Vec::new(),
+                accumulators: aggregation
+                    .measures
+                    .iter()
+                    .map(|_| Accumulator::default())
+                    .collect(),
+            });
+        }
+        Ok(Self {
+            key_fields,
+            measures: aggregation.measures.clone(),
+            sets,
+        })
+    }
+
+    // `keys` and `values` (one per measure argument) are the filtered rows of a batch
+    pub async fn update(
+        &mut self,
+        executor: &QueryExecutor,
+        keys: &[ArrayRef],
+        values: &[ArrayRef],
+    ) -> anyhow::Result<()> {
+        let rows = keys.iter().chain(values).next().map_or(0, |c| c.len());
+
+        // DISTINCT compares values by their row encoding, the rest adds up
+        // (sum, count) pairs from the rows or from intermediate state
+        let mut distinct_rows = Vec::new();
+        let mut numbers = Vec::new();
+        for (measure, values) in self.measures.iter().zip(values) {
+            distinct_rows.push(if measure.distinct {
+                let converter =
+                    RowConverter::new(vec![SortField::new(values.data_type().clone())])?;
+                Some(converter.convert_columns(std::slice::from_ref(values))?)
+            } else {
+                None
+            });
+            numbers.push(partials(measure, values)?);
+        }
+
+        for set in &mut self.sets {
+            let group_of = set.assign(executor, keys, rows).await?;
+            let groups = set.len();
+
+            for (m, accumulator) in set.accumulators.iter_mut().enumerate() {
+                accumulator.sums.resize(groups, 0.0);
+                accumulator.counts.resize(groups, 0);
+                if distinct_rows[m].is_some() {
+                    accumulator.distinct.resize_with(groups, HashMap::new);
+                }
+                let (sums, counts) = &numbers[m];
+
+                for (row, group) in group_of.iter().enumerate() {
+                    // Aggregates skip nulls
+                    if values[m].is_null(row) {
+                        continue;
+                    }
+                    match &distinct_rows[m] {
+                        Some(distinct) => {
+                            accumulator.distinct[*group]
+                                .insert(distinct.row(row).owned(), sums.value(row));
+                        }
+                        None => {
+                            accumulator.sums[*group] += sums.value(row);
+                            accumulator.counts[*group] += counts.value(row) as u64;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self, grouping_id: bool) -> anyhow::Result<RecordBatch> {
+        let mut fields: Vec<Field> = self
+            .key_fields
+            .iter()
+            .map(|f| f.clone().with_nullable(true))
+            .collect();
+        for (i, measure) in self.measures.iter().enumerate() {
+            fields.push(Field::new(
+                format!("expr_{}", self.key_fields.len() + i),
+                output_type(measure),
+                true,
+            ));
+        }
+        if grouping_id {
+            fields.push(Field::new("grouping_id", DataType::Int32, false));
+        }
+        let schema = Arc::new(Schema::new(fields));
+
+        let mut batches = Vec::new();
+        for (id, mut set) in self.sets.into_iter().enumerate() {
+            let groups = set.len();
+            let set_keys = if set.keys.is_empty() {
+                Vec::new()
+            } else {
+                set.converter
+                    .convert_rows(set.rows.iter().map(|r| r.row()))?
+            };
+            let mut columns: Vec<ArrayRef> = Vec::new();
+            for (k, field) in self.key_fields.iter().enumerate() {
+                columns.push(match set.keys.iter().position(|s| *s == k) {
+                    Some(pos) => set_keys[pos].clone(),
+                    None => arrow::array::new_null_array(field.data_type(), groups),
+                });
+            }
+
+            for (measure, accumulator) in self.measures.iter().zip(&mut set.accumulators) {
+                accumulator.sums.resize(groups, 0.0);
+                accumulator.counts.resize(groups, 0);
+                accumulator.distinct.resize_with(groups, HashMap::new);
+                let (sums, counts): (Vec<f64>, Vec<u64>) = if measure.distinct {
+                    accumulator
+                        .distinct
+                        .iter()
+                        .map(|values| (values.values().sum::<f64>(), values.len() as u64))
+                        .unzip()
+                } else {
+                    (accumulator.sums.clone(), accumulator.counts.clone())
+                };
+                columns.push(output(measure, &sums, &counts)?);
+            }
+
+            if grouping_id {
+                columns.push(Arc::new(Int32Array::from(vec![id as i32; groups])));
+            }
+            batches.push(RecordBatch::try_new(schema.clone(), columns)?);
+        }
+
+        Ok(arrow::compute::concat_batches(&schema, &batches)?)
+    }
+}
+
+// Result or intermediate state type of a measure
+pub(crate) fn output_type(measure: &sub::Measure) -> DataType {
+    match (measure.function, measure.phase.emits_state()) {
+        (sub::AggregateFunction::Count, _) => DataType::Int64,
+        (sub::AggregateFunction::Sum, true) => DataType::Float64,
+        (sub::AggregateFunction::Avg, true) => DataType::Struct(avg_state_fields()),
+        (_, false) => DataType::Float32,
+    }
+}
+
+fn avg_state_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("sum", DataType::Float64, true),
+        Field::new("count", DataType::Int64, false),
+    ])
+}
+
+// (sum, count) each row adds to its group. Input rows count once, state
+// carries its own count; null rows are skipped by the caller.
+fn partials(
+    measure: &sub::Measure,
+    values: &ArrayRef,
+) -> anyhow::Result<(Float64Array, Int64Array)> {
+    let ones = || Int64Array::from(vec![1; values.len()]);
+    let zeros = || Float64Array::from(vec![0.0; values.len()]);
+    let as_f64 = |a: &ArrayRef| -> anyhow::Result<Float64Array> {
+        Ok(arrow::compute::cast(a, &DataType::Float64)?
+            .as_primitive::<Float64Type>()
+            .clone())
+    };
+    let as_i64 = |a: &ArrayRef| -> anyhow::Result<Int64Array> {
+        Ok(arrow::compute::cast(a, &DataType::Int64)?
+            .as_primitive::<Int64Type>()
+            .clone())
+    };
+
+    if !measure.phase.merges_state() {
+        return match measure.function {
+            sub::AggregateFunction::Count => Ok((zeros(), ones())),
+            _ => Ok((as_f64(values)?, ones())),
+        };
+    }
+    match measure.function {
+        sub::AggregateFunction::Sum => Ok((as_f64(values)?, ones())),
+        sub::AggregateFunction::Count => Ok((zeros(), as_i64(values)?)),
+        sub::AggregateFunction::Avg => {
+            let Some(state) = values.as_struct_opt() else {
+                anyhow::bail!("AVG state must be a {{sum, count}} struct");
+            };
+            let column = |name: &str| {
+                state
+                    .column_by_name(name)
+                    .ok_or_else(|| anyhow::anyhow!("AVG state has no {} field", name))
+            };
+            Ok((as_f64(column("sum")?)?, as_i64(column("count")?)?))
+        }
+    }
+}
+
+fn output(measure: &sub::Measure, sums: &[f64], counts: &[u64]) -> anyhow::Result<ArrayRef> {
+    let groups = sums.len();
+    // SUM and AVG over no values are null
+    let sum = |g: usize| (counts[g] > 0).then_some(sums[g]);
+    Ok(match (measure.function, measure.phase.emits_state()) {
+        (sub::AggregateFunction::Sum, true) => {
+            Arc::new((0..groups).map(sum).collect::<Float64Array>())
+        }
+        (sub::AggregateFunction::Sum, false) => Arc::new(
+            (0..groups)
+                .map(|g| sum(g).map(|s| s as f32))
+                .collect::<Float32Array>(),
+        ),
+        (sub::AggregateFunction::Count, _) => Arc::new(Int64Array::from_iter_values(
+            counts.iter().map(|c| *c as i64),
+        )),
+        (sub::AggregateFunction::Avg, true) => Arc::new(StructArray::try_new(
+            avg_state_fields(),
+            vec![
+                Arc::new((0..groups).map(sum).collect::<Float64Array>()),
+                Arc::new(Int64Array::from_iter_values(
+                    counts.iter().map(|c| *c as i64),
+                )),
+            ],
+            None,
+        )?),
+        (sub::AggregateFunction::Avg, false) => Arc::new(
+            (0..groups)
+                .map(|g| sum(g).map(|s| (s / counts[g] as f64) as f32))
+                .collect::<Float32Array>(),
+        ),
+    })
+}
+
+impl GroupingSet {
+    // The empty grouping set always has its one group, even over no rows
+    fn len(&self) -> usize {
+        if self.keys.is_empty() {
+            1
+        } else {
+            self.rows.len()
+        }
+    }
+
+    // Group of every row, new keys get the next group
+    async fn assign(
+        &mut self,
+        executor: &QueryExecutor,
+        keys: &[ArrayRef],
+        rows: usize,
+    ) -> anyhow::Result<Vec<usize>> {
+        if self.keys.is_empty() {
+            return Ok(vec![0; rows]);
+        }
+
+        let columns: Vec<ArrayRef> = self.keys.iter().map(|k| keys[*k].clone()).collect();
+        let (words, width) = distinct::encode(&columns)?;
+        let converted = self.converter.convert_columns(&columns)?;
+        let max_rows = executor.hash_set_rows(width);
+
+        let mut group_of = vec![0; rows];
+        for (c, chunk) in words.chunks(max_rows * width).enumerate() {
+            let base = c * max_rows;
+            let representatives = executor.hash_groups(chunk, width).await?;
+
+            // Only representatives touch the global table, the rest follow them
+            for (i, rep) in representatives.iter().enumerate() {
+                if *rep as usize == i {
+                    let row = converted.row(base + i).owned();
+                    let next = self.rows.len();
+                    group_of[base + i] = *self.groups.entry(row.clone()).or_insert_with(|| {
+                        self.rows.push(row);
+                        next
+                    });
+                }
+            }
+            for (i, rep) in representatives.iter().enumerate() {
+                group_of[base + i] = group_of[base + *rep as usize];
+            }
+        }
+        Ok(group_of)
+    }
+}
@@ -0,0 +1,534 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, OnceLock},
+};
+
+use arrow::{
+    array::{
+        ArrayRef, Date32Array, Int32Array, RecordBatchIterator, RecordBatchReader, StringArray,
+        UInt32Array, new_null_array,
+    },
+    compute::kernels::cast_utils::Parser,
+    datatypes::{DataType, Date32Type, Field, Schema, SchemaRef},
+    error::ArrowError,
+    record_batch::{RecordBatch, RecordBatchOptions},
+};
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use opendal::{EntryMode, Operator};
+use parquet::arrow::{ParquetRecordBatchStreamBuilder, ProjectionMask};
+
+use crate::{
+    jit,
+    source::TableSource,
+    storage::{Location, OpendalReader, Storage},
+};
+
+// Name of a partition directory whose value is null
+const HIVE_DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+// A set of parquet files listed from an OpenDAL operator, e.g. every file
+// under a directory or matching `sales/year=*/*.parquet`. Directories named
+// `key=value` between the root and a file become columns after the file
+// columns, holding the same value for every row of the file. Scans stream
+// files concurrently with range reads and skip the partitions a filter rules
+// out.
+pub struct DatasetTable {
+    operator: Operator,
+    // Directory the file paths are relative to, empty or ending with /
+    root: String,
+    files: Vec<DatasetFile>,
+    file_schema: SchemaRef,
+    schema: SchemaRef,
+    batch_size: usize,
+    concurrency: usize,
+}
+
+struct DatasetFile {
+    path: String,
+    // One per partition column, None for nulls
+    partition: Vec<Option<String>>,
+}
+
+impl DatasetTable {
+    // A local directory, parquet file or glob pattern
+    pub async fn open(location: &str) -> anyhow::Result<Self> {
+        Self::open_with(&Storage::default(), location).await
+    }
+
+    // A directory, parquet file or glob pattern at a location of `storage`,
+    // e.g. `s3://bucket/sales/year=*/*.parquet`
+    pub async fn open_with(storage: &Storage, location: &str) -> anyhow::Result<Self> {
+        let Location { operator, path } = storage.resolve(location)?;
+        let is_file = operator.stat(&path).await.is_ok_and(|m| m.is_file());
+        let (root, pattern) = match split_location(&path) {
+            (root, Some(rest)) if rest.contains(GLOB_CHARS) => (root, Some(rest)),
+            // Partition directories are listed from above, so they are columns
+            (root, Some(rest)) if is_file => (root, Some(glob::Pattern::escape(&rest))),
+            (root, Some(rest)) => (
+                root,
+                Some(format!("{}/**/*.parquet", glob::Pattern::escape(&rest))),
+            ),
+            (path, None) if is_file => match path.rsplit_once('/') {
+                Some((root, name)) => (root.to_string(), Some(glob::Pattern::escape(name))),
+                None => (String::new(), Some(glob::Pattern::escape(&path))),
+            },
+            (root, None) => (root, None),
+        };
+        Self::list(operator, &root, pattern.as_deref()).await
+    }
+
+    // Every file under the directory `root` of `operator` whose path relative
+    // to it matches `pattern`, or every parquet file when there is none.
+    // Names starting with `.` or `_` are skipped, e.g. `_SUCCESS` markers.
+    pub async fn list(
+        operator: Operator,
+        root: &str,
+        pattern: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let root = match root.trim_matches('/') {
+            "" => String::new(),
+            root => format!("{root}/"),
+        };
+        let pattern = pattern.map(glob::Pattern::new).transpose()?;
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        let mut paths: Vec<String> = operator
+            .list_with(if root.is_empty() { "/" } else { &root })
+            .recursive(true)
+            .await?
+            .into_iter()
+            .filter(|entry| entry.metadata().mode() == EntryMode::FILE)
+            .filter_map(|entry| {
+                let path = entry.path().trim_start_matches('/');
+                Some(path.strip_prefix(&root)?.to_string())
+            })
+            .filter(|path| {
+                !path
+                    .split('/')
+                    .any(|part| part.starts_with('.') || part.starts_with('_'))
+            })
+            .filter(|path| match &pattern {
+                Some(pattern) => pattern.matches_with(path, options),
+                None => path.ends_with(".parquet"),
+            })
+            .collect();
+        paths.sort();
+        anyhow::ensure!(!paths.is_empty(), "Dataset has no files");
+
+        let mut keys: Option<Vec<String>> = None;
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let (file_keys, partition) = parse_partition(&path)?;
+            match &keys {
+                Some(keys) => anyhow::ensure!(
+                    *keys == file_keys,
+                    "File {} is partitioned by {:?}, expected {:?}",
+                    path,
+                    file_keys,
+                    keys
+                ),
+                None => keys = Some(file_keys),
+            }
+            files.push(DatasetFile { path, partition });
+        }
+        let keys = keys.unwrap_or_default();
+
+        let file_schema = read_schema(&operator, &format!("{root}{}", files[0].path)).await?;
+        let mut fields: Vec<Field> = file_schema
+            .fields()
+            .iter()
+            .map(|f| f.as_ref().clone())
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            anyhow::ensure!(
+                file_schema.field_with_name(key).is_err(),
+                "Partition column {} is also a column of the files",
+                key
+            );
+            let values = files.iter().filter_map(|f| f.partition[i].as_deref());
+            fields.push(Field::new(key, partition_type(values), true));
+        }
+
+        Ok(Self {
+            operator,
+            root,
+            files,
+            file_schema,
+            schema: Arc::new(Schema::new(fields)),
+            batch_size: 65536,
+            concurrency: 4,
+        })
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    // Files read at the same time by a scan
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    // Paths of the files a scan with `filter` reads, relative to the root
+    pub fn files(&self, filter: Option<&jit::Expression>) -> Vec<&str> {
+        self.matching(filter)
+            .into_iter()
+            .map(|f| f.path.as_str())
+            .collect()
+    }
+
+    fn matching(&self, filter: Option<&jit::Expression>) -> Vec<&DatasetFile> {
+        let offset = self.file_schema.fields().len();
+        self.files
+            .iter()
+            .filter(|file| match filter {
+                Some(filter) => holds(filter, offset, &self.partition_values(file)) != Some(false),
+                None => true,
+            })
+            .collect()
+    }
+
+    // Single row arrays of the partition values of `file`
+    fn partition_scalars(&self, file: &DatasetFile) -> anyhow::Result<Vec<ArrayRef>> {
+        let offset = self.file_schema.fields().len();
+        file.partition
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let data_type = self.schema.field(offset + i).data_type();
+                let Some(value) = value.as_deref() else {
+                    return Ok(new_null_array(data_type, 1));
+                };
+                let array: ArrayRef = match data_type {
+                    DataType::Int32 => Arc::new(Int32Array::from(vec![value.parse::<i32>()?])),
+                    DataType::Date32 => Arc::new(Date32Array::from(vec![
+                        Date32Type::parse(value)
+                            .ok_or_else(|| anyhow::anyhow!("Invalid date {}", value))?,
+                    ])),
+                    _ => Arc::new(StringArray::from(vec![value])),
+                };
+                Ok(array)
+            })
+            .collect()
+    }
+
+    // Partition values as numbers, None when null or not numeric
+    fn partition_values(&self, file: &DatasetFile) -> Vec<Option<f64>> {
+        let offset = self.file_schema.fields().len();
+        file.partition
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let value = value.as_deref()?;
+                match self.schema.field(offset + i).data_type() {
+                    DataType::Int32 => value.parse::<i32>().ok().map(f64::from),
+                    DataType::Date32 => Date32Type::parse(value).map(f64::from),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+}
+
+impl TableSource for DatasetTable {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn scan(&self) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        self.scan_with(None, None)
+    }
+
+    // Partitions the filter rules out are skipped, only the projected file
+    // columns are fetched and decoded
+    fn scan_with(
+        &self,
+        filter: Option<&jit::Expression>,
+        projection: Option<&[usize]>,
+    ) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        let all: Vec<usize> = (0..self.schema.fields().len()).collect();
+        let projection = projection.unwrap_or(&all);
+        let width = self.file_schema.fields().len();
+        let (columns, partitions): (Vec<usize>, Vec<usize>) =
+            projection.iter().partition(|&&i| i < width);
+        let files: Vec<(String, Vec<ArrayRef>)> = self
+            .matching(filter)
+            .into_iter()
+            .map(|file| {
+                let path = format!("{}{}", self.root, file.path);
+                let values = self.partition_scalars(file)?;
+                let values = partitions
+                    .iter()
+                    .map(|i| values[i - width].clone())
+                    .collect();
+                Ok((path, values))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let operator = self.operator.clone();
+        let file_schema = self.file_schema.clone();
+        let schema = Arc::new(self.schema.project(projection)?);
+        let (batch_size, concurrency) = (self.batch_size, self.concurrency);
+
+        // Files are streamed on the shared IO runtime so the reader can be
+        // consumed from sync code while the next batches are fetched and
+        // decoded. Batches come out in file order.
+        let (mut sender, mut receiver) = futures::channel::mpsc::channel(concurrency);
+        let reader_schema = schema.clone();
+        io_runtime()?.spawn(async move {
+            let mut files = files.into_iter();
+            let mut pending = VecDeque::new();
+            loop {
+                while pending.len() < concurrency
+                    && let Some((path, partition)) = files.next()
+                {
+                    pending.push_back(stream_file(
+                        operator.clone(),
+                        path,
+                        file_schema.clone(),
+                        columns.clone(),
+                        schema.clone(),
+                        partition,
+                        batch_size,
+                    ));
+                }
+                let Some(mut batches) = pending.pop_front() else {
+                    return;
+                };
+                while let Some(batch) = batches.recv().await {
+                    let failed = batch.is_err();
+                    let batch = batch.map_err(|e| ArrowError::ExternalError(e.into()));
+                    // Stops once the scan was dropped or failed
+                    if sender.send(batch).await.is_err() || failed {
+                        return;
+                    }
+                }
+            }
+        });
+        let batches = std::iter::from_fn(move || futures::executor::block_on(receiver.next()));
+        Ok(Box::new(RecordBatchIterator::new(batches, reader_schema)))
+    }
+}
+
+// Runtime every dataset scan streams its files on, started by the first scan
+fn io_runtime() -> anyhow::Result<&'static tokio::runtime::Runtime> {
+    static RUNTIME: OnceLock<std::io::Result<tokio::runtime::Runtime>> = OnceLock::new();
+    RUNTIME
+        .get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .thread_name("wsql-io")
+                .enable_all()
+                .build()
+        })
+        .as_ref()
+        .map_err(|e| anyhow::anyhow!("Cannot start the IO runtime: {}", e))
+}
+
+// Batches of a file decoded ahead of the consumer
+const READ_AHEAD: usize = 2;
+
+// Streams the file `columns` of a parquet file on the current runtime with
+// its partition values appended to every batch
+fn stream_file(
+    operator: Operator,
+    path: String,
+    file_schema: SchemaRef,
+    columns: Vec<usize>,
+    schema: SchemaRef,
+    partition: Vec<ArrayRef>,
+    batch_size: usize,
+) -> tokio::sync::mpsc::Receiver<anyhow::Result<RecordBatch>> {
+    let (sender, receiver) = tokio::sync::mpsc::channel(READ_AHEAD);
+    tokio::spawn(async move {
+        let stream = async {
+            let reader = OpendalReader::open(operator, path.as_str()).await?;
+            let builder = ParquetRecordBatchStreamBuilder::new(reader).await?;
+            anyhow::ensure!(
+                builder.schema().fields() == file_schema.fields(),
+                "File {} does not have the columns of the dataset",
+                path
+            );
+            let mask = ProjectionMask::roots(builder.parquet_schema(), columns);
+            let mut stream = builder
+                .with_projection(mask)
+                .with_batch_size(batch_size)
+                .build()?;
+            while let Some(batch) = stream.try_next().await? {
+                let mut columns = batch.columns().to_vec();
+                for value in &partition {
+                    let indices = UInt32Array::from(vec![0; batch.num_rows()]);
+                    columns.push(arrow::compute::take(value, &indices, None)?);
+                }
+                let options = RecordBatchOptions::new().with_row_count(Some(batch.num_rows()));
+                let batch = RecordBatch::try_new_with_options(schema.clone(), columns, &options)?;
+                if sender.send(Ok(batch)).await.is_err() {
+                    // The scan was dropped
+                    break;
+                }
+            }
+            Ok(())
+        };
+        if let Err(e) = stream.await {
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+    receiver
+}
+
+// Reads the arrow schema from the footer of a parquet file
+async fn read_schema(operator: &Operator, path: &str) -> anyhow::Result<SchemaRef> {
+    let reader = OpendalReader::open(operator.clone(), path).await?;
+    Ok(ParquetRecordBatchStreamBuilder::new(reader)
+        .await?
+        .schema()
+        .clone())
+}
+
+const GLOB_CHARS: [char; 3] = ['*', '?', '['];
+
+// Splits a path before its first component with glob characters or a
+// partition value
+fn split_location(location: &str) -> (String, Option<String>) {
+    let parts: Vec<&str> = location.split('/').collect();
+    match parts
+        .iter()
+        .position(|part| part.contains(GLOB_CHARS) || part.contains('='))
+    {
+        Some(i) => (parts[..i].join("/"), Some(parts[i..].join("/"))),
+        None => (location.to_string(), None),
+    }
+}
+
+// Keys and values of the `key=value` directories in a path
+fn parse_partition(path: &str) -> anyhow::Result<(Vec<String>, Vec<Option<String>>)> {
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    let directories = path.split('/').rev().skip(1).collect::<Vec<_>>();
+    for directory in directories.into_iter().rev() {
+        if let Some((key, value)) = directory.split_once('=') {
+            keys.push(unescape(key)?);
+            values.push(Some(unescape(value)?).filter(|v| v != HIVE_DEFAULT_PARTITION));
+        }
+    }
+    Ok((keys, values))
+}
+
+// Decodes the %XX escapes Hive writes for characters like / and =
+fn unescape(part: &str) -> anyhow::Result<String> {
+    let bytes = part.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = part
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+// Int32 when every value is an integer, Date32 when every value is a date
+fn partition_type<'a>(values: impl Iterator<Item = &'a str> + Clone) -> DataType {
+    let mut values = values.peekable();
+    if values.peek().is_none() {
+        DataType::Utf8
+    } else if values.clone().all(|v| v.parse::<i32>().is_ok()) {
+        DataType::Int32
+    } else if values.all(|v| Date32Type::parse(v).is_some()) {
+        DataType::Date32
+    } else {
+        DataType::Utf8
+    }
+}
+
+// Whether `expr` holds for a file given its partition values, None when it
+// depends on file columns or cannot be evaluated on the CPU
+fn holds(expr: &jit::Expression, offset: usize, partition: &[Option<f64>]) -> Option<bool> {
+    use jit::Expression::*;
+    let compare = |a, b, f: fn(f64, f64) -> bool| {
+        Some(f(
+            value(a, offset, partition)?,
+            value(b, offset, partition)?,
+        ))
+    };
+    match expr {
+        And(a, b) => match (holds(a, offset, partition), holds(b, offset, partition)) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        },
+        Or(a, b) => match (holds(a, offset, partition), holds(b, offset, partition)) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        },
+        GreaterThan(a, b) => compare(a, b, |a, b| a > b),
+        LessThan(a, b) => compare(a, b, |a, b| a < b),
+        Equal(a, b) => compare(a, b, |a, b| a == b),
+        Not(a) => holds(a, offset, partition).map(|h| !h),
+        _ => None,
+    }
+}
+
+fn value(expr: &jit::Expression, offset: usize, partition: &[Option<f64>]) -> Option<f64> {
+    use jit::{Expression::*, LiteralTypes};
+    let value = |e| value(e, offset, partition);
+    match expr {
+        Literal(LiteralTypes::I32(v) | LiteralTypes::Date(v)) => Some(f64::from(*v)),
+        Literal(LiteralTypes::F32(v)) => Some(f64::from(*v)),
+        Column(i) => *partition.get((*i as usize).checked_sub(offset)?)?,
+        Add(a, b) => Some(value(a)? + value(b)?),
+        Subtract(a, b) => Some(value(a)? - value(b)?),
+        Multiply(a, b) => Some(value(a)? * value(b)?),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jit::{Expression, LiteralTypes};
+
+    #[test]
+    fn test_parse_partition() {
+        let (keys, values) =
+            parse_partition("region=north%2Feast/day=__HIVE_DEFAULT_PARTITION__/part-0.parquet")
+                .unwrap();
+        assert_eq!(keys, ["region", "day"]);
+        assert_eq!(values, [Some("north/east".to_string()), None]);
+        assert_eq!(
+            split_location("data/year=2024/*.parquet"),
+            ("data".to_string(), Some("year=2024/*.parquet".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_holds() {
+        let column = |i| Box::new(Expression::Column(i));
+        let literal = |v| Box::new(Expression::Literal(LiteralTypes::I32(v)));
+        // year > 2022 AND x < 5, year is the partition column after x
+        let filter = Expression::And(
+            Box::new(Expression::GreaterThan(column(1), literal(2022))),
+            Box::new(Expression::LessThan(column(0), literal(5))),
+        );
+        assert_eq!(holds(&filter, 1, &[Some(2021.0)]), Some(false));
+        assert_eq!(holds(&filter, 1, &[Some(2023.0)]), None);
+        // Null partitions are never pruned
+        assert_eq!(holds(&filter, 1, &[None]), None);
+        let filter = Expression::Or(
+            Box::new(Expression::Equal(column(1), literal(2021))),
+            Box::new(Expression::LessThan(column(0), literal(5))),
+        );
+        assert_eq!(holds(&filter, 1, &[Some(2022.0)]), None);
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+
+use arrow::{
+    array::{Array, ArrayRef, AsArray, BooleanArray, UInt32Array},
+    datatypes::{
+        DataType, Decimal128Type, Float32Type, Float64Type, Int32Type, Int64Type, SchemaRef,
+        UInt32Type, UInt64Type,
+    },
+    record_batch::RecordBatch,
+};
+
+use crate::executor::QueryExecutor;
+
+// Rounds of hash partitioning before giving up on a key set too large for the GPU
+const MAX_SPLITS: u64 = 16;
+
+// Distinct rows across batches. Every batch is deduplicated on the GPU on its
+// own, the survivors are merged again once they pass `budget` rows so
+// duplicates across batches do not pile up. More than `limit` distinct rows
+// is an error rather than unbounded host memory.
+pub struct DistinctSet {
+    schema: SchemaRef,
+    batches: Vec<RecordBatch>,
+    rows: usize,
+    budget: usize,
+    limit: usize,
+}
+
+impl DistinctSet {
+    pub fn new(schema: SchemaRef, budget: usize, limit: usize) -> Self {
+        Self {
+            schema,
+            batches: Vec::new(),
+            rows: 0,
+            budget: budget.min(limit),
+            limit,
+        }
+    }
+
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    pub async fn insert(
+        &mut self,
+        executor: &QueryExecutor,
+        batch: RecordBatch,
+    ) -> anyhow::Result<()> {
+        let batch = dedup(executor, batch).await?;
+        self.rows += batch.num_rows();
+        self.batches.push(batch);
+
+        if self.rows > self.budget {
+            let merged = arrow::compute::concat_batches(&self.schema, &self.batches)?;
+            let merged = dedup(executor, merged).await?;
+            self.rows = merged.num_rows();
+            self.batches = vec![merged];
+            if self.rows > self.limit {
+                anyhow::bail!(
+                    "DISTINCT has more than {} distinct rows, the limit held in memory",
+                    self.limit
+                );
+            }
+            // High cardinality keys barely shrink, back off instead of merging every batch
+            self.budget = self.budget.max(self.rows * 2).min(self.limit);
+        }
+        Ok(())
+    }
+
+    pub async fn finish(self, executor: &QueryExecutor) -> anyhow::Result<RecordBatch> {
+        let batch = arrow::compute::concat_batches(&self.schema, &self.batches)?;
+        if self.batches.len() == 1 {
+            return Ok(batch);
+        }
+        dedup(executor, batch).await
+    }
+}
+
+// Keeps the first row of every distinct key. Batches larger than one GPU hash
+// set are deduplicated in chunks, then split by hash so equal rows meet in the
+// same part, until every part fits.
+pub async fn dedup(executor: &QueryExecutor, batch: RecordBatch) -> anyhow::Result<RecordBatch> {
+    let schema = batch.schema();
+    let mut pending = vec![(batch, 0)];
+    let mut done = Vec::new();
+
+    while let Some((batch, seed)) = pending.pop() {
+        let (keys, width) = encode(batch.columns())?;
+        let max_rows = executor.hash_set_rows(width);
+
+        let mut flags = Vec::with_capacity(batch.num_rows());
+        // A row survives when it represents its own key
+        for chunk in keys.chunks(max_rows * width) {
+            let groups = executor.hash_groups(chunk, width).await?;
+            flags.extend(
+                groups
+                    .iter()
+                    .enumerate()
+                    .map(|(i, g)| (*g as usize == i) as u32),
+            );
+        }
+        let mask: BooleanArray = flags.iter().map(|f| Some(*f != 0)).collect();
+        if batch.num_rows() <= max_rows {
+            done.push(arrow::compute::filter_record_batch(&batch, &mask)?);
+            continue;
+        }
+        if seed == MAX_SPLITS {
+            anyhow::bail!("Too many distinct keys for the GPU hash set");
+        }
+
+        let survivors = (0..batch.num_rows()).filter(|&i| flags[i] != 0);
+        let parts = batch.num_rows().div_ceil(max_rows) * 2;
+        let mut indices = vec![Vec::new(); parts];
+        for row in survivors {
+            let key = &keys[row * width..(row + 1) * width];
+            indices[(hash(key, seed) % parts as u64) as usize].push(row as u32);
+        }
+        for part in indices.into_iter().filter(|p| !p.is_empty()) {
+            let part = UInt32Array::from(part);
+            let columns = batch
+                .columns()
+                .iter()
+                .map(|c| arrow::compute::take(c, &part, None))
+                .collect::<Result<_, _>>()?;
+            pending.push((RecordBatch::try_new(schema.clone(), columns)?, seed + 1));
+        }
+    }
+
+    Ok(arrow::compute::concat_batches(&schema, &done)?)
+}
+
+fn hash(key: &[u32], seed: u64) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    seed.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Row major u32 words for every row: the value words of each column followed
+// by a null bitmap. Equal rows, nulls included, get equal words.
+pub fn encode(columns: &[ArrayRef]) -> anyhow::Result<(Vec<u32>, usize)> {
+    if columns.is_empty() {
+        anyhow::bail!("DISTINCT needs at least one column");
+    }
+    let rows = columns.first().map_or(0, |c| c.len());
+    let mut words = Vec::new();
+    for column in columns {
+        words.extend(column_words(column)?);
+    }
+    let null_words = columns.len().div_ceil(32);
+    for w in 0..null_words {
+        let mut bitmap = vec![0u32; rows];
+        for (bit, column) in columns.iter().skip(w * 32).take(32).enumerate() {
+            for (row, b) in bitmap.iter_mut().enumerate() {
+                if column.is_null(row) {
+                    *b |= 1 << bit;
+                }
+            }
+        }
+        words.push(bitmap);
+    }
+
+    let width = words.len();
+    let mut keys = vec![0u32; rows * width];
+    for (w, column) in words.iter().enumerate() {
+        for (row, v) in column.iter().enumerate() {
+            keys[row * width + w] = *v;
+        }
+    }
+    Ok((keys, width))
+}
+
+// One or more word columns per array, nulls are zeroed
+fn column_words(column: &ArrayRef) -> anyhow::Result<Vec<Vec<u32>>> {
+    fn split<T: Copy>(
+        column: &ArrayRef,
+        len: usize,
+        f: impl Fn(T) -> Vec<u32>,
+        values: &[T],
+    ) -> Vec<Vec<u32>> {
+        let mut words = vec![vec![0u32; column.len()]; len];
+        for (row, v) in values.iter().enumerate() {
+            if column.is_valid(row) {
+                for (w, word) in f(*v).into_iter().enumerate() {
+                    words[w][row] = word;
+                }
+            }
+        }
+        words
+    }
+
+    Ok(match column.data_type() {
+        DataType::Boolean => {
+            let values = column.as_boolean();
+            vec![
+                (0..column.len())
+                    .map(|i| (column.is_valid(i) && values.value(i)) as u32)
+                    .collect(),
+            ]
+        }
+        DataType::UInt32 => split(
+            column,
+            1,
+            |v: u32| vec![v],
+            column.as_primitive::<UInt32Type>().values(),
+        ),
+        DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::Date32 => {
+            let column = arrow::compute::cast(column, &DataType::Int32)?;
+            split(
+                &column,
+                1,
+                |v: i32| vec![v as u32],
+                column.as_primitive::<Int32Type>().values(),
+            )
+        }
+        // -0.0 equals 0.0 and all NaNs are one key
+        DataType::Float32 => split(
+            column,
+            1,
+            |v: f32| vec![if v.is_nan() { f32::NAN } else { v + 0.0 }.to_bits()],
+            column.as_primitive::<Float32Type>().values(),
+        ),
+        DataType::Float64 => split(
+            column,
+            2,
+            |v: f64| {
+                let bits = if v.is_nan() { f64::NAN } else { v + 0.0 }.to_bits();
+                vec![bits as u32, (bits >> 32) as u32]
+            },
+            column.as_primitive::<Float64Type>().values(),
+        ),
+        DataType::UInt64 => split(
+            column,
+            2,
+            |v: u64| vec![v as u32, (v >> 32) as u32],
+            column.as_primitive::<UInt64Type>().values(),
+        ),
+        DataType::Int64 | DataType::Date64 | DataType::Timestamp(_, _) => {
+            let column = arrow::compute::cast(column, &DataType::Int64)?;
+            split(
+                &column,
+                2,
+                |v: i64| vec![v as u32, (v >> 32) as u32],
+                column.as_primitive::<Int64Type>().values(),
+            )
+        }
+        DataType::Decimal128(_, _) => split(
+            column,
+            4,
+            |v: i128| (0..4).map(|w| (v >> (w * 32)) as u32).collect(),
+            column.as_primitive::<Decimal128Type>().values(),
+        ),
+        // Anything else is interned to a dense id per distinct value
+        _ => {
+            let converter = arrow::row::RowConverter::new(vec![arrow::row::SortField::new(
+                column.data_type().clone(),
+            )])?;
+            let converted = converter.convert_columns(std::slice::from_ref(column))?;
+            let mut ids = HashMap::new();
+            let words = (0..column.len())
+                .map(|row| {
+                    if column.is_null(row) {
+                        return 0;
+                    }
+                    let next = ids.len() as u32 + 1;
+                    *ids.entry(converted.row(row)).or_insert(next)
+                })
+                .collect();
+            vec![words]
+        }
+    })
+}
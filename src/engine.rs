@@ -1,47 +1,951 @@
-use arrow::array::RecordBatchReader;
-use parquet::arrow::arrow_reader::ParquetRecordBatchReader;
+use std::{collections::HashMap, sync::Arc, time::Instant};
 
-use crate::{executor, sub};
+use arrow::{
+    array::{ArrayRef, AsArray, Int64Array, RecordBatchReader},
+    datatypes::{DataType, Field, Schema, SchemaRef},
+    record_batch::RecordBatch,
+};
+
+use crate::{aggregate, distinct, executor, explain, jit, join, source, sub};
+
+// Distinct rows kept before the per batch sets are merged again
+const DISTINCT_BUDGET_ROWS: usize = 1 << 20;
+// Distinct rows a DISTINCT may hold in host memory before the query fails
+const DISTINCT_LIMIT_ROWS: usize = 1 << 26;
 
 pub struct QueryEngine {
     executor: executor::QueryExecutor,
+    tables: HashMap<String, Arc<dyn source::TableSource>>,
+}
+
+// Reader or table passed to `run`, scanned by reads of tables the engine
+// does not know
+enum DefaultInput {
+    Stream(Option<Box<dyn RecordBatchReader + Send>>),
+    // Tables, and readers of plans scanning them more than once replayed from
+    // memory
+    Table(Arc<dyn source::TableSource>),
+}
+
+// Where each output column of a pipeline comes from
+enum Output {
+    // Passed through from the input batch without touching the GPU
+    Column(usize),
+    Kernel(executor::CompiledQuery),
+    // NULL in every row, e.g. arithmetic on a scalar subquery without a value
+    Null(DataType),
 }
 
 impl QueryEngine {
     pub fn new(executor: executor::QueryExecutor) -> Self {
-        Self { executor }
+        Self {
+            executor,
+            tables: HashMap::new(),
+        }
+    }
+
+    // Makes `table` scannable by name from every plan this engine runs
+    pub fn register_table(&mut self, name: impl Into<String>, table: Arc<dyn source::TableSource>) {
+        self.tables.insert(name.into(), table);
+    }
+
+    pub fn table(&self, name: &str) -> Option<&Arc<dyn source::TableSource>> {
+        self.tables.get(name)
+    }
+
+    // Registered table names in order
+    pub fn table_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.tables.keys().map(String::as_str).collect();
+        names.sort();
+        names
     }
 
     pub async fn run(
         &self,
-        reader: ParquetRecordBatchReader,
+        reader: impl RecordBatchReader + Send + 'static,
         json_plan: &str,
-    ) -> anyhow::Result<executor::QueryResult> {
+    ) -> anyhow::Result<RecordBatch> {
         let plan: substrait::proto::Plan = serde_json::from_str(json_plan)?;
-        let mut physical_plan = sub::lower_plan(&plan)?;
+        self.run_substrait(&plan, Some(Box::new(reader))).await
+    }
+
+    // Binary protobuf plans, as other Substrait producers emit them
+    pub async fn run_protobuf(
+        &self,
+        reader: impl RecordBatchReader + Send + 'static,
+        plan: &[u8],
+    ) -> anyhow::Result<RecordBatch> {
+        self.run_substrait(&sub::decode_plan(plan)?, Some(Box::new(reader)))
+            .await
+    }
+
+    pub async fn run_plan(
+        &self,
+        reader: impl RecordBatchReader + Send + 'static,
+        plan: &substrait::proto::Plan,
+    ) -> anyhow::Result<RecordBatch> {
+        self.run_substrait(plan, Some(Box::new(reader))).await
+    }
+
+    // Scans of the default input push their filter and columns into `table`,
+    // e.g. to decode only the parquet columns the plan reads
+    pub async fn run_table(
+        &self,
+        table: Arc<dyn source::TableSource>,
+        plan: &substrait::proto::Plan,
+    ) -> anyhow::Result<RecordBatch> {
+        self.run_input(plan, DefaultInput::Table(table)).await
+    }
+
+    // Plans a SELECT over the registered tables and runs it
+    pub async fn sql(&self, query: &str) -> anyhow::Result<RecordBatch> {
+        self.query(&self.plan_sql(query)?).await
+    }
+
+    // Substrait plan of a SELECT over the registered tables
+    pub fn plan_sql(&self, query: &str) -> anyhow::Result<substrait::proto::Plan> {
+        crate::sql::plan(query, |name| {
+            self.tables.get(name).map(|table| table.schema())
+        })
+    }
+
+    // Runs a plan that only scans registered tables
+    pub async fn query(&self, plan: &substrait::proto::Plan) -> anyhow::Result<RecordBatch> {
+        self.run_substrait(plan, None).await
+    }
+
+    // Schema `query` returns for `plan`, without running it
+    pub fn output_schema(&self, plan: &substrait::proto::Plan) -> anyhow::Result<SchemaRef> {
+        let explain = self.explain(plan, None)?;
+        let output = explain
+            .pipelines
+            .last()
+            .ok_or_else(|| anyhow::anyhow!("Plan has no pipelines"))?
+            .output
+            .clone();
+        let names = sub::output_names(plan);
+        if names.len() != output.fields().len() {
+            return Ok(output);
+        }
+        let fields: Vec<Field> = output
+            .fields()
+            .iter()
+            .zip(names)
+            .map(|(f, name)| f.as_ref().clone().with_name(name))
+            .collect();
+        Ok(Arc::new(Schema::new(fields)))
+    }
+
+    async fn run_substrait(
+        &self,
+        plan: &substrait::proto::Plan,
+        reader: Option<Box<dyn RecordBatchReader + Send>>,
+    ) -> anyhow::Result<RecordBatch> {
+        let physical_plan = sub::lower_plan(plan)?;
+        let input = self.default_input(&physical_plan, reader)?;
+        self.run_physical(plan, &physical_plan, input).await
+    }
+
+    async fn run_input(
+        &self,
+        plan: &substrait::proto::Plan,
+        input: DefaultInput,
+    ) -> anyhow::Result<RecordBatch> {
+        self.run_physical(plan, &sub::lower_plan(plan)?, input)
+            .await
+    }
+
+    async fn run_physical(
+        &self,
+        plan: &substrait::proto::Plan,
+        physical_plan: &sub::PhysicalPlan,
+        mut input: DefaultInput,
+    ) -> anyhow::Result<RecordBatch> {
+        let output = self.execute(physical_plan, &mut input, None).await?;
+
+        let names = sub::output_names(plan);
+        if names.len() != output.num_columns() {
+            return Ok(output);
+        }
+        let fields: Vec<Field> = output
+            .schema()
+            .fields()
+            .iter()
+            .zip(names)
+            .map(|(f, name)| f.as_ref().clone().with_name(name))
+            .collect();
+        Ok(RecordBatch::try_new(
+            Arc::new(Schema::new(fields)),
+            output.columns().to_vec(),
+        )?)
+    }
+
+    fn default_input(
+        &self,
+        plan: &sub::PhysicalPlan,
+        reader: Option<Box<dyn RecordBatchReader + Send>>,
+    ) -> anyhow::Result<DefaultInput> {
+        let mut tables = Vec::new();
+        plan.tables(&mut tables);
+        let default_scans = tables
+            .iter()
+            .filter(|t| {
+                t.as_ref()
+                    .is_none_or(|name| !self.tables.contains_key(name))
+            })
+            .count();
+        Ok(match reader {
+            Some(reader) if default_scans > 1 => {
+                DefaultInput::Table(Arc::new(source::MemoryTable::collect(reader)?))
+            }
+            reader => DefaultInput::Stream(reader),
+        })
+    }
+
+    pub fn explain_sql(&self, query: &str) -> anyhow::Result<explain::Explain> {
+        self.explain(&self.plan_sql(query)?, None)
+    }
+
+    // Describes how `plan` would run without running it. `input` is the schema
+    // of the default input, needed when the plan reads it.
+    pub fn explain(
+        &self,
+        plan: &substrait::proto::Plan,
+        input: Option<SchemaRef>,
+    ) -> anyhow::Result<explain::Explain> {
+        self.explain_physical(Arc::new(sub::lower_plan(plan)?), input.as_ref())
+    }
+
+    fn explain_physical(
+        &self,
+        plan: Arc<sub::PhysicalPlan>,
+        input: Option<&SchemaRef>,
+    ) -> anyhow::Result<explain::Explain> {
+        let mut pipelines: Vec<explain::PipelineExplain> = Vec::new();
+        for mut pipeline in plan.pipelines()? {
+            let schema = match &pipeline.source {
+                sub::Source::Table(name) => {
+                    match name.as_deref().and_then(|name| self.tables.get(name)) {
+                        Some(table) => table.schema(),
+                        None => input.cloned().ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "Explaining a scan of the default input needs its schema"
+                            )
+                        })?,
+                    }
+                }
+                sub::Source::Pipeline(i) => pipelines[*i].output.clone(),
+            };
+
+            // Subqueries are typed from their plan, a literal of that type
+            // stands in for the value when deriving the output schema
+            let mut subqueries = Vec::new();
+            for subquery in pipeline.subqueries() {
+                let explain = self.explain_physical(subquery.0.clone(), input)?;
+                let output = &explain
+                    .pipelines
+                    .last()
+                    .ok_or_else(|| anyhow::anyhow!("Plan has no pipelines"))?
+                    .output;
+                pipeline.bind(&subquery, &scalar_placeholder(output)?)?;
+                subqueries.push(explain);
+            }
+            let kernels = if subqueries.is_empty() {
+                explain::pipeline_kernels(&pipeline, &schema)
+            } else {
+                Vec::new()
+            };
+            pipelines.push(explain::PipelineExplain {
+                output: explain::output_schema(&pipeline, &schema),
+                profile: None,
+                input: schema,
+                breaker: pipeline.breaker.to_string(),
+                source: pipeline.source,
+                kernels,
+                subqueries,
+            });
+        }
+        Ok(explain::Explain {
+            plan,
+            pipelines,
+            elapsed: None,
+        })
+    }
+
+    pub async fn analyze_sql(&self, query: &str) -> anyhow::Result<explain::Explain> {
+        self.analyze(&self.plan_sql(query)?, None).await
+    }
+
+    // Runs `plan` and describes it like explain, with the rows every pipeline
+    // read and produced and the time it spent in each stage
+    pub async fn analyze(
+        &self,
+        plan: &substrait::proto::Plan,
+        reader: Option<Box<dyn RecordBatchReader + Send>>,
+    ) -> anyhow::Result<explain::Explain> {
+        let physical_plan = Arc::new(sub::lower_plan(plan)?);
+        let schema = reader.as_ref().map(|r| r.schema());
+        let input = self.default_input(&physical_plan, reader)?;
+        self.analyze_input(physical_plan, schema, input).await
+    }
+
+    // Analyze of `run_table`, the profile shows the pruned and projected scan
+    pub async fn analyze_table(
+        &self,
+        table: Arc<dyn source::TableSource>,
+        plan: &substrait::proto::Plan,
+    ) -> anyhow::Result<explain::Explain> {
+        let physical_plan = Arc::new(sub::lower_plan(plan)?);
+        let schema = table.schema();
+        self.analyze_input(physical_plan, Some(schema), DefaultInput::Table(table))
+            .await
+    }
+
+    async fn analyze_input(
+        &self,
+        physical_plan: Arc<sub::PhysicalPlan>,
+        schema: Option<SchemaRef>,
+        mut input: DefaultInput,
+    ) -> anyhow::Result<explain::Explain> {
+        let mut analysis = self.explain_physical(physical_plan.clone(), schema.as_ref())?;
+
+        let start = Instant::now();
+        self.execute(&physical_plan, &mut input, Some(&mut analysis))
+            .await?;
+        analysis.elapsed = Some(start.elapsed());
+        Ok(analysis)
+    }
+
+    // Runs the pipelines of a plan in order, every one streams a table or
+    // reads the output of an earlier pipeline. With an `analysis` of the plan
+    // every pipeline is profiled into it.
+    async fn execute(
+        &self,
+        plan: &sub::PhysicalPlan,
+        input: &mut DefaultInput,
+        mut analysis: Option<&mut explain::Explain>,
+    ) -> anyhow::Result<RecordBatch> {
+        let mut pipelines = plan.pipelines()?;
+
+        // Uncorrelated scalar subqueries run first, their value is bound as a literal
+        for (i, pipeline) in pipelines.iter_mut().enumerate() {
+            for (s, subquery) in pipeline.subqueries().into_iter().enumerate() {
+                let analysis = analysis
+                    .as_deref_mut()
+                    .map(|a| &mut a.pipelines[i].subqueries[s]);
+                let result = Box::pin(self.execute(&subquery.0, input, analysis)).await?;
+                pipeline.bind(&subquery, &scalar_value(&result)?)?;
+            }
+        }
+
+        let mut outputs: Vec<RecordBatch> = Vec::new();
+        for (i, pipeline) in pipelines.iter_mut().enumerate() {
+            // Analyzed pipelines run on an executor of their own that profiles them
+            let profiled = analysis.is_some().then(|| self.executor.profiled());
+            let executor = profiled.as_ref().unwrap_or(&self.executor);
+            let output = match pipeline.source.clone() {
+                sub::Source::Table(name) => {
+                    // Only the columns the pipeline reads are scanned
+                    let columns = pipeline.input_columns();
+                    let reader = self.scan(
+                        name.as_deref(),
+                        pipeline.filter.as_ref(),
+                        columns.as_deref(),
+                        input,
+                    )?;
+                    if let Some(columns) = &columns {
+                        pipeline.project_input(columns)?;
+                    }
+                    self.run_pipeline(
+                        executor,
+                        pipeline,
+                        reader.schema(),
+                        timed(executor, reader.map(|b| b.map_err(anyhow::Error::from))),
+                    )
+                    .await?
+                }
+                sub::Source::Pipeline(p) => {
+                    let batch = outputs[p].clone();
+                    self.run_pipeline(
+                        executor,
+                        pipeline,
+                        batch.schema(),
+                        timed(executor, std::iter::once(Ok(batch))),
+                    )
+                    .await?
+                }
+            };
+            let output = match &pipeline.breaker {
+                sub::Breaker::Join { build, kind, keys } => {
+                    join::semi_join(executor, output, &outputs[*build], keys, *kind).await?
+                }
+                _ => output,
+            };
+            if let Some(analysis) = analysis.as_deref_mut() {
+                analysis.pipelines[i].profile =
+                    profiled.and_then(|p| p.finish_profile(output.num_rows()));
+            }
+            outputs.push(output);
+        }
+        outputs
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("Plan has no pipelines"))
+    }
+
+    fn scan(
+        &self,
+        table: Option<&str>,
+        filter: Option<&jit::Expression>,
+        projection: Option<&[usize]>,
+        input: &mut DefaultInput,
+    ) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        if let Some(table) = table.and_then(|name| self.tables.get(name)) {
+            return table.scan_with(filter, projection);
+        }
+        match input {
+            DefaultInput::Stream(reader) => {
+                let reader = reader.take().ok_or_else(|| {
+                    anyhow::anyhow!("The default input can only be streamed once")
+                })?;
+                source::project(reader, projection)
+            }
+            DefaultInput::Table(table) => table.scan_with(filter, projection),
+        }
+    }
+
+    async fn run_pipeline(
+        &self,
+        executor: &executor::QueryExecutor,
+        pipeline: &sub::Pipeline,
+        schema: SchemaRef,
+        batches: impl Iterator<Item = anyhow::Result<RecordBatch>>,
+    ) -> anyhow::Result<RecordBatch> {
+        let column_types: std::collections::HashMap<u32, DataType> = schema
+            .fields()
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (i as u32, f.data_type().clone()))
+            .collect();
 
-        let schema = reader.schema();
-        for (i, field) in schema.fields().iter().enumerate() {
-            physical_plan
-                .column_types
-                .insert(i as u32, field.data_type().clone());
+        if let sub::Breaker::Aggregate(aggregation) = &pipeline.breaker
+            && !aggregation.reduces_on_gpu()
+        {
+            return self
+                .run_hash_aggregate(
+                    executor,
+                    pipeline,
+                    aggregation,
+                    schema,
+                    column_types,
+                    batches,
+                )
+                .await;
         }
-        let compiled = self.executor.compile(physical_plan)?;
 
-        let mut global_results: Option<executor::QueryResult> = None;
+        let mut fields = Vec::new();
+        let mut compiled = Vec::new();
+        let mut aggregates = Vec::new();
+        match &pipeline.breaker {
+            sub::Breaker::Aggregate(aggregation) => {
+                for (i, measure) in aggregation.measures.iter().enumerate() {
+                    fields.push(Field::new(
+                        format!("expr_{i}"),
+                        aggregate::output_type(measure),
+                        true,
+                    ));
+                    if measure.distinct {
+                        let (field, output) = self.value_output(
+                            pipeline,
+                            i,
+                            &measure.argument,
+                            &schema,
+                            &column_types,
+                        )?;
+                        let set_schema = Arc::new(Schema::new(vec![field]));
+                        compiled.push(output);
+                        aggregates.push(Accumulator::Distinct(
+                            measure.function,
+                            self.measure_filter(measure, &column_types)?,
+                            distinct::DistinctSet::new(
+                                set_schema,
+                                DISTINCT_BUDGET_ROWS,
+                                DISTINCT_LIMIT_ROWS,
+                            ),
+                        ));
+                    } else {
+                        let kernel = pipeline.measure_kernel(measure, &column_types);
+                        // Filtered SUMs count their rows to tell zero from no rows
+                        let rows = match measure.function {
+                            sub::AggregateFunction::Sum if kernel.filter.is_some() => {
+                                let count = sub::Measure {
+                                    function: sub::AggregateFunction::Count,
+                                    ..measure.clone()
+                                };
+                                let kernel = pipeline.measure_kernel(&count, &column_types);
+                                Some(executor.compile(kernel)?)
+                            }
+                            _ => None,
+                        };
+                        compiled.push(Output::Kernel(executor.compile(kernel)?));
+                        aggregates.push(Accumulator::Sum {
+                            function: measure.function,
+                            total: None,
+                            rows,
+                            passed: false,
+                        });
+                    }
+                }
+            }
+            _ => {
+                for (i, expr) in pipeline.outputs(schema.fields().len()).iter().enumerate() {
+                    let (field, output) =
+                        self.value_output(pipeline, i, expr, &schema, &column_types)?;
+                    fields.push(field);
+                    compiled.push(output);
+                }
+            }
+        }
+        let out_schema = Arc::new(Schema::new(fields));
+        let is_aggregate = matches!(pipeline.breaker, sub::Breaker::Aggregate(_));
+        let mut distinct_rows = matches!(pipeline.breaker, sub::Breaker::Distinct).then(|| {
+            distinct::DistinctSet::new(
+                out_schema.clone(),
+                DISTINCT_BUDGET_ROWS,
+                DISTINCT_LIMIT_ROWS,
+            )
+        });
+
+        // Summed aggregates have the filter fused into their kernels
+        let needs_selection = !is_aggregate
+            || aggregates
+                .iter()
+                .any(|a| matches!(a, Accumulator::Distinct(..)));
+        let selection = match pipeline.selection_kernel(&column_types) {
+            Some(kernel) if needs_selection => Some(executor.compile(kernel)?),
+            _ => None,
+        };
+
+        let mut collected = Vec::new();
 
         // stream batches
-        for batch_res in reader {
+        for batch_res in batches {
             let batch = batch_res?;
-            let batch_out = self.executor.execute(&compiled, &batch).await?;
 
-            if let Some(ref mut global) = global_results {
-                global.accumulate(batch_out)?;
-            } else {
-                global_results = Some(batch_out);
+            let mask = self
+                .selection_mask(executor, selection.as_ref(), &batch)
+                .await?;
+
+            if is_aggregate {
+                for (output, accumulator) in compiled.iter().zip(aggregates.iter_mut()) {
+                    match accumulator {
+                        Accumulator::Sum {
+                            total,
+                            rows,
+                            passed,
+                            ..
+                        } => {
+                            let Output::Kernel(kernel) = output else {
+                                anyhow::bail!("Aggregate measures run on the GPU");
+                            };
+                            let result = executor.execute(kernel, &batch).await?;
+                            // Rows only need counting while every batch summed to zero
+                            if !*passed {
+                                *passed = match rows {
+                                    None => batch.num_rows() > 0,
+                                    Some(_) if result != executor::QueryResult::Aggregate(0.0) => {
+                                        true
+                                    }
+                                    Some(rows) => matches!(
+                                        executor.execute(rows, &batch).await?,
+                                        executor::QueryResult::Aggregate(n) if n > 0.0
+                                    ),
+                                };
+                            }
+                            match total {
+                                Some(total) => total.accumulate(result)?,
+                                None => *total = Some(result),
+                            }
+                        }
+                        // Nulls never count towards a distinct aggregate
+                        Accumulator::Distinct(_, filter, set) => {
+                            let values = self
+                                .measure_values(
+                                    executor,
+                                    output,
+                                    filter.as_ref(),
+                                    &batch,
+                                    mask.as_ref(),
+                                )
+                                .await?;
+                            let values = arrow::compute::filter(
+                                &values,
+                                &arrow::compute::is_not_null(&values)?,
+                            )?;
+                            let values = RecordBatch::try_new(set.schema(), vec![values])?;
+                            set.insert(executor, values).await?;
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let mut columns: Vec<ArrayRef> = Vec::new();
+            for output in &compiled {
+                columns.push(
+                    self.evaluate(executor, output, &batch, mask.as_ref())
+                        .await?,
+                );
+            }
+            let batch = RecordBatch::try_new(out_schema.clone(), columns)?;
+            match &mut distinct_rows {
+                Some(set) => set.insert(executor, batch).await?,
+                None => collected.push(batch),
+            }
+        }
+
+        if is_aggregate {
+            let mut columns = Vec::new();
+            for accumulator in aggregates {
+                columns.push(accumulator.finish(executor).await?);
+            }
+            return Ok(RecordBatch::try_new(out_schema, columns)?);
+        }
+        if let Some(set) = distinct_rows {
+            return set.finish(executor).await;
+        }
+        let batch = arrow::compute::concat_batches(&out_schema, &collected)?;
+
+        match &pipeline.breaker {
+            sub::Breaker::Sort(sorts) => {
+                let sort_columns: Vec<_> = sorts
+                    .iter()
+                    .map(|s| arrow::compute::SortColumn {
+                        values: batch.column(s.column as usize).clone(),
+                        options: Some(arrow::compute::SortOptions {
+                            descending: s.descending,
+                            nulls_first: s.nulls_first,
+                        }),
+                    })
+                    .collect();
+                let indices = arrow::compute::lexsort_to_indices(&sort_columns, None)?;
+                let columns = batch
+                    .columns()
+                    .iter()
+                    .map(|c| arrow::compute::take(c, &indices, None))
+                    .collect::<Result<_, _>>()?;
+                Ok(RecordBatch::try_new(out_schema, columns)?)
+            }
+            sub::Breaker::Fetch { offset, count } => {
+                let offset = (*offset).min(batch.num_rows());
+                let len = count.unwrap_or(usize::MAX).min(batch.num_rows() - offset);
+                Ok(batch.slice(offset, len))
+            }
+            sub::Breaker::Window(window) => crate::window::evaluate(executor, batch, window).await,
+            _ => Ok(batch),
+        }
+    }
+
+    // Grouped or split aggregation, every grouping set is fed from the same pass
+    async fn run_hash_aggregate(
+        &self,
+        executor: &executor::QueryExecutor,
+        pipeline: &sub::Pipeline,
+        aggregation: &sub::Aggregation,
+        schema: SchemaRef,
+        column_types: std::collections::HashMap<u32, DataType>,
+        batches: impl Iterator<Item = anyhow::Result<RecordBatch>>,
+    ) -> anyhow::Result<RecordBatch> {
+        let mut key_fields = Vec::new();
+        let mut keys = Vec::new();
+        for (i, expr) in aggregation.keys.iter().enumerate() {
+            let (field, output) = self.value_output(pipeline, i, expr, &schema, &column_types)?;
+            key_fields.push(field);
+            keys.push(output);
+        }
+        let mut values = Vec::new();
+        for (i, measure) in aggregation.measures.iter().enumerate() {
+            let i = aggregation.keys.len() + i;
+            values.push((
+                self.value_output(pipeline, i, &measure.argument, &schema, &column_types)?
+                    .1,
+                self.measure_filter(measure, &column_types)?,
+            ));
+        }
+        let selection = pipeline
+            .selection_kernel(&column_types)
+            .map(|kernel| executor.compile(kernel))
+            .transpose()?;
+
+        let mut state = aggregate::GroupedAggregate::new(key_fields, aggregation)?;
+        for batch_res in batches {
+            let batch = batch_res?;
+            let mask = self
+                .selection_mask(executor, selection.as_ref(), &batch)
+                .await?;
+
+            let mut key_columns = Vec::new();
+            for output in &keys {
+                key_columns.push(
+                    self.evaluate(executor, output, &batch, mask.as_ref())
+                        .await?,
+                );
+            }
+            let mut value_columns = Vec::new();
+            for (output, filter) in &values {
+                value_columns.push(
+                    self.measure_values(executor, output, filter.as_ref(), &batch, mask.as_ref())
+                        .await?,
+                );
             }
+            state.update(executor, &key_columns, &value_columns).await?;
         }
+        state.finish(aggregation.groupings.len() > 1)
+    }
+
+    // Rows passing the pipeline filter, None keeps every row
+    async fn selection_mask(
+        &self,
+        executor: &executor::QueryExecutor,
+        selection: Option<&executor::CompiledQuery>,
+        batch: &RecordBatch,
+    ) -> anyhow::Result<Option<arrow::array::BooleanArray>> {
+        let Some(kernel) = selection else {
+            return Ok(None);
+        };
+        match executor.execute(kernel, batch).await? {
+            executor::QueryResult::Projection(v) => {
+                Ok(Some(v.into_iter().map(|x| Some(x != 0)).collect()))
+            }
+            _ => anyhow::bail!("Selection kernel must produce i32 flags"),
+        }
+    }
+
+    fn measure_filter(
+        &self,
+        measure: &sub::Measure,
+        column_types: &std::collections::HashMap<u32, DataType>,
+    ) -> anyhow::Result<Option<executor::CompiledQuery>> {
+        measure
+            .filter
+            .as_ref()
+            .map(|f| {
+                self.executor
+                    .compile(sub::predicate_kernel(f, column_types))
+            })
+            .transpose()
+    }
+
+    // Measure arguments of the selected rows, null where the measure's own
+    // filter rejects the row so the aggregate skips it
+    async fn measure_values(
+        &self,
+        executor: &executor::QueryExecutor,
+        output: &Output,
+        filter: Option<&executor::CompiledQuery>,
+        batch: &RecordBatch,
+        mask: Option<&arrow::array::BooleanArray>,
+    ) -> anyhow::Result<ArrayRef> {
+        let values = self.evaluate(executor, output, batch, mask).await?;
+        let Some(keep) = self.selection_mask(executor, filter, batch).await? else {
+            return Ok(values);
+        };
+        let keep = match mask {
+            Some(mask) => arrow::compute::filter(&keep, mask)?.as_boolean().clone(),
+            None => keep,
+        };
+        Ok(arrow::compute::nullif(
+            &values,
+            &arrow::compute::not(&keep)?,
+        )?)
+    }
+
+    // Output field and source of one projected column
+    fn value_output(
+        &self,
+        pipeline: &sub::Pipeline,
+        i: usize,
+        expr: &jit::Expression,
+        schema: &SchemaRef,
+        column_types: &std::collections::HashMap<u32, DataType>,
+    ) -> anyhow::Result<(Field, Output)> {
+        let field = explain::value_field(i, expr, schema, column_types);
+        if let jit::Expression::Column(idx) = expr {
+            return Ok((field, Output::Column(*idx as usize)));
+        }
+        if jit::is_null(expr)? {
+            let data_type = field.data_type().clone();
+            return Ok((field, Output::Null(data_type)));
+        }
+        let kernel = self
+            .executor
+            .compile(pipeline.value_kernel(expr, column_types))?;
+        Ok((field, Output::Kernel(kernel)))
+    }
 
-        global_results.ok_or_else(|| anyhow::anyhow!("No data processed"))
+    // Column values for the rows passing the selection mask
+    async fn evaluate(
+        &self,
+        executor: &executor::QueryExecutor,
+        output: &Output,
+        batch: &RecordBatch,
+        mask: Option<&arrow::array::BooleanArray>,
+    ) -> anyhow::Result<ArrayRef> {
+        let column = match output {
+            Output::Column(idx) => batch.column(*idx).clone(),
+            Output::Kernel(kernel) => {
+                let values = executor.execute(kernel, batch).await?.into_array();
+                // Predicates are written out as 0/1
+                match jit::infer_type(&kernel.kernel.projection, &kernel.kernel.column_types) {
+                    jit::ScalarType::Bool => arrow::compute::cast(&values, &DataType::Boolean)?,
+                    _ => values,
+                }
+            }
+            Output::Null(data_type) => arrow::array::new_null_array(data_type, batch.num_rows()),
+        };
+        Ok(match mask {
+            Some(mask) => arrow::compute::filter(&column, mask)?,
+            None => column,
+        })
     }
 }
+
+// Reports the time to read every batch to the executor's profiler
+fn timed<'a>(
+    executor: &'a executor::QueryExecutor,
+    mut batches: impl Iterator<Item = anyhow::Result<RecordBatch>> + 'a,
+) -> impl Iterator<Item = anyhow::Result<RecordBatch>> + 'a {
+    std::iter::from_fn(move || {
+        let read = Instant::now();
+        let batch = batches.next();
+        let rows = match &batch {
+            Some(Ok(batch)) => Some(batch.num_rows()),
+            _ => None,
+        };
+        executor.profile_batch(read, rows);
+        batch
+    })
+}
+
+// Running state of one aggregate measure
+enum Accumulator {
+    // Reduced on the GPU. A SUM is null until a row passes its filters,
+    // `rows` counts them on the batches that sum to zero.
+    Sum {
+        function: sub::AggregateFunction,
+        total: Option<executor::QueryResult>,
+        rows: Option<executor::CompiledQuery>,
+        passed: bool,
+    },
+    Distinct(
+        sub::AggregateFunction,
+        Option<executor::CompiledQuery>,
+        distinct::DistinctSet,
+    ),
+}
+
+impl Accumulator {
+    async fn finish(self, executor: &executor::QueryExecutor) -> anyhow::Result<ArrayRef> {
+        match self {
+            Accumulator::Sum {
+                function,
+                total,
+                passed,
+                ..
+            } => {
+                match (function, total) {
+                    (sub::AggregateFunction::Sum, _) if !passed => {
+                        Ok(arrow::array::new_null_array(&DataType::Float32, 1))
+                    }
+                    // Every workgroup adds up whole rows, the total is integral
+                    (
+                        sub::AggregateFunction::Count,
+                        Some(executor::QueryResult::Aggregate(count)),
+                    ) => Ok(Arc::new(Int64Array::from(vec![count.round() as i64]))),
+                    // No batches, e.g. every partition was pruned
+                    (sub::AggregateFunction::Count, None) => {
+                        Ok(Arc::new(Int64Array::from(vec![0])))
+                    }
+                    (_, Some(total)) => Ok(total.into_array()),
+                    (_, None) => Ok(arrow::array::new_null_array(&DataType::Float32, 1)),
+                }
+            }
+            Accumulator::Distinct(function, _, set) => {
+                let values = set.finish(executor).await?;
+                let values = values.column(0);
+                let sum = || -> anyhow::Result<f32> {
+                    let values = arrow::compute::cast(values, &DataType::Float32)?;
+                    Ok(
+                        arrow::compute::sum(values.as_primitive::<arrow::datatypes::Float32Type>())
+                            .unwrap_or(0.0),
+                    )
+                };
+                // SUM and AVG over no values are null
+                let total = match function {
+                    sub::AggregateFunction::Count => {
+                        return Ok(Arc::new(Int64Array::from(vec![values.len() as i64])));
+                    }
+                    _ if values.is_empty() => None,
+                    sub::AggregateFunction::Sum => Some(sum()?),
+                    sub::AggregateFunction::Avg => Some(sum()? / values.len() as f32),
+                };
+                Ok(Arc::new(arrow::array::Float32Array::from(vec![total])))
+            }
+        }
+    }
+}
+
+// Literal of the type `scalar_value` binds for a subquery with `output`
+fn scalar_placeholder(output: &Schema) -> anyhow::Result<jit::Expression> {
+    if output.fields().len() != 1 {
+        anyhow::bail!(
+            "Scalar subquery returns {} columns, expected one",
+            output.fields().len()
+        );
+    }
+    let literal = match output.field(0).data_type() {
+        DataType::Date32 => jit::LiteralTypes::Date(0),
+        DataType::Float32 | DataType::Float64 | DataType::Decimal128(_, _) => {
+            jit::LiteralTypes::F32(0.0)
+        }
+        _ => jit::LiteralTypes::I32(0),
+    };
+    Ok(jit::Expression::Literal(literal))
+}
+
+// Literal of a scalar subquery's single value
+fn scalar_value(batch: &RecordBatch) -> anyhow::Result<jit::Expression> {
+    if batch.num_columns() != 1 || batch.num_rows() > 1 {
+        anyhow::bail!(
+            "Scalar subquery returned {} rows of {} columns, expected one value",
+            batch.num_rows(),
+            batch.num_columns()
+        );
+    }
+    // No row is a NULL value
+    let column = batch.column(0);
+    if column.is_empty() || column.is_null(0) {
+        let placeholder = scalar_placeholder(&batch.schema())?;
+        let scalar = jit::infer_type(&placeholder, &Default::default());
+        return Ok(jit::Expression::Literal(jit::LiteralTypes::Null(scalar)));
+    }
+    let literal = match column.data_type() {
+        DataType::Date32 => jit::LiteralTypes::Date(
+            column
+                .as_primitive::<arrow::datatypes::Date32Type>()
+                .value(0),
+        ),
+        DataType::Float32 | DataType::Float64 | DataType::Decimal128(_, _) => {
+            let value = arrow::compute::cast(column, &DataType::Float32)?;
+            jit::LiteralTypes::F32(
+                value
+                    .as_primitive::<arrow::datatypes::Float32Type>()
+                    .value(0),
+            )
+        }
+        _ => {
+            let value = arrow::compute::cast(column, &DataType::Int32)?;
+            jit::LiteralTypes::I32(value.as_primitive::<arrow::datatypes::Int32Type>().value(0))
+        }
+    };
+    Ok(jit::Expression::Literal(literal))
+}
@@ -1,81 +1,103 @@
 use arrow::array::AsArray;
 
-use crate::{gpu::Gpu, jit, sub::PhysicalPlan};
+use crate::{gpu::Gpu, jit, profile, sub::KernelPlan};
 
 pub struct QueryExecutor {
     gpu: Gpu,
+    // Pipelines by shader source, reused by every query this executor runs
+    pipelines:
+        std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, wgpu::ComputePipeline>>>,
+    // Stage times of the pipeline being analyzed, only set on the executors
+    // `profiled` returns
+    profiler: Option<std::sync::Mutex<profile::Profiler>>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum QueryResult {
     Projection(Vec<i32>),
-    Aggregate(f32),
+    FloatProjection(Vec<f32>),
+    // Summed in f64 on the host, so counts stay exact past 2^24
+    Aggregate(f64),
 }
 
 pub struct CompiledQuery {
     pub pipeline: wgpu::ComputePipeline,
     pub mapping: std::collections::BTreeMap<u32, u32>,
     pub used_cols: std::collections::BTreeSet<u32>,
-    pub physical_plan: PhysicalPlan,
+    pub output_type: jit::ScalarType,
+    pub kernel: KernelPlan,
 }
 
-impl QueryExecutor {
-    pub fn new(gpu: Gpu) -> Self {
-        Self { gpu }
+// WGSL of a kernel and the binding of every column it reads. Columns bind
+// in order from 0, followed by the output and the params uniform.
+pub fn kernel_shader(kernel: &KernelPlan) -> (String, std::collections::BTreeMap<u32, u32>) {
+    let mut used_cols = std::collections::BTreeSet::new();
+    jit::collect_columns(&kernel.projection, &mut used_cols);
+    if let Some(f) = &kernel.filter {
+        jit::collect_columns(f, &mut used_cols);
     }
 
-    pub fn compile(&self, physical_plan: PhysicalPlan) -> anyhow::Result<CompiledQuery> {
-        let mut used_cols = std::collections::BTreeSet::new();
-        // Columns in the query
-        jit::collect_columns(&physical_plan.projection, &mut used_cols);
+    let mapping = used_cols
+        .iter()
+        .enumerate()
+        .map(|(binding, &col)| (col, binding as u32))
+        .collect();
+    (jit::generate_shader(kernel, &mapping), mapping)
+}
 
-        // Check for filters
-        if let Some(f) = &physical_plan.filter {
-            jit::collect_columns(f, &mut used_cols);
+impl QueryExecutor {
+    pub fn new(gpu: Gpu) -> Self {
+        Self {
+            gpu,
+            pipelines: Default::default(),
+            profiler: None,
         }
+    }
 
-        // Map columns to sequential bindings
-        let mut mapping = std::collections::BTreeMap::new();
-        for (idx, &col) in used_cols.iter().enumerate() {
-            mapping.insert(col, idx as u32);
+    // Executor on the same device and pipeline cache that records the stage
+    // times of its work until `finish_profile`. The engine creates one for
+    // every pipeline it analyzes, so concurrent queries never share one.
+    pub fn profiled(&self) -> Self {
+        Self {
+            gpu: self.gpu.clone(),
+            pipelines: self.pipelines.clone(),
+            profiler: Some(Default::default()),
         }
+    }
 
-        // Generate shader from mapping
-        let wgsl = jit::generate_shader(&physical_plan, &mapping);
+    pub fn finish_profile(self, rows_out: usize) -> Option<profile::PipelineProfile> {
+        self.profiler
+            .map(|p| p.into_inner().unwrap_or_else(|e| e.into_inner()))
+            .map(|p| p.finish(rows_out))
+    }
 
-        if cfg!(debug_assertions) {
-            wgsl.lines()
-                .enumerate()
-                .for_each(|(i, l)| println!("{:>3} | {}", i + 1, l));
+    // An input batch of `rows` was read starting at `read`, None at the end
+    pub fn profile_batch(&self, read: std::time::Instant, rows: Option<usize>) {
+        if let Some(profiler) = &self.profiler {
+            profiler
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .batch(read, rows);
         }
+    }
 
-        // LOAD SHADER
-        let shader = self
-            .gpu
-            .device
-            .create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: Some("Dynamic Shader"),
-                source: wgpu::ShaderSource::Wgsl(wgsl.into()),
-            });
+    fn record(&self, stage: impl FnOnce(&mut profile::StageTimes)) {
+        if let Some(profiler) = &self.profiler {
+            stage(profiler.lock().unwrap_or_else(|e| e.into_inner()).times());
+        }
+    }
 
-        // PIPELINE
-        let pipeline = self
-            .gpu
-            .device
-            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                label: Some("Compute Pipeline"),
-                layout: None,
-                module: &shader,
-                entry_point: Some("main"),
-                compilation_options: Default::default(),
-                cache: None,
-            });
+    pub fn compile(&self, kernel: KernelPlan) -> anyhow::Result<CompiledQuery> {
+        let (wgsl, mapping) = kernel_shader(&kernel);
+        let pipeline = self.compute_pipeline("Dynamic Shader", wgsl);
+        let used_cols = mapping.keys().copied().collect();
 
         Ok(CompiledQuery {
             pipeline,
             mapping,
             used_cols,
-            physical_plan,
+            output_type: jit::output_type(&kernel),
+            kernel,
         })
     }
 
@@ -85,9 +107,10 @@ impl QueryExecutor {
         batch: &arrow::record_batch::RecordBatch,
     ) -> anyhow::Result<QueryResult> {
         // BUFFERS
+        let upload = std::time::Instant::now();
         let row_count = batch.num_rows() as u32;
         let workgroup_count = row_count.div_ceil(64);
-        let output_len = if query.physical_plan.is_aggregate {
+        let output_len = if query.kernel.is_aggregate {
             // aggregtion 6400 rows need 100 write operation
             workgroup_count
         } else {
@@ -120,6 +143,16 @@ impl QueryExecutor {
                     "col",
                     data.as_primitive::<arrow::datatypes::Date32Type>().values(),
                 ),
+                // COUNT results, narrowed to i32 and saturated on overflow
+                arrow::datatypes::DataType::Int64 => {
+                    let values: Vec<i32> = data
+                        .as_primitive::<arrow::datatypes::Int64Type>()
+                        .values()
+                        .iter()
+                        .map(|v| (*v).clamp(i32::MIN as i64 + 1, i32::MAX as i64) as i32)
+                        .collect();
+                    self.gpu.input_buffer("col", &values)
+                }
                 // Duckdb generates Decimal128, move f32 downcasting to GPU
                 arrow::datatypes::DataType::Decimal128(_precision, _scale) => {
                     let array = data.as_primitive::<arrow::datatypes::Decimal128Type>();
@@ -161,8 +194,11 @@ impl QueryExecutor {
                 layout: &query.pipeline.get_bind_group_layout(0),
                 entries: &entries,
             });
+        self.record(|t| t.upload += upload.elapsed());
 
         // EXECUTE
+        let dispatch = std::time::Instant::now();
+        let timestamps = self.timestamp_queries();
         let mut encoder = self
             .gpu
             .device
@@ -173,7 +209,13 @@ impl QueryExecutor {
         {
             let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
                 label: Some("Compute Pass"),
-                ..Default::default()
+                timestamp_writes: timestamps.as_ref().map(|(query_set, _, _)| {
+                    wgpu::ComputePassTimestampWrites {
+                        query_set,
+                        beginning_of_pass_write_index: Some(0),
+                        end_of_pass_write_index: Some(1),
+                    }
+                }),
             });
 
             compute_pass.set_pipeline(&query.pipeline);
@@ -183,12 +225,16 @@ impl QueryExecutor {
 
         // COPY TO STAGING BUFFER
         encoder.copy_buffer_to_buffer(&output_buffer, 0, &stagging_buffer, 0, size);
+        if let Some((query_set, resolve, stage)) = &timestamps {
+            encoder.resolve_query_set(query_set, 0..2, resolve, 0);
+            encoder.copy_buffer_to_buffer(resolve, 0, stage, 0, 16);
+        }
 
-        println!("Submitting command encoder");
         self.gpu.queue.submit(Some(encoder.finish()));
+        self.record(|t| t.dispatch += dispatch.elapsed());
 
         // BACK TO CPU
-        println!("Mapping Buffer");
+        let readback = std::time::Instant::now();
         let buffer_slice = stagging_buffer.slice(..);
         let (sender, receiver) = tokio::sync::oneshot::channel();
 
@@ -196,7 +242,6 @@ impl QueryExecutor {
             let _ = sender.send(v);
         });
 
-        println!("Polling");
         self.gpu
             .device
             .poll(wgpu::PollType::Wait {
@@ -207,7 +252,6 @@ impl QueryExecutor {
 
         // wait for gpu
         // Handle aggregate
-        println!("Awaiting receiver");
         let result_val = receiver
             .await
             .map_err(|_| anyhow::anyhow!("Channel closed"))?;
@@ -216,9 +260,18 @@ impl QueryExecutor {
 
         let data = buffer_slice.get_mapped_range();
 
-        let final_result = if query.physical_plan.is_aggregate {
+        let final_result = if query.kernel.is_aggregate {
             let partials: &[f32] = bytemuck::cast_slice(&data);
-            QueryResult::Aggregate(partials[0..workgroup_count as usize].iter().sum())
+            QueryResult::Aggregate(
+                partials[0..workgroup_count as usize]
+                    .iter()
+                    .map(|p| *p as f64)
+                    .sum(),
+            )
+        } else if query.output_type == jit::ScalarType::F32 {
+            let mut result = bytemuck::cast_slice(&data).to_vec();
+            result.truncate(row_count as usize);
+            QueryResult::FloatProjection(result)
         } else {
             let mut result = bytemuck::cast_slice(&data).to_vec();
             result.truncate(row_count as usize);
@@ -227,16 +280,419 @@ impl QueryExecutor {
 
         drop(data);
         stagging_buffer.unmap();
+
+        if let Some((_, _, stage)) = &timestamps {
+            let ticks: Vec<u64> = self.mapped(stage).await?;
+            let period = self.gpu.queue.get_timestamp_period() as f64;
+            let gpu = std::time::Duration::from_nanos(
+                (ticks[1].saturating_sub(ticks[0]) as f64 * period) as u64,
+            );
+            self.record(|t| t.gpu = Some(t.gpu.unwrap_or_default() + gpu));
+        }
+        self.record(|t| t.readback += readback.elapsed());
         Ok(final_result)
     }
 }
 
+// Window functions and other breakers use these generic GPU building blocks
+impl QueryExecutor {
+    // Workgroups for `threads` invocations, spilling into y past the per-dimension limit
+    fn dispatch_size(&self, threads: u32) -> (u32, u32) {
+        let groups = threads.div_ceil(64).max(1);
+        let max = self
+            .gpu
+            .device
+            .limits()
+            .max_compute_workgroups_per_dimension;
+        let x = groups.min(max);
+        (x, groups.div_ceil(x))
+    }
+
+    fn compute_pipeline(&self, label: &str, wgsl: String) -> wgpu::ComputePipeline {
+        let mut pipelines = self.pipelines.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(pipeline) = pipelines.get(&wgsl) {
+            return pipeline.clone();
+        }
+
+        let shader = self
+            .gpu
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(label),
+                source: wgpu::ShaderSource::Wgsl(wgsl.as_str().into()),
+            });
+
+        let pipeline = self
+            .gpu
+            .device
+            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: None,
+                module: &shader,
+                entry_point: Some("main"),
+                compilation_options: Default::default(),
+                cache: None,
+            });
+        pipelines.insert(wgsl, pipeline.clone());
+        pipeline
+    }
+
+    // Query set, resolve and staging buffers for the start and end timestamps
+    // of a compute pass, only while profiling on an adapter that has them
+    fn timestamp_queries(&self) -> Option<(wgpu::QuerySet, wgpu::Buffer, wgpu::Buffer)> {
+        if self.profiler.is_none()
+            || !self
+                .gpu
+                .device
+                .features()
+                .contains(wgpu::Features::TIMESTAMP_QUERY)
+        {
+            return None;
+        }
+        let query_set = self.gpu.device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("Timestamps"),
+            ty: wgpu::QueryType::Timestamp,
+            count: 2,
+        });
+        let resolve = self.gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("timestamps"),
+            size: 16,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        Some((
+            query_set,
+            resolve,
+            self.gpu.stagging_buffer("timestamps_stage", 16),
+        ))
+    }
+
+    // Parameters for every dispatch in one buffer, each at a bindable offset
+    fn stage_params(&self, stages: &[[u32; 4]]) -> (wgpu::Buffer, u64) {
+        let align = self.gpu.device.limits().min_storage_buffer_offset_alignment as usize;
+        let mut bytes = vec![0u8; stages.len().max(1) * align];
+        for (i, stage) in stages.iter().enumerate() {
+            bytes[i * align..i * align + 16].copy_from_slice(bytemuck::bytes_of(stage));
+        }
+        (self.gpu.input_buffer("stages", &bytes), align as u64)
+    }
+
+    // Copy `buffer` to a staging buffer after the recorded work and map it back
+    async fn read_back<T: bytemuck::Pod>(
+        &self,
+        mut encoder: wgpu::CommandEncoder,
+        buffer: &wgpu::Buffer,
+        size: u64,
+    ) -> anyhow::Result<Vec<T>> {
+        let dispatch = std::time::Instant::now();
+        let stagging_buffer = self.gpu.stagging_buffer("stage", size);
+        encoder.copy_buffer_to_buffer(buffer, 0, &stagging_buffer, 0, size);
+        self.gpu.queue.submit(Some(encoder.finish()));
+        self.record(|t| t.dispatch += dispatch.elapsed());
+
+        let readback = std::time::Instant::now();
+        let data = self.mapped(&stagging_buffer).await;
+        self.record(|t| t.readback += readback.elapsed());
+        data
+    }
+
+    // Maps a staging buffer once the submitted work is done and copies it out
+    async fn mapped<T: bytemuck::Pod>(
+        &self,
+        stagging_buffer: &wgpu::Buffer,
+    ) -> anyhow::Result<Vec<T>> {
+        let buffer_slice = stagging_buffer.slice(..);
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        buffer_slice.map_async(wgpu::MapMode::Read, move |v| {
+            let _ = sender.send(v);
+        });
+        self.gpu
+            .device
+            .poll(wgpu::PollType::Wait {
+                submission_index: None,
+                timeout: None,
+            })
+            .map_err(|e| anyhow::anyhow!("GPU Poll error: {e}"))?;
+        receiver
+            .await
+            .map_err(|_| anyhow::anyhow!("Channel closed"))?
+            .map_err(|e| anyhow::anyhow!("Buffer mapping failed: {e}"))?;
+
+        let data = bytemuck::cast_slice(&buffer_slice.get_mapped_range()).to_vec();
+        stagging_buffer.unmap();
+        Ok(data)
+    }
+
+    // Stable bitonic sort on the GPU, returns the row positions in ascending key order
+    pub async fn sort_indices(&self, keys: &[u32]) -> anyhow::Result<Vec<u32>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Padding sorts after every real row: max key and a larger index
+        let len = keys.len().next_power_of_two().max(2);
+        let mut padded = keys.to_vec();
+        padded.resize(len, u32::MAX);
+        let indices: Vec<u32> = (0..len as u32).collect();
+
+        let key_buffer = self.gpu.storage_buffer("sort_keys", &padded);
+        let index_buffer = self.gpu.storage_buffer("sort_indices", &indices);
+
+        let (groups_x, groups_y) = self.dispatch_size(len as u32);
+        let stride = groups_x * 64;
+        let mut stages = Vec::new();
+        let mut k = 2;
+        while k <= len as u32 {
+            let mut j = k / 2;
+            while j > 0 {
+                stages.push([j, k, stride, len as u32]);
+                j /= 2;
+            }
+            k *= 2;
+        }
+        let (params, align) = self.stage_params(&stages);
+
+        let pipeline = self.compute_pipeline("Sort", jit::generate_sort_shader());
+        let layout = pipeline.get_bind_group_layout(0);
+        let bind_groups: Vec<_> = (0..stages.len() as u64)
+            .map(|s| {
+                self.gpu
+                    .device
+                    .create_bind_group(&wgpu::BindGroupDescriptor {
+                        label: Some("Sort Bind Group"),
+                        layout: &layout,
+                        entries: &[
+                            wgpu::BindGroupEntry {
+                                binding: 0,
+                                resource: key_buffer.as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 1,
+                                resource: index_buffer.as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 2,
+                                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                                    buffer: &params,
+                                    offset: s * align,
+                                    size: wgpu::BufferSize::new(16),
+                                }),
+                            },
+                        ],
+                    })
+            })
+            .collect();
+
+        let mut encoder = self
+            .gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Sort Encoder"),
+            });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Sort Pass"),
+                ..Default::default()
+            });
+            compute_pass.set_pipeline(&pipeline);
+            for bind_group in &bind_groups {
+                compute_pass.set_bind_group(0, bind_group, &[]);
+                compute_pass.dispatch_workgroups(groups_x, groups_y, 1);
+            }
+        }
+
+        let mut sorted: Vec<u32> = self
+            .read_back(encoder, &index_buffer, (len * 4) as u64)
+            .await?;
+        sorted.truncate(keys.len());
+        Ok(sorted)
+    }
+
+    // Inclusive scan of `values` that restarts at every row with a head flag of 1
+    pub async fn segmented_scan<T: bytemuck::Pod>(
+        &self,
+        values: &[T],
+        heads: &[u32],
+        elem: jit::ScalarType,
+        op: jit::ScanOp,
+    ) -> anyhow::Result<Vec<T>> {
+        if values.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let len = values.len() as u32;
+        let size = std::mem::size_of_val(values) as u64;
+        // Ping-pong between the two buffer pairs, one step per dispatch
+        let vals = [
+            self.gpu.storage_buffer("scan_val_a", values),
+            self.gpu.output_buffer("scan_val_b", size),
+        ];
+        let flags = [
+            self.gpu.storage_buffer("scan_head_a", heads),
+            self.gpu
+                .output_buffer("scan_head_b", (heads.len() * 4) as u64),
+        ];
+
+        let (groups_x, groups_y) = self.dispatch_size(len);
+        let mut steps = Vec::new();
+        let mut offset = 1;
+        while offset < len {
+            steps.push([offset, groups_x * 64, len, 0]);
+            offset *= 2;
+        }
+        let (params, align) = self.stage_params(&steps);
+
+        let pipeline = self.compute_pipeline("Scan", jit::generate_scan_shader(elem, op));
+        let layout = pipeline.get_bind_group_layout(0);
+        let bind_groups: Vec<_> = (0..steps.len())
+            .map(|s| {
+                let (src, dst) = (s % 2, (s + 1) % 2);
+                self.gpu
+                    .device
+                    .create_bind_group(&wgpu::BindGroupDescriptor {
+                        label: Some("Scan Bind Group"),
+                        layout: &layout,
+                        entries: &[
+                            wgpu::BindGroupEntry {
+                                binding: 0,
+                                resource: vals[src].as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 1,
+                                resource: flags[src].as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 2,
+                                resource: vals[dst].as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 3,
+                                resource: flags[dst].as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 4,
+                                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                                    buffer: &params,
+                                    offset: s as u64 * align,
+                                    size: wgpu::BufferSize::new(16),
+                                }),
+                            },
+                        ],
+                    })
+            })
+            .collect();
+
+        let mut encoder = self
+            .gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Scan Encoder"),
+            });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Scan Pass"),
+                ..Default::default()
+            });
+            compute_pass.set_pipeline(&pipeline);
+            for bind_group in &bind_groups {
+                compute_pass.set_bind_group(0, bind_group, &[]);
+                compute_pass.dispatch_workgroups(groups_x, groups_y, 1);
+            }
+        }
+
+        self.read_back(encoder, &vals[steps.len() % 2], size).await
+    }
+
+    // Rows of `width` words one hash set dispatch can take, the table is kept
+    // at most half full and both buffers must fit in a storage binding
+    pub fn hash_set_rows(&self, width: usize) -> usize {
+        let limits = self.gpu.device.limits();
+        let words = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size) / 4;
+        let capacity = 1u64 << words.ilog2();
+        ((capacity / 2).min(words / width.max(1) as u64)) as usize
+    }
+
+    // Groups equal keys in `keys`, a row major array of `width` words per row.
+    // Every row maps to one representative row of its key, which maps to
+    // itself. At most `hash_set_rows(width)` rows.
+    pub async fn hash_groups(&self, keys: &[u32], width: usize) -> anyhow::Result<Vec<u32>> {
+        let rows = keys.len() / width.max(1);
+        if rows == 0 {
+            return Ok(Vec::new());
+        }
+        if rows > self.hash_set_rows(width) {
+            anyhow::bail!("{} rows do not fit in one GPU hash set", rows);
+        }
+
+        let capacity = (rows * 2).next_power_of_two();
+        let key_buffer = self.gpu.input_buffer("hash_keys", keys);
+        let table = self.gpu.output_buffer("hash_table", (capacity * 4) as u64);
+        let groups = self.gpu.output_buffer("hash_groups", (rows * 4) as u64);
+
+        let (groups_x, groups_y) = self.dispatch_size(rows as u32);
+        let (params, _) =
+            self.stage_params(&[[rows as u32, width as u32, capacity as u32, groups_x * 64]]);
+
+        let pipeline = self.compute_pipeline("Hash Set", jit::generate_hash_set_shader());
+        let bind_group = self
+            .gpu
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Hash Set Bind Group"),
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: key_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: table.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: groups.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                            buffer: &params,
+                            offset: 0,
+                            size: wgpu::BufferSize::new(16),
+                        }),
+                    },
+                ],
+            });
+
+        let mut encoder = self
+            .gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Hash Set Encoder"),
+            });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Hash Set Pass"),
+                ..Default::default()
+            });
+            compute_pass.set_pipeline(&pipeline);
+            compute_pass.set_bind_group(0, &bind_group, &[]);
+            compute_pass.dispatch_workgroups(groups_x, groups_y, 1);
+        }
+
+        self.read_back(encoder, &groups, (rows * 4) as u64).await
+    }
+}
+
 impl QueryResult {
     pub fn accumulate(&mut self, other: QueryResult) -> anyhow::Result<()> {
         match (self, other) {
             (QueryResult::Projection(v1), QueryResult::Projection(v2)) => {
                 v1.extend(v2);
             }
+            (QueryResult::FloatProjection(v1), QueryResult::FloatProjection(v2)) => {
+                v1.extend(v2);
+            }
             (QueryResult::Aggregate(s1), QueryResult::Aggregate(s2)) => {
                 *s1 += s2;
             }
@@ -244,4 +700,15 @@ impl QueryResult {
         }
         Ok(())
     }
+    pub fn into_array(self) -> arrow::array::ArrayRef {
+        match self {
+            QueryResult::Projection(v) => std::sync::Arc::new(arrow::array::Int32Array::from(v)),
+            QueryResult::FloatProjection(v) => {
+                std::sync::Arc::new(arrow::array::Float32Array::from(v))
+            }
+            QueryResult::Aggregate(v) => {
+                std::sync::Arc::new(arrow::array::Float32Array::from(vec![v as f32]))
+            }
+        }
+    }
 }
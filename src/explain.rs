@@ -0,0 +1,405 @@
+use std::{collections::HashMap, fmt, sync::Arc, time::Duration};
+
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+
+use crate::{aggregate, executor, gpu::QueryParams, jit, profile, sub};
+
+// How the engine runs a plan: the physical operators, the pipelines they are
+// split into and the GPU kernels of every pipeline
+pub struct Explain {
+    pub plan: Arc<sub::PhysicalPlan>,
+    pub pipelines: Vec<PipelineExplain>,
+    // Wall time of the whole query, set by analyze
+    pub elapsed: Option<Duration>,
+}
+
+pub struct PipelineExplain {
+    pub source: sub::Source,
+    pub breaker: String,
+    pub input: SchemaRef,
+    pub output: SchemaRef,
+    pub kernels: Vec<KernelExplain>,
+    // Scalar subqueries run before the pipeline, its kernels are generated
+    // once their values are bound
+    pub subqueries: Vec<Explain>,
+    // Stage times and row counts, set by analyze
+    pub profile: Option<profile::PipelineProfile>,
+}
+
+pub struct KernelExplain {
+    // What the kernel computes for its pipeline, e.g. "filter" or "output 1"
+    pub role: String,
+    // Input columns in binding order, the output and params follow them
+    pub bindings: Vec<Binding>,
+    pub output_type: jit::ScalarType,
+    // Writes one partial sum per workgroup instead of one value per row
+    pub is_aggregate: bool,
+    pub wgsl: String,
+}
+
+pub struct Binding {
+    pub column: u32,
+    pub binding: u32,
+    pub data_type: DataType,
+}
+
+impl KernelExplain {
+    pub const WORKGROUP_SIZE: u32 = 64;
+
+    pub fn new(role: impl Into<String>, kernel: &sub::KernelPlan) -> Self {
+        let (wgsl, mapping) = executor::kernel_shader(kernel);
+        let bindings = mapping
+            .into_iter()
+            .map(|(column, binding)| Binding {
+                column,
+                binding,
+                data_type: kernel.column_types[&column].clone(),
+            })
+            .collect();
+        Self {
+            role: role.into(),
+            bindings,
+            output_type: jit::output_type(kernel),
+            is_aggregate: kernel.is_aggregate,
+            wgsl,
+        }
+    }
+
+    pub fn workgroups(&self, rows: usize) -> u32 {
+        (rows as u32).div_ceil(Self::WORKGROUP_SIZE)
+    }
+
+    // Bytes of every buffer bound for a batch of `rows`, in binding order
+    pub fn buffer_sizes(&self, rows: usize) -> Vec<u64> {
+        let mut sizes: Vec<u64> = self
+            .bindings
+            .iter()
+            .map(|b| (rows * binding_width(&b.data_type)) as u64)
+            .collect();
+        let outputs = if self.is_aggregate {
+            self.workgroups(rows) as usize
+        } else {
+            rows
+        };
+        sizes.push(((outputs * 4) as u64).max(64));
+        sizes.push(std::mem::size_of::<QueryParams>() as u64);
+        sizes
+    }
+}
+
+fn column_types(schema: &Schema) -> HashMap<u32, DataType> {
+    schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (i as u32, f.data_type().clone()))
+        .collect()
+}
+
+// Bytes per row of a column bound to a kernel, Int64 is narrowed to i32
+fn binding_width(data_type: &DataType) -> usize {
+    match data_type {
+        DataType::Int64 => 4,
+        t => t.primitive_width().unwrap_or(4),
+    }
+}
+
+// Field of one pipeline output, input columns pass through and kernels
+// write i32, f32 or a predicate's 0/1 returned as Boolean
+pub fn value_field(
+    i: usize,
+    expr: &jit::Expression,
+    schema: &Schema,
+    column_types: &HashMap<u32, DataType>,
+) -> Field {
+    if let jit::Expression::Column(idx) = expr {
+        return schema.field(*idx as usize).clone().with_nullable(true);
+    }
+    let data_type = match jit::infer_type(expr, column_types) {
+        jit::ScalarType::F32 => DataType::Float32,
+        jit::ScalarType::Bool => DataType::Boolean,
+        jit::ScalarType::I32 => DataType::Int32,
+    };
+    Field::new(format!("expr_{i}"), data_type, true)
+}
+
+// Schema of what the pipeline produces from `input`
+pub fn output_schema(pipeline: &sub::Pipeline, input: &Schema) -> SchemaRef {
+    let column_types = column_types(input);
+    let value = |(i, expr)| value_field(i, expr, input, &column_types);
+
+    let fields = match &pipeline.breaker {
+        sub::Breaker::Aggregate(aggregation) if aggregation.reduces_on_gpu() => aggregation
+            .measures
+            .iter()
+            .enumerate()
+            .map(|(i, m)| Field::new(format!("expr_{i}"), aggregate::output_type(m), true))
+            .collect(),
+        sub::Breaker::Aggregate(aggregation) => {
+            let mut fields: Vec<Field> = aggregation.keys.iter().enumerate().map(value).collect();
+            for (i, measure) in aggregation.measures.iter().enumerate() {
+                fields.push(Field::new(
+                    format!("expr_{}", aggregation.keys.len() + i),
+                    aggregate::output_type(measure),
+                    true,
+                ));
+            }
+            if aggregation.groupings.len() > 1 {
+                fields.push(Field::new("grouping_id", DataType::Int32, false));
+            }
+            fields
+        }
+        breaker => {
+            let outputs = pipeline.outputs(input.fields().len());
+            let mut fields: Vec<Field> = outputs.iter().enumerate().map(value).collect();
+            if let sub::Breaker::Window(window) = breaker {
+                for (k, function) in window.functions.iter().enumerate() {
+                    let data_type = match &function.kind {
+                        sub::WindowKind::RowNumber
+                        | sub::WindowKind::Rank
+                        | sub::WindowKind::DenseRank => DataType::Int32,
+                        sub::WindowKind::Lag { column, .. }
+                        | sub::WindowKind::Lead { column, .. } => {
+                            fields[*column as usize].data_type().clone()
+                        }
+                        sub::WindowKind::Sum(_) | sub::WindowKind::Avg(_) => DataType::Float32,
+                    };
+                    fields.push(Field::new(format!("window_{k}"), data_type, true));
+                }
+            }
+            fields
+        }
+    };
+    Arc::new(Schema::new(fields))
+}
+
+// The kernels the engine compiles for a pipeline over `input`
+pub fn pipeline_kernels(pipeline: &sub::Pipeline, input: &Schema) -> Vec<KernelExplain> {
+    let column_types = column_types(input);
+    let mut kernels = Vec::new();
+    // Columns evaluated per row, with the measure filter of each
+    let mut values: Vec<(String, &jit::Expression, Option<&jit::Expression>)> = Vec::new();
+    let mut needs_selection = true;
+
+    match &pipeline.breaker {
+        sub::Breaker::Aggregate(aggregation) if aggregation.reduces_on_gpu() => {
+            // Summed measures have the filter fused into their kernels
+            needs_selection = aggregation.measures.iter().any(|m| m.distinct);
+            for (i, measure) in aggregation.measures.iter().enumerate() {
+                let role = format!("measure {i}");
+                if measure.distinct {
+                    values.push((role, &measure.argument, measure.filter.as_ref()));
+                } else {
+                    let kernel = pipeline.measure_kernel(measure, &column_types);
+                    kernels.push(KernelExplain::new(role, &kernel));
+                }
+            }
+        }
+        sub::Breaker::Aggregate(aggregation) => {
+            for (i, key) in aggregation.keys.iter().enumerate() {
+                values.push((format!("key {i}"), key, None));
+            }
+            for (i, measure) in aggregation.measures.iter().enumerate() {
+                let role = format!("measure {i}");
+                values.push((role, &measure.argument, measure.filter.as_ref()));
+            }
+        }
+        _ => {
+            for (i, expr) in pipeline.projection.iter().flatten().enumerate() {
+                values.push((format!("output {i}"), expr, None));
+            }
+        }
+    }
+
+    if needs_selection && let Some(kernel) = pipeline.selection_kernel(&column_types) {
+        kernels.insert(0, KernelExplain::new("filter", &kernel));
+    }
+    for (role, expr, filter) in values {
+        if !matches!(expr, jit::Expression::Column(_)) {
+            let kernel = pipeline.value_kernel(expr, &column_types);
+            kernels.push(KernelExplain::new(role.clone(), &kernel));
+        }
+        if let Some(filter) = filter {
+            let kernel = sub::predicate_kernel(filter, &column_types);
+            kernels.push(KernelExplain::new(format!("{role} filter"), &kernel));
+        }
+    }
+    kernels
+}
+
+// One line per operator, inputs indented below it
+fn describe(plan: &sub::PhysicalPlan, depth: usize, f: &mut fmt::Formatter) -> fmt::Result {
+    let indent = "  ".repeat(depth);
+    let inputs: Vec<&sub::PhysicalPlan> = match plan {
+        sub::PhysicalPlan::Read {
+            column_types,
+            table,
+        } => {
+            let table = table.as_deref().unwrap_or("<default input>");
+            writeln!(f, "{indent}Read {table} ({} columns)", column_types.len())?;
+            Vec::new()
+        }
+        sub::PhysicalPlan::Filter { input, condition } => {
+            writeln!(f, "{indent}Filter {condition:?}")?;
+            vec![input]
+        }
+        sub::PhysicalPlan::Project { input, expressions } => {
+            writeln!(f, "{indent}Project {expressions:?}")?;
+            vec![input]
+        }
+        sub::PhysicalPlan::Aggregate { input, aggregation } => {
+            writeln!(
+                f,
+                "{indent}Aggregate keys {:?} groupings {:?} measures {:?}",
+                aggregation.keys, aggregation.groupings, aggregation.measures
+            )?;
+            vec![input]
+        }
+        sub::PhysicalPlan::Distinct { input } => {
+            writeln!(f, "{indent}Distinct")?;
+            vec![input]
+        }
+        sub::PhysicalPlan::Sort { input, sorts } => {
+            writeln!(f, "{indent}Sort {sorts:?}")?;
+            vec![input]
+        }
+        sub::PhysicalPlan::Fetch {
+            input,
+            offset,
+            count,
+        } => {
+            writeln!(f, "{indent}Fetch offset {offset} count {count:?}")?;
+            vec![input]
+        }
+        sub::PhysicalPlan::Window { input, window } => {
+            writeln!(f, "{indent}Window {window:?}")?;
+            vec![input]
+        }
+        sub::PhysicalPlan::Join {
+            probe,
+            build,
+            kind,
+            keys,
+        } => {
+            writeln!(f, "{indent}{kind:?} join on probe columns {keys:?}")?;
+            vec![probe, build]
+        }
+    };
+    for input in inputs {
+        describe(input, depth + 1, f)?;
+    }
+    Ok(())
+}
+
+fn describe_schema(schema: &Schema) -> String {
+    schema
+        .fields()
+        .iter()
+        .map(|f| format!("{} {}", f.name(), f.data_type()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl fmt::Display for Explain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Physical plan:")?;
+        describe(&self.plan, 1, f)?;
+        if let Some(elapsed) = self.elapsed {
+            writeln!(f, "Execution time: {elapsed:?}")?;
+        }
+
+        for (i, pipeline) in self.pipelines.iter().enumerate() {
+            let source = match &pipeline.source {
+                sub::Source::Table(Some(name)) => format!("table {name}"),
+                sub::Source::Table(None) => "default input".to_string(),
+                sub::Source::Pipeline(p) => format!("pipeline {p}"),
+            };
+            writeln!(f, "\nPipeline {i}: {source} -> {}", pipeline.breaker)?;
+            writeln!(f, "  input: {}", describe_schema(&pipeline.input))?;
+            writeln!(f, "  output: {}", describe_schema(&pipeline.output))?;
+            if let Some(profile) = &pipeline.profile {
+                writeln!(
+                    f,
+                    "  analyze: {} batches, {} rows in, {} rows out, {:?}",
+                    profile.batches.len(),
+                    profile.rows_in(),
+                    profile.rows_out,
+                    profile.elapsed
+                )?;
+                let mut batches = profile::StageTimes::default();
+                for batch in &profile.batches {
+                    batches += batch.times;
+                }
+                writeln!(f, "    setup: {}", profile.setup)?;
+                writeln!(f, "    batches: {batches}")?;
+                writeln!(f, "    finish: {}", profile.finish)?;
+            }
+
+            for (s, subquery) in pipeline.subqueries.iter().enumerate() {
+                writeln!(f, "  Scalar subquery {s}:")?;
+                for line in subquery.to_string().lines() {
+                    writeln!(f, "    {line}")?;
+                }
+            }
+            if !pipeline.subqueries.is_empty() {
+                writeln!(
+                    f,
+                    "  Kernels are generated once the subquery values are bound"
+                )?;
+            }
+
+            for kernel in &pipeline.kernels {
+                let output = match (kernel.is_aggregate, kernel.output_type) {
+                    (true, _) => "f32 per workgroup",
+                    (false, jit::ScalarType::F32) => "f32 per row",
+                    (false, _) => "i32 per row",
+                };
+                writeln!(f, "  Kernel {} -> {output}", kernel.role)?;
+                for b in &kernel.bindings {
+                    let width = binding_width(&b.data_type);
+                    writeln!(
+                        f,
+                        "    @binding({}) column {} {} ({width} B/row)",
+                        b.binding, b.column, b.data_type
+                    )?;
+                }
+                let out = kernel.bindings.len();
+                writeln!(
+                    f,
+                    "    @binding({out}) output, @binding({}) params",
+                    out + 1
+                )?;
+                writeln!(
+                    f,
+                    "    workgroups: ceil(rows / {})",
+                    KernelExplain::WORKGROUP_SIZE
+                )?;
+                for (n, line) in kernel.wgsl.lines().enumerate() {
+                    writeln!(f, "    {:>3} | {}", n + 1, line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for sub::Breaker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            sub::Breaker::Materialize => write!(f, "Materialize"),
+            sub::Breaker::Aggregate(aggregation) if aggregation.reduces_on_gpu() => {
+                write!(f, "GPU reduction")
+            }
+            sub::Breaker::Aggregate(_) => write!(f, "Hash aggregate"),
+            sub::Breaker::Distinct => write!(f, "Distinct"),
+            sub::Breaker::Sort(sorts) => write!(f, "Sort {sorts:?}"),
+            sub::Breaker::Fetch { offset, count } => {
+                write!(f, "Fetch offset {offset} count {count:?}")
+            }
+            sub::Breaker::Window(_) => write!(f, "Window"),
+            sub::Breaker::Join { build, kind, keys } => {
+                write!(f, "{kind:?} join against pipeline {build} on {keys:?}")
+            }
+        }
+    }
+}
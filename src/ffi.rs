@@ -0,0 +1,194 @@
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_int},
+    panic::AssertUnwindSafe,
+    sync::Arc,
+};
+
+use arrow::{
+    array::{RecordBatch, RecordBatchIterator},
+    ffi_stream::{ArrowArrayStreamReader, FFI_ArrowArrayStream},
+};
+
+use crate::{
+    engine::QueryEngine,
+    executor::QueryExecutor,
+    gpu::Gpu,
+    source::{MemoryTable, ParquetTable},
+    sub,
+};
+
+// C ABI over QueryEngine, declared in include/wsql.h. Tables come in and
+// results go out as Arrow C streams, so buffers are shared with the caller
+// instead of copied. Functions returning int give 0 on success and -1 on
+// failure, with the message from wsql_last_error on the same thread.
+
+pub struct WsqlEngine {
+    engine: QueryEngine,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).ok();
+    LAST_ERROR.with(|e| *e.borrow_mut() = message);
+}
+
+// Runs `f`, turning errors and panics into the last error
+fn guard<T>(f: impl FnOnce() -> anyhow::Result<T>) -> Option<T> {
+    match std::panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(e)) => {
+            set_error(e.to_string());
+            None
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Unknown panic".to_string());
+            set_error(format!("Panic: {message}"));
+            None
+        }
+    }
+}
+
+fn status(result: Option<()>) -> c_int {
+    match result {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, what: &str) -> anyhow::Result<&'a str> {
+    anyhow::ensure!(!s.is_null(), "{what} is null");
+    Ok(unsafe { CStr::from_ptr(s) }.to_str()?)
+}
+
+unsafe fn engine_arg<'a>(engine: *mut WsqlEngine) -> anyhow::Result<&'a mut QueryEngine> {
+    anyhow::ensure!(!engine.is_null(), "Engine is null");
+    Ok(&mut unsafe { &mut *engine }.engine)
+}
+
+// Hands `batch` to the caller as a stream of one batch
+unsafe fn export(batch: RecordBatch, out: *mut FFI_ArrowArrayStream) -> anyhow::Result<()> {
+    anyhow::ensure!(!out.is_null(), "Output stream is null");
+    let schema = batch.schema();
+    let reader = RecordBatchIterator::new([Ok(batch)], schema);
+    // `out` is uninitialised, nothing to drop
+    unsafe { std::ptr::write(out, FFI_ArrowArrayStream::new(Box::new(reader))) };
+    Ok(())
+}
+
+/// Opens the GPU and an engine without tables. Returns null on failure.
+#[unsafe(no_mangle)]
+pub extern "C" fn wsql_open() -> *mut WsqlEngine {
+    guard(|| {
+        let gpu = pollster::block_on(Gpu::new());
+        let engine = QueryEngine::new(QueryExecutor::new(gpu));
+        Ok(Box::into_raw(Box::new(WsqlEngine { engine })))
+    })
+    .unwrap_or(std::ptr::null_mut())
+}
+
+/// # Safety
+/// `engine` is null or from `wsql_open`, and not used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wsql_close(engine: *mut WsqlEngine) {
+    if !engine.is_null() {
+        drop(unsafe { Box::from_raw(engine) });
+    }
+}
+
+/// Registers the batches of `stream` as table `name`. The stream is read to
+/// the end and released, its buffers are kept without copying.
+///
+/// # Safety
+/// `engine` is from `wsql_open`, `name` a NUL-terminated string and `stream`
+/// a valid ArrowArrayStream the engine takes ownership of.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wsql_register_stream(
+    engine: *mut WsqlEngine,
+    name: *const c_char,
+    stream: *mut FFI_ArrowArrayStream,
+) -> c_int {
+    status(guard(|| {
+        let engine = unsafe { engine_arg(engine) }?;
+        let name = unsafe { str_arg(name, "Table name") }?;
+        anyhow::ensure!(!stream.is_null(), "Input stream is null");
+        let reader = unsafe { ArrowArrayStreamReader::from_raw(stream) }?;
+        let table = MemoryTable::collect(reader)?;
+        engine.register_table(name, Arc::new(table));
+        Ok(())
+    }))
+}
+
+/// Registers the parquet file at `path` as table `name`.
+///
+/// # Safety
+/// `engine` is from `wsql_open`, `name` and `path` NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wsql_register_parquet(
+    engine: *mut WsqlEngine,
+    name: *const c_char,
+    path: *const c_char,
+) -> c_int {
+    status(guard(|| {
+        let engine = unsafe { engine_arg(engine) }?;
+        let name = unsafe { str_arg(name, "Table name") }?;
+        let table = ParquetTable::open(unsafe { str_arg(path, "Path") }?)?;
+        engine.register_table(name, Arc::new(table));
+        Ok(())
+    }))
+}
+
+/// Runs a Substrait plan, as `len` bytes of JSON or binary protobuf, over
+/// the registered tables and writes the result to `out`.
+///
+/// # Safety
+/// `engine` is from `wsql_open`, `plan` points to `len` readable bytes and
+/// `out` to an ArrowArrayStream the caller releases.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wsql_execute_substrait(
+    engine: *mut WsqlEngine,
+    plan: *const u8,
+    len: usize,
+    out: *mut FFI_ArrowArrayStream,
+) -> c_int {
+    status(guard(|| {
+        let engine = unsafe { engine_arg(engine) }?;
+        anyhow::ensure!(!plan.is_null(), "Plan is null");
+        let plan = sub::parse_plan(unsafe { std::slice::from_raw_parts(plan, len) })?;
+        let batch = pollster::block_on(engine.query(&plan))?;
+        unsafe { export(batch, out) }
+    }))
+}
+
+/// Runs a SQL SELECT over the registered tables and writes the result to `out`.
+///
+/// # Safety
+/// `engine` is from `wsql_open`, `sql` a NUL-terminated string and `out`
+/// points to an ArrowArrayStream the caller releases.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wsql_execute_sql(
+    engine: *mut WsqlEngine,
+    sql: *const c_char,
+    out: *mut FFI_ArrowArrayStream,
+) -> c_int {
+    status(guard(|| {
+        let engine = unsafe { engine_arg(engine) }?;
+        let sql = unsafe { str_arg(sql, "Query") }?;
+        let batch = pollster::block_on(engine.sql(sql))?;
+        unsafe { export(batch, out) }
+    }))
+}
+
+/// Message of the last failure on this thread, or null. Valid until the
+/// next failing call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn wsql_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(std::ptr::null(), |e| e.as_ptr()))
+}
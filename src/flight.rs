@@ -0,0 +1,666 @@
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use arrow::{
+    array::{ArrayRef, BinaryArray, RecordBatch, StringArray},
+    datatypes::{DataType, Field, Schema, SchemaRef},
+    error::ArrowError,
+    ipc::writer::{CompressionContext, DictionaryTracker, IpcDataGenerator, IpcWriteOptions},
+};
+use prost::Message;
+use tonic::{
+    Status,
+    codegen::{Body, BoxFuture, BoxStream, Service, StdError, http},
+    server::{Grpc, NamedService, ServerStreamingService, UnaryService},
+};
+use tonic_prost::ProstCodec;
+
+use crate::{engine::QueryEngine, sub};
+
+// Rows per FlightData message streamed back to clients
+const FLIGHT_BATCH_ROWS: usize = 65536;
+
+const TYPE_URL_PREFIX: &str = "type.googleapis.com/arrow.flight.protocol.sql.";
+
+// The messages of Flight.proto and FlightSql.proto the server speaks, field
+// numbers as upstream. Fields the server never reads or sets are left out.
+pub mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Any {
+        #[prost(string, tag = "1")]
+        pub type_url: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub value: Vec<u8>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum DescriptorType {
+        Unknown = 0,
+        Path = 1,
+        Cmd = 2,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FlightDescriptor {
+        #[prost(enumeration = "DescriptorType", tag = "1")]
+        pub r#type: i32,
+        #[prost(bytes = "vec", tag = "2")]
+        pub cmd: Vec<u8>,
+        #[prost(string, repeated, tag = "3")]
+        pub path: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FlightInfo {
+        // Schema as an encapsulated IPC message
+        #[prost(bytes = "vec", tag = "1")]
+        pub schema: Vec<u8>,
+        #[prost(message, optional, tag = "2")]
+        pub flight_descriptor: Option<FlightDescriptor>,
+        #[prost(message, repeated, tag = "3")]
+        pub endpoint: Vec<FlightEndpoint>,
+        #[prost(int64, tag = "4")]
+        pub total_records: i64,
+        #[prost(int64, tag = "5")]
+        pub total_bytes: i64,
+        #[prost(bool, tag = "6")]
+        pub ordered: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FlightEndpoint {
+        #[prost(message, optional, tag = "1")]
+        pub ticket: Option<Ticket>,
+        // Empty, the data is fetched from this server
+        #[prost(message, repeated, tag = "2")]
+        pub location: Vec<Location>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Location {
+        #[prost(string, tag = "1")]
+        pub uri: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Ticket {
+        #[prost(bytes = "vec", tag = "1")]
+        pub ticket: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SchemaResult {
+        #[prost(bytes = "vec", tag = "1")]
+        pub schema: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FlightData {
+        #[prost(message, optional, tag = "1")]
+        pub flight_descriptor: Option<FlightDescriptor>,
+        // IPC message flatbuffer
+        #[prost(bytes = "vec", tag = "2")]
+        pub data_header: Vec<u8>,
+        #[prost(bytes = "vec", tag = "3")]
+        pub app_metadata: Vec<u8>,
+        #[prost(bytes = "vec", tag = "1000")]
+        pub data_body: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Action {
+        #[prost(string, tag = "1")]
+        pub r#type: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub body: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Result {
+        #[prost(bytes = "vec", tag = "1")]
+        pub body: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandStatementQuery {
+        #[prost(string, tag = "1")]
+        pub query: String,
+        #[prost(bytes = "vec", optional, tag = "2")]
+        pub transaction_id: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SubstraitPlan {
+        #[prost(bytes = "vec", tag = "1")]
+        pub plan: Vec<u8>,
+        #[prost(string, tag = "2")]
+        pub version: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandStatementSubstraitPlan {
+        #[prost(message, optional, tag = "1")]
+        pub plan: Option<SubstraitPlan>,
+        #[prost(bytes = "vec", optional, tag = "2")]
+        pub transaction_id: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TicketStatementQuery {
+        #[prost(bytes = "vec", tag = "1")]
+        pub statement_handle: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandPreparedStatementQuery {
+        #[prost(bytes = "vec", tag = "1")]
+        pub prepared_statement_handle: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ActionCreatePreparedStatementRequest {
+        #[prost(string, tag = "1")]
+        pub query: String,
+        #[prost(bytes = "vec", optional, tag = "2")]
+        pub transaction_id: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ActionCreatePreparedSubstraitPlanRequest {
+        #[prost(message, optional, tag = "1")]
+        pub plan: Option<SubstraitPlan>,
+        #[prost(bytes = "vec", optional, tag = "2")]
+        pub transaction_id: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ActionCreatePreparedStatementResult {
+        #[prost(bytes = "vec", tag = "1")]
+        pub prepared_statement_handle: Vec<u8>,
+        #[prost(bytes = "vec", tag = "2")]
+        pub dataset_schema: Vec<u8>,
+        #[prost(bytes = "vec", tag = "3")]
+        pub parameter_schema: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandGetCatalogs {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandGetDbSchemas {
+        #[prost(string, optional, tag = "1")]
+        pub catalog: Option<String>,
+        #[prost(string, optional, tag = "2")]
+        pub db_schema_filter_pattern: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandGetTables {
+        #[prost(string, optional, tag = "1")]
+        pub catalog: Option<String>,
+        #[prost(string, optional, tag = "2")]
+        pub db_schema_filter_pattern: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        pub table_name_filter_pattern: Option<String>,
+        #[prost(string, repeated, tag = "4")]
+        pub table_types: Vec<String>,
+        #[prost(bool, tag = "5")]
+        pub include_schema: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommandGetTableTypes {}
+}
+
+// Packs a Flight SQL message into the Any descriptors, tickets and action
+// bodies carry
+pub fn pack(name: &str, message: &impl Message) -> Vec<u8> {
+    proto::Any {
+        type_url: format!("{TYPE_URL_PREFIX}{name}"),
+        value: message.encode_to_vec(),
+    }
+    .encode_to_vec()
+}
+
+// The Flight SQL commands the server understands
+enum Command {
+    Statement(proto::CommandStatementQuery),
+    Substrait(proto::CommandStatementSubstraitPlan),
+    Prepared(proto::CommandPreparedStatementQuery),
+    Ticket(proto::TicketStatementQuery),
+    Catalogs,
+    DbSchemas,
+    Tables(proto::CommandGetTables),
+    TableTypes,
+}
+
+impl Command {
+    fn decode(bytes: &[u8]) -> Result<Self, Status> {
+        let any = proto::Any::decode(bytes)
+            .map_err(|e| Status::invalid_argument(format!("Malformed command: {e}")))?;
+        let value = any.value.as_slice();
+        let decode_error = |e: prost::DecodeError| Status::invalid_argument(e.to_string());
+        let name = any
+            .type_url
+            .strip_prefix(TYPE_URL_PREFIX)
+            .unwrap_or(&any.type_url);
+        Ok(match name {
+            "CommandStatementQuery" => {
+                Command::Statement(Message::decode(value).map_err(decode_error)?)
+            }
+            "CommandStatementSubstraitPlan" => {
+                Command::Substrait(Message::decode(value).map_err(decode_error)?)
+            }
+            "CommandPreparedStatementQuery" => {
+                Command::Prepared(Message::decode(value).map_err(decode_error)?)
+            }
+            "TicketStatementQuery" => {
+                Command::Ticket(Message::decode(value).map_err(decode_error)?)
+            }
+            "CommandGetCatalogs" => Command::Catalogs,
+            "CommandGetDbSchemas" => Command::DbSchemas,
+            "CommandGetTables" => Command::Tables(Message::decode(value).map_err(decode_error)?),
+            "CommandGetTableTypes" => Command::TableTypes,
+            _ => {
+                return Err(Status::unimplemented(format!(
+                    "Unsupported command {}",
+                    any.type_url
+                )));
+            }
+        })
+    }
+}
+
+// Flight SQL service over one engine, every session shares its GPU and
+// compiled pipelines
+#[derive(Clone)]
+pub struct FlightSqlService {
+    engine: Arc<QueryEngine>,
+}
+
+impl FlightSqlService {
+    pub fn new(engine: Arc<QueryEngine>) -> Self {
+        Self { engine }
+    }
+
+    // Plan of a statement, a Substrait plan or a prepared statement, whose
+    // handle is the statement command itself
+    fn plan(&self, command: Command) -> Result<substrait::proto::Plan, Status> {
+        match command {
+            Command::Statement(statement) => self
+                .engine
+                .plan_sql(&statement.query)
+                .map_err(|e| Status::invalid_argument(e.to_string())),
+            Command::Substrait(substrait) => {
+                let plan = substrait
+                    .plan
+                    .ok_or_else(|| Status::invalid_argument("Missing Substrait plan"))?;
+                sub::decode_plan(&plan.plan).map_err(|e| Status::invalid_argument(e.to_string()))
+            }
+            Command::Prepared(prepared) => {
+                self.plan(Command::decode(&prepared.prepared_statement_handle)?)
+            }
+            Command::Ticket(ticket) => self.plan(Command::decode(&ticket.statement_handle)?),
+            _ => Err(Status::invalid_argument("Not a query")),
+        }
+    }
+
+    fn schema(&self, command: Command) -> Result<SchemaRef, Status> {
+        match command {
+            Command::Catalogs | Command::DbSchemas | Command::TableTypes => {
+                Ok(self.metadata(command)?.schema())
+            }
+            Command::Tables(tables) => Ok(tables_schema(tables.include_schema)),
+            command => self
+                .engine
+                .output_schema(&self.plan(command)?)
+                .map_err(|e| Status::invalid_argument(e.to_string())),
+        }
+    }
+
+    // Catalog queries, answered from the registered tables
+    fn metadata(&self, command: Command) -> Result<RecordBatch, Status> {
+        let utf8 = |name, nullable| Field::new(name, DataType::Utf8, nullable);
+        let batch = match command {
+            Command::Catalogs => {
+                RecordBatch::new_empty(Arc::new(Schema::new(vec![utf8("catalog_name", false)])))
+            }
+            Command::DbSchemas => RecordBatch::new_empty(Arc::new(Schema::new(vec![
+                utf8("catalog_name", true),
+                utf8("db_schema_name", false),
+            ]))),
+            Command::TableTypes => RecordBatch::try_new(
+                Arc::new(Schema::new(vec![utf8("table_type", false)])),
+                vec![Arc::new(StringArray::from(vec!["TABLE"]))],
+            )
+            .map_err(|e| Status::internal(e.to_string()))?,
+            Command::Tables(request) => self
+                .tables(&request)
+                .map_err(|e| Status::internal(e.to_string()))?,
+            _ => return Err(Status::invalid_argument("Not a catalog query")),
+        };
+        Ok(batch)
+    }
+
+    // Registered tables matching the name pattern, wsql has no catalogs or schemas
+    fn tables(&self, request: &proto::CommandGetTables) -> Result<RecordBatch, ArrowError> {
+        let schema = tables_schema(request.include_schema);
+        let wants_tables =
+            request.table_types.is_empty() || request.table_types.iter().any(|t| t == "TABLE");
+        let mut names: Vec<&str> = if wants_tables {
+            self.engine.table_names()
+        } else {
+            Vec::new()
+        };
+        if let Some(pattern) = &request.table_name_filter_pattern {
+            let mask = arrow::compute::kernels::comparison::like(
+                &StringArray::from(names.clone()),
+                &StringArray::new_scalar(pattern),
+            )?;
+            names = names
+                .into_iter()
+                .zip(mask.iter())
+                .filter_map(|(name, keep)| keep.unwrap_or(false).then_some(name))
+                .collect();
+        }
+
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::new_null(names.len())),
+            Arc::new(StringArray::new_null(names.len())),
+            Arc::new(StringArray::from(names.clone())),
+            Arc::new(StringArray::from(vec!["TABLE"; names.len()])),
+        ];
+        if request.include_schema {
+            let schemas: Vec<Vec<u8>> = names
+                .iter()
+                .filter_map(|name| self.engine.table(name))
+                .map(|table| ipc_schema(&table.schema()))
+                .collect::<Result<_, _>>()?;
+            columns.push(Arc::new(BinaryArray::from_iter_values(schemas)));
+        }
+        RecordBatch::try_new(schema, columns)
+    }
+
+    async fn get_flight_info(
+        &self,
+        descriptor: proto::FlightDescriptor,
+    ) -> Result<proto::FlightInfo, Status> {
+        if descriptor.r#type != proto::DescriptorType::Cmd as i32 {
+            return Err(Status::invalid_argument(
+                "Only command descriptors are supported",
+            ));
+        }
+        let command = Command::decode(&descriptor.cmd)?;
+        let ticket = match command {
+            Command::Statement(_) | Command::Substrait(_) | Command::Prepared(_) => pack(
+                "TicketStatementQuery",
+                &proto::TicketStatementQuery {
+                    statement_handle: descriptor.cmd.clone(),
+                },
+            ),
+            _ => descriptor.cmd.clone(),
+        };
+        let schema = self.schema(command)?;
+        Ok(proto::FlightInfo {
+            schema: ipc_schema(&schema).map_err(|e| Status::internal(e.to_string()))?,
+            flight_descriptor: Some(descriptor),
+            endpoint: vec![proto::FlightEndpoint {
+                ticket: Some(proto::Ticket { ticket }),
+                location: Vec::new(),
+            }],
+            total_records: -1,
+            total_bytes: -1,
+            ordered: true,
+        })
+    }
+
+    async fn get_schema(
+        &self,
+        descriptor: proto::FlightDescriptor,
+    ) -> Result<proto::SchemaResult, Status> {
+        let schema = self.schema(Command::decode(&descriptor.cmd)?)?;
+        Ok(proto::SchemaResult {
+            schema: ipc_schema(&schema).map_err(|e| Status::internal(e.to_string()))?,
+        })
+    }
+
+    async fn do_get(&self, ticket: proto::Ticket) -> Result<BoxStream<proto::FlightData>, Status> {
+        let batch = match Command::decode(&ticket.ticket)? {
+            command @ Command::Ticket(_) => {
+                let plan = self.plan(command)?;
+                self.engine
+                    .query(&plan)
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?
+            }
+            command => self.metadata(command)?,
+        };
+        let data = flight_data(batch).map(|data| data.map_err(|e| Status::internal(e.to_string())));
+        Ok(Box::pin(tokio_stream::iter(data)))
+    }
+
+    // Prepared statements keep no server state: the handle is the statement
+    // command, so closing one is a no-op
+    async fn do_action(&self, action: proto::Action) -> Result<BoxStream<proto::Result>, Status> {
+        let decode_error = |e: prost::DecodeError| Status::invalid_argument(e.to_string());
+        let body = proto::Any::decode(action.body.as_slice()).map_err(decode_error)?;
+        let handle = match action.r#type.as_str() {
+            "CreatePreparedStatement" => {
+                let request =
+                    proto::ActionCreatePreparedStatementRequest::decode(body.value.as_slice())
+                        .map_err(decode_error)?;
+                pack(
+                    "CommandStatementQuery",
+                    &proto::CommandStatementQuery {
+                        query: request.query,
+                        transaction_id: None,
+                    },
+                )
+            }
+            "CreatePreparedSubstraitPlan" => {
+                let request =
+                    proto::ActionCreatePreparedSubstraitPlanRequest::decode(body.value.as_slice())
+                        .map_err(decode_error)?;
+                pack(
+                    "CommandStatementSubstraitPlan",
+                    &proto::CommandStatementSubstraitPlan {
+                        plan: request.plan,
+                        transaction_id: None,
+                    },
+                )
+            }
+            "ClosePreparedStatement" => return Ok(Box::pin(tokio_stream::empty())),
+            action => {
+                return Err(Status::unimplemented(format!(
+                    "Unsupported action {action}"
+                )));
+            }
+        };
+
+        let schema = self.schema(Command::decode(&handle)?)?;
+        let result = proto::ActionCreatePreparedStatementResult {
+            prepared_statement_handle: handle,
+            dataset_schema: ipc_schema(&schema).map_err(|e| Status::internal(e.to_string()))?,
+            parameter_schema: Vec::new(),
+        };
+        let body = pack("ActionCreatePreparedStatementResult", &result);
+        Ok(Box::pin(tokio_stream::iter([Ok(proto::Result { body })])))
+    }
+}
+
+// A gRPC method of the service, with the handler that answers it
+struct Method<M, R> {
+    service: FlightSqlService,
+    handler: fn(FlightSqlService, M) -> BoxFuture<R, Status>,
+}
+
+impl<M, R: 'static> UnaryService<M> for Method<M, R> {
+    type Response = R;
+    type Future = BoxFuture<tonic::Response<R>, Status>;
+
+    fn call(&mut self, request: tonic::Request<M>) -> Self::Future {
+        let response = (self.handler)(self.service.clone(), request.into_inner());
+        Box::pin(async move { response.await.map(tonic::Response::new) })
+    }
+}
+
+// A method answering with a stream of `R`
+struct StreamingMethod<M, R> {
+    service: FlightSqlService,
+    handler: fn(FlightSqlService, M) -> BoxFuture<BoxStream<R>, Status>,
+}
+
+impl<M, R: 'static> ServerStreamingService<M> for StreamingMethod<M, R> {
+    type Response = R;
+    type ResponseStream = BoxStream<R>;
+    type Future = BoxFuture<tonic::Response<BoxStream<R>>, Status>;
+
+    fn call(&mut self, request: tonic::Request<M>) -> Self::Future {
+        let response = (self.handler)(self.service.clone(), request.into_inner());
+        Box::pin(async move { response.await.map(tonic::Response::new) })
+    }
+}
+
+impl<B> Service<http::Request<B>> for FlightSqlService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let service = self.clone();
+        match request.uri().path() {
+            "/arrow.flight.protocol.FlightService/GetFlightInfo" => Box::pin(async move {
+                let method = Method {
+                    service,
+                    handler: |service, descriptor| {
+                        Box::pin(async move { service.get_flight_info(descriptor).await })
+                    },
+                };
+                Ok(Grpc::new(ProstCodec::default())
+                    .unary(method, request)
+                    .await)
+            }),
+            "/arrow.flight.protocol.FlightService/GetSchema" => Box::pin(async move {
+                let method = Method {
+                    service,
+                    handler: |service, descriptor| {
+                        Box::pin(async move { service.get_schema(descriptor).await })
+                    },
+                };
+                Ok(Grpc::new(ProstCodec::default())
+                    .unary(method, request)
+                    .await)
+            }),
+            "/arrow.flight.protocol.FlightService/DoGet" => Box::pin(async move {
+                let method = StreamingMethod {
+                    service,
+                    handler: |service, ticket| {
+                        Box::pin(async move { service.do_get(ticket).await })
+                    },
+                };
+                Ok(Grpc::new(ProstCodec::default())
+                    .server_streaming(method, request)
+                    .await)
+            }),
+            "/arrow.flight.protocol.FlightService/DoAction" => Box::pin(async move {
+                let method = StreamingMethod {
+                    service,
+                    handler: |service, action| {
+                        Box::pin(async move { service.do_action(action).await })
+                    },
+                };
+                Ok(Grpc::new(ProstCodec::default())
+                    .server_streaming(method, request)
+                    .await)
+            }),
+            path => {
+                let status = Status::unimplemented(format!("{path} is not supported"));
+                Box::pin(async move { Ok(status.into_http()) })
+            }
+        }
+    }
+}
+
+impl NamedService for FlightSqlService {
+    const NAME: &'static str = "arrow.flight.protocol.FlightService";
+}
+
+// Serves Flight SQL on `addr` until the process exits
+pub async fn serve(engine: Arc<QueryEngine>, addr: SocketAddr) -> anyhow::Result<()> {
+    tonic::transport::Server::builder()
+        .add_service(FlightSqlService::new(engine))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+fn tables_schema(include_schema: bool) -> SchemaRef {
+    let mut fields = vec![
+        Field::new("catalog_name", DataType::Utf8, true),
+        Field::new("db_schema_name", DataType::Utf8, true),
+        Field::new("table_name", DataType::Utf8, false),
+        Field::new("table_type", DataType::Utf8, false),
+    ];
+    if include_schema {
+        fields.push(Field::new("table_schema", DataType::Binary, false));
+    }
+    Arc::new(Schema::new(fields))
+}
+
+// Schema as an encapsulated IPC message, as FlightInfo and prepared
+// statements carry it
+pub fn ipc_schema(schema: &Schema) -> Result<Vec<u8>, ArrowError> {
+    let options = IpcWriteOptions::default();
+    let encoded = IpcDataGenerator::default().schema_to_bytes_with_dictionary_tracker(
+        schema,
+        &mut DictionaryTracker::new(false),
+        &options,
+    );
+    let mut bytes = Vec::new();
+    arrow::ipc::writer::write_message(&mut bytes, encoded, &options)?;
+    Ok(bytes)
+}
+
+// The schema message followed by the batch in slices, encoded as they are sent
+fn flight_data(
+    batch: RecordBatch,
+) -> impl Iterator<Item = Result<proto::FlightData, ArrowError>> + Send + 'static {
+    let generator = IpcDataGenerator::default();
+    let options = IpcWriteOptions::default();
+    let mut tracker = DictionaryTracker::new(false);
+    let mut compression = CompressionContext::default();
+    let schema =
+        generator.schema_to_bytes_with_dictionary_tracker(&batch.schema(), &mut tracker, &options);
+
+    let message = |encoded: arrow::ipc::writer::EncodedData| proto::FlightData {
+        data_header: encoded.ipc_message,
+        data_body: encoded.arrow_data,
+        ..Default::default()
+    };
+    let slices = (0..batch.num_rows())
+        .step_by(FLIGHT_BATCH_ROWS)
+        .flat_map(move |offset| {
+            let slice = batch.slice(offset, FLIGHT_BATCH_ROWS.min(batch.num_rows() - offset));
+            match generator.encode(&slice, &mut tracker, &options, &mut compression) {
+                Ok((dictionaries, encoded)) => dictionaries
+                    .into_iter()
+                    .chain(std::iter::once(encoded))
+                    .map(|encoded| Ok(message(encoded)))
+                    .collect::<Vec<_>>(),
+                Err(e) => vec![Err(e)],
+            }
+        });
+    std::iter::once(Ok(message(schema))).chain(slices)
+}
@@ -1,5 +1,6 @@
 use wgpu::util::DeviceExt;
 
+#[derive(Clone)]
 pub struct Gpu {
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
@@ -25,7 +26,11 @@ impl Gpu {
             .expect("Failed to get an adapter");
 
         let (device, queue) = adapter
-            .request_device(&wgpu::DeviceDescriptor::default())
+            .request_device(&wgpu::DeviceDescriptor {
+                // Lets EXPLAIN ANALYZE time compute passes
+                required_features: adapter.features() & wgpu::Features::TIMESTAMP_QUERY,
+                ..Default::default()
+            })
             .await
             .expect("Unable to Request Device");
 
@@ -41,6 +46,16 @@ impl Gpu {
             })
     }
 
+    // read_write storage initialised from the CPU, can be copied back
+    pub fn storage_buffer<T: bytemuck::Pod>(&self, name: &str, contents: &[T]) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(name),
+                contents: bytemuck::cast_slice(contents),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            })
+    }
+
     // Should merge output_buffer and stagging_buffer into one genric function
     pub fn output_buffer(&self, name: &str, size: u64) -> wgpu::Buffer {
         self.device.create_buffer(&wgpu::BufferDescriptor {
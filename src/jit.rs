@@ -1,22 +1,49 @@
-use crate::sub::PhysicalPlan;
+use crate::sub::KernelPlan;
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
     Literal(LiteralTypes),
     Column(u32),
     Add(Box<Expression>, Box<Expression>),
     Subtract(Box<Expression>, Box<Expression>),
     Multiply(Box<Expression>, Box<Expression>),
+    // Integer division truncates, as in SQL
+    Divide(Box<Expression>, Box<Expression>),
     GreaterThan(Box<Expression>, Box<Expression>),
     LessThan(Box<Expression>, Box<Expression>),
     Equal(Box<Expression>, Box<Expression>),
     And(Box<Expression>, Box<Expression>),
     Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    // Uncorrelated scalar subquery, bound to a literal before compiling
+    Subquery(Subquery),
 }
 
+// Shared handle to a subquery plan, two handles are equal when they point
+// at the same plan
+#[derive(Clone)]
+pub struct Subquery(pub std::sync::Arc<crate::sub::PhysicalPlan>);
+
+impl PartialEq for Subquery {
+    fn eq(&self, other: &Self) -> bool {
+        std::sync::Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl std::fmt::Debug for Subquery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0.summary())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum LiteralTypes {
     I32(i32),
     F32(f32),
     Date(i32),
+    // Bound by a scalar subquery without a value. Filters fold it away with
+    // `null_predicate`, values that are NULL are produced without a kernel.
+    Null(ScalarType),
 }
 
 pub fn collect_columns(expr: &Expression, cols: &mut std::collections::BTreeSet<u32>) {
@@ -27,6 +54,7 @@ pub fn collect_columns(expr: &Expression, cols: &mut std::collections::BTreeSet<
         Expression::Add(l, r)
         | Expression::Subtract(l, r)
         | Expression::Multiply(l, r)
+        | Expression::Divide(l, r)
         | Expression::GreaterThan(l, r)
         | Expression::LessThan(l, r)
         | Expression::Equal(l, r)
@@ -35,26 +63,260 @@ pub fn collect_columns(expr: &Expression, cols: &mut std::collections::BTreeSet<
             collect_columns(l, cols);
             collect_columns(r, cols);
         }
-        Expression::Literal(_) => {}
+        Expression::Not(e) => collect_columns(e, cols),
+        Expression::Literal(_) | Expression::Subquery(_) => {}
+    }
+}
+
+pub fn collect_subqueries(expr: &Expression, found: &mut Vec<Subquery>) {
+    match expr {
+        Expression::Subquery(s) => {
+            if !found.contains(s) {
+                found.push(s.clone());
+            }
+        }
+        Expression::Add(l, r)
+        | Expression::Subtract(l, r)
+        | Expression::Multiply(l, r)
+        | Expression::Divide(l, r)
+        | Expression::GreaterThan(l, r)
+        | Expression::LessThan(l, r)
+        | Expression::Equal(l, r)
+        | Expression::And(l, r)
+        | Expression::Or(l, r) => {
+            collect_subqueries(l, found);
+            collect_subqueries(r, found);
+        }
+        Expression::Not(e) => collect_subqueries(e, found),
+        Expression::Literal(_) | Expression::Column(_) => {}
+    }
+}
+
+// Replace every occurrence of `subquery` in `expr` with its value
+pub fn bind_subquery(expr: &Expression, subquery: &Subquery, value: &Expression) -> Expression {
+    let bind = |e: &Expression| Box::new(bind_subquery(e, subquery, value));
+    match expr {
+        Expression::Subquery(s) if s == subquery => value.clone(),
+        Expression::Literal(_) | Expression::Column(_) | Expression::Subquery(_) => expr.clone(),
+        Expression::Add(l, r) => Expression::Add(bind(l), bind(r)),
+        Expression::Subtract(l, r) => Expression::Subtract(bind(l), bind(r)),
+        Expression::Multiply(l, r) => Expression::Multiply(bind(l), bind(r)),
+        Expression::Divide(l, r) => Expression::Divide(bind(l), bind(r)),
+        Expression::GreaterThan(l, r) => Expression::GreaterThan(bind(l), bind(r)),
+        Expression::LessThan(l, r) => Expression::LessThan(bind(l), bind(r)),
+        Expression::Equal(l, r) => Expression::Equal(bind(l), bind(r)),
+        Expression::And(l, r) => Expression::And(bind(l), bind(r)),
+        Expression::Or(l, r) => Expression::Or(bind(l), bind(r)),
+        Expression::Not(e) => Expression::Not(bind(e)),
+    }
+}
+
+// Whether a NULL literal flows into `expr`. Arithmetic, comparisons and NOT
+// over NULL are NULL, AND / OR only are for some values of the other side.
+pub fn is_null(expr: &Expression) -> anyhow::Result<bool> {
+    Ok(match expr {
+        Expression::Literal(LiteralTypes::Null(_)) => true,
+        Expression::Literal(_) | Expression::Column(_) | Expression::Subquery(_) => false,
+        Expression::Add(l, r)
+        | Expression::Subtract(l, r)
+        | Expression::Multiply(l, r)
+        | Expression::Divide(l, r)
+        | Expression::GreaterThan(l, r)
+        | Expression::LessThan(l, r)
+        | Expression::Equal(l, r) => is_null(l)? || is_null(r)?,
+        Expression::Not(e) => is_null(e)?,
+        Expression::And(l, r) | Expression::Or(l, r) => {
+            if is_null(l)? || is_null(r)? {
+                anyhow::bail!("NULL in AND / OR is only supported in filters");
+            }
+            false
+        }
+    })
+}
+
+// Rows where the predicate is TRUE, with NULL literals folded away by three
+// valued logic. `0 = 1` when no row can pass.
+pub fn null_predicate(expr: &Expression) -> Expression {
+    if !has_null(expr) {
+        return expr.clone();
+    }
+    holds(expr, true).unwrap_or_else(|| {
+        Expression::Equal(
+            Box::new(Expression::Literal(LiteralTypes::I32(0))),
+            Box::new(Expression::Literal(LiteralTypes::I32(1))),
+        )
+    })
+}
+
+fn has_null(expr: &Expression) -> bool {
+    match expr {
+        Expression::Literal(LiteralTypes::Null(_)) => true,
+        Expression::Literal(_) | Expression::Column(_) | Expression::Subquery(_) => false,
+        Expression::Add(l, r)
+        | Expression::Subtract(l, r)
+        | Expression::Multiply(l, r)
+        | Expression::Divide(l, r)
+        | Expression::GreaterThan(l, r)
+        | Expression::LessThan(l, r)
+        | Expression::Equal(l, r)
+        | Expression::And(l, r)
+        | Expression::Or(l, r) => has_null(l) || has_null(r),
+        Expression::Not(e) => has_null(e),
+    }
+}
+
+// Predicate that `expr` is `value`, None when it never is
+fn holds(expr: &Expression, value: bool) -> Option<Expression> {
+    let either = |a: Option<Expression>, b: Option<Expression>| match (a, b) {
+        (Some(a), Some(b)) => Some(Expression::Or(Box::new(a), Box::new(b))),
+        (a, b) => a.or(b),
+    };
+    let both = |a: Option<Expression>, b: Option<Expression>| {
+        Some(Expression::And(Box::new(a?), Box::new(b?)))
+    };
+    match expr {
+        Expression::Not(e) => holds(e, !value),
+        Expression::And(l, r) if value => both(holds(l, true), holds(r, true)),
+        Expression::And(l, r) => either(holds(l, false), holds(r, false)),
+        Expression::Or(l, r) if value => either(holds(l, true), holds(r, true)),
+        Expression::Or(l, r) => both(holds(l, false), holds(r, false)),
+        // Neither TRUE nor FALSE
+        _ if is_null(expr).unwrap_or(true) => None,
+        _ if value => Some(expr.clone()),
+        _ => Some(Expression::Not(Box::new(expr.clone()))),
+    }
+}
+
+// Replace every Column(i) in `expr` with `inputs[i]`, used to fuse a projection into its parent
+pub fn substitute(expr: &Expression, inputs: &[Expression]) -> anyhow::Result<Expression> {
+    let sub = |e: &Expression| substitute(e, inputs).map(Box::new);
+    Ok(match expr {
+        Expression::Column(i) => inputs
+            .get(*i as usize)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Field {} is out of range of its input", i))?,
+        Expression::Literal(_) | Expression::Subquery(_) => expr.clone(),
+        Expression::Add(l, r) => Expression::Add(sub(l)?, sub(r)?),
+        Expression::Subtract(l, r) => Expression::Subtract(sub(l)?, sub(r)?),
+        Expression::Multiply(l, r) => Expression::Multiply(sub(l)?, sub(r)?),
+        Expression::Divide(l, r) => Expression::Divide(sub(l)?, sub(r)?),
+        Expression::GreaterThan(l, r) => Expression::GreaterThan(sub(l)?, sub(r)?),
+        Expression::LessThan(l, r) => Expression::LessThan(sub(l)?, sub(r)?),
+        Expression::Equal(l, r) => Expression::Equal(sub(l)?, sub(r)?),
+        Expression::And(l, r) => Expression::And(sub(l)?, sub(r)?),
+        Expression::Or(l, r) => Expression::Or(sub(l)?, sub(r)?),
+        Expression::Not(e) => Expression::Not(sub(e)?),
+    })
+}
+
+// WGSL scalar type an expression evaluates to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScalarType {
+    I32,
+    F32,
+    Bool,
+}
+
+impl ScalarType {
+    pub fn of(dtype: &arrow::datatypes::DataType) -> Self {
+        match dtype {
+            arrow::datatypes::DataType::Float32 | arrow::datatypes::DataType::Decimal128(_, _) => {
+                ScalarType::F32
+            }
+            // Date32 and everything else is treated as i32
+            _ => ScalarType::I32,
+        }
+    }
+
+    pub fn wgsl(&self) -> &'static str {
+        match self {
+            ScalarType::I32 => "i32",
+            ScalarType::F32 => "f32",
+            ScalarType::Bool => "bool",
+        }
+    }
+}
+
+pub fn infer_type(
+    expr: &Expression,
+    column_types: &std::collections::HashMap<u32, arrow::datatypes::DataType>,
+) -> ScalarType {
+    match expr {
+        Expression::Literal(LiteralTypes::I32(_) | LiteralTypes::Date(_)) => ScalarType::I32,
+        Expression::Literal(LiteralTypes::F32(_)) => ScalarType::F32,
+        Expression::Literal(LiteralTypes::Null(t)) => *t,
+        Expression::Column(i) => ScalarType::of(column_types.get(i).expect("Missing column type")),
+        Expression::Add(l, r)
+        | Expression::Subtract(l, r)
+        | Expression::Multiply(l, r)
+        | Expression::Divide(l, r) => {
+            if infer_type(l, column_types) == ScalarType::F32
+                || infer_type(r, column_types) == ScalarType::F32
+            {
+                ScalarType::F32
+            } else {
+                ScalarType::I32
+            }
+        }
+        Expression::GreaterThan(_, _)
+        | Expression::LessThan(_, _)
+        | Expression::Equal(_, _)
+        | Expression::And(_, _)
+        | Expression::Or(_, _)
+        | Expression::Not(_) => ScalarType::Bool,
+        Expression::Subquery(_) => unreachable!("Scalar subqueries are bound before compiling"),
     }
 }
 
+// Translate `expr` and convert the result into `target`
+pub fn translate_as(
+    expr: &Expression,
+    target: ScalarType,
+    mapping: &std::collections::BTreeMap<u32, u32>,
+    column_types: &std::collections::HashMap<u32, arrow::datatypes::DataType>,
+) -> String {
+    let code = translate(expr, mapping, column_types);
+    match (infer_type(expr, column_types), target) {
+        (from, to) if from == to => code,
+        (ScalarType::Bool, ScalarType::I32) => format!("select(0i, 1i, {code})"),
+        (ScalarType::Bool, ScalarType::F32) => format!("select(0.0f, 1.0f, {code})"),
+        (_, to) => format!("{}({code})", to.wgsl()),
+    }
+}
+
+// Binary operator, promoting i32 operands to f32 when the other side is f32
+fn binary(
+    op: &str,
+    l: &Expression,
+    r: &Expression,
+    mapping: &std::collections::BTreeMap<u32, u32>,
+    column_types: &std::collections::HashMap<u32, arrow::datatypes::DataType>,
+) -> String {
+    let (lt, rt) = (infer_type(l, column_types), infer_type(r, column_types));
+    let operand_type = if lt == ScalarType::F32 || rt == ScalarType::F32 {
+        ScalarType::F32
+    } else {
+        lt
+    };
+    format!(
+        "({} {} {})",
+        translate_as(l, operand_type, mapping, column_types),
+        op,
+        translate_as(r, operand_type, mapping, column_types)
+    )
+}
+
 pub fn translate(
     expr: &Expression,
     mapping: &std::collections::BTreeMap<u32, u32>,
     column_types: &std::collections::HashMap<u32, arrow::datatypes::DataType>,
-    is_aggregate: bool,
 ) -> String {
     match expr {
         Expression::Literal(val) => match val {
-            LiteralTypes::I32(v) | LiteralTypes::Date(v) => {
-                if !is_aggregate {
-                    format!("{}i", v)
-                } else {
-                    format!("{}.0f", v)
-                }
-            }
+            LiteralTypes::I32(v) | LiteralTypes::Date(v) => format!("{}i", v),
             LiteralTypes::F32(v) => format!("{}f", v),
+            // Never read, NULL values do not run a kernel
+            LiteralTypes::Null(t) => format!("{}()", t.wgsl()),
         },
         Expression::Column(i) => {
             let binding_idx = mapping.get(i).expect("Column mapping missing");
@@ -70,111 +332,68 @@ pub fn translate(
                         binding_idx, binding_idx, divisor
                     )
                 }
-                arrow::datatypes::DataType::Float32 => format!("in_col_{}[idx]", binding_idx),
-                _ => {
-                    if is_aggregate {
-                        format!("f32(in_col_{}[idx])", binding_idx)
-                    } else {
-                        // default is i32
-                        format!("in_col_{}[idx]", binding_idx)
-                    }
-                }
+                // f32 and i32 (default) are read as is
+                _ => format!("in_col_{}[idx]", binding_idx),
             }
         }
-        Expression::Add(l, r) => format!(
-            "({} + {})",
-            translate(l, mapping, column_types, is_aggregate),
-            translate(r, mapping, column_types, is_aggregate)
+        Expression::Add(l, r) => binary("+", l, r, mapping, column_types),
+        Expression::Subtract(l, r) => binary("-", l, r, mapping, column_types),
+        Expression::Multiply(l, r) => binary("*", l, r, mapping, column_types),
+        Expression::Divide(l, r) => binary("/", l, r, mapping, column_types),
+        Expression::GreaterThan(l, r) => binary(">", l, r, mapping, column_types),
+        Expression::LessThan(l, r) => binary("<", l, r, mapping, column_types),
+        Expression::Equal(l, r) => binary("==", l, r, mapping, column_types),
+        Expression::And(l, r) => binary("&&", l, r, mapping, column_types),
+        Expression::Or(l, r) => binary("||", l, r, mapping, column_types),
+        Expression::Not(e) => format!(
+            "(!{})",
+            translate_as(e, ScalarType::Bool, mapping, column_types)
         ),
-        Expression::Subtract(l, r) => {
-            format!(
-                "({} - {})",
-                translate(l, mapping, column_types, is_aggregate),
-                translate(r, mapping, column_types, is_aggregate)
-            )
-        }
-        Expression::Multiply(l, r) => {
-            format!(
-                "({} * {})",
-                translate(l, mapping, column_types, is_aggregate),
-                translate(r, mapping, column_types, is_aggregate)
-            )
-        }
-        Expression::GreaterThan(l, r) => {
-            format!(
-                "({} > {})",
-                translate(l, mapping, column_types, is_aggregate),
-                translate(r, mapping, column_types, is_aggregate)
-            )
-        }
-        Expression::LessThan(l, r) => {
-            format!(
-                "({} < {})",
-                translate(l, mapping, column_types, is_aggregate),
-                translate(r, mapping, column_types, is_aggregate)
-            )
-        }
-        Expression::Equal(l, r) => {
-            format!(
-                "({} == {})",
-                translate(l, mapping, column_types, is_aggregate),
-                translate(r, mapping, column_types, is_aggregate)
-            )
-        }
-        Expression::And(l, r) => {
-            format!(
-                "({} && {})",
-                translate(l, mapping, column_types, is_aggregate),
-                translate(r, mapping, column_types, is_aggregate)
-            )
-        }
-        Expression::Or(l, r) => {
-            format!(
-                "({} || {})",
-                translate(l, mapping, column_types, is_aggregate),
-                translate(r, mapping, column_types, is_aggregate)
-            )
+        Expression::Subquery(_) => unreachable!("Scalar subqueries are bound before compiling"),
+    }
+}
+
+// Element type of the kernel output buffer
+pub fn output_type(kernel: &KernelPlan) -> ScalarType {
+    if kernel.is_aggregate {
+        ScalarType::F32
+    } else {
+        match infer_type(&kernel.projection, &kernel.column_types) {
+            // predicates are written out as 0/1
+            ScalarType::Bool => ScalarType::I32,
+            t => t,
         }
     }
 }
 
 pub fn generate_shader(
-    physical_plan: &PhysicalPlan,
+    kernel: &KernelPlan,
     mapping: &std::collections::BTreeMap<u32, u32>,
 ) -> String {
-    let logic = translate(
-        &physical_plan.projection,
+    // data types
+    let output_type = output_type(kernel);
+    let logic = translate_as(
+        &kernel.projection,
+        output_type,
         mapping,
-        &physical_plan.column_types,
-        physical_plan.is_aggregate,
+        &kernel.column_types,
     );
 
     // check for FILTER
-    let condition = physical_plan.filter.as_ref().map_or("true".into(), |f| {
-        translate(
-            f,
-            mapping,
-            &physical_plan.column_types,
-            physical_plan.is_aggregate,
-        )
+    let condition = kernel.filter.as_ref().map_or("true".into(), |f| {
+        translate(f, mapping, &kernel.column_types)
     });
 
-    // data types
-    let output_type = if physical_plan.is_aggregate {
-        "f32"
-    } else {
-        "i32"
-    };
-    let sentinel = if physical_plan.is_aggregate {
-        "0.0f"
-    } else {
+    let sentinel = match output_type {
         // 'Dynamic Shader' parsing error: numeric literal not representable by target type: 2147483648i
         // Parser sees the +ive integer first and overflows
         // "-2147483648i"
-        "bitcast<i32>(0x80000000u)"
+        ScalarType::I32 => "bitcast<i32>(0x80000000u)",
+        _ => "0.0f",
     };
+    let output_type = output_type.wgsl();
 
-    let (globals, write_logic) = if physical_plan.is_aggregate {
+    let (globals, write_logic) = if kernel.is_aggregate {
         (
             "var<workgroup> scratch: array<f32, 64>;",
             r#"
@@ -209,7 +428,7 @@ pub fn generate_shader(
 
     // Input bindings for every column from the mapping
     for (&col_idx, &binding_idx) in mapping {
-        let input_type = match &physical_plan.column_types[&col_idx] {
+        let input_type = match &kernel.column_types[&col_idx] {
             arrow::datatypes::DataType::Float32 => "f32",
             arrow::datatypes::DataType::Decimal128(_, _) => "vec4<i32>",
             _ => "i32",
@@ -266,3 +485,162 @@ pub fn generate_shader(
     "#,
     )
 }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScanOp {
+    Sum,
+    Max,
+}
+
+// One compare-and-swap stage of a bitonic sort over (key, index) pairs.
+// Ties on key are broken by index, which makes the sort stable.
+pub fn generate_sort_shader() -> String {
+    r#"
+        struct Stage {
+            j: u32,
+            k: u32,
+            stride: u32,
+            len: u32,
+        }
+
+        @group(0) @binding(0) var<storage, read_write> keys: array<u32>;
+        @group(0) @binding(1) var<storage, read_write> indices: array<u32>;
+        @group(0) @binding(2) var<storage, read> stage: Stage;
+
+        @compute @workgroup_size(64)
+        fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+            let i = global_id.x + global_id.y * stage.stride;
+            let l = i ^ stage.j;
+            if (i >= stage.len || l <= i) {
+                return;
+            }
+
+            let a_key = keys[i];
+            let b_key = keys[l];
+            let a_idx = indices[i];
+            let b_idx = indices[l];
+
+            let greater = a_key > b_key || (a_key == b_key && a_idx > b_idx);
+            let ascending = (i & stage.k) == 0u;
+            if (greater == ascending) {
+                keys[i] = b_key;
+                keys[l] = a_key;
+                indices[i] = b_idx;
+                indices[l] = a_idx;
+            }
+        }
+    "#
+    .into()
+}
+
+// One Hillis-Steele step of an inclusive scan that restarts at every head flag
+pub fn generate_scan_shader(elem: ScalarType, op: ScanOp) -> String {
+    let elem = elem.wgsl();
+    let combine = match op {
+        ScanOp::Sum => "in_val[i - step.offset] + val",
+        ScanOp::Max => "max(in_val[i - step.offset], val)",
+    };
+
+    format!(
+        r#"
+        struct Step {{
+            offset: u32,
+            stride: u32,
+            len: u32,
+        }}
+
+        @group(0) @binding(0) var<storage, read> in_val: array<{elem}>;
+        @group(0) @binding(1) var<storage, read> in_head: array<u32>;
+        @group(0) @binding(2) var<storage, read_write> out_val: array<{elem}>;
+        @group(0) @binding(3) var<storage, read_write> out_head: array<u32>;
+        @group(0) @binding(4) var<storage, read> step: Step;
+
+        @compute @workgroup_size(64)
+        fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {{
+            let i = global_id.x + global_id.y * step.stride;
+            if (i >= step.len) {{
+                return;
+            }}
+
+            var val = in_val[i];
+            var head = in_head[i];
+            // Only pull from the left while still inside the segment
+            if (head == 0u && i >= step.offset) {{
+                val = {combine};
+                head = in_head[i - step.offset];
+            }}
+
+            out_val[i] = val;
+            out_head[i] = head;
+        }}
+    "#
+    )
+}
+
+// Open addressing hash set over rows of `width` u32 words. Each slot holds
+// row + 1 of the first row inserted with that key, every row gets the row
+// that claimed the slot for its key as its group.
+pub fn generate_hash_set_shader() -> String {
+    r#"
+        struct Params {
+            rows: u32,
+            width: u32,
+            capacity: u32,
+            stride: u32,
+        }
+
+        @group(0) @binding(0) var<storage, read> keys: array<u32>;
+        @group(0) @binding(1) var<storage, read_write> table: array<atomic<u32>>;
+        @group(0) @binding(2) var<storage, read_write> groups: array<u32>;
+        @group(0) @binding(3) var<storage, read> params: Params;
+
+        fn same_key(a: u32, b: u32) -> bool {
+            for (var w = 0u; w < params.width; w++) {
+                if (keys[a * params.width + w] != keys[b * params.width + w]) {
+                    return false;
+                }
+            }
+            return true;
+        }
+
+        @compute @workgroup_size(64)
+        fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+            let row = global_id.x + global_id.y * params.stride;
+            if (row >= params.rows) {
+                return;
+            }
+
+            // FNV-1a over the key words with a murmur3 finalizer
+            var h = 2166136261u;
+            for (var w = 0u; w < params.width; w++) {
+                h = (h ^ keys[row * params.width + w]) * 16777619u;
+            }
+            h ^= h >> 16u;
+            h *= 0x85ebca6bu;
+            h ^= h >> 13u;
+            h *= 0xc2b2ae35u;
+            h ^= h >> 16u;
+
+            let mask = params.capacity - 1u;
+            var slot = h & mask;
+            for (var probes = 0u; probes < params.capacity;) {
+                let res = atomicCompareExchangeWeak(&table[slot], 0u, row + 1u);
+                if (res.exchanged) {
+                    groups[row] = row;
+                    return;
+                }
+                // Weak exchange can fail spuriously on an empty slot, retry it
+                if (res.old_value == 0u) {
+                    continue;
+                }
+                if (same_key(res.old_value - 1u, row)) {
+                    groups[row] = res.old_value - 1u;
+                    return;
+                }
+                slot = (slot + 1u) & mask;
+                probes++;
+            }
+        }
+    "#
+    .into()
+}
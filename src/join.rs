@@ -0,0 +1,96 @@
+use std::collections::HashSet;
+
+use arrow::{
+    array::{Array, ArrayRef, BooleanArray},
+    record_batch::RecordBatch,
+};
+
+use crate::{distinct, executor::QueryExecutor, sub};
+
+// Semi/anti join of `probe` against the first `keys.len()` columns of
+// `build`. Build and probe keys go through one GPU hash set together, a
+// probe row matches when its group holds a build row. Null keys never match.
+pub async fn semi_join(
+    executor: &QueryExecutor,
+    probe: RecordBatch,
+    build: &RecordBatch,
+    keys: &[u32],
+    kind: sub::JoinKind,
+) -> anyhow::Result<RecordBatch> {
+    let probe_rows = probe.num_rows();
+    let build_rows = build.num_rows();
+    if build.num_columns() < keys.len() {
+        anyhow::bail!("Join build side has fewer columns than keys");
+    }
+
+    let matched = if keys.is_empty() || probe_rows == 0 {
+        vec![build_rows > 0; probe_rows]
+    } else {
+        let probe_keys: Vec<ArrayRef> = keys
+            .iter()
+            .map(|k| probe.column(*k as usize).clone())
+            .collect();
+        // Same types on both sides so equal values encode to equal words
+        let build_keys = probe_keys
+            .iter()
+            .enumerate()
+            .map(|(i, p)| arrow::compute::cast(build.column(i), p.data_type()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let build_valid = valid_rows(&build_keys, build_rows);
+        // NOT IN is unknown for every row once the subquery returns a null
+        if kind == sub::JoinKind::NullAwareAnti && build_valid.iter().any(|v| !v) {
+            return Ok(probe.slice(0, 0));
+        }
+
+        let columns = build_keys
+            .iter()
+            .zip(&probe_keys)
+            .map(|(b, p)| arrow::compute::concat(&[b.as_ref(), p.as_ref()]))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (words, width) = distinct::encode(&columns)?;
+        let row = |i: usize| &words[i * width..(i + 1) * width];
+        let probe_valid = valid_rows(&probe_keys, probe_rows);
+
+        if build_rows + probe_rows <= executor.hash_set_rows(width) {
+            let groups = executor.hash_groups(&words, width).await?;
+            let mut has_build = vec![false; groups.len()];
+            for i in (0..build_rows).filter(|i| build_valid[*i]) {
+                has_build[groups[i] as usize] = true;
+            }
+            (0..probe_rows)
+                .map(|j| probe_valid[j] && has_build[groups[build_rows + j] as usize])
+                .collect()
+        } else {
+            // Too large for one GPU hash set, probe a CPU set of the encoded keys
+            let set: HashSet<&[u32]> = (0..build_rows)
+                .filter(|i| build_valid[*i])
+                .map(row)
+                .collect();
+            (0..probe_rows)
+                .map(|j| probe_valid[j] && set.contains(row(build_rows + j)))
+                .collect::<Vec<_>>()
+        }
+        .into_iter()
+        .enumerate()
+        .map(|(j, m)| match kind {
+            // A null probe key is unknown under NOT IN, unless the subquery is empty
+            sub::JoinKind::NullAwareAnti if !probe_valid[j] && build_rows > 0 => true,
+            _ => m,
+        })
+        .collect()
+    };
+
+    let keep: BooleanArray = matched
+        .into_iter()
+        .map(|m| Some(m == (kind == sub::JoinKind::Semi)))
+        .collect();
+    Ok(arrow::compute::filter_record_batch(&probe, &keep)?)
+}
+
+// Rows without a null in any key
+fn valid_rows(keys: &[ArrayRef], rows: usize) -> Vec<bool> {
+    (0..rows)
+        .map(|i| keys.iter().all(|k| k.is_valid(i)))
+        .collect()
+}
@@ -1,5 +1,18 @@
+pub mod aggregate;
+pub mod dataset;
+pub mod distinct;
 pub mod engine;
 pub mod executor;
+pub mod explain;
+pub mod ffi;
+pub mod flight;
 pub mod gpu;
 pub mod jit;
+pub mod join;
+pub mod pgwire;
+pub mod profile;
+pub mod source;
+pub mod sql;
+pub mod storage;
 pub mod sub;
+pub mod window;
@@ -1,4 +1,313 @@
+mod repl;
+
+use std::{
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+use arrow::{
+    array::RecordBatchReader, datatypes::SchemaRef, ipc::reader::StreamReader,
+    record_batch::RecordBatch,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use wsql::{
+    dataset::DatasetTable,
+    engine::QueryEngine,
+    executor::QueryExecutor,
+    gpu::Gpu,
+    source::{CsvOptions, CsvTable, IpcTable, NdjsonTable, ParquetTable, TableSource},
+    storage::{self, Storage},
+};
+
+#[derive(Parser)]
+#[command(
+    name = "wsql",
+    about = "Run Substrait plans and SQL queries on the GPU"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a Substrait plan over a data file
+    Query {
+        /// Substrait plan, in JSON or binary protobuf
+        #[arg(long)]
+        plan: PathBuf,
+        /// Parquet, CSV, NDJSON or Arrow IPC file read by scans of unregistered
+        /// tables, - for an IPC stream on stdin
+        data: PathBuf,
+        #[command(flatten)]
+        options: Options,
+    },
+    /// Run a SQL SELECT over the registered tables
+    Sql {
+        query: String,
+        #[command(flatten)]
+        options: Options,
+    },
+    /// Start an interactive SQL shell
+    Repl {
+        #[command(flatten)]
+        tables: TableArgs,
+    },
+    /// Serve the registered tables over Arrow Flight SQL
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:50051")]
+        listen: SocketAddr,
+        /// Also accept PostgreSQL wire protocol clients on this address
+        #[arg(long, value_name = "ADDR")]
+        postgres: Option<SocketAddr>,
+        #[command(flatten)]
+        tables: TableArgs,
+    },
+}
+
+#[derive(Args)]
+struct TableArgs {
+    /// Register a parquet, CSV, NDJSON or Arrow IPC file, or a directory, glob
+    /// pattern or URL (s3://, file://) of parquet files, as a table, as NAME=PATH
+    #[arg(long = "table", value_name = "NAME=PATH", value_parser = parse_table)]
+    tables: Vec<(String, PathBuf)>,
+    /// Field delimiter of CSV files
+    #[arg(long, default_value_t = ',')]
+    csv_delimiter: char,
+    /// CSV files have no header row, columns are named column_1, column_2, ...
+    #[arg(long)]
+    csv_no_header: bool,
+    /// OpenDAL config of s3:// locations, e.g. endpoint=http://127.0.0.1:9000
+    /// or region=us-east-1, as KEY=VALUE
+    #[arg(long = "storage-option", value_name = "KEY=VALUE", value_parser = parse_option)]
+    storage_options: Vec<(String, String)>,
+}
+
+// How tables are read, kept by the shell for .table
+pub struct TableOptions {
+    pub csv: CsvOptions,
+    pub storage: Storage,
+}
+
+impl TableArgs {
+    fn table_options(&self) -> anyhow::Result<TableOptions> {
+        let delimiter = u8::try_from(self.csv_delimiter)
+            .ok()
+            .filter(u8::is_ascii)
+            .ok_or_else(|| anyhow::anyhow!("CSV delimiter must be ASCII"))?;
+        let csv = CsvOptions {
+            header: !self.csv_no_header,
+            delimiter,
+            schema: None,
+        };
+        let mut storage = Storage::default();
+        storage.options.extend(self.storage_options.iter().cloned());
+        Ok(TableOptions { csv, storage })
+    }
+
+    async fn engine(&self, gpu: Gpu) -> anyhow::Result<QueryEngine> {
+        let options = self.table_options()?;
+        let mut engine = QueryEngine::new(QueryExecutor::new(gpu));
+        for (name, path) in &self.tables {
+            engine.register_table(name.clone(), open_table(name, path, &options).await?);
+        }
+        Ok(engine)
+    }
+}
+
+#[derive(Args)]
+struct Options {
+    #[command(flatten)]
+    tables: TableArgs,
+    /// Output format of the result
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    format: Format,
+    /// Print the physical plan, pipelines and kernels instead of running the query
+    #[arg(long)]
+    explain: bool,
+    /// Run the query and print the time every pipeline spent in each stage
+    /// instead of the result
+    #[arg(long, conflicts_with = "explain")]
+    analyze: bool,
+    /// Print the query time to stderr
+    #[arg(long)]
+    timing: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Table,
+    Csv,
+    Json,
+    /// Arrow IPC stream
+    Arrow,
+}
+
+fn parse_option(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Expected KEY=VALUE, got {arg}")),
+    }
+}
+
+pub fn parse_table(arg: &str) -> Result<(String, PathBuf), String> {
+    match arg.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(format!("Expected NAME=PATH, got {arg}")),
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let options = match &cli.command {
+        Command::Query { options, .. } | Command::Sql { options, .. } => options,
+        Command::Repl { tables } => {
+            let engine = tables.engine(Gpu::new().await).await?;
+            return repl::Repl::new(engine, tables.table_options()?).run().await;
+        }
+        Command::Serve {
+            listen,
+            postgres,
+            tables,
+        } => {
+            let engine = Arc::new(tables.engine(Gpu::new().await).await?);
+            eprintln!("Serving Flight SQL on {listen}");
+            let Some(postgres) = postgres else {
+                return wsql::flight::serve(engine, *listen).await;
+            };
+            eprintln!("Serving PostgreSQL on {postgres}");
+            tokio::try_join!(
+                wsql::flight::serve(engine.clone(), *listen),
+                wsql::pgwire::serve(engine, *postgres),
+            )?;
+            return Ok(());
+        }
+    };
+
+    let engine = options.tables.engine(Gpu::new().await).await?;
+
+    let start = Instant::now();
+    let result = match &cli.command {
+        Command::Query { plan, data, .. } => {
+            let plan = wsql::sub::parse_plan(&std::fs::read(plan)?)?;
+            let input = open_input(data, &options.tables.table_options()?).await?;
+            if options.explain {
+                print!("{}", engine.explain(&plan, Some(input.schema()))?);
+                return Ok(());
+            }
+            if options.analyze {
+                let analysis = match input {
+                    Input::Table(table) => engine.analyze_table(table, &plan).await?,
+                    Input::Stream(reader) => engine.analyze(&plan, Some(reader)).await?,
+                };
+                print!("{analysis}");
+                return Ok(());
+            }
+            match input {
+                // Scans of a file only read the columns the plan uses
+                Input::Table(table) => engine.run_table(table, &plan).await?,
+                Input::Stream(reader) => engine.run_plan(reader, &plan).await?,
+            }
+        }
+        Command::Sql { query, .. } => {
+            if options.explain {
+                print!("{}", engine.explain_sql(query)?);
+                return Ok(());
+            }
+            if options.analyze {
+                print!("{}", engine.analyze_sql(query).await?);
+                return Ok(());
+            }
+            engine.sql(query).await?
+        }
+        Command::Repl { .. } | Command::Serve { .. } => unreachable!(),
+    };
+    let duration = start.elapsed();
+
+    write_result(&result, options.format)?;
+    if options.timing {
+        eprintln!("Query time: {:?}", duration);
+    }
+    Ok(())
+}
+
+// URLs like s3://bucket/sales, directories and glob patterns are read as
+// parquet datasets. Otherwise the format of a local file is picked by
+// extension: .csv, .json, .ndjson, .jsonl, .arrow, .arrows, .feather and
+// .ipc, everything else is read as parquet
+pub async fn open_table(
+    name: &str,
+    path: &Path,
+    options: &TableOptions,
+) -> anyhow::Result<Arc<dyn TableSource>> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let location = path.to_string_lossy();
+    let is_dataset =
+        storage::is_url(&location) || path.is_dir() || location.contains(['*', '?', '[']);
+    let table: anyhow::Result<Arc<dyn TableSource>> = match extension.as_str() {
+        _ if is_dataset => DatasetTable::open_with(&options.storage, &location)
+            .await
+            .map(|t| Arc::new(t) as _),
+        "csv" => CsvTable::open_with(path, options.csv.clone()).map(|t| Arc::new(t) as _),
+        "json" | "ndjson" | "jsonl" => NdjsonTable::open(path).map(|t| Arc::new(t) as _),
+        "arrow" | "arrows" | "feather" | "ipc" => IpcTable::open(path).map(|t| Arc::new(t) as _),
+        _ => ParquetTable::open(path).map(|t| Arc::new(t) as _),
+    };
+    table.map_err(|e| anyhow::anyhow!("Cannot open table {}: {}", name, e))
+}
+
+// The data argument of `query`, a table file or an IPC stream on stdin
+enum Input {
+    Table(Arc<dyn TableSource>),
+    Stream(Box<dyn RecordBatchReader + Send>),
+}
+
+impl Input {
+    fn schema(&self) -> SchemaRef {
+        match self {
+            Input::Table(table) => table.schema(),
+            Input::Stream(reader) => reader.schema(),
+        }
+    }
+}
+
+async fn open_input(path: &Path, options: &TableOptions) -> anyhow::Result<Input> {
+    if path == Path::new("-") {
+        let reader = StreamReader::try_new(std::io::BufReader::new(std::io::stdin()), None)?;
+        return Ok(Input::Stream(Box::new(reader)));
+    }
+    Ok(Input::Table(open_table("data", path, options).await?))
+}
+
+pub fn write_result(batch: &RecordBatch, format: Format) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    match format {
+        Format::Table => {
+            let table = arrow::util::pretty::pretty_format_batches(std::slice::from_ref(batch))?;
+            writeln!(stdout, "{table}")?;
+        }
+        Format::Csv => arrow::csv::Writer::new(stdout).write(batch)?,
+        Format::Json => {
+            let mut writer = arrow::json::LineDelimitedWriter::new(stdout);
+            writer.write(batch)?;
+            writer.finish()?;
+        }
+        Format::Arrow => {
+            let mut writer = arrow::ipc::writer::StreamWriter::try_new(stdout, &batch.schema())?;
+            writer.write(batch)?;
+            writer.finish()?;
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,905 @@
+use std::{collections::HashMap, fmt, net::SocketAddr, sync::Arc};
+
+use arrow::{
+    array::{Array, ArrayRef, AsArray, RecordBatch},
+    compute::cast,
+    datatypes::{
+        DataType, Date32Type, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, Schema,
+        TimeUnit, TimestampMicrosecondType,
+    },
+    error::ArrowError,
+    util::display::{ArrayFormatter, FormatOptions},
+};
+use sqlparser::{ast, dialect::PostgreSqlDialect, parser::Parser};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::engine::QueryEngine;
+
+const PROTOCOL_3: i32 = 196608;
+const SSL_REQUEST: i32 = 80877103;
+const GSSENC_REQUEST: i32 = 80877104;
+const CANCEL_REQUEST: i32 = 80877102;
+
+// Type OIDs from pg_type
+const BOOL: u32 = 16;
+const BYTEA: u32 = 17;
+const INT8: u32 = 20;
+const INT2: u32 = 21;
+const INT4: u32 = 23;
+const TEXT: u32 = 25;
+const FLOAT4: u32 = 700;
+const FLOAT8: u32 = 701;
+const VARCHAR: u32 = 1043;
+const DATE: u32 = 1082;
+const TIMESTAMP: u32 = 1114;
+const TIMESTAMPTZ: u32 = 1184;
+const NUMERIC: u32 = 1700;
+
+// SQLSTATE codes
+const PROTOCOL_VIOLATION: &str = "08P01";
+const FEATURE_NOT_SUPPORTED: &str = "0A000";
+const SYNTAX_ERROR: &str = "42601";
+const SYNTAX_ERROR_OR_ACCESS_RULE_VIOLATION: &str = "42000";
+const INVALID_SQL_STATEMENT_NAME: &str = "26000";
+const INVALID_CURSOR_NAME: &str = "34000";
+const INTERNAL_ERROR: &str = "XX000";
+
+// Binary dates and timestamps count from 2000-01-01
+const EPOCH_2000_DAYS: i32 = 10957;
+const EPOCH_2000_MICROS: i64 = 946_684_800_000_000;
+
+// Accepts Postgres clients on `addr` until the listener fails
+pub async fn serve(engine: Arc<QueryEngine>, addr: SocketAddr) -> anyhow::Result<()> {
+    serve_listener(engine, TcpListener::bind(addr).await?).await
+}
+
+pub async fn serve_listener(engine: Arc<QueryEngine>, listener: TcpListener) -> anyhow::Result<()> {
+    let mut next_id = 0;
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        next_id += 1;
+        let connection = Connection::new(engine.clone(), socket, next_id);
+        tokio::spawn(async move {
+            if let Err(e) = connection.run().await {
+                eprintln!("Postgres connection from {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+#[derive(Debug)]
+struct PgError {
+    code: &'static str,
+    message: String,
+}
+
+impl PgError {
+    fn new(code: &'static str, message: impl fmt::Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for PgError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(INTERNAL_ERROR, e)
+    }
+}
+
+impl From<ArrowError> for PgError {
+    fn from(e: ArrowError) -> Self {
+        Self::new(INTERNAL_ERROR, e)
+    }
+}
+
+#[derive(Clone)]
+enum Statement {
+    Empty,
+    // A SELECT, as SQL with $n placeholders until bound
+    Query(String),
+    // Session and transaction statements drivers send on their own, accepted
+    // without effect and answered with their command tag
+    Command(&'static str),
+}
+
+struct Prepared {
+    statement: Statement,
+    // Declared parameter types, 0 where the client left it to the server
+    param_types: Vec<u32>,
+}
+
+struct Portal {
+    statement: Statement,
+    plan: Option<substrait::proto::Plan>,
+    result_formats: Vec<i16>,
+    // The result and the next row to send, once executed
+    result: Option<(RecordBatch, usize)>,
+}
+
+struct Connection {
+    engine: Arc<QueryEngine>,
+    stream: BufReader<TcpStream>,
+    id: i32,
+    // Messages waiting for the next flush
+    out: Vec<u8>,
+    statements: HashMap<String, Prepared>,
+    portals: HashMap<String, Portal>,
+    // Transaction status sent with ReadyForQuery, I when idle, T in a block
+    status: u8,
+    // An extended protocol message failed, skip everything up to Sync
+    failed: bool,
+}
+
+impl Connection {
+    fn new(engine: Arc<QueryEngine>, socket: TcpStream, id: i32) -> Self {
+        Self {
+            engine,
+            stream: BufReader::new(socket),
+            id,
+            out: Vec::new(),
+            statements: HashMap::new(),
+            portals: HashMap::new(),
+            status: b'I',
+            failed: false,
+        }
+    }
+
+    async fn run(mut self) -> anyhow::Result<()> {
+        if !self.startup().await? {
+            return Ok(());
+        }
+        loop {
+            let tag = match self.stream.read_u8().await {
+                Ok(tag) => tag,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let len = self.stream.read_i32().await?;
+            anyhow::ensure!(len >= 4, "Invalid message length {len}");
+            let mut body = vec![0; len as usize - 4];
+            self.stream.read_exact(&mut body).await?;
+
+            if self.failed && !matches!(tag, b'S' | b'X') {
+                continue;
+            }
+            let mut body = Body(&body);
+            let result = match tag {
+                b'Q' => {
+                    match body.str() {
+                        Ok(sql) => self.simple_query(sql).await,
+                        Err(e) => error_response(&mut self.out, &e),
+                    }
+                    self.ready_for_query().await?;
+                    continue;
+                }
+                b'P' => self.parse(&mut body),
+                b'B' => self.bind(&mut body),
+                b'D' => self.describe(&mut body),
+                b'E' => self.execute(&mut body).await,
+                b'C' => self.close(&mut body),
+                b'S' => {
+                    self.failed = false;
+                    self.ready_for_query().await?;
+                    continue;
+                }
+                b'H' => {
+                    self.flush().await?;
+                    continue;
+                }
+                b'X' => return Ok(()),
+                tag => Err(PgError::new(
+                    PROTOCOL_VIOLATION,
+                    format!("Unsupported message type {:?}", tag as char),
+                )),
+            };
+            if let Err(e) = result {
+                error_response(&mut self.out, &e);
+                self.failed = true;
+            }
+        }
+    }
+
+    // Negotiates the protocol, false for cancel requests which carry no session
+    async fn startup(&mut self) -> anyhow::Result<bool> {
+        loop {
+            let len = self.stream.read_i32().await?;
+            anyhow::ensure!(
+                (8..=10000).contains(&len),
+                "Invalid startup packet length {len}"
+            );
+            let mut body = vec![0; len as usize - 4];
+            self.stream.read_exact(&mut body).await?;
+            match i32::from_be_bytes(body[..4].try_into()?) {
+                // No TLS or GSSAPI, the client retries in plain text
+                SSL_REQUEST | GSSENC_REQUEST => {
+                    self.stream.write_all(b"N").await?;
+                    self.stream.flush().await?;
+                }
+                // Queries run to completion, there is nothing to cancel
+                CANCEL_REQUEST => return Ok(false),
+                PROTOCOL_3 => break,
+                version => anyhow::bail!(
+                    "Unsupported protocol version {}.{}",
+                    version >> 16,
+                    version & 0xffff
+                ),
+            }
+        }
+
+        // Any user is trusted
+        message(&mut self.out, b'R', |b| b.extend(0i32.to_be_bytes()));
+        for (name, value) in [
+            ("server_version", "14.0"),
+            ("server_encoding", "UTF8"),
+            ("client_encoding", "UTF8"),
+            ("DateStyle", "ISO, MDY"),
+            ("TimeZone", "UTC"),
+            ("integer_datetimes", "on"),
+            ("standard_conforming_strings", "on"),
+        ] {
+            message(&mut self.out, b'S', |b| {
+                put_str(b, name);
+                put_str(b, value);
+            });
+        }
+        message(&mut self.out, b'K', |b| {
+            b.extend(self.id.to_be_bytes());
+            b.extend(0i32.to_be_bytes());
+        });
+        self.ready_for_query().await?;
+        Ok(true)
+    }
+
+    async fn simple_query(&mut self, sql: &str) {
+        let statements = match parse(sql) {
+            Ok(statements) => statements,
+            Err(e) => return error_response(&mut self.out, &e),
+        };
+        if statements.is_empty() {
+            message(&mut self.out, b'I', |_| {});
+        }
+        for statement in statements {
+            if let Err(e) = self.simple_statement(statement).await {
+                // The rest of the string is skipped
+                return error_response(&mut self.out, &e);
+            }
+        }
+    }
+
+    async fn simple_statement(&mut self, statement: Statement) -> Result<(), PgError> {
+        match statement {
+            Statement::Empty => message(&mut self.out, b'I', |_| {}),
+            Statement::Query(sql) => {
+                let plan = self
+                    .engine
+                    .plan_sql(&sql)
+                    .map_err(|e| PgError::new(SYNTAX_ERROR_OR_ACCESS_RULE_VIOLATION, e))?;
+                let batch = self.engine.query(&plan).await?;
+                row_description(&mut self.out, &batch.schema(), &[])?;
+                data_rows(&mut self.out, &batch, &[])?;
+                command_complete(&mut self.out, &format!("SELECT {}", batch.num_rows()));
+            }
+            Statement::Command(tag) => self.command(tag),
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, tag: &str) {
+        match tag {
+            "BEGIN" => self.status = b'T',
+            "COMMIT" | "ROLLBACK" => self.status = b'I',
+            _ => {}
+        }
+        command_complete(&mut self.out, tag);
+    }
+
+    fn parse(&mut self, body: &mut Body) -> Result<(), PgError> {
+        let name = body.str()?.to_string();
+        let sql = body.str()?;
+        let param_types = (0..body.i16()?)
+            .map(|_| Ok(body.i32()? as u32))
+            .collect::<Result<_, PgError>>()?;
+
+        let mut statements = parse(sql)?;
+        if statements.len() > 1 {
+            return Err(PgError::new(
+                SYNTAX_ERROR,
+                "cannot insert multiple commands into a prepared statement",
+            ));
+        }
+        let statement = statements.pop().unwrap_or(Statement::Empty);
+        self.statements.insert(
+            name,
+            Prepared {
+                statement,
+                param_types,
+            },
+        );
+        message(&mut self.out, b'1', |_| {});
+        Ok(())
+    }
+
+    fn bind(&mut self, body: &mut Body) -> Result<(), PgError> {
+        let portal = body.str()?.to_string();
+        let name = body.str()?;
+        let prepared = self.statements.get(name).ok_or_else(|| {
+            PgError::new(
+                INVALID_SQL_STATEMENT_NAME,
+                format!("prepared statement \"{name}\" does not exist"),
+            )
+        })?;
+
+        let formats = (0..body.i16()?)
+            .map(|_| body.i16())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut params = Vec::new();
+        for i in 0..body.i16()? as usize {
+            // A length of -1 is a NULL parameter
+            let len = body.i32()?;
+            if len < 0 {
+                params.push("NULL".to_string());
+                continue;
+            }
+            let value = body.bytes(len as usize)?;
+            let oid = prepared.param_types.get(i).copied().unwrap_or(0);
+            params.push(param_literal(value, oid, format(&formats, i) == 1)?);
+        }
+        let result_formats = (0..body.i16()?)
+            .map(|_| body.i16())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let statement = match &prepared.statement {
+            Statement::Query(sql) => {
+                Statement::Query(bind_params(sql, |n| params.get(n - 1).cloned())?)
+            }
+            statement => statement.clone(),
+        };
+        let plan = match &statement {
+            Statement::Query(sql) => Some(
+                self.engine
+                    .plan_sql(sql)
+                    .map_err(|e| PgError::new(SYNTAX_ERROR_OR_ACCESS_RULE_VIOLATION, e))?,
+            ),
+            _ => None,
+        };
+        self.portals.insert(
+            portal,
+            Portal {
+                statement,
+                plan,
+                result_formats,
+                result: None,
+            },
+        );
+        message(&mut self.out, b'2', |_| {});
+        Ok(())
+    }
+
+    fn describe(&mut self, body: &mut Body) -> Result<(), PgError> {
+        let kind = body.u8()?;
+        let name = body.str()?;
+        if kind == b'P' {
+            let portal = portal(&self.portals, name)?;
+            return match &portal.plan {
+                Some(plan) => {
+                    let schema = self.engine.output_schema(plan)?;
+                    row_description(&mut self.out, &schema, &portal.result_formats)
+                }
+                None => message(&mut self.out, b'n', |_| Ok(())),
+            };
+        }
+
+        let prepared = self.statements.get(name).ok_or_else(|| {
+            PgError::new(
+                INVALID_SQL_STATEMENT_NAME,
+                format!("prepared statement \"{name}\" does not exist"),
+            )
+        })?;
+        let mut count = prepared.param_types.len();
+        if let Statement::Query(sql) = &prepared.statement {
+            bind_params(sql, |n| {
+                count = count.max(n);
+                Some(String::new())
+            })?;
+        }
+        // Undeclared parameters are described as text, their values are
+        // bound as numbers when they parse as one
+        let types: Vec<u32> = (0..count)
+            .map(|i| match prepared.param_types.get(i) {
+                Some(&oid) if oid != 0 => oid,
+                _ => TEXT,
+            })
+            .collect();
+        message(&mut self.out, b't', |b| {
+            b.extend((types.len() as i16).to_be_bytes());
+            for oid in &types {
+                b.extend(oid.to_be_bytes());
+            }
+        });
+
+        match &prepared.statement {
+            Statement::Query(sql) => {
+                let schema = self.describe_query(sql)?;
+                row_description(&mut self.out, &schema, &[])
+            }
+            _ => message(&mut self.out, b'n', |_| Ok(())),
+        }
+    }
+
+    // Output schema of an unbound query. Parameters are planned as 0, or as
+    // '' if that does not type check, which is enough where they appear in
+    // filters.
+    fn describe_query(&self, sql: &str) -> Result<Arc<Schema>, PgError> {
+        let mut result = Err(PgError::new(INTERNAL_ERROR, "unreachable"));
+        for placeholder in ["0", "''"] {
+            let bound = bind_params(sql, |_| Some(placeholder.to_string()))?;
+            result = self
+                .engine
+                .plan_sql(&bound)
+                .and_then(|plan| self.engine.output_schema(&plan))
+                .map_err(|e| PgError::new(SYNTAX_ERROR_OR_ACCESS_RULE_VIOLATION, e));
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    async fn execute(&mut self, body: &mut Body<'_>) -> Result<(), PgError> {
+        let name = body.str()?;
+        let max_rows = body.i32()?;
+        let portal = portal(&self.portals, name)?;
+        let plan = match &portal.statement {
+            Statement::Empty => {
+                message(&mut self.out, b'I', |_| {});
+                return Ok(());
+            }
+            &Statement::Command(tag) => {
+                self.command(tag);
+                return Ok(());
+            }
+            Statement::Query(_) => portal.plan.clone(),
+        };
+        if portal.result.is_none() {
+            let plan = plan.expect("bound queries are planned");
+            let batch = self.engine.query(&plan).await?;
+            self.portals.get_mut(name).unwrap().result = Some((batch, 0));
+        }
+
+        let portal = self.portals.get_mut(name).unwrap();
+        let (batch, next) = portal.result.as_mut().unwrap();
+        let end = match max_rows {
+            n if n > 0 => (*next + n as usize).min(batch.num_rows()),
+            _ => batch.num_rows(),
+        };
+        data_rows(
+            &mut self.out,
+            &batch.slice(*next, end - *next),
+            &portal.result_formats,
+        )?;
+        *next = end;
+        if end < batch.num_rows() {
+            message(&mut self.out, b's', |_| {});
+        } else {
+            command_complete(&mut self.out, &format!("SELECT {}", batch.num_rows()));
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, body: &mut Body) -> Result<(), PgError> {
+        let kind = body.u8()?;
+        let name = body.str()?;
+        match kind {
+            b'S' => self.statements.remove(name).map(|_| ()),
+            _ => self.portals.remove(name).map(|_| ()),
+        };
+        message(&mut self.out, b'3', |_| {});
+        Ok(())
+    }
+
+    async fn ready_for_query(&mut self) -> std::io::Result<()> {
+        let status = self.status;
+        message(&mut self.out, b'Z', |b| b.push(status));
+        self.flush().await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.write_all(&self.out).await?;
+        self.out.clear();
+        self.stream.flush().await
+    }
+}
+
+fn portal<'a>(portals: &'a HashMap<String, Portal>, name: &str) -> Result<&'a Portal, PgError> {
+    portals.get(name).ok_or_else(|| {
+        PgError::new(
+            INVALID_CURSOR_NAME,
+            format!("portal \"{name}\" does not exist"),
+        )
+    })
+}
+
+// Splits a query string into the statements the server runs
+fn parse(sql: &str) -> Result<Vec<Statement>, PgError> {
+    let statements =
+        Parser::parse_sql(&PostgreSqlDialect {}, sql).map_err(|e| PgError::new(SYNTAX_ERROR, e))?;
+    statements
+        .into_iter()
+        .map(|statement| match statement {
+            ast::Statement::Query(_) => Ok(Statement::Query(statement.to_string())),
+            ast::Statement::SetVariable { .. }
+            | ast::Statement::SetTimeZone { .. }
+            | ast::Statement::SetNames { .. }
+            | ast::Statement::SetNamesDefault {}
+            | ast::Statement::SetTransaction { .. } => Ok(Statement::Command("SET")),
+            ast::Statement::StartTransaction { .. } => Ok(Statement::Command("BEGIN")),
+            ast::Statement::Commit { .. } => Ok(Statement::Command("COMMIT")),
+            ast::Statement::Rollback { .. } => Ok(Statement::Command("ROLLBACK")),
+            statement => Err(PgError::new(
+                FEATURE_NOT_SUPPORTED,
+                format!("Unsupported statement: {statement}"),
+            )),
+        })
+        .collect()
+}
+
+// Replaces the $n placeholders outside of quotes with `value(n)`, padded
+// with spaces so a negative number after `-` does not start a comment
+fn bind_params(
+    sql: &str,
+    mut value: impl FnMut(usize) -> Option<String>,
+) -> Result<String, PgError> {
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '$') if chars.peek().is_some_and(char::is_ascii_digit) => {
+                let mut n = 0;
+                while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    n = n * 10 + digit as usize;
+                    chars.next();
+                }
+                let literal = value(n).ok_or_else(|| {
+                    PgError::new(PROTOCOL_VIOLATION, format!("No value for parameter ${n}"))
+                })?;
+                out.push(' ');
+                out.push_str(&literal);
+                out.push(' ');
+                continue;
+            }
+            _ => {}
+        }
+        out.push(c);
+    }
+    Ok(out)
+}
+
+// SQL literal of a parameter value of type `oid`
+fn param_literal(value: &[u8], oid: u32, binary: bool) -> Result<String, PgError> {
+    let invalid = || PgError::new(PROTOCOL_VIOLATION, "Invalid binary parameter value");
+    let text = match (binary, oid) {
+        (true, BOOL) => (value.first().ok_or_else(invalid)? != &0).to_string(),
+        (true, INT2) => i16::from_be_bytes(value.try_into().map_err(|_| invalid())?).to_string(),
+        (true, INT4) => i32::from_be_bytes(value.try_into().map_err(|_| invalid())?).to_string(),
+        (true, INT8) => i64::from_be_bytes(value.try_into().map_err(|_| invalid())?).to_string(),
+        (true, FLOAT4) => f32::from_be_bytes(value.try_into().map_err(|_| invalid())?).to_string(),
+        (true, FLOAT8) => f64::from_be_bytes(value.try_into().map_err(|_| invalid())?).to_string(),
+        (true, 0 | TEXT | VARCHAR) | (false, _) => std::str::from_utf8(value)
+            .map_err(|_| invalid())?
+            .to_string(),
+        (true, oid) => {
+            return Err(PgError::new(
+                FEATURE_NOT_SUPPORTED,
+                format!("Binary parameters of type {oid} are not supported"),
+            ));
+        }
+    };
+    let numeric = text.parse::<f64>().is_ok_and(f64::is_finite);
+    Ok(match oid {
+        BOOL if matches!(
+            text.to_ascii_lowercase().as_str(),
+            "t" | "true" | "1" | "on"
+        ) =>
+        {
+            "TRUE".to_string()
+        }
+        BOOL => "FALSE".to_string(),
+        INT2 | INT4 | INT8 | FLOAT4 | FLOAT8 | NUMERIC | 0 if numeric => text,
+        _ => format!("'{}'", text.replace('\'', "''")),
+    })
+}
+
+// Format code of column or parameter `i`: none means all text, one applies to all
+fn format(formats: &[i16], i: usize) -> i16 {
+    match formats {
+        [] => 0,
+        [format] => *format,
+        formats => formats.get(i).copied().unwrap_or(0),
+    }
+}
+
+// How columns of an Arrow type are sent: the Postgres type OID and length,
+// and the Arrow type they are cast to first. Types without a cast are sent
+// as text only.
+fn pg_type(data_type: &DataType) -> (u32, i16, Option<DataType>) {
+    match data_type {
+        DataType::Boolean => (BOOL, 1, Some(DataType::Boolean)),
+        DataType::Int8 | DataType::Int16 | DataType::UInt8 => (INT2, 2, Some(DataType::Int16)),
+        DataType::Int32 | DataType::UInt16 => (INT4, 4, Some(DataType::Int32)),
+        DataType::Int64 | DataType::UInt32 => (INT8, 8, Some(DataType::Int64)),
+        DataType::Float16 | DataType::Float32 => (FLOAT4, 4, Some(DataType::Float32)),
+        DataType::Float64 => (FLOAT8, 8, Some(DataType::Float64)),
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => {
+            (TEXT, -1, Some(DataType::Utf8))
+        }
+        DataType::Binary
+        | DataType::LargeBinary
+        | DataType::BinaryView
+        | DataType::FixedSizeBinary(_) => (BYTEA, -1, Some(DataType::Binary)),
+        DataType::Date32 | DataType::Date64 => (DATE, 4, Some(DataType::Date32)),
+        DataType::Timestamp(_, None) => (
+            TIMESTAMP,
+            8,
+            Some(DataType::Timestamp(TimeUnit::Microsecond, None)),
+        ),
+        DataType::Timestamp(_, tz) => (
+            TIMESTAMPTZ,
+            8,
+            Some(DataType::Timestamp(TimeUnit::Microsecond, tz.clone())),
+        ),
+        DataType::UInt64 | DataType::Decimal128(..) | DataType::Decimal256(..) => {
+            (NUMERIC, -1, None)
+        }
+        _ => (TEXT, -1, None),
+    }
+}
+
+fn row_description(out: &mut Vec<u8>, schema: &Schema, formats: &[i16]) -> Result<(), PgError> {
+    for (i, field) in schema.fields().iter().enumerate() {
+        if format(formats, i) == 1 && pg_type(field.data_type()).2.is_none() {
+            return Err(PgError::new(
+                FEATURE_NOT_SUPPORTED,
+                format!("Binary format of {} is not supported", field.data_type()),
+            ));
+        }
+    }
+    message(out, b'T', |b| {
+        b.extend((schema.fields().len() as i16).to_be_bytes());
+        for (i, field) in schema.fields().iter().enumerate() {
+            let (oid, len, _) = pg_type(field.data_type());
+            put_str(b, field.name());
+            b.extend(0i32.to_be_bytes());
+            b.extend(0i16.to_be_bytes());
+            b.extend(oid.to_be_bytes());
+            b.extend(len.to_be_bytes());
+            b.extend((-1i32).to_be_bytes());
+            b.extend(format(formats, i).to_be_bytes());
+        }
+    });
+    Ok(())
+}
+
+fn data_rows(out: &mut Vec<u8>, batch: &RecordBatch, formats: &[i16]) -> Result<(), PgError> {
+    let mut columns = Vec::new();
+    for (i, array) in batch.columns().iter().enumerate() {
+        let binary = format(formats, i) == 1;
+        let array = match pg_type(array.data_type()).2 {
+            Some(data_type) => cast(array, &data_type)?,
+            None if binary => {
+                return Err(PgError::new(
+                    FEATURE_NOT_SUPPORTED,
+                    format!("Binary format of {} is not supported", array.data_type()),
+                ));
+            }
+            None => array.clone(),
+        };
+        columns.push((array, binary));
+    }
+    let options = FormatOptions::default()
+        .with_timestamp_format(Some("%Y-%m-%d %H:%M:%S%.f"))
+        .with_timestamp_tz_format(Some("%Y-%m-%d %H:%M:%S%.f%:z"));
+    let formatters = columns
+        .iter()
+        .map(|(array, _)| ArrayFormatter::try_new(array.as_ref(), &options))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let start = out.len();
+    for row in 0..batch.num_rows() {
+        let written = message(out, b'D', |b| {
+            b.extend((columns.len() as i16).to_be_bytes());
+            for ((array, binary), formatter) in columns.iter().zip(&formatters) {
+                if array.is_null(row) {
+                    b.extend((-1i32).to_be_bytes());
+                    continue;
+                }
+                let len_at = b.len();
+                b.extend([0; 4]);
+                match binary {
+                    true => binary_value(b, array, row)?,
+                    false => text_value(b, array, formatter, row)?,
+                }
+                let len = (b.len() - len_at - 4) as i32;
+                b[len_at..len_at + 4].copy_from_slice(&len.to_be_bytes());
+            }
+            Ok::<_, ArrowError>(())
+        });
+        if let Err(e) = written {
+            out.truncate(start);
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+fn text_value(
+    b: &mut Vec<u8>,
+    array: &ArrayRef,
+    formatter: &ArrayFormatter,
+    row: usize,
+) -> Result<(), ArrowError> {
+    match array.data_type() {
+        DataType::Boolean => b.push(if array.as_boolean().value(row) {
+            b't'
+        } else {
+            b'f'
+        }),
+        DataType::Float32 => float_text(b, array.as_primitive::<Float32Type>().value(row)),
+        DataType::Float64 => float_text(b, array.as_primitive::<Float64Type>().value(row)),
+        DataType::Binary => {
+            b.extend(b"\\x");
+            for byte in array.as_binary::<i32>().value(row) {
+                b.extend(format!("{byte:02x}").as_bytes());
+            }
+        }
+        _ => b.extend(formatter.value(row).try_to_string()?.as_bytes()),
+    }
+    Ok(())
+}
+
+fn float_text(b: &mut Vec<u8>, value: impl Into<f64> + fmt::Display + Copy) {
+    match value.into() {
+        v if v.is_nan() => b.extend(b"NaN"),
+        f64::INFINITY => b.extend(b"Infinity"),
+        f64::NEG_INFINITY => b.extend(b"-Infinity"),
+        _ => b.extend(value.to_string().as_bytes()),
+    }
+}
+
+// Values already cast by `pg_type`
+fn binary_value(b: &mut Vec<u8>, array: &ArrayRef, row: usize) -> Result<(), ArrowError> {
+    match array.data_type() {
+        DataType::Boolean => b.push(array.as_boolean().value(row) as u8),
+        DataType::Int16 => b.extend(array.as_primitive::<Int16Type>().value(row).to_be_bytes()),
+        DataType::Int32 => b.extend(array.as_primitive::<Int32Type>().value(row).to_be_bytes()),
+        DataType::Int64 => b.extend(array.as_primitive::<Int64Type>().value(row).to_be_bytes()),
+        DataType::Float32 => b.extend(array.as_primitive::<Float32Type>().value(row).to_be_bytes()),
+        DataType::Float64 => b.extend(array.as_primitive::<Float64Type>().value(row).to_be_bytes()),
+        DataType::Utf8 => b.extend(array.as_string::<i32>().value(row).as_bytes()),
+        DataType::Binary => b.extend(array.as_binary::<i32>().value(row)),
+        DataType::Date32 => {
+            let days = array.as_primitive::<Date32Type>().value(row) - EPOCH_2000_DAYS;
+            b.extend(days.to_be_bytes())
+        }
+        DataType::Timestamp(TimeUnit::Microsecond, _) => {
+            let micros =
+                array.as_primitive::<TimestampMicrosecondType>().value(row) - EPOCH_2000_MICROS;
+            b.extend(micros.to_be_bytes())
+        }
+        data_type => {
+            return Err(ArrowError::NotYetImplemented(format!(
+                "Binary format of {data_type}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn command_complete(out: &mut Vec<u8>, tag: &str) {
+    message(out, b'C', |b| put_str(b, tag));
+}
+
+fn error_response(out: &mut Vec<u8>, e: &PgError) {
+    message(out, b'E', |b| {
+        for (field, value) in [
+            (b'S', "ERROR"),
+            (b'V', "ERROR"),
+            (b'C', e.code),
+            (b'M', &e.message),
+        ] {
+            b.push(field);
+            put_str(b, value);
+        }
+        b.push(0);
+    });
+}
+
+// Appends a message with `tag`, filling in its length once `body` wrote it
+fn message<T>(out: &mut Vec<u8>, tag: u8, body: impl FnOnce(&mut Vec<u8>) -> T) -> T {
+    out.push(tag);
+    let start = out.len();
+    out.extend([0; 4]);
+    let result = body(out);
+    let len = (out.len() - start) as i32;
+    out[start..start + 4].copy_from_slice(&len.to_be_bytes());
+    result
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    out.extend(s.replace('\0', "").as_bytes());
+    out.push(0);
+}
+
+// Reads the fields of a message body
+struct Body<'a>(&'a [u8]);
+
+impl<'a> Body<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], PgError> {
+        if self.0.len() < n {
+            return Err(PgError::new(PROTOCOL_VIOLATION, "Message is too short"));
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, PgError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn i16(&mut self) -> Result<i16, PgError> {
+        Ok(i16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, PgError> {
+        Ok(i32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str, PgError> {
+        let end = self
+            .0
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| PgError::new(PROTOCOL_VIOLATION, "Unterminated string"))?;
+        let s = std::str::from_utf8(&self.0[..end])
+            .map_err(|_| PgError::new(PROTOCOL_VIOLATION, "Invalid UTF-8 string"))?;
+        self.0 = &self.0[end + 1..];
+        Ok(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_params() {
+        let params = ["5".to_string(), "'it''s'".to_string()];
+        let sql = bind_params("SELECT '$1', \"$2\" FROM t WHERE a > $1 AND b = $2", |n| {
+            params.get(n - 1).cloned()
+        })
+        .unwrap();
+        assert_eq!(
+            sql,
+            "SELECT '$1', \"$2\" FROM t WHERE a >  5  AND b =  'it''s' "
+        );
+        assert!(bind_params("SELECT $3", |_| None).is_err());
+
+        let negative = param_literal(b"-5", 0, false).unwrap();
+        let sql = bind_params("SELECT id-$1 FROM t", |_| Some(negative.clone())).unwrap();
+        assert_eq!(sql, "SELECT id- -5  FROM t");
+
+        assert_eq!(param_literal(b"4", 0, false).unwrap(), "4");
+        assert_eq!(param_literal(b"it's", 0, false).unwrap(), "'it''s'");
+        assert_eq!(param_literal(b"4", TEXT, false).unwrap(), "'4'");
+        assert_eq!(param_literal(&[1], BOOL, true).unwrap(), "TRUE");
+        assert_eq!(param_literal(&7i32.to_be_bytes(), INT4, true).unwrap(), "7");
+    }
+}
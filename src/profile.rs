@@ -0,0 +1,186 @@
+use std::{
+    fmt,
+    ops::AddAssign,
+    time::{Duration, Instant},
+};
+
+// Wall time a query spent in each stage
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StageTimes {
+    // Reading and decoding input batches
+    pub decode: Duration,
+    // Creating GPU buffers from the input columns
+    pub upload: Duration,
+    // Encoding and submitting compute passes
+    pub dispatch: Duration,
+    // Waiting for the GPU and copying results back
+    pub readback: Duration,
+    // CPU work on the results: masks, hash tables, sorts, concatenation
+    pub merge: Duration,
+    // Time inside compute passes from GPU timestamps, None when the adapter
+    // does not support TIMESTAMP_QUERY
+    pub gpu: Option<Duration>,
+}
+
+impl AddAssign for StageTimes {
+    fn add_assign(&mut self, other: Self) {
+        self.decode += other.decode;
+        self.upload += other.upload;
+        self.dispatch += other.dispatch;
+        self.readback += other.readback;
+        self.merge += other.merge;
+        self.gpu = match (self.gpu, other.gpu) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+impl fmt::Display for StageTimes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "decode {:?}, upload {:?}, dispatch {:?}, readback {:?}, merge {:?}",
+            self.decode, self.upload, self.dispatch, self.readback, self.merge
+        )?;
+        match self.gpu {
+            Some(gpu) => write!(f, ", gpu {gpu:?}"),
+            None => write!(f, ", gpu n/a"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchProfile {
+    pub rows: usize,
+    pub times: StageTimes,
+}
+
+// What one pipeline did while it ran
+#[derive(Clone, Debug, Default)]
+pub struct PipelineProfile {
+    // Kernel compilation before the first batch
+    pub setup: StageTimes,
+    pub batches: Vec<BatchProfile>,
+    // Breaker work after the last batch: sorts, joins, windows, aggregate results
+    pub finish: StageTimes,
+    pub rows_out: usize,
+    pub elapsed: Duration,
+}
+
+impl PipelineProfile {
+    pub fn rows_in(&self) -> usize {
+        self.batches.iter().map(|b| b.rows).sum()
+    }
+
+    // Every stage summed over setup, batches and finish
+    pub fn total(&self) -> StageTimes {
+        let mut total = self.setup;
+        for batch in &self.batches {
+            total += batch.times;
+        }
+        total += self.finish;
+        total
+    }
+}
+
+// Splits the stage times of a running pipeline at every input batch. Time
+// between two batches not spent in another stage counts as merge.
+pub struct Profiler {
+    start: Instant,
+    segment: Instant,
+    times: StageTimes,
+    // Rows of the batch being processed, None before the first and after the last
+    rows: Option<usize>,
+    done: bool,
+    profile: PipelineProfile,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            segment: now,
+            times: StageTimes::default(),
+            rows: None,
+            done: false,
+            profile: PipelineProfile::default(),
+        }
+    }
+}
+
+impl Profiler {
+    pub fn times(&mut self) -> &mut StageTimes {
+        &mut self.times
+    }
+
+    // A batch of `rows` was read, starting at `read`. None once the input ended.
+    pub fn batch(&mut self, read: Instant, rows: Option<usize>) {
+        self.close(read);
+        // Up to the start of the next segment, so merge does not count it again
+        self.times.decode = self.segment.saturating_duration_since(read);
+        self.rows = rows;
+        self.done = rows.is_none();
+    }
+
+    pub fn finish(mut self, rows_out: usize) -> PipelineProfile {
+        self.close(Instant::now());
+        self.profile.rows_out = rows_out;
+        self.profile.elapsed = self.start.elapsed();
+        self.profile
+    }
+
+    fn close(&mut self, end: Instant) {
+        let mut times = std::mem::take(&mut self.times);
+        let busy = times.upload + times.dispatch + times.readback;
+        times.merge += end
+            .saturating_duration_since(self.segment)
+            .saturating_sub(busy);
+        self.segment = Instant::now();
+
+        match self.rows.take() {
+            Some(rows) => self.profile.batches.push(BatchProfile { rows, times }),
+            None if self.done => self.profile.finish += times,
+            None => self.profile.setup += times,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiler_splits_batches() {
+        let mut profiler = Profiler::default();
+        profiler.times().upload = Duration::from_millis(1);
+
+        profiler.batch(Instant::now(), Some(10));
+        profiler.times().readback = Duration::from_millis(2);
+        profiler.times().gpu = Some(Duration::from_micros(5));
+        profiler.batch(Instant::now(), Some(5));
+        profiler.batch(Instant::now(), None);
+        profiler.times().dispatch = Duration::from_millis(3);
+        let profile = profiler.finish(3);
+
+        assert_eq!(profile.setup.upload, Duration::from_millis(1));
+        assert_eq!(profile.rows_in(), 15);
+        assert_eq!(
+            profile.batches.iter().map(|b| b.rows).collect::<Vec<_>>(),
+            vec![10, 5]
+        );
+        assert_eq!(profile.batches[0].times.readback, Duration::from_millis(2));
+        assert_eq!(profile.batches[1].times.gpu, None);
+        assert_eq!(profile.finish.dispatch, Duration::from_millis(3));
+        assert_eq!(profile.rows_out, 3);
+
+        let total = profile.total();
+        assert_eq!(total.gpu, Some(Duration::from_micros(5)));
+        assert_eq!(
+            total.upload + total.dispatch + total.readback,
+            Duration::from_millis(6)
+        );
+        assert!(profile.elapsed >= total.decode + total.merge);
+    }
+}
@@ -0,0 +1,170 @@
+use std::{path::PathBuf, time::Instant};
+
+use clap::ValueEnum;
+use rustyline::{DefaultEditor, error::ReadlineError};
+use wsql::engine::QueryEngine;
+
+use crate::{Format, TableOptions};
+
+const HELP: &str = "\
+.tables             List the registered tables
+.schema [TABLE]     Show the columns of one or every table
+.table NAME=PATH    Register a file, directory, glob or URL as a table
+.timer on|off       Print the time every query takes
+.mode FORMAT        Print results as table, csv, json or arrow
+.explain QUERY      Print the plan, pipelines and kernels of a query
+.analyze QUERY      Run a query and print the time each pipeline spent per stage
+.help               Show this help
+.quit               Exit";
+
+// Interactive SQL shell. The engine, and with it the GPU device and every
+// compiled pipeline, lives across queries.
+pub struct Repl {
+    engine: QueryEngine,
+    // How .table reads files
+    tables: TableOptions,
+    timer: bool,
+    format: Format,
+}
+
+impl Repl {
+    pub fn new(engine: QueryEngine, tables: TableOptions) -> Self {
+        Self {
+            engine,
+            tables,
+            timer: false,
+            format: Format::Table,
+        }
+    }
+
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let mut editor = DefaultEditor::new()?;
+        let history =
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".wsql_history"));
+        if let Some(path) = &history {
+            // Missing on the first run
+            let _ = editor.load_history(path);
+        }
+
+        let mut statement = String::new();
+        loop {
+            let prompt = if statement.is_empty() {
+                "wsql> "
+            } else {
+                "  ...> "
+            };
+            let line = match editor.readline(prompt) {
+                Ok(line) => line,
+                // Ctrl-C drops the statement being typed
+                Err(ReadlineError::Interrupted) => {
+                    statement.clear();
+                    continue;
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(e.into()),
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if statement.is_empty() && line.starts_with('.') {
+                editor.add_history_entry(line)?;
+                match self.command(line).await {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => eprintln!("Error: {e}"),
+                }
+                continue;
+            }
+
+            if !statement.is_empty() {
+                statement.push('\n');
+            }
+            statement.push_str(line);
+            // Statements span lines until a semicolon
+            if let Some(query) = statement.strip_suffix(';') {
+                let query = query.to_string();
+                editor.add_history_entry(&statement)?;
+                statement.clear();
+                if let Err(e) = self.query(&query).await {
+                    eprintln!("Error: {e}");
+                }
+            }
+        }
+
+        if let Some(path) = &history {
+            editor.save_history(path)?;
+        }
+        Ok(())
+    }
+
+    // Runs a dot command, false once the shell should exit
+    async fn command(&mut self, line: &str) -> anyhow::Result<bool> {
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+        match command {
+            ".quit" | ".exit" => return Ok(false),
+            ".help" => println!("{HELP}"),
+            ".tables" => {
+                for name in self.engine.table_names() {
+                    println!("{name}");
+                }
+            }
+            ".schema" => {
+                let names = if arg.is_empty() {
+                    self.engine.table_names()
+                } else {
+                    vec![arg]
+                };
+                for name in names {
+                    let table = self
+                        .engine
+                        .table(name)
+                        .ok_or_else(|| anyhow::anyhow!("Table {} does not exist", name))?;
+                    println!("{name}");
+                    for field in table.schema().fields() {
+                        let null = if field.is_nullable() { "" } else { " NOT NULL" };
+                        println!("  {} {}{}", field.name(), field.data_type(), null);
+                    }
+                }
+            }
+            ".table" => {
+                let (name, path) = crate::parse_table(arg).map_err(anyhow::Error::msg)?;
+                let table = crate::open_table(&name, &path, &self.tables).await?;
+                self.engine.register_table(name, table);
+            }
+            ".timer" => {
+                self.timer = match arg {
+                    "on" => true,
+                    "off" => false,
+                    _ => anyhow::bail!("Usage: .timer on|off"),
+                };
+            }
+            ".mode" => self.format = Format::from_str(arg, true).map_err(anyhow::Error::msg)?,
+            ".explain" => {
+                print!("{}", self.engine.explain_sql(arg.trim_end_matches(';'))?);
+            }
+            ".analyze" => {
+                let analysis = self.engine.analyze_sql(arg.trim_end_matches(';')).await?;
+                print!("{analysis}");
+            }
+            _ => anyhow::bail!("Unknown command {}, see .help", command),
+        }
+        Ok(true)
+    }
+
+    async fn query(&self, query: &str) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let result = self.engine.sql(query).await?;
+        let duration = start.elapsed();
+
+        crate::write_result(&result, self.format)?;
+        if self.timer {
+            println!("Query time: {:?}", duration);
+        }
+        Ok(())
+    }
+}
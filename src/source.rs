@@ -0,0 +1,421 @@
+use std::{
+    io::{BufReader, Read},
+    path::PathBuf,
+    ptr::NonNull,
+    sync::Arc,
+};
+
+use arrow::{
+    array::{RecordBatchIterator, RecordBatchReader},
+    buffer::Buffer,
+    csv,
+    datatypes::{DataType, Field, Schema, SchemaRef},
+    ipc, json,
+    record_batch::RecordBatch,
+};
+use parquet::arrow::{ProjectionMask, arrow_reader::ParquetRecordBatchReaderBuilder};
+
+use crate::jit;
+
+// A table the engine can scan by name, as many times as a query needs
+pub trait TableSource: Send + Sync {
+    fn schema(&self) -> SchemaRef;
+
+    fn scan(&self) -> anyhow::Result<Box<dyn RecordBatchReader + Send>>;
+
+    // A scan for a pipeline that filters its rows with `filter`, an
+    // expression over the columns of `schema`, and reads only the columns at
+    // `projection`, ascending. The batches hold exactly those columns. Sources
+    // may skip data the filter rules out but need not apply it, the pipeline
+    // still does.
+    fn scan_with(
+        &self,
+        filter: Option<&jit::Expression>,
+        projection: Option<&[usize]>,
+    ) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        let _ = filter;
+        project(self.scan()?, projection)
+    }
+}
+
+// Keeps the columns at `projection` of every batch, without copying them
+pub fn project(
+    reader: Box<dyn RecordBatchReader + Send>,
+    projection: Option<&[usize]>,
+) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+    let Some(projection) = projection else {
+        return Ok(reader);
+    };
+    let schema = Arc::new(reader.schema().project(projection)?);
+    let projection = projection.to_vec();
+    Ok(Box::new(RecordBatchIterator::new(
+        reader.map(move |batch| batch?.project(&projection)),
+        schema,
+    )))
+}
+
+// Batches held in memory
+pub struct MemoryTable {
+    schema: SchemaRef,
+    batches: Vec<RecordBatch>,
+}
+
+impl MemoryTable {
+    pub fn new(schema: SchemaRef, batches: Vec<RecordBatch>) -> Self {
+        Self { schema, batches }
+    }
+
+    // Drains a reader, e.g. a file that has to be read more than once
+    pub fn collect(reader: impl RecordBatchReader) -> anyhow::Result<Self> {
+        let schema = reader.schema();
+        let batches = reader.collect::<Result<_, _>>()?;
+        Ok(Self::new(schema, batches))
+    }
+}
+
+impl TableSource for MemoryTable {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn scan(&self) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        Ok(Box::new(RecordBatchIterator::new(
+            self.batches.clone().into_iter().map(Ok),
+            self.schema.clone(),
+        )))
+    }
+}
+
+// A parquet file on local disk, reopened for every scan
+pub struct ParquetTable {
+    path: PathBuf,
+    schema: SchemaRef,
+    batch_size: usize,
+}
+
+impl ParquetTable {
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let builder = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path)?)?;
+        Ok(Self {
+            schema: builder.schema().clone(),
+            path,
+            batch_size: 65536,
+        })
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+}
+
+impl TableSource for ParquetTable {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn scan(&self) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        self.scan_with(None, None)
+    }
+
+    // Only the column chunks of the projected columns are read and decoded
+    fn scan_with(
+        &self,
+        _filter: Option<&jit::Expression>,
+        projection: Option<&[usize]>,
+    ) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&self.path)?)?;
+        let mask = projection.map(|columns| {
+            ProjectionMask::roots(builder.parquet_schema(), columns.iter().copied())
+        });
+        let reader = builder
+            .with_projection(mask.unwrap_or(ProjectionMask::all()))
+            .with_batch_size(self.batch_size)
+            .build()?;
+        Ok(Box::new(reader))
+    }
+}
+
+// Records read to infer the schema of a CSV or NDJSON file without one
+const INFER_RECORDS: usize = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct CsvOptions {
+    pub header: bool,
+    pub delimiter: u8,
+    // Column names and types, inferred from the file when None
+    pub schema: Option<SchemaRef>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            header: true,
+            delimiter: b',',
+            schema: None,
+        }
+    }
+}
+
+// A CSV file on local disk, reopened and parsed again for every scan
+pub struct CsvTable {
+    path: PathBuf,
+    schema: SchemaRef,
+    format: csv::reader::Format,
+    batch_size: usize,
+}
+
+impl CsvTable {
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        Self::open_with(path, CsvOptions::default())
+    }
+
+    pub fn open_with(path: impl Into<PathBuf>, options: CsvOptions) -> anyhow::Result<Self> {
+        let path = path.into();
+        let format = csv::reader::Format::default()
+            .with_header(options.header)
+            .with_delimiter(options.delimiter);
+        let schema = match options.schema {
+            Some(schema) => schema,
+            None => {
+                let file = std::fs::File::open(&path)?;
+                let (schema, _) = format.infer_schema(file, Some(INFER_RECORDS))?;
+                Arc::new(gpu_schema(&schema))
+            }
+        };
+        Ok(Self {
+            path,
+            schema,
+            format,
+            batch_size: 65536,
+        })
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+}
+
+impl TableSource for CsvTable {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn scan(&self) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        self.scan_with(None, None)
+    }
+
+    // Skipped fields are not parsed
+    fn scan_with(
+        &self,
+        _filter: Option<&jit::Expression>,
+        projection: Option<&[usize]>,
+    ) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        let mut builder = csv::ReaderBuilder::new(self.schema.clone())
+            .with_format(self.format.clone())
+            .with_batch_size(self.batch_size);
+        let Some(projection) = projection else {
+            return Ok(Box::new(builder.build(std::fs::File::open(&self.path)?)?));
+        };
+        builder = builder.with_projection(projection.to_vec());
+        let reader = builder.build(std::fs::File::open(&self.path)?)?;
+        // The reader's RecordBatchReader schema is the unprojected one
+        let schema = Arc::new(self.schema.project(projection)?);
+        Ok(Box::new(RecordBatchIterator::new(reader, schema)))
+    }
+}
+
+// A newline-delimited JSON file on local disk, one object per line. Fields
+// missing from a line are null, fields outside the schema are ignored.
+// Nested objects are flattened into columns named by their path, e.g.
+// `request.status`.
+pub struct NdjsonTable {
+    path: PathBuf,
+    // Schema of the objects as read, with nested structs
+    read_schema: SchemaRef,
+    schema: SchemaRef,
+    batch_size: usize,
+}
+
+impl NdjsonTable {
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let file = BufReader::new(std::fs::File::open(&path)?);
+        let (schema, _) = json::reader::infer_json_schema(file, Some(INFER_RECORDS))?;
+        Self::open_with_schema(path, Arc::new(gpu_schema(&schema)))
+    }
+
+    // `schema` describes the objects, with nested ones as structs
+    pub fn open_with_schema(path: impl Into<PathBuf>, schema: SchemaRef) -> anyhow::Result<Self> {
+        Ok(Self {
+            path: path.into(),
+            schema: Arc::new(schema.normalize(".", None)?),
+            read_schema: schema,
+            batch_size: 65536,
+        })
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+}
+
+impl TableSource for NdjsonTable {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn scan(&self) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        let reader = json::ReaderBuilder::new(self.read_schema.clone())
+            .with_batch_size(self.batch_size)
+            .build(BufReader::new(std::fs::File::open(&self.path)?))?;
+        Ok(Box::new(RecordBatchIterator::new(
+            reader.map(|batch| batch?.normalize(".", None)),
+            self.schema.clone(),
+        )))
+    }
+}
+
+// Inference yields 64 bit numbers, the kernels work on 32 bit ones. Integers
+// that do not fit fail to parse instead of wrapping, floats lose precision
+// like Decimal128 does.
+fn gpu_schema(schema: &Schema) -> Schema {
+    let fields: Vec<Field> = schema.fields().iter().map(|f| gpu_field(f)).collect();
+    Schema::new(fields)
+}
+
+fn gpu_field(field: &Field) -> Field {
+    let data_type = match field.data_type() {
+        DataType::Int64 => DataType::Int32,
+        DataType::Float64 => DataType::Float32,
+        DataType::Struct(fields) => DataType::Struct(fields.iter().map(|f| gpu_field(f)).collect()),
+        data_type => data_type.clone(),
+    };
+    field.clone().with_data_type(data_type)
+}
+
+// First bytes of an IPC file, streams start with a message instead
+const IPC_FILE_MAGIC: &[u8; 6] = b"ARROW1";
+
+// An Arrow IPC file (Feather v2) or stream on local disk. Files are memory
+// mapped and their batches point into the mapping, so scans skip decoding
+// and kernels upload columns straight from the mapped pages. Streams have no
+// footer to seek with and are read front to back on every scan.
+pub struct IpcTable {
+    path: PathBuf,
+    schema: SchemaRef,
+    // Batches of a mapped file, None for streams
+    batches: Option<Vec<RecordBatch>>,
+}
+
+impl IpcTable {
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let mut magic = [0; 6];
+        let is_file =
+            std::fs::File::open(&path)?.read_exact(&mut magic).is_ok() && magic == *IPC_FILE_MAGIC;
+        if !is_file {
+            let reader = ipc::reader::StreamReader::try_new(std::fs::File::open(&path)?, None)?;
+            return Ok(Self {
+                schema: reader.schema(),
+                path,
+                batches: None,
+            });
+        }
+
+        let file = std::fs::File::open(&path)?;
+        // Safety: the mapping is read only. Truncating the file while it is
+        // mapped is undefined behaviour, as for every mapped reader.
+        let mmap = Arc::new(unsafe { memmap2::Mmap::map(&file)? });
+        let buffer = match NonNull::new(mmap.as_ptr() as *mut u8) {
+            // Safety: the buffer keeps the mapping alive
+            Some(ptr) => unsafe { Buffer::from_custom_allocation(ptr, mmap.len(), mmap.clone()) },
+            None => anyhow::bail!("Cannot map empty file {}", path.display()),
+        };
+        let (schema, batches) = read_ipc_file(&buffer)?;
+        Ok(Self {
+            path,
+            schema,
+            batches: Some(batches),
+        })
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.batches.is_some()
+    }
+}
+
+impl TableSource for IpcTable {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn scan(&self) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        self.scan_with(None, None)
+    }
+
+    fn scan_with(
+        &self,
+        _filter: Option<&jit::Expression>,
+        projection: Option<&[usize]>,
+    ) -> anyhow::Result<Box<dyn RecordBatchReader + Send>> {
+        match &self.batches {
+            Some(batches) => project(
+                Box::new(RecordBatchIterator::new(
+                    batches.clone().into_iter().map(Ok),
+                    self.schema.clone(),
+                )),
+                projection,
+            ),
+            // Skipped columns of a stream are not decoded
+            None => {
+                let file = BufReader::new(std::fs::File::open(&self.path)?);
+                let projection = projection.map(<[usize]>::to_vec);
+                Ok(Box::new(ipc::reader::StreamReader::try_new(
+                    file, projection,
+                )?))
+            }
+        }
+    }
+}
+
+// Decodes the batches of an IPC file in `buffer` without copying their data
+fn read_ipc_file(buffer: &Buffer) -> anyhow::Result<(SchemaRef, Vec<RecordBatch>)> {
+    anyhow::ensure!(buffer.len() >= 10, "IPC file is too short");
+    let trailer_start = buffer.len() - 10;
+    let footer_len = ipc::reader::read_footer_length(buffer[trailer_start..].try_into()?)?;
+    anyhow::ensure!(footer_len <= trailer_start, "Invalid IPC footer length");
+    let footer = ipc::root_as_footer(&buffer[trailer_start - footer_len..trailer_start])
+        .map_err(|e| anyhow::anyhow!("Invalid IPC footer: {e}"))?;
+    let schema = Arc::new(ipc::convert::fb_to_schema(
+        footer
+            .schema()
+            .ok_or_else(|| anyhow::anyhow!("IPC file has no schema"))?,
+    ));
+
+    let mut decoder = ipc::reader::FileDecoder::new(schema.clone(), footer.version());
+    let block_data = |block: &ipc::Block| {
+        let offset = block.offset() as usize;
+        let len = block.bodyLength() as usize + block.metaDataLength() as usize;
+        anyhow::ensure!(
+            offset
+                .checked_add(len)
+                .is_some_and(|end| end <= buffer.len()),
+            "IPC block out of bounds"
+        );
+        Ok(buffer.slice_with_length(offset, len))
+    };
+    for block in footer.dictionaries().iter().flatten() {
+        decoder.read_dictionary(block, &block_data(block)?)?;
+    }
+    let mut batches = Vec::new();
+    for block in footer.recordBatches().iter().flatten() {
+        if let Some(batch) = decoder.read_record_batch(block, &block_data(block)?)? {
+            batches.push(batch);
+        }
+    }
+    Ok((schema, batches))
+}
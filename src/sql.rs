@@ -0,0 +1,1138 @@
+use arrow::datatypes::{DataType, SchemaRef, TimeUnit};
+use sqlparser::{ast, dialect::PostgreSqlDialect, parser::Parser};
+use substrait::proto::{
+    self, Expression, FunctionArgument, Rel, RelCommon,
+    expression::{
+        FieldReference, Literal, ReferenceSegment, RexType, field_reference, literal::LiteralType,
+        reference_segment, subquery, window_function,
+    },
+    extensions::{SimpleExtensionDeclaration, simple_extension_declaration},
+    function_argument::ArgType,
+    rel::RelType,
+    rel_common::{Emit, EmitKind},
+};
+
+// Plans a SELECT statement as the Substrait relations `sub::lower_plan`
+// understands. Table names are bound through `schema_of`, columns against
+// the table schemas. Comparisons the JIT lacks (>=, <=, <>) are rewritten in
+// terms of gt/lt/equal.
+pub fn plan(
+    sql: &str,
+    schema_of: impl Fn(&str) -> Option<SchemaRef>,
+) -> anyhow::Result<proto::Plan> {
+    let statements = Parser::parse_sql(&PostgreSqlDialect {}, sql)?;
+    let [ast::Statement::Query(query)] = &statements[..] else {
+        anyhow::bail!("Expected a single SELECT statement");
+    };
+
+    let mut planner = Planner {
+        schema_of: &schema_of,
+        functions: Vec::new(),
+    };
+    let (input, names) = planner.query(query, None)?;
+
+    let extensions = planner
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(i, name)| SimpleExtensionDeclaration {
+            mapping_type: Some(
+                simple_extension_declaration::MappingType::ExtensionFunction(
+                    simple_extension_declaration::ExtensionFunction {
+                        function_anchor: i as u32 + 1,
+                        name: name.clone(),
+                        ..Default::default()
+                    },
+                ),
+            ),
+        })
+        .collect();
+    Ok(proto::Plan {
+        extensions,
+        relations: vec![proto::PlanRel {
+            rel_type: Some(proto::plan_rel::RelType::Root(proto::RelRoot {
+                input: Some(input),
+                names,
+            })),
+        }],
+        ..Default::default()
+    })
+}
+
+struct Planner<'a> {
+    schema_of: &'a dyn Fn(&str) -> Option<SchemaRef>,
+    // Function names by anchor - 1
+    functions: Vec<String>,
+}
+
+// Columns an expression can refer to. `outer` is the enclosing query of a
+// subquery, its columns become outer references.
+struct Scope<'a> {
+    columns: Vec<Column>,
+    // After GROUP BY only the keys and aggregates are visible, as output columns
+    grouped: Option<Grouped>,
+    outer: Option<&'a Scope<'a>>,
+}
+
+struct Column {
+    table: Option<String>,
+    name: String,
+}
+
+struct Grouped {
+    keys: Vec<ast::Expr>,
+    aggregates: Vec<ast::Expr>,
+}
+
+impl Planner<'_> {
+    // Relation of a query and the names of its output columns
+    fn query(
+        &mut self,
+        query: &ast::Query,
+        outer: Option<&Scope>,
+    ) -> anyhow::Result<(Rel, Vec<String>)> {
+        if query.with.is_some() {
+            anyhow::bail!("WITH is not supported");
+        }
+        let select = match query.body.as_ref() {
+            ast::SetExpr::Select(select) => select,
+            ast::SetExpr::Query(query) => return self.query(query, outer),
+            _ => anyhow::bail!("Only SELECT queries are supported"),
+        };
+
+        let (mut rel, columns) = self.from(&select.from, outer)?;
+        let rows = Scope {
+            columns,
+            grouped: None,
+            outer,
+        };
+        if let Some(condition) = &select.selection {
+            let condition = self.expr(condition, &rows)?;
+            rel = filter(rel, condition);
+        }
+
+        let items = select_items(&select.projection, &rows)?;
+        let order_by: Vec<&ast::OrderByExpr> =
+            query.order_by.iter().flat_map(|o| o.exprs.iter()).collect();
+
+        // Aggregation, afterwards expressions refer to keys and aggregates by position
+        let group_by = match &select.group_by {
+            ast::GroupByExpr::Expressions(exprs, modifiers) if modifiers.is_empty() => exprs,
+            _ => anyhow::bail!("Unsupported GROUP BY clause"),
+        };
+        let mut aggregates = Vec::new();
+        for e in items
+            .iter()
+            .map(|(e, _)| e)
+            .chain(select.having.iter())
+            .chain(order_by.iter().map(|o| &o.expr))
+        {
+            collect_aggregates(e, &mut aggregates);
+        }
+        let mut width = rows.columns.len();
+        let scope = if group_by.is_empty() && aggregates.is_empty() {
+            rows
+        } else {
+            let (keys, groupings) = grouping_sets(group_by, &items, &rows)?;
+            let mut aggregate = proto::AggregateRel {
+                input: Some(Box::new(rel)),
+                ..Default::default()
+            };
+            for key in &keys {
+                aggregate.grouping_expressions.push(self.expr(key, &rows)?);
+            }
+            for set in &groupings {
+                aggregate.groupings.push(proto::aggregate_rel::Grouping {
+                    expression_references: set.iter().map(|k| *k as u32).collect(),
+                    ..Default::default()
+                });
+            }
+            for e in &aggregates {
+                aggregate.measures.push(self.measure(e, &rows)?);
+            }
+            rel = wrap(RelType::Aggregate(Box::new(aggregate)));
+            width = keys.len() + aggregates.len() + usize::from(groupings.len() > 1);
+            Scope {
+                columns: Vec::new(),
+                grouped: Some(Grouped { keys, aggregates }),
+                outer,
+            }
+        };
+
+        if let Some(having) = &select.having {
+            if scope.grouped.is_none() {
+                anyhow::bail!("HAVING needs GROUP BY or an aggregate");
+            }
+            let condition = self.expr(having, &scope)?;
+            rel = filter(rel, condition);
+        }
+
+        let mut expressions = Vec::new();
+        for (e, _) in &items {
+            expressions.push(self.expr(e, &scope)?);
+        }
+        let names: Vec<String> = items.iter().map(|(_, name)| name.clone()).collect();
+        let distinct = match &select.distinct {
+            None => false,
+            Some(ast::Distinct::Distinct) => true,
+            Some(ast::Distinct::On(_)) => anyhow::bail!("DISTINCT ON is not supported"),
+        };
+
+        // ORDER BY output columns, other expressions are computed as hidden columns
+        let mut sorts = Vec::new();
+        for o in &order_by {
+            let column = match &o.expr {
+                ast::Expr::Value(ast::Value::Number(n, _)) => {
+                    let n: usize = n.parse()?;
+                    if n == 0 || n > items.len() {
+                        anyhow::bail!("ORDER BY position {} is not in the select list", n);
+                    }
+                    n - 1
+                }
+                ast::Expr::Identifier(ident) if names.iter().any(|name| matches(ident, name)) => {
+                    names.iter().position(|name| matches(ident, name)).unwrap()
+                }
+                e => match items.iter().position(|(item, _)| item == e) {
+                    Some(pos) => pos,
+                    None if distinct => anyhow::bail!(
+                        "ORDER BY expressions must appear in the select list of SELECT DISTINCT"
+                    ),
+                    None => {
+                        expressions.push(self.expr(e, &scope)?);
+                        expressions.len() - 1
+                    }
+                },
+            };
+            sorts.push(sort_field(field(column, 0), o.asc, o.nulls_first));
+        }
+
+        let hidden = expressions.len() - items.len();
+        let output = (width..width + expressions.len()).collect();
+        rel = project(rel, expressions, output);
+        if distinct {
+            rel = wrap(RelType::Aggregate(Box::new(proto::AggregateRel {
+                input: Some(Box::new(rel)),
+                grouping_expressions: (0..items.len()).map(|i| field(i, 0)).collect(),
+                groupings: vec![proto::aggregate_rel::Grouping {
+                    expression_references: (0..items.len() as u32).collect(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })));
+        }
+        if !sorts.is_empty() {
+            rel = wrap(RelType::Sort(Box::new(proto::SortRel {
+                input: Some(Box::new(rel)),
+                sorts,
+                ..Default::default()
+            })));
+        }
+        if query.limit.is_some() || query.offset.is_some() {
+            let integer = |e: &ast::Expr| -> anyhow::Result<i64> {
+                match e {
+                    ast::Expr::Value(ast::Value::Number(n, _)) => Ok(n.parse()?),
+                    _ => anyhow::bail!("LIMIT and OFFSET must be integer literals"),
+                }
+            };
+            let literal_i64 = |v| Box::new(literal(LiteralType::I64(v)));
+            let mut fetch = proto::FetchRel {
+                input: Some(Box::new(rel)),
+                ..Default::default()
+            };
+            if let Some(offset) = &query.offset {
+                fetch.offset_mode = Some(proto::fetch_rel::OffsetMode::OffsetExpr(literal_i64(
+                    integer(&offset.value)?,
+                )));
+            }
+            if let Some(limit) = &query.limit {
+                fetch.count_mode = Some(proto::fetch_rel::CountMode::CountExpr(literal_i64(
+                    integer(limit)?,
+                )));
+            }
+            rel = wrap(RelType::Fetch(Box::new(fetch)));
+        }
+        if hidden > 0 {
+            rel = project(rel, Vec::new(), (0..items.len()).collect());
+        }
+        Ok((rel, names))
+    }
+
+    fn from(
+        &mut self,
+        from: &[ast::TableWithJoins],
+        outer: Option<&Scope>,
+    ) -> anyhow::Result<(Rel, Vec<Column>)> {
+        let [table] = from else {
+            anyhow::bail!("Queries must read exactly one table");
+        };
+        if !table.joins.is_empty() {
+            anyhow::bail!("Joins are not supported, use IN or EXISTS subqueries");
+        }
+
+        match &table.relation {
+            ast::TableFactor::Table { name, alias, .. } => {
+                let parts: Vec<String> = name.0.iter().map(|i| i.value.clone()).collect();
+                let table_name = parts.join(".");
+                let schema = (self.schema_of)(&table_name)
+                    .ok_or_else(|| anyhow::anyhow!("Table {} does not exist", table_name))?;
+                let qualifier = match alias {
+                    Some(alias) => alias.name.value.clone(),
+                    None => parts.last().cloned().unwrap_or_default(),
+                };
+
+                let mut types = Vec::new();
+                for f in schema.fields() {
+                    types.push(substrait_type(f.data_type())?);
+                }
+                let read = proto::ReadRel {
+                    base_schema: Some(proto::NamedStruct {
+                        names: schema.fields().iter().map(|f| f.name().clone()).collect(),
+                        r#struct: Some(proto::r#type::Struct {
+                            types,
+                            ..Default::default()
+                        }),
+                    }),
+                    read_type: Some(proto::read_rel::ReadType::NamedTable(
+                        proto::read_rel::NamedTable {
+                            names: parts,
+                            ..Default::default()
+                        },
+                    )),
+                    ..Default::default()
+                };
+                let columns = schema
+                    .fields()
+                    .iter()
+                    .map(|f| Column {
+                        table: Some(qualifier.clone()),
+                        name: f.name().clone(),
+                    })
+                    .collect();
+                Ok((wrap(RelType::Read(Box::new(read))), columns))
+            }
+            ast::TableFactor::Derived {
+                subquery, alias, ..
+            } => {
+                let (rel, names) = self.query(subquery, outer)?;
+                let table = match alias {
+                    Some(alias) if !alias.columns.is_empty() => {
+                        anyhow::bail!("Column aliases on derived tables are not supported")
+                    }
+                    Some(alias) => Some(alias.name.value.clone()),
+                    None => None,
+                };
+                let columns = names
+                    .into_iter()
+                    .map(|name| Column {
+                        table: table.clone(),
+                        name,
+                    })
+                    .collect();
+                Ok((rel, columns))
+            }
+            _ => anyhow::bail!("Unsupported FROM clause"),
+        }
+    }
+
+    fn function(&mut self, name: &str) -> u32 {
+        match self.functions.iter().position(|f| f == name) {
+            Some(pos) => pos as u32 + 1,
+            None => {
+                self.functions.push(name.to_string());
+                self.functions.len() as u32
+            }
+        }
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Expression>) -> Expression {
+        Expression {
+            rex_type: Some(RexType::ScalarFunction(proto::expression::ScalarFunction {
+                function_reference: self.function(name),
+                arguments: args.into_iter().map(value).collect(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn expr(&mut self, e: &ast::Expr, scope: &Scope) -> anyhow::Result<Expression> {
+        if let Some(grouped) = &scope.grouped {
+            if let Some(pos) = grouped.keys.iter().position(|k| k == e) {
+                return Ok(field(pos, 0));
+            }
+            if let Some(pos) = grouped.aggregates.iter().position(|a| a == e) {
+                return Ok(field(grouped.keys.len() + pos, 0));
+            }
+        }
+
+        match e {
+            ast::Expr::Identifier(ident) => column(std::slice::from_ref(ident), scope),
+            ast::Expr::CompoundIdentifier(idents) => column(idents, scope),
+            ast::Expr::Nested(e) => self.expr(e, scope),
+            ast::Expr::Value(v) => value_literal(v, false),
+            ast::Expr::UnaryOp {
+                op: ast::UnaryOperator::Minus,
+                expr,
+            } if matches!(**expr, ast::Expr::Value(_)) => {
+                let ast::Expr::Value(v) = expr.as_ref() else {
+                    unreachable!()
+                };
+                value_literal(v, true)
+            }
+            ast::Expr::UnaryOp {
+                op: ast::UnaryOperator::Minus,
+                expr,
+            } => {
+                let expr = self.expr(expr, scope)?;
+                Ok(self.call("sub", vec![literal(LiteralType::I32(0)), expr]))
+            }
+            ast::Expr::UnaryOp {
+                op: ast::UnaryOperator::Not,
+                expr,
+            } => {
+                let expr = self.expr(expr, scope)?;
+                Ok(self.call("not", vec![expr]))
+            }
+            ast::Expr::TypedString { .. } => Ok(literal(LiteralType::Date(date_literal(e)?))),
+            ast::Expr::BinaryOp { left, op, right } => self.binary(left, op, right, scope),
+            ast::Expr::Between {
+                expr,
+                negated,
+                low,
+                high,
+            } => {
+                let (lower, upper) = if *negated {
+                    (ast::BinaryOperator::Lt, ast::BinaryOperator::Gt)
+                } else {
+                    (ast::BinaryOperator::GtEq, ast::BinaryOperator::LtEq)
+                };
+                let low = self.binary(expr, &lower, low, scope)?;
+                let high = self.binary(expr, &upper, high, scope)?;
+                Ok(self.call(if *negated { "or" } else { "and" }, vec![low, high]))
+            }
+            ast::Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                let op = if *negated {
+                    ast::BinaryOperator::NotEq
+                } else {
+                    ast::BinaryOperator::Eq
+                };
+                let mut terms = Vec::new();
+                for item in list {
+                    terms.push(self.binary(expr, &op, item, scope)?);
+                }
+                let join = if *negated { "and" } else { "or" };
+                terms
+                    .into_iter()
+                    .reduce(|a, b| self.call(join, vec![a, b]))
+                    .ok_or_else(|| anyhow::anyhow!("IN list is empty"))
+            }
+            ast::Expr::InSubquery {
+                expr,
+                subquery,
+                negated,
+            } => {
+                let needle = self.expr(expr, scope)?;
+                let (haystack, _) = self.query(subquery, Some(scope))?;
+                let predicate = subquery_expression(subquery::SubqueryType::InPredicate(Box::new(
+                    subquery::InPredicate {
+                        needles: vec![needle],
+                        haystack: Some(Box::new(haystack)),
+                    },
+                )));
+                Ok(self.negate(predicate, *negated))
+            }
+            ast::Expr::Exists { subquery, negated } => {
+                let (tuples, _) = self.query(subquery, Some(scope))?;
+                let predicate = subquery_expression(subquery::SubqueryType::SetPredicate(
+                    Box::new(subquery::SetPredicate {
+                        predicate_op: subquery::set_predicate::PredicateOp::Exists as i32,
+                        tuples: Some(Box::new(tuples)),
+                    }),
+                ));
+                Ok(self.negate(predicate, *negated))
+            }
+            ast::Expr::Subquery(query) => {
+                let (input, _) = self.query(query, Some(scope))?;
+                Ok(subquery_expression(subquery::SubqueryType::Scalar(
+                    Box::new(subquery::Scalar {
+                        input: Some(Box::new(input)),
+                    }),
+                )))
+            }
+            ast::Expr::Function(f) => match &f.over {
+                Some(ast::WindowType::WindowSpec(spec)) => self.window(f, spec, scope),
+                Some(ast::WindowType::NamedWindow(_)) => {
+                    anyhow::bail!("Named windows are not supported")
+                }
+                None if is_aggregate(f) => {
+                    anyhow::bail!("Aggregate {} is not allowed here", e)
+                }
+                None => anyhow::bail!("Unsupported function: {}", f.name),
+            },
+            _ => anyhow::bail!("Unsupported expression: {}", e),
+        }
+    }
+
+    fn negate(&mut self, predicate: Expression, negated: bool) -> Expression {
+        if negated {
+            self.call("not", vec![predicate])
+        } else {
+            predicate
+        }
+    }
+
+    fn binary(
+        &mut self,
+        left: &ast::Expr,
+        op: &ast::BinaryOperator,
+        right: &ast::Expr,
+        scope: &Scope,
+    ) -> anyhow::Result<Expression> {
+        use ast::BinaryOperator as Op;
+
+        // Date arithmetic with a constant interval folds into a date literal
+        if let (ast::Expr::Interval(interval), Op::Plus | Op::Minus) = (right, op)
+            && let Ok(date) = date_literal(left)
+        {
+            let sign = if *op == Op::Minus { -1 } else { 1 };
+            return Ok(literal(LiteralType::Date(add_interval(
+                date, interval, sign,
+            )?)));
+        }
+
+        let l = self.expr(left, scope)?;
+        let r = self.expr(right, scope)?;
+        let name = match op {
+            Op::Plus => "add",
+            Op::Minus => "sub",
+            Op::Multiply => "mul",
+            Op::Divide => "div",
+            Op::Gt => "gt",
+            Op::Lt => "lt",
+            Op::Eq => "equal",
+            Op::And => "and",
+            Op::Or => "or",
+            Op::GtEq | Op::LtEq | Op::NotEq => {
+                let (first, second) = match op {
+                    Op::GtEq => ("gt", "equal"),
+                    Op::LtEq => ("lt", "equal"),
+                    _ => ("lt", "gt"),
+                };
+                let first = self.call(first, vec![l.clone(), r.clone()]);
+                let second = self.call(second, vec![l, r]);
+                return Ok(self.call("or", vec![first, second]));
+            }
+            _ => anyhow::bail!("Unsupported operator: {}", op),
+        };
+        Ok(self.call(name, vec![l, r]))
+    }
+
+    fn arguments(
+        &mut self,
+        f: &ast::Function,
+        scope: &Scope,
+    ) -> anyhow::Result<(Vec<FunctionArgument>, bool)> {
+        let list = match &f.args {
+            ast::FunctionArguments::List(list) => list,
+            ast::FunctionArguments::None => return Ok((Vec::new(), false)),
+            ast::FunctionArguments::Subquery(_) => {
+                anyhow::bail!("Subquery arguments are not supported")
+            }
+        };
+        let mut arguments = Vec::new();
+        for arg in &list.args {
+            match arg {
+                ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(e)) => {
+                    arguments.push(value(self.expr(e, scope)?));
+                }
+                // COUNT(*) has no arguments
+                ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Wildcard) => {}
+                _ => anyhow::bail!("Unsupported argument in {}", f.name),
+            }
+        }
+        let distinct = matches!(
+            list.duplicate_treatment,
+            Some(ast::DuplicateTreatment::Distinct)
+        );
+        Ok((arguments, distinct))
+    }
+
+    // Aggregate call bound over the input rows
+    fn measure(
+        &mut self,
+        e: &ast::Expr,
+        rows: &Scope,
+    ) -> anyhow::Result<proto::aggregate_rel::Measure> {
+        use proto::aggregate_function::AggregationInvocation;
+
+        let ast::Expr::Function(f) = e else {
+            anyhow::bail!("Expected an aggregate function, got {}", e);
+        };
+        let (arguments, distinct) = self.arguments(f, rows)?;
+        let filter = f
+            .filter
+            .as_ref()
+            .map(|filter| self.expr(filter, rows))
+            .transpose()?;
+        let invocation = if distinct {
+            AggregationInvocation::Distinct
+        } else {
+            AggregationInvocation::All
+        };
+        Ok(proto::aggregate_rel::Measure {
+            measure: Some(proto::AggregateFunction {
+                function_reference: self.function(&function_name(f)),
+                arguments,
+                phase: proto::AggregationPhase::InitialToResult as i32,
+                invocation: invocation as i32,
+                ..Default::default()
+            }),
+            filter,
+        })
+    }
+
+    fn window(
+        &mut self,
+        f: &ast::Function,
+        spec: &ast::WindowSpec,
+        scope: &Scope,
+    ) -> anyhow::Result<Expression> {
+        use window_function::{Bound, BoundsType, bound};
+
+        let (arguments, distinct) = self.arguments(f, scope)?;
+        if distinct {
+            anyhow::bail!("DISTINCT window functions are not supported");
+        }
+        let mut partitions = Vec::new();
+        for p in &spec.partition_by {
+            partitions.push(self.expr(p, scope)?);
+        }
+        let mut sorts = Vec::new();
+        for o in &spec.order_by {
+            sorts.push(sort_field(self.expr(&o.expr, scope)?, o.asc, o.nulls_first));
+        }
+
+        let mut window = proto::expression::WindowFunction {
+            function_reference: self.function(&function_name(f)),
+            arguments,
+            partitions,
+            sorts,
+            ..Default::default()
+        };
+        if let Some(frame) = &spec.window_frame {
+            window.bounds_type = match frame.units {
+                ast::WindowFrameUnits::Rows => BoundsType::Rows,
+                ast::WindowFrameUnits::Range => BoundsType::Range,
+                ast::WindowFrameUnits::Groups => anyhow::bail!("GROUPS frames are not supported"),
+            } as i32;
+            let offset = |e: &Option<Box<ast::Expr>>| -> anyhow::Result<Option<i64>> {
+                match e.as_deref() {
+                    None => Ok(None),
+                    Some(ast::Expr::Value(ast::Value::Number(n, _))) => Ok(Some(n.parse()?)),
+                    Some(e) => anyhow::bail!("Unsupported frame offset: {}", e),
+                }
+            };
+            let to_bound = |b: &ast::WindowFrameBound| -> anyhow::Result<Bound> {
+                let kind = match b {
+                    ast::WindowFrameBound::CurrentRow => {
+                        bound::Kind::CurrentRow(bound::CurrentRow {})
+                    }
+                    ast::WindowFrameBound::Preceding(e) => match offset(e)? {
+                        Some(offset) => bound::Kind::Preceding(bound::Preceding { offset }),
+                        None => bound::Kind::Unbounded(bound::Unbounded {}),
+                    },
+                    ast::WindowFrameBound::Following(e) => match offset(e)? {
+                        Some(offset) => bound::Kind::Following(bound::Following { offset }),
+                        None => bound::Kind::Unbounded(bound::Unbounded {}),
+                    },
+                };
+                Ok(Bound { kind: Some(kind) })
+            };
+            window.lower_bound = Some(to_bound(&frame.start_bound)?);
+            // A frame without BETWEEN ends at the current row
+            window.upper_bound = Some(match &frame.end_bound {
+                Some(end) => to_bound(end)?,
+                None => to_bound(&ast::WindowFrameBound::CurrentRow)?,
+            });
+        }
+        Ok(Expression {
+            rex_type: Some(RexType::WindowFunction(window)),
+        })
+    }
+}
+
+// Select list as (expression, output name), wildcards expanded to columns
+fn select_items(
+    projection: &[ast::SelectItem],
+    rows: &Scope,
+) -> anyhow::Result<Vec<(ast::Expr, String)>> {
+    let mut items = Vec::new();
+    let columns = |table: Option<&ast::ObjectName>| {
+        rows.columns
+            .iter()
+            .filter(move |c| match table.and_then(|t| t.0.last()) {
+                Some(t) => c.table.as_ref().is_some_and(|name| matches(t, name)),
+                None => true,
+            })
+            .map(|c| {
+                let ident = ast::Ident::with_quote('"', c.name.clone());
+                let expr = match &c.table {
+                    Some(t) => ast::Expr::CompoundIdentifier(vec![
+                        ast::Ident::with_quote('"', t.clone()),
+                        ident,
+                    ]),
+                    None => ast::Expr::Identifier(ident),
+                };
+                (expr, c.name.clone())
+            })
+            .collect::<Vec<_>>()
+    };
+    for item in projection {
+        match item {
+            ast::SelectItem::UnnamedExpr(e) => {
+                let name = match e {
+                    ast::Expr::Identifier(ident) => ident.value.clone(),
+                    ast::Expr::CompoundIdentifier(idents) => {
+                        idents.last().map(|i| i.value.clone()).unwrap_or_default()
+                    }
+                    _ => e.to_string(),
+                };
+                items.push((e.clone(), name));
+            }
+            ast::SelectItem::ExprWithAlias { expr, alias } => {
+                items.push((expr.clone(), alias.value.clone()));
+            }
+            ast::SelectItem::Wildcard(_) => items.extend(columns(None)),
+            ast::SelectItem::QualifiedWildcard(table, _) => items.extend(columns(Some(table))),
+        }
+    }
+    Ok(items)
+}
+
+// GROUP BY keys (ordinals and select aliases resolved) and the grouping sets
+// over them. ROLLUP, CUBE and GROUPING SETS multiply with the plain keys.
+fn grouping_sets(
+    group_by: &[ast::Expr],
+    items: &[(ast::Expr, String)],
+    rows: &Scope,
+) -> anyhow::Result<(Vec<ast::Expr>, Vec<Vec<usize>>)> {
+    let mut keys: Vec<ast::Expr> = Vec::new();
+    let mut key = |e: &ast::Expr| -> anyhow::Result<usize> {
+        let e = match e {
+            ast::Expr::Value(ast::Value::Number(n, _)) => {
+                let n: usize = n.parse()?;
+                items
+                    .get(n.wrapping_sub(1))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("GROUP BY position {} is not in the select list", n)
+                    })?
+                    .0
+                    .clone()
+            }
+            // A select alias, unless it names an input column
+            ast::Expr::Identifier(ident)
+                if !rows.columns.iter().any(|c| matches(ident, &c.name)) =>
+            {
+                match items.iter().find(|(_, name)| matches(ident, name)) {
+                    Some((expr, _)) => expr.clone(),
+                    None => e.clone(),
+                }
+            }
+            _ => e.clone(),
+        };
+        Ok(match keys.iter().position(|k| *k == e) {
+            Some(pos) => pos,
+            None => {
+                keys.push(e);
+                keys.len() - 1
+            }
+        })
+    };
+    let mut list = |exprs: &[ast::Expr]| -> anyhow::Result<Vec<usize>> {
+        exprs.iter().map(&mut key).collect()
+    };
+
+    let mut sets: Vec<Vec<usize>> = vec![Vec::new()];
+    for e in group_by {
+        let options: Vec<Vec<usize>> = match e {
+            ast::Expr::Rollup(elements) => {
+                let elements: Vec<_> = elements
+                    .iter()
+                    .map(|e| list(e))
+                    .collect::<anyhow::Result<_>>()?;
+                (0..=elements.len())
+                    .rev()
+                    .map(|n| elements[..n].concat())
+                    .collect()
+            }
+            ast::Expr::Cube(elements) => {
+                let elements: Vec<_> = elements
+                    .iter()
+                    .map(|e| list(e))
+                    .collect::<anyhow::Result<_>>()?;
+                (0..1usize << elements.len())
+                    .rev()
+                    .map(|mask| {
+                        (0..elements.len())
+                            .filter(|i| mask & (1 << (elements.len() - 1 - i)) != 0)
+                            .flat_map(|i| elements[i].clone())
+                            .collect()
+                    })
+                    .collect()
+            }
+            ast::Expr::GroupingSets(sets) => sets
+                .iter()
+                .map(|s| list(s))
+                .collect::<anyhow::Result<_>>()?,
+            e => vec![list(std::slice::from_ref(e))?],
+        };
+        sets = sets
+            .iter()
+            .flat_map(|set| {
+                options.iter().map(move |option| {
+                    let mut set = set.clone();
+                    set.extend(
+                        option
+                            .iter()
+                            .filter(|k| !set.contains(k))
+                            .collect::<Vec<_>>(),
+                    );
+                    set
+                })
+            })
+            .collect();
+    }
+    Ok((keys, sets))
+}
+
+fn is_aggregate(f: &ast::Function) -> bool {
+    f.over.is_none()
+        && matches!(
+            function_name(f).as_str(),
+            "sum" | "count" | "avg" | "min" | "max"
+        )
+}
+
+fn function_name(f: &ast::Function) -> String {
+    f.name.to_string().to_lowercase()
+}
+
+// Aggregate calls in `e`, subqueries have their own
+fn collect_aggregates(e: &ast::Expr, found: &mut Vec<ast::Expr>) {
+    match e {
+        ast::Expr::Function(f) if is_aggregate(f) && !found.contains(e) => {
+            found.push(e.clone());
+        }
+        ast::Expr::Function(f) if is_aggregate(f) => {}
+        ast::Expr::Function(f) => {
+            if let ast::FunctionArguments::List(list) = &f.args {
+                for arg in &list.args {
+                    if let ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(e)) = arg {
+                        collect_aggregates(e, found);
+                    }
+                }
+            }
+            if let Some(ast::WindowType::WindowSpec(spec)) = &f.over {
+                for e in spec
+                    .partition_by
+                    .iter()
+                    .chain(spec.order_by.iter().map(|o| &o.expr))
+                {
+                    collect_aggregates(e, found);
+                }
+            }
+        }
+        ast::Expr::BinaryOp { left, right, .. } => {
+            collect_aggregates(left, found);
+            collect_aggregates(right, found);
+        }
+        ast::Expr::UnaryOp { expr, .. }
+        | ast::Expr::Nested(expr)
+        | ast::Expr::InSubquery { expr, .. } => collect_aggregates(expr, found),
+        ast::Expr::Between {
+            expr, low, high, ..
+        } => {
+            for e in [expr, low, high] {
+                collect_aggregates(e, found);
+            }
+        }
+        ast::Expr::InList { expr, list, .. } => {
+            collect_aggregates(expr, found);
+            for e in list {
+                collect_aggregates(e, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn matches(ident: &ast::Ident, name: &str) -> bool {
+    match ident.quote_style {
+        Some(_) => ident.value == name,
+        None => ident.value.eq_ignore_ascii_case(name),
+    }
+}
+
+// Resolves a (possibly qualified) column, walking out through enclosing queries
+fn column(idents: &[ast::Ident], scope: &Scope) -> anyhow::Result<Expression> {
+    let display = idents
+        .iter()
+        .map(|i| i.value.as_str())
+        .collect::<Vec<_>>()
+        .join(".");
+    let (qualifier, name) = match idents {
+        [name] => (None, name),
+        [.., qualifier, name] => (Some(qualifier), name),
+        [] => anyhow::bail!("Empty column reference"),
+    };
+
+    let mut current = Some(scope);
+    let mut steps_out = 0;
+    while let Some(s) = current {
+        // Grouped rows only expose keys and aggregates, matched before getting here
+        if s.grouped.is_none() {
+            let found: Vec<usize> = s
+                .columns
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| {
+                    matches(name, &c.name)
+                        && qualifier.is_none_or(|q| c.table.as_ref().is_some_and(|t| matches(q, t)))
+                })
+                .map(|(i, _)| i)
+                .collect();
+            match found[..] {
+                [i] => return Ok(field(i, steps_out)),
+                [] => {}
+                _ => anyhow::bail!("Column reference {} is ambiguous", display),
+            }
+        }
+        current = s.outer;
+        steps_out += 1;
+    }
+    if scope.grouped.is_some() {
+        anyhow::bail!(
+            "Column {} must appear in GROUP BY or be used in an aggregate function",
+            display
+        );
+    }
+    anyhow::bail!("Column {} does not exist", display)
+}
+
+fn value_literal(v: &ast::Value, negative: bool) -> anyhow::Result<Expression> {
+    let ast::Value::Number(n, _) = v else {
+        anyhow::bail!("Unsupported literal: {}", v);
+    };
+    let text = if negative { format!("-{n}") } else { n.clone() };
+    let value = if let Ok(v) = text.parse::<i32>() {
+        LiteralType::I32(v)
+    } else if let Ok(v) = text.parse::<i64>() {
+        LiteralType::I64(v)
+    } else {
+        LiteralType::Fp32(text.parse()?)
+    };
+    Ok(literal(value))
+}
+
+// Days since the epoch of a DATE 'YYYY-MM-DD' literal
+fn date_literal(e: &ast::Expr) -> anyhow::Result<i32> {
+    let ast::Expr::TypedString {
+        data_type: ast::DataType::Date,
+        value,
+    } = e
+    else {
+        anyhow::bail!("Unsupported literal: {}", e);
+    };
+    let parsed = arrow::compute::cast(
+        &arrow::array::StringArray::from(vec![value.as_str()]),
+        &DataType::Date32,
+    )?;
+    let days = parsed.as_any().downcast_ref::<arrow::array::Date32Array>();
+    match days {
+        Some(days) if arrow::array::Array::is_valid(days, 0) => Ok(days.value(0)),
+        _ => anyhow::bail!("Invalid date: {}", value),
+    }
+}
+
+fn add_interval(date: i32, interval: &ast::Interval, sign: i32) -> anyhow::Result<i32> {
+    let amount: i32 = match interval.value.as_ref() {
+        ast::Expr::Value(ast::Value::SingleQuotedString(s) | ast::Value::Number(s, _)) => {
+            s.trim().parse()?
+        }
+        e => anyhow::bail!("Unsupported interval: {}", e),
+    };
+    let amount = amount * sign;
+    Ok(match &interval.leading_field {
+        Some(ast::DateTimeField::Year) => {
+            arrow::datatypes::Date32Type::add_year_months(date, amount * 12)
+        }
+        Some(ast::DateTimeField::Month) => {
+            arrow::datatypes::Date32Type::add_year_months(date, amount)
+        }
+        Some(ast::DateTimeField::Day) => date + amount,
+        _ => anyhow::bail!("Only YEAR, MONTH and DAY intervals are supported"),
+    })
+}
+
+fn substrait_type(data_type: &DataType) -> anyhow::Result<proto::Type> {
+    use proto::r#type::{self, Kind};
+
+    let nullability = r#type::Nullability::Nullable as i32;
+    let kind = match data_type {
+        DataType::Boolean => Kind::Bool(r#type::Boolean {
+            nullability,
+            ..Default::default()
+        }),
+        DataType::Int8 => Kind::I8(r#type::I8 {
+            nullability,
+            ..Default::default()
+        }),
+        DataType::Int16 | DataType::UInt8 => Kind::I16(r#type::I16 {
+            nullability,
+            ..Default::default()
+        }),
+        DataType::Int32 | DataType::UInt16 => Kind::I32(r#type::I32 {
+            nullability,
+            ..Default::default()
+        }),
+        DataType::Int64 | DataType::UInt32 => Kind::I64(r#type::I64 {
+            nullability,
+            ..Default::default()
+        }),
+        DataType::Float32 => Kind::Fp32(r#type::Fp32 {
+            nullability,
+            ..Default::default()
+        }),
+        DataType::Float64 => Kind::Fp64(r#type::Fp64 {
+            nullability,
+            ..Default::default()
+        }),
+        DataType::Date32 => Kind::Date(r#type::Date {
+            nullability,
+            ..Default::default()
+        }),
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => Kind::String(r#type::String {
+            nullability,
+            ..Default::default()
+        }),
+        DataType::Binary | DataType::LargeBinary | DataType::BinaryView => {
+            Kind::Binary(r#type::Binary {
+                nullability,
+                ..Default::default()
+            })
+        }
+        DataType::Decimal128(precision, scale) => Kind::Decimal(r#type::Decimal {
+            precision: *precision as i32,
+            scale: *scale as i32,
+            nullability,
+            ..Default::default()
+        }),
+        DataType::Timestamp(unit, _) => Kind::PrecisionTimestamp(r#type::PrecisionTimestamp {
+            precision: match unit {
+                TimeUnit::Second => 0,
+                TimeUnit::Millisecond => 3,
+                TimeUnit::Microsecond => 6,
+                TimeUnit::Nanosecond => 9,
+            },
+            nullability,
+            ..Default::default()
+        }),
+        _ => anyhow::bail!("Column type {} has no Substrait equivalent", data_type),
+    };
+    Ok(proto::Type { kind: Some(kind) })
+}
+
+fn wrap(rel_type: RelType) -> Rel {
+    Rel {
+        rel_type: Some(rel_type),
+    }
+}
+
+fn filter(input: Rel, condition: Expression) -> Rel {
+    wrap(RelType::Filter(Box::new(proto::FilterRel {
+        input: Some(Box::new(input)),
+        condition: Some(Box::new(condition)),
+        ..Default::default()
+    })))
+}
+
+// Project emitting only `output` of its input fields followed by `expressions`
+fn project(input: Rel, expressions: Vec<Expression>, output: Vec<usize>) -> Rel {
+    wrap(RelType::Project(Box::new(proto::ProjectRel {
+        common: Some(RelCommon {
+            emit_kind: Some(EmitKind::Emit(Emit {
+                output_mapping: output.into_iter().map(|i| i as i32).collect(),
+            })),
+            ..Default::default()
+        }),
+        input: Some(Box::new(input)),
+        expressions,
+        ..Default::default()
+    })))
+}
+
+fn sort_field(expr: Expression, asc: Option<bool>, nulls_first: Option<bool>) -> proto::SortField {
+    use proto::sort_field::{SortDirection, SortKind};
+
+    // Nulls sort as the largest value unless told otherwise
+    let asc = asc.unwrap_or(true);
+    let direction = match (asc, nulls_first.unwrap_or(!asc)) {
+        (true, true) => SortDirection::AscNullsFirst,
+        (true, false) => SortDirection::AscNullsLast,
+        (false, true) => SortDirection::DescNullsFirst,
+        (false, false) => SortDirection::DescNullsLast,
+    };
+    proto::SortField {
+        expr: Some(expr),
+        sort_kind: Some(SortKind::Direction(direction as i32)),
+    }
+}
+
+fn field(index: usize, steps_out: u32) -> Expression {
+    let root_type = if steps_out == 0 {
+        field_reference::RootType::RootReference(field_reference::RootReference {})
+    } else {
+        field_reference::RootType::OuterReference(field_reference::OuterReference { steps_out })
+    };
+    Expression {
+        rex_type: Some(RexType::Selection(Box::new(FieldReference {
+            reference_type: Some(field_reference::ReferenceType::DirectReference(
+                ReferenceSegment {
+                    reference_type: Some(reference_segment::ReferenceType::StructField(Box::new(
+                        reference_segment::StructField {
+                            field: index as i32,
+                            child: None,
+                        },
+                    ))),
+                },
+            )),
+            root_type: Some(root_type),
+        }))),
+    }
+}
+
+fn literal(value: LiteralType) -> Expression {
+    Expression {
+        rex_type: Some(RexType::Literal(Literal {
+            literal_type: Some(value),
+            ..Default::default()
+        })),
+    }
+}
+
+fn value(e: Expression) -> FunctionArgument {
+    FunctionArgument {
+        arg_type: Some(ArgType::Value(e)),
+    }
+}
+
+fn subquery_expression(subquery_type: subquery::SubqueryType) -> Expression {
+    Expression {
+        rex_type: Some(RexType::Subquery(Box::new(proto::expression::Subquery {
+            subquery_type: Some(subquery_type),
+        }))),
+    }
+}
@@ -0,0 +1,209 @@
+use std::{collections::HashMap, ops::Range, sync::Arc};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use opendal::{Operator, services};
+use parquet::{
+    arrow::{arrow_reader::ArrowReaderOptions, async_reader::AsyncFileReader},
+    errors::ParquetError,
+    file::metadata::{ParquetMetaData, ParquetMetaDataReader},
+};
+
+// Bytes read from the end of a parquet file for its footer, enough for the
+// metadata of most files in one request
+const FOOTER_PREFETCH: usize = 64 * 1024;
+
+// Resolves table locations into OpenDAL operators:
+// - `file:///data/sales` or a plain path, local files
+// - `s3://bucket/prefix`, S3 and compatible stores like MinIO, with the `s3`
+//   feature. Credentials come from `options` or the usual AWS environment
+//   variables and profiles.
+// - `memory://prefix`, an in-process store shared by clones of the Storage
+#[derive(Clone)]
+pub struct Storage {
+    // OpenDAL config of object store operators, e.g. endpoint, region,
+    // access_key_id, secret_access_key
+    pub options: HashMap<String, String>,
+    memory: Operator,
+}
+
+// A path inside the store of an operator
+pub struct Location {
+    pub operator: Operator,
+    pub path: String,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            options: HashMap::new(),
+            memory: Operator::new(services::Memory::default())
+                .expect("Memory operator")
+                .finish(),
+        }
+    }
+}
+
+impl Storage {
+    pub fn with_option(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.options.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn resolve(&self, location: &str) -> anyhow::Result<Location> {
+        let (scheme, path) = location.split_once("://").unwrap_or(("file", location));
+        match scheme {
+            "file" => {
+                let path = std::env::current_dir()?.join(path);
+                let path = path
+                    .to_str()
+                    .ok_or_else(|| anyhow::anyhow!("Path {} is not UTF-8", path.display()))?;
+                Ok(Location {
+                    operator: Operator::new(services::Fs::default().root("/"))?.finish(),
+                    path: path.trim_start_matches('/').to_string(),
+                })
+            }
+            "s3" => {
+                let (bucket, path) = path.split_once('/').unwrap_or((path, ""));
+                anyhow::ensure!(!bucket.is_empty(), "Location {} has no bucket", location);
+                Ok(Location {
+                    operator: self.s3(bucket)?,
+                    path: path.to_string(),
+                })
+            }
+            "memory" => Ok(Location {
+                operator: self.memory.clone(),
+                path: path.to_string(),
+            }),
+            _ => anyhow::bail!("Unsupported storage scheme {}", scheme),
+        }
+    }
+
+    // The in-process store of memory:// locations
+    pub fn memory(&self) -> &Operator {
+        &self.memory
+    }
+
+    #[cfg(feature = "s3")]
+    fn s3(&self, bucket: &str) -> anyhow::Result<Operator> {
+        let mut options = self.options.clone();
+        options.insert("bucket".to_string(), bucket.to_string());
+        Ok(Operator::via_iter(services::S3_SCHEME, options)?)
+    }
+
+    #[cfg(not(feature = "s3"))]
+    fn s3(&self, _bucket: &str) -> anyhow::Result<Operator> {
+        anyhow::bail!("s3:// locations need wsql built with the s3 feature")
+    }
+}
+
+// Parquet reads through an OpenDAL operator. Only the ranges a stream asks
+// for are fetched, the footer and then the column chunks of each row group,
+// so a file never has to fit in memory.
+pub struct OpendalReader {
+    operator: Operator,
+    path: String,
+    size: u64,
+}
+
+impl OpendalReader {
+    pub async fn open(operator: Operator, path: impl Into<String>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let size = operator.stat(&path).await?.content_length();
+        Ok(Self {
+            operator,
+            path,
+            size,
+        })
+    }
+}
+
+impl AsyncFileReader for OpendalReader {
+    fn get_bytes(&mut self, range: Range<u64>) -> BoxFuture<'_, parquet::errors::Result<Bytes>> {
+        Box::pin(read_range(self.operator.clone(), self.path.clone(), range))
+    }
+
+    // Column chunks of a row group are fetched concurrently
+    fn get_byte_ranges(
+        &mut self,
+        ranges: Vec<Range<u64>>,
+    ) -> BoxFuture<'_, parquet::errors::Result<Vec<Bytes>>> {
+        let reads = ranges
+            .into_iter()
+            .map(|range| read_range(self.operator.clone(), self.path.clone(), range));
+        Box::pin(futures::future::try_join_all(reads))
+    }
+
+    fn get_metadata<'a>(
+        &'a mut self,
+        options: Option<&'a ArrowReaderOptions>,
+    ) -> BoxFuture<'a, parquet::errors::Result<Arc<ParquetMetaData>>> {
+        Box::pin(async move {
+            let size = self.size;
+            let metadata = ParquetMetaDataReader::new()
+                .with_metadata_options(options.map(|o| o.metadata_options().clone()))
+                .with_prefetch_hint(Some(FOOTER_PREFETCH))
+                .load_and_finish(self, size)
+                .await?;
+            Ok(Arc::new(metadata))
+        })
+    }
+}
+
+async fn read_range(
+    operator: Operator,
+    path: String,
+    range: Range<u64>,
+) -> parquet::errors::Result<Bytes> {
+    let buffer = operator
+        .read_with(&path)
+        .range(range)
+        .await
+        .map_err(|e| ParquetError::External(Box::new(e)))?;
+    Ok(buffer.to_bytes())
+}
+
+// Whether a location names a scheme, plain paths are local files
+pub fn is_url(location: &str) -> bool {
+    location.contains("://")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let storage = Storage::default();
+        let local = storage.resolve("/tmp/sales/*.parquet").unwrap();
+        assert_eq!(local.path, "tmp/sales/*.parquet");
+        let local = storage.resolve("file:///tmp/sales").unwrap();
+        assert_eq!(local.path, "tmp/sales");
+        assert!(storage.resolve("ftp://host/file").is_err());
+
+        let memory = storage.resolve("memory://sales/year=2024").unwrap();
+        assert_eq!(memory.path, "sales/year=2024");
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn test_resolve_s3() {
+        // Building the operator does not contact the endpoint
+        let storage = Storage::default()
+            .with_option("endpoint", "http://127.0.0.1:9000")
+            .with_option("region", "us-east-1");
+        let s3 = storage.resolve("s3://warehouse/sales/").unwrap();
+        assert_eq!(s3.operator.info().name(), "warehouse");
+        assert_eq!(s3.path, "sales/");
+    }
+
+    #[cfg(not(feature = "s3"))]
+    #[test]
+    fn test_resolve_s3_needs_feature() {
+        let err = Storage::default()
+            .resolve("s3://warehouse/sales/")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("built with the s3 feature"));
+    }
+}
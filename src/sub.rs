@@ -3,23 +3,650 @@ use std::collections::HashMap;
 use prost::Message;
 use substrait::proto::{
     Plan,
-    expression::{RexType, field_reference, reference_segment as direct_reference},
+    expression::{
+        RexType, field_reference, reference_segment as direct_reference,
+        subquery::{SubqueryType, set_predicate::PredicateOp},
+    },
     extensions::simple_extension_declaration::MappingType,
 };
 
 use crate::jit;
 
-pub struct PhysicalPlan {
+// One fused kernel: a single output expression (or aggregate argument) and
+// an optional filter over the columns of its input batch
+pub struct KernelPlan {
     pub projection: jit::Expression,
     pub filter: Option<jit::Expression>,
     pub is_aggregate: bool,
     pub column_types: std::collections::HashMap<u32, arrow::datatypes::DataType>,
 }
 
+// Physical operator tree lowered from the Substrait relations
+#[derive(Debug)]
+pub enum PhysicalPlan {
+    Read {
+        column_types: HashMap<u32, arrow::datatypes::DataType>,
+        // Named table, None (or a name the engine does not know) reads its default input
+        table: Option<String>,
+    },
+    Filter {
+        input: Box<PhysicalPlan>,
+        condition: jit::Expression,
+    },
+    Project {
+        input: Box<PhysicalPlan>,
+        expressions: Vec<jit::Expression>,
+    },
+    // Pipeline breaker
+    Aggregate {
+        input: Box<PhysicalPlan>,
+        aggregation: Aggregation,
+    },
+    // Pipeline breaker, drops duplicate rows
+    Distinct {
+        input: Box<PhysicalPlan>,
+    },
+    // Pipeline breaker
+    Sort {
+        input: Box<PhysicalPlan>,
+        sorts: Vec<SortField>,
+    },
+    // Pipeline breaker
+    Fetch {
+        input: Box<PhysicalPlan>,
+        offset: usize,
+        count: Option<usize>,
+    },
+    // Pipeline breaker, outputs the input fields followed by one field per function
+    Window {
+        input: Box<PhysicalPlan>,
+        window: WindowSpec,
+    },
+    // Pipeline breaker, keeps the probe rows with (semi) or without (anti) a
+    // matching build row. Outputs the probe fields.
+    Join {
+        probe: Box<PhysicalPlan>,
+        // Its first `keys.len()` columns are the join keys
+        build: Box<PhysicalPlan>,
+        kind: JoinKind,
+        // Probe columns matched against the build columns, in order
+        keys: Vec<u32>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    // EXISTS / IN
+    Semi,
+    // NOT EXISTS
+    Anti,
+    // NOT IN, no row survives a build side with a null key
+    NullAwareAnti,
+}
+
+// Output is the keys, then the measures, then the grouping set index when
+// there is more than one set. Keys outside a row's grouping set are null.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregation {
+    pub keys: Vec<jit::Expression>,
+    // Grouping sets as positions into `keys`, all evaluated in one pass
+    pub groupings: Vec<Vec<usize>>,
+    pub measures: Vec<Measure>,
+}
+
+impl Aggregation {
+    // Keys, measure arguments and measure filters
+    pub fn expressions(&self) -> Vec<&jit::Expression> {
+        let mut expressions: Vec<_> = self.keys.iter().collect();
+        for measure in &self.measures {
+            expressions.push(&measure.argument);
+            expressions.extend(measure.filter.iter());
+        }
+        expressions
+    }
+
+    pub fn is_grouped(&self) -> bool {
+        !self.keys.is_empty() || self.groupings.len() > 1
+    }
+
+    // Ungrouped SUM/COUNT producing results reduce into a single row on the
+    // GPU, everything else goes through the hash aggregation
+    // NULL arguments are left to the CPU, which skips null values
+    pub fn reduces_on_gpu(&self) -> bool {
+        !self.is_grouped()
+            && self.measures.iter().all(|m| {
+                m.function != AggregateFunction::Avg
+                    && m.phase == AggregationPhase::InitialToResult
+                    && !jit::is_null(&m.argument).unwrap_or(true)
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateFunction {
+    Sum,
+    Count,
+    Avg,
+}
+
+// Which part of a split aggregation a measure runs. Intermediate state is
+// the partial SUM as Float64, the partial COUNT as Int64 and a
+// {sum: Float64, count: Int64} struct for AVG.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AggregationPhase {
+    #[default]
+    InitialToResult,
+    InitialToIntermediate,
+    IntermediateToIntermediate,
+    IntermediateToResult,
+}
+
+impl AggregationPhase {
+    // Arguments are intermediate state instead of input rows
+    pub fn merges_state(&self) -> bool {
+        matches!(
+            self,
+            AggregationPhase::IntermediateToIntermediate | AggregationPhase::IntermediateToResult
+        )
+    }
+
+    pub fn emits_state(&self) -> bool {
+        matches!(
+            self,
+            AggregationPhase::InitialToIntermediate | AggregationPhase::IntermediateToIntermediate
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measure {
+    pub function: AggregateFunction,
+    pub argument: jit::Expression,
+    // Aggregate only the distinct values of the argument
+    pub distinct: bool,
+    // FILTER (WHERE ...) of this measure, on top of the pipeline filter
+    pub filter: Option<jit::Expression>,
+    pub phase: AggregationPhase,
+}
+
+impl Measure {
+    // Expression summed by the GPU reduction, COUNT adds one per row
+    pub fn summand(&self) -> jit::Expression {
+        match self.function {
+            AggregateFunction::Sum | AggregateFunction::Avg => self.argument.clone(),
+            AggregateFunction::Count => jit::Expression::Literal(jit::LiteralTypes::F32(1.0)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortField {
+    pub column: u32,
+    pub descending: bool,
+    pub nulls_first: bool,
+}
+
+// Window functions sharing one PARTITION BY / ORDER BY
+#[derive(Debug, Clone)]
+pub struct WindowSpec {
+    pub partition_by: Vec<u32>,
+    pub order_by: Vec<SortField>,
+    pub functions: Vec<WindowFunction>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WindowFunction {
+    pub kind: WindowKind,
+    pub frame: WindowFrame,
+}
+
+#[derive(Debug, Clone)]
+pub enum WindowKind {
+    RowNumber,
+    Rank,
+    DenseRank,
+    Lag { column: u32, offset: usize },
+    Lead { column: u32, offset: usize },
+    Sum(u32),
+    Avg(u32),
+}
+
+// Frame of the aggregate window functions, ranking functions ignore it
+#[derive(Debug, Clone)]
+pub struct WindowFrame {
+    // None is UNBOUNDED PRECEDING
+    pub preceding: Option<usize>,
+    // Frame ends at the end of the partition instead of the current row
+    pub unbounded_following: bool,
+    // RANGE frames include the peers of the current row
+    pub range: bool,
+}
+
+// Streaming operators (filter/project) fused together, ending at a breaker
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    pub source: Source,
+    pub filter: Option<jit::Expression>,
+    // None passes the input columns through untouched
+    pub projection: Option<Vec<jit::Expression>>,
+    pub breaker: Breaker,
+}
+
+#[derive(Debug, Default)]
+pub enum Breaker {
+    // Collect the filtered/projected rows
+    #[default]
+    Materialize,
+    Aggregate(Aggregation),
+    // Collect the distinct filtered/projected rows
+    Distinct,
+    Sort(Vec<SortField>),
+    Fetch {
+        offset: usize,
+        count: Option<usize>,
+    },
+    Window(WindowSpec),
+    // Semi/anti join of the collected rows against the output of pipeline `build`
+    Join {
+        build: usize,
+        kind: JoinKind,
+        keys: Vec<u32>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    // Streams a table, None is the engine's default input
+    Table(Option<String>),
+    // Output of an earlier pipeline
+    Pipeline(usize),
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Source::Table(None)
+    }
+}
+
+impl PhysicalPlan {
+    // Split the tree into pipelines in execution order
+    pub fn pipelines(&self) -> anyhow::Result<Vec<Pipeline>> {
+        let mut done = Vec::new();
+        let open = self.build_pipeline(&mut done)?;
+        // Nothing left to do after the last breaker
+        if open.filter.is_some() || open.projection.is_some() || done.is_empty() {
+            done.push(open);
+        }
+        Ok(done)
+    }
+
+    // Returns the pipeline still open at `self`, finished ones are pushed into `done`
+    fn build_pipeline(&self, done: &mut Vec<Pipeline>) -> anyhow::Result<Pipeline> {
+        match self {
+            PhysicalPlan::Read { table, .. } => Ok(Pipeline {
+                source: Source::Table(table.clone()),
+                ..Pipeline::default()
+            }),
+            PhysicalPlan::Filter { input, condition } => {
+                let mut pipeline = input.build_pipeline(done)?;
+                let condition = pipeline.resolve(condition)?;
+                pipeline.filter = Some(match pipeline.filter.take() {
+                    Some(f) => jit::Expression::And(Box::new(f), Box::new(condition)),
+                    None => condition,
+                });
+                Ok(pipeline)
+            }
+            PhysicalPlan::Project { input, expressions } => {
+                let mut pipeline = input.build_pipeline(done)?;
+                let expressions = expressions
+                    .iter()
+                    .map(|e| pipeline.resolve(e))
+                    .collect::<anyhow::Result<_>>()?;
+                pipeline.projection = Some(expressions);
+                Ok(pipeline)
+            }
+            PhysicalPlan::Aggregate { input, aggregation } => {
+                let mut pipeline = input.build_pipeline(done)?;
+                let keys = aggregation
+                    .keys
+                    .iter()
+                    .map(|e| pipeline.resolve(e))
+                    .collect::<anyhow::Result<_>>()?;
+                let measures = aggregation
+                    .measures
+                    .iter()
+                    .map(|m| {
+                        Ok(Measure {
+                            argument: pipeline.resolve(&m.argument)?,
+                            filter: m.filter.as_ref().map(|f| pipeline.resolve(f)).transpose()?,
+                            ..m.clone()
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                pipeline.breaker = Breaker::Aggregate(Aggregation {
+                    keys,
+                    groupings: aggregation.groupings.clone(),
+                    measures,
+                });
+                Ok(Pipeline::after(pipeline, done))
+            }
+            PhysicalPlan::Distinct { input } => {
+                let mut pipeline = input.build_pipeline(done)?;
+                pipeline.breaker = Breaker::Distinct;
+                Ok(Pipeline::after(pipeline, done))
+            }
+            PhysicalPlan::Sort { input, sorts } => {
+                let mut pipeline = input.build_pipeline(done)?;
+                pipeline.breaker = Breaker::Sort(sorts.clone());
+                Ok(Pipeline::after(pipeline, done))
+            }
+            PhysicalPlan::Fetch {
+                input,
+                offset,
+                count,
+            } => {
+                let mut pipeline = input.build_pipeline(done)?;
+                pipeline.breaker = Breaker::Fetch {
+                    offset: *offset,
+                    count: *count,
+                };
+                Ok(Pipeline::after(pipeline, done))
+            }
+            PhysicalPlan::Window { input, window } => {
+                let mut pipeline = input.build_pipeline(done)?;
+                pipeline.breaker = Breaker::Window(window.clone());
+                Ok(Pipeline::after(pipeline, done))
+            }
+            // The build side runs first, the probe side joins against its output
+            PhysicalPlan::Join {
+                probe,
+                build,
+                kind,
+                keys,
+            } => {
+                let pipeline = build.build_pipeline(done)?;
+                done.push(pipeline);
+                let build = done.len() - 1;
+                let mut pipeline = probe.build_pipeline(done)?;
+                pipeline.breaker = Breaker::Join {
+                    build,
+                    kind: *kind,
+                    keys: keys.clone(),
+                };
+                Ok(Pipeline::after(pipeline, done))
+            }
+        }
+    }
+
+    // Operators from the root down to the scans on one line, e.g.
+    // `Aggregate <- Filter <- Read lineitem`
+    pub fn summary(&self) -> String {
+        let (name, input) = match self {
+            PhysicalPlan::Read { table, .. } => {
+                return format!("Read {}", table.as_deref().unwrap_or("<default input>"));
+            }
+            PhysicalPlan::Join { probe, build, .. } => {
+                return format!("Join({}, {})", probe.summary(), build.summary());
+            }
+            PhysicalPlan::Filter { input, .. } => ("Filter", input),
+            PhysicalPlan::Project { input, .. } => ("Project", input),
+            PhysicalPlan::Aggregate { input, .. } => ("Aggregate", input),
+            PhysicalPlan::Distinct { input } => ("Distinct", input),
+            PhysicalPlan::Sort { input, .. } => ("Sort", input),
+            PhysicalPlan::Fetch { input, .. } => ("Fetch", input),
+            PhysicalPlan::Window { input, .. } => ("Window", input),
+        };
+        format!("{name} <- {}", input.summary())
+    }
+
+    // Tables every scan in the plan reads, scalar subqueries included
+    pub fn tables(&self, tables: &mut Vec<Option<String>>) {
+        let mut subqueries = Vec::new();
+        match self {
+            PhysicalPlan::Read { table, .. } => tables.push(table.clone()),
+            PhysicalPlan::Filter { input, condition } => {
+                jit::collect_subqueries(condition, &mut subqueries);
+                input.tables(tables);
+            }
+            PhysicalPlan::Project { input, expressions } => {
+                for e in expressions {
+                    jit::collect_subqueries(e, &mut subqueries);
+                }
+                input.tables(tables);
+            }
+            PhysicalPlan::Aggregate { input, aggregation } => {
+                for e in aggregation.expressions() {
+                    jit::collect_subqueries(e, &mut subqueries);
+                }
+                input.tables(tables);
+            }
+            PhysicalPlan::Distinct { input }
+            | PhysicalPlan::Sort { input, .. }
+            | PhysicalPlan::Fetch { input, .. }
+            | PhysicalPlan::Window { input, .. } => input.tables(tables),
+            PhysicalPlan::Join { probe, build, .. } => {
+                build.tables(tables);
+                probe.tables(tables);
+            }
+        }
+        for subquery in subqueries {
+            subquery.0.tables(tables);
+        }
+    }
+}
+
+impl Pipeline {
+    // Finish `pipeline` and open the one reading its output
+    fn after(pipeline: Pipeline, done: &mut Vec<Pipeline>) -> Pipeline {
+        done.push(pipeline);
+        Pipeline {
+            source: Source::Pipeline(done.len() - 1),
+            ..Pipeline::default()
+        }
+    }
+
+    // Scalar subqueries the pipeline's expressions still refer to
+    pub fn subqueries(&self) -> Vec<jit::Subquery> {
+        let mut found = Vec::new();
+        let breaker = match &self.breaker {
+            Breaker::Aggregate(aggregation) => aggregation.expressions(),
+            _ => Vec::new(),
+        };
+        for expr in self.filter.iter().chain(self.projection.iter().flatten()) {
+            jit::collect_subqueries(expr, &mut found);
+        }
+        for expr in breaker {
+            jit::collect_subqueries(expr, &mut found);
+        }
+        found
+    }
+
+    // Replace a scalar subquery with its value everywhere in the pipeline. A
+    // NULL value is folded out of the filters, values it makes NULL are
+    // produced without a kernel.
+    pub fn bind(
+        &mut self,
+        subquery: &jit::Subquery,
+        value: &jit::Expression,
+    ) -> anyhow::Result<()> {
+        let predicate = |e: &mut jit::Expression| {
+            *e = jit::null_predicate(&jit::bind_subquery(e, subquery, value))
+        };
+        let bind = |e: &mut jit::Expression| -> anyhow::Result<()> {
+            *e = jit::bind_subquery(e, subquery, value);
+            jit::is_null(e)?;
+            Ok(())
+        };
+        self.filter.iter_mut().for_each(predicate);
+        for expr in self.projection.iter_mut().flatten() {
+            bind(expr)?;
+        }
+        if let Breaker::Aggregate(aggregation) = &mut self.breaker {
+            for key in &mut aggregation.keys {
+                bind(key)?;
+            }
+            for measure in &mut aggregation.measures {
+                bind(&mut measure.argument)?;
+                measure.filter.iter_mut().for_each(predicate);
+            }
+        }
+        Ok(())
+    }
+
+    // Input columns the pipeline reads, ascending, None when it passes every
+    // input column through
+    pub fn input_columns(&self) -> Option<Vec<usize>> {
+        let aggregation = match &self.breaker {
+            Breaker::Aggregate(aggregation) => Some(aggregation),
+            _ if self.projection.is_none() => return None,
+            _ => None,
+        };
+        let mut columns = std::collections::BTreeSet::new();
+        let expressions = self.filter.iter().chain(self.projection.iter().flatten());
+        for expr in expressions.chain(aggregation.into_iter().flat_map(|a| a.expressions())) {
+            jit::collect_columns(expr, &mut columns);
+        }
+        Some(columns.into_iter().map(|c| c as usize).collect())
+    }
+
+    // Rewrite the pipeline to read batches of only the input `columns`, as
+    // given by `input_columns`
+    pub fn project_input(&mut self, columns: &[usize]) -> anyhow::Result<()> {
+        let width = columns.iter().max().map_or(0, |max| max + 1);
+        let mut inputs: Vec<jit::Expression> =
+            (0..width as u32).map(jit::Expression::Column).collect();
+        for (i, &column) in columns.iter().enumerate() {
+            inputs[column] = jit::Expression::Column(i as u32);
+        }
+        let mut remap = |e: &mut jit::Expression| -> anyhow::Result<()> {
+            *e = jit::substitute(e, &inputs)?;
+            Ok(())
+        };
+        self.filter.iter_mut().try_for_each(&mut remap)?;
+        self.projection
+            .iter_mut()
+            .flatten()
+            .try_for_each(&mut remap)?;
+        if let Breaker::Aggregate(aggregation) = &mut self.breaker {
+            aggregation.keys.iter_mut().try_for_each(&mut remap)?;
+            for measure in &mut aggregation.measures {
+                remap(&mut measure.argument)?;
+                measure.filter.iter_mut().try_for_each(&mut remap)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Rewrite an expression over the projection into one over the pipeline input
+    fn resolve(&self, expr: &jit::Expression) -> anyhow::Result<jit::Expression> {
+        match &self.projection {
+            Some(projection) => jit::substitute(expr, projection),
+            None => Ok(expr.clone()),
+        }
+    }
+
+    // Output expressions for an input batch `width` columns wide
+    pub fn outputs(&self, width: usize) -> Vec<jit::Expression> {
+        match &self.projection {
+            Some(projection) => projection.clone(),
+            None => (0..width as u32).map(jit::Expression::Column).collect(),
+        }
+    }
+
+    // Aggregates fuse the filter into the kernel, everything else is
+    // compacted with the selection kernel afterwards
+    pub fn kernel(
+        &self,
+        projection: &jit::Expression,
+        column_types: &HashMap<u32, arrow::datatypes::DataType>,
+    ) -> KernelPlan {
+        let is_aggregate = matches!(self.breaker, Breaker::Aggregate(_));
+        KernelPlan {
+            projection: projection.clone(),
+            filter: if is_aggregate {
+                self.filter.clone()
+            } else {
+                None
+            },
+            is_aggregate,
+            column_types: column_types.clone(),
+        }
+    }
+
+    // Per row values of an expression, unfiltered. DISTINCT measures dedupe
+    // these before aggregating.
+    pub fn value_kernel(
+        &self,
+        projection: &jit::Expression,
+        column_types: &HashMap<u32, arrow::datatypes::DataType>,
+    ) -> KernelPlan {
+        KernelPlan {
+            projection: projection.clone(),
+            filter: None,
+            is_aggregate: false,
+            column_types: column_types.clone(),
+        }
+    }
+
+    // GPU reduction of a measure, the pipeline and measure filters are both fused
+    pub fn measure_kernel(
+        &self,
+        measure: &Measure,
+        column_types: &HashMap<u32, arrow::datatypes::DataType>,
+    ) -> KernelPlan {
+        let filter = match (&self.filter, &measure.filter) {
+            (Some(a), Some(b)) => Some(jit::Expression::And(
+                Box::new(a.clone()),
+                Box::new(b.clone()),
+            )),
+            (a, b) => a.clone().or_else(|| b.clone()),
+        };
+        KernelPlan {
+            projection: measure.summand(),
+            filter,
+            is_aggregate: true,
+            column_types: column_types.clone(),
+        }
+    }
+
+    // Kernel writing 1 for every row passing the filter and 0 otherwise
+    pub fn selection_kernel(
+        &self,
+        column_types: &HashMap<u32, arrow::datatypes::DataType>,
+    ) -> Option<KernelPlan> {
+        self.filter
+            .as_ref()
+            .map(|filter| predicate_kernel(filter, column_types))
+    }
+}
+
+// 0/1 flags of a predicate for every row
+pub fn predicate_kernel(
+    predicate: &jit::Expression,
+    column_types: &HashMap<u32, arrow::datatypes::DataType>,
+) -> KernelPlan {
+    KernelPlan {
+        projection: predicate.clone(),
+        filter: None,
+        is_aggregate: false,
+        column_types: column_types.clone(),
+    }
+}
+
 pub fn decode_plan(bytes: &[u8]) -> anyhow::Result<Plan> {
     Plan::decode(bytes).map_err(|e| anyhow::anyhow!("Failed to decode plan: {e}"))
 }
 
+// JSON or binary protobuf. JSON plans are objects; protobuf bytes that happen
+// to look like one after leading newlines are decoded when JSON fails.
+pub fn parse_plan(bytes: &[u8]) -> anyhow::Result<Plan> {
+    match bytes.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') => serde_json::from_slice(bytes)
+            .or_else(|e| decode_plan(bytes).map_err(|_| anyhow::Error::from(e))),
+        _ => decode_plan(bytes),
+    }
+}
+
 pub fn get_functions_map(plan: &Plan) -> HashMap<u32, String> {
     let mut map = HashMap::new();
     for ext in &plan.extensions {
@@ -67,6 +694,14 @@ pub fn lower_expression(
         }
 
         // Selection
+        RexType::Selection(sel)
+            if matches!(
+                sel.root_type,
+                Some(field_reference::RootType::OuterReference(_))
+            ) =>
+        {
+            anyhow::bail!("Correlated subqueries are only supported in EXISTS filters")
+        }
         RexType::Selection(sel) => match sel.reference_type.as_ref() {
             Some(field_reference::ReferenceType::DirectReference(dr)) => {
                 match dr.reference_type.as_ref() {
@@ -108,14 +743,36 @@ pub fn lower_expression(
                 "add" => Ok(jit::Expression::Add(next_arg()?, next_arg()?)),
                 "sub" => Ok(jit::Expression::Subtract(next_arg()?, next_arg()?)),
                 "mul" => Ok(jit::Expression::Multiply(next_arg()?, next_arg()?)),
+                "div" => Ok(jit::Expression::Divide(next_arg()?, next_arg()?)),
                 "gt" => Ok(jit::Expression::GreaterThan(next_arg()?, next_arg()?)),
                 "lt" => Ok(jit::Expression::LessThan(next_arg()?, next_arg()?)),
+                "equal" => Ok(jit::Expression::Equal(next_arg()?, next_arg()?)),
                 "and" => Ok(jit::Expression::And(next_arg()?, next_arg()?)),
                 "or" => Ok(jit::Expression::Or(next_arg()?, next_arg()?)),
+                "not" => Ok(jit::Expression::Not(next_arg()?)),
                 _ => anyhow::bail!("Unsupported function: {}", func_name),
             }
         }
 
+        // Uncorrelated scalar subqueries run before the query and get bound as a literal,
+        // IN and EXISTS are turned into joins by the Filter lowering
+        RexType::Subquery(subquery) => match &subquery.subquery_type {
+            Some(SubqueryType::Scalar(scalar)) => {
+                let input = scalar
+                    .input
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Scalar subquery has no input"))?;
+                let (plan, width) = lower_rel(input, function_map)?;
+                if width.is_some_and(|w| w != 1) {
+                    anyhow::bail!("Scalar subquery must return a single column");
+                }
+                Ok(jit::Expression::Subquery(jit::Subquery(
+                    std::sync::Arc::new(plan),
+                )))
+            }
+            _ => anyhow::bail!("IN and EXISTS subqueries are only supported as filter conjuncts"),
+        },
+
         _ => anyhow::bail!("Unsupported Substrait type"),
     }
 }
@@ -149,7 +806,15 @@ pub fn get_project_expression(plan: &Plan) -> anyhow::Result<Vec<substrait::prot
     }
 }
 
-// FUsing all expressions into one PhysicalPlan
+// Column names of the final output
+pub fn output_names(plan: &Plan) -> Vec<String> {
+    match plan.relations.first().and_then(|r| r.rel_type.as_ref()) {
+        Some(substrait::proto::plan_rel::RelType::Root(r)) => r.names.clone(),
+        _ => Vec::new(),
+    }
+}
+
+// Lower the relations under the Root into a PhysicalPlan tree
 pub fn lower_plan(plan: &substrait::proto::Plan) -> anyhow::Result<PhysicalPlan> {
     let root = plan
         .relations
@@ -157,94 +822,949 @@ pub fn lower_plan(plan: &substrait::proto::Plan) -> anyhow::Result<PhysicalPlan>
         .and_then(|r| r.rel_type.as_ref())
         .ok_or_else(|| anyhow::anyhow!("Missing root"))?;
 
-    let mut current_rel = match root {
-        substrait::proto::plan_rel::RelType::Root(r) => r.input.as_ref(),
+    let input = match root {
+        substrait::proto::plan_rel::RelType::Root(r) => r
+            .input
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Root has no input relation"))?,
         _ => anyhow::bail!("Expected Root"),
     };
     let fn_map = get_functions_map(plan);
-    let mut filter = None;
-    let mut projection = None;
-    let mut is_aggregate = false;
-    let mut column_types = HashMap::new();
-
-    while let Some(rel) = current_rel {
-        match rel.rel_type.as_ref() {
-            Some(substrait::proto::rel::RelType::Read(read_rel)) => {
-                if let Some(base_schema) = &read_rel.base_schema
-                    && let Some(named_struct) = &base_schema.r#struct
-                {
-                    for (i, field_type) in named_struct.types.iter().enumerate() {
-                        let arrow_type = match field_type.kind.as_ref() {
-                            Some(substrait::proto::r#type::Kind::I32(_)) => {
-                                arrow::datatypes::DataType::Int32
-                            }
-                            Some(substrait::proto::r#type::Kind::Fp32(_)) => {
-                                arrow::datatypes::DataType::Float32
-                            }
-                            Some(substrait::proto::r#type::Kind::Date(_)) => {
-                                arrow::datatypes::DataType::Int32
-                            }
-                            _ => arrow::datatypes::DataType::Int32,
-                        };
-
-                        column_types.insert(i as u32, arrow_type);
+
+    Ok(lower_rel(input, &fn_map)?.0)
+}
+
+// Lowered relation and the number of fields it outputs
+// (None for reads without a base_schema, their width is only known from the data)
+type Lowered = (PhysicalPlan, Option<usize>);
+
+fn lower_input(
+    input: &Option<Box<substrait::proto::Rel>>,
+    fn_map: &HashMap<u32, String>,
+) -> anyhow::Result<(Box<PhysicalPlan>, Option<usize>)> {
+    let input = input
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Relation has no input"))?;
+    let (plan, width) = lower_rel(input, fn_map)?;
+    Ok((Box::new(plan), width))
+}
+
+fn lower_rel(
+    rel: &substrait::proto::Rel,
+    fn_map: &HashMap<u32, String>,
+) -> anyhow::Result<Lowered> {
+    use substrait::proto::rel::RelType;
+
+    let rel_type = rel
+        .rel_type
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Relation has no type"))?;
+    let (plan, width) = lower_rel_type(rel_type, fn_map)?;
+
+    let common = match rel_type {
+        RelType::Read(r) => r.common.as_ref(),
+        RelType::Filter(r) => r.common.as_ref(),
+        RelType::Project(r) => r.common.as_ref(),
+        RelType::Aggregate(r) => r.common.as_ref(),
+        RelType::Sort(r) => r.common.as_ref(),
+        RelType::Fetch(r) => r.common.as_ref(),
+        RelType::Window(r) => r.common.as_ref(),
+        _ => None,
+    };
+
+    // RelCommon.emit picks and reorders the output fields
+    match common.and_then(|c| c.emit_kind.as_ref()) {
+        Some(substrait::proto::rel_common::EmitKind::Emit(emit)) => {
+            let expressions = emit
+                .output_mapping
+                .iter()
+                .map(|&i| {
+                    if i < 0 || width.is_some_and(|w| i as usize >= w) {
+                        anyhow::bail!("Emit field {} is out of range of the relation output", i);
                     }
+                    Ok(jit::Expression::Column(i as u32))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let width = expressions.len();
+            Ok((
+                PhysicalPlan::Project {
+                    input: Box::new(plan),
+                    expressions,
+                },
+                Some(width),
+            ))
+        }
+        _ => Ok((plan, width)),
+    }
+}
+
+fn lower_rel_type(
+    rel_type: &substrait::proto::rel::RelType,
+    fn_map: &HashMap<u32, String>,
+) -> anyhow::Result<Lowered> {
+    match rel_type {
+        substrait::proto::rel::RelType::Read(read_rel) => {
+            let mut column_types = HashMap::new();
+            let mut width = None;
+            if let Some(base_schema) = &read_rel.base_schema
+                && let Some(named_struct) = &base_schema.r#struct
+            {
+                for (i, field_type) in named_struct.types.iter().enumerate() {
+                    let arrow_type = match field_type.kind.as_ref() {
+                        Some(substrait::proto::r#type::Kind::I32(_)) => {
+                            arrow::datatypes::DataType::Int32
+                        }
+                        Some(substrait::proto::r#type::Kind::Fp32(_)) => {
+                            arrow::datatypes::DataType::Float32
+                        }
+                        Some(substrait::proto::r#type::Kind::Date(_)) => {
+                            arrow::datatypes::DataType::Int32
+                        }
+                        _ => arrow::datatypes::DataType::Int32,
+                    };
+
+                    column_types.insert(i as u32, arrow_type);
                 }
+                width = Some(named_struct.types.len());
+            }
+            let table = match &read_rel.read_type {
+                Some(substrait::proto::read_rel::ReadType::NamedTable(t)) => {
+                    Some(t.names.join("."))
+                }
+                _ => None,
+            };
+            let mut plan = PhysicalPlan::Read {
+                column_types,
+                table,
+            };
+
+            // Pushed down filter over the base_schema fields, applied before the projection
+            if let Some(condition) = &read_rel.filter {
+                plan = PhysicalPlan::Filter {
+                    input: Box::new(plan),
+                    condition: lower_expression(condition, fn_map)?,
+                };
+            }
 
-                break;
+            // Read projection selects a subset of the base_schema fields
+            if let Some(select) = read_rel.projection.as_ref().and_then(|p| p.select.as_ref()) {
+                let expressions: Vec<_> = select
+                    .struct_items
+                    .iter()
+                    .map(|item| jit::Expression::Column(item.field as u32))
+                    .collect();
+                width = Some(expressions.len());
+                plan = PhysicalPlan::Project {
+                    input: Box::new(plan),
+                    expressions,
+                };
             }
 
-            Some(substrait::proto::rel::RelType::Filter(filter_rel)) => {
-                filter = Some(lower_expression(
-                    filter_rel.condition.as_ref().unwrap(),
-                    &fn_map,
+            Ok((plan, width))
+        }
+
+        substrait::proto::rel::RelType::Filter(filter_rel) => {
+            let condition = filter_rel
+                .condition
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Filter has no condition"))?;
+            let (input, width) = lower_input(&filter_rel.input, fn_map)?;
+
+            // IN / EXISTS conjuncts become semi or anti joins over the filtered rows
+            let mut conditions = Vec::new();
+            let mut subqueries = Vec::new();
+            for conjunct in conjuncts(condition, fn_map) {
+                match subquery_predicate(conjunct, fn_map) {
+                    Some(predicate) => subqueries.push(predicate),
+                    None => conditions.push(lower_expression(conjunct, fn_map)?),
+                }
+            }
+            let mut plan = input;
+            if let Some(condition) = conditions
+                .into_iter()
+                .reduce(|a, b| jit::Expression::And(Box::new(a), Box::new(b)))
+            {
+                plan = Box::new(PhysicalPlan::Filter {
+                    input: plan,
+                    condition,
+                });
+            }
+            for (negated, subquery) in subqueries {
+                plan = lower_subquery_join(plan, width, negated, subquery, fn_map)?;
+            }
+            Ok((*plan, width))
+        }
+
+        // Output is every input field followed by the expressions
+        substrait::proto::rel::RelType::Project(project_rel) => {
+            let (input, width) = lower_input(&project_rel.input, fn_map)?;
+            let width = width.ok_or_else(|| {
+                anyhow::anyhow!("Cannot resolve Project fields without the input's base_schema")
+            })?;
+
+            let (input, expressions) =
+                lower_projection(input, width, &project_rel.expressions, fn_map)?;
+            let width = expressions.len();
+            Ok((PhysicalPlan::Project { input, expressions }, Some(width)))
+        }
+
+        substrait::proto::rel::RelType::Window(window_rel) => {
+            let (input, width) = lower_input(&window_rel.input, fn_map)?;
+
+            let mut inputs = ComputedInputs {
+                width,
+                extra: Vec::new(),
+            };
+            let mut partition_by = Vec::new();
+            for e in &window_rel.partition_expressions {
+                partition_by.push(inputs.column(lower_expression(e, fn_map)?)?);
+            }
+            let mut order_by = Vec::new();
+            for s in &window_rel.sorts {
+                let (expr, descending, nulls_first) = lower_sort_field(s, fn_map)?;
+                order_by.push(SortField {
+                    column: inputs.column(expr)?,
+                    descending,
+                    nulls_first,
+                });
+            }
+            let mut functions = Vec::new();
+            for f in &window_rel.window_functions {
+                functions.push(lower_window_function(
+                    function_name(f.function_reference, fn_map)?,
+                    &f.arguments,
+                    f.bounds_type,
+                    f.lower_bound.as_ref(),
+                    f.upper_bound.as_ref(),
+                    !order_by.is_empty(),
+                    &mut inputs,
+                    fn_map,
                 )?);
-                current_rel = filter_rel.input.as_ref().map(|b| b.as_ref());
             }
 
-            Some(substrait::proto::rel::RelType::Aggregate(aggregate_rel)) => {
-                is_aggregate = true;
-                let measure = aggregate_rel.measures[0]
+            let extra = inputs.extra.len();
+            let function_count = functions.len();
+            let (input, _) = inputs.project(input);
+            let mut plan = PhysicalPlan::Window {
+                input,
+                window: WindowSpec {
+                    partition_by,
+                    order_by,
+                    functions,
+                },
+            };
+
+            // Output is the input fields followed by the functions, drop the extra inputs
+            if extra > 0 {
+                let width = width.unwrap_or_default();
+                let expressions = (0..width)
+                    .chain(width + extra..width + extra + function_count)
+                    .map(|i| jit::Expression::Column(i as u32))
+                    .collect();
+                plan = PhysicalPlan::Project {
+                    input: Box::new(plan),
+                    expressions,
+                };
+            }
+            Ok((plan, width.map(|w| w + function_count)))
+        }
+
+        substrait::proto::rel::RelType::Aggregate(aggregate_rel) => {
+            use substrait::proto::AggregationPhase as Phase;
+            use substrait::proto::aggregate_function::AggregationInvocation;
+
+            let (input, _) = lower_input(&aggregate_rel.input, fn_map)?;
+
+            let mut keys = Vec::new();
+            for e in &aggregate_rel.grouping_expressions {
+                keys.push(lower_expression(e, fn_map)?);
+            }
+            let mut groupings = Vec::new();
+            for grouping in &aggregate_rel.groupings {
+                let mut set = Vec::new();
+                for r in &grouping.expression_references {
+                    if *r as usize >= aggregate_rel.grouping_expressions.len() {
+                        anyhow::bail!("Grouping expression {} does not exist", r);
+                    }
+                    set.push(*r as usize);
+                }
+                // Older plans repeat the expressions in every grouping set
+                #[allow(deprecated)]
+                for e in &grouping.grouping_expressions {
+                    let e = lower_expression(e, fn_map)?;
+                    let pos = match keys.iter().position(|k| *k == e) {
+                        Some(pos) => pos,
+                        None => {
+                            keys.push(e);
+                            keys.len() - 1
+                        }
+                    };
+                    set.push(pos);
+                }
+                groupings.push(set);
+            }
+            if groupings.is_empty() {
+                groupings.push(Vec::new());
+            }
+
+            // One grouping set over every key without measures is SELECT DISTINCT
+            let covers_keys = groupings.len() == 1 && {
+                let mut set = groupings[0].clone();
+                set.sort_unstable();
+                set.dedup();
+                set.len() == keys.len()
+            };
+            if aggregate_rel.measures.is_empty() && !keys.is_empty() && covers_keys {
+                let width = keys.len();
+                let input = Box::new(PhysicalPlan::Project {
+                    input,
+                    expressions: keys,
+                });
+                return Ok((PhysicalPlan::Distinct { input }, Some(width)));
+            }
+
+            let mut measures = Vec::new();
+            for m in &aggregate_rel.measures {
+                let measure = m
                     .measure
                     .as_ref()
                     .ok_or_else(|| anyhow::anyhow!("Missing measure in Aggregate"))?;
-                let arg = measure.arguments[0]
-                    .arg_type
-                    .as_ref()
-                    .ok_or_else(|| anyhow::anyhow!("Missing argument in Aggregate function"))?;
-                if let substrait::proto::function_argument::ArgType::Value(v) = arg {
-                    projection = Some(lower_expression(v, &fn_map)?);
+                let func_name = function_name(measure.function_reference, fn_map)?;
+                let function = match func_name {
+                    "sum" => AggregateFunction::Sum,
+                    "count" => AggregateFunction::Count,
+                    "avg" => AggregateFunction::Avg,
+                    _ => anyhow::bail!("Unsupported aggregate function: {}", func_name),
+                };
+                let distinct = AggregationInvocation::try_from(measure.invocation)?
+                    == AggregationInvocation::Distinct;
+                // Plans that leave the phase out mean a complete aggregation
+                let phase = match Phase::try_from(measure.phase)? {
+                    Phase::Unspecified | Phase::InitialToResult => {
+                        AggregationPhase::InitialToResult
+                    }
+                    Phase::InitialToIntermediate => AggregationPhase::InitialToIntermediate,
+                    Phase::IntermediateToIntermediate => {
+                        AggregationPhase::IntermediateToIntermediate
+                    }
+                    Phase::IntermediateToResult => AggregationPhase::IntermediateToResult,
+                };
+                if distinct && phase != AggregationPhase::InitialToResult {
+                    anyhow::bail!("DISTINCT aggregates cannot be split into phases");
                 }
-                current_rel = aggregate_rel.input.as_ref().map(|b| b.as_ref());
-            }
-            Some(substrait::proto::rel::RelType::Project(project_rel)) => {
-                match projection {
-                    None => {
-                        projection = Some(lower_expression(&project_rel.expressions[0], &fn_map)?);
+
+                let argument = match measure.arguments.first().and_then(|a| a.arg_type.as_ref()) {
+                    Some(substrait::proto::function_argument::ArgType::Value(v)) => {
+                        lower_expression(v, fn_map)?
                     }
-                    Some(jit::Expression::Column(idx)) => {
-                        let expr = &project_rel.expressions[idx as usize];
-                        projection = Some(lower_expression(expr, &fn_map)?);
+                    // COUNT(*)
+                    None if function == AggregateFunction::Count
+                        && !distinct
+                        && !phase.merges_state() =>
+                    {
+                        jit::Expression::Literal(jit::LiteralTypes::F32(1.0))
                     }
-                    _ => {}
+                    None => anyhow::bail!("Missing argument in Aggregate function"),
+                    _ => anyhow::bail!("Aggregate argument must be a value"),
+                };
+                let filter = m
+                    .filter
+                    .as_ref()
+                    .map(|f| lower_expression(f, fn_map))
+                    .transpose()?;
+                measures.push(Measure {
+                    function,
+                    argument,
+                    distinct,
+                    filter,
+                    phase,
+                });
+            }
+
+            let width = keys.len() + measures.len() + usize::from(groupings.len() > 1);
+            let aggregation = Aggregation {
+                keys,
+                groupings,
+                measures,
+            };
+            Ok((PhysicalPlan::Aggregate { input, aggregation }, Some(width)))
+        }
+
+        substrait::proto::rel::RelType::Sort(sort_rel) => {
+            let mut sorts = Vec::new();
+            for s in &sort_rel.sorts {
+                let (expr, descending, nulls_first) = lower_sort_field(s, fn_map)?;
+                let column = match expr {
+                    jit::Expression::Column(idx) => idx,
+                    _ => anyhow::bail!("Only sorting by a field reference is supported"),
+                };
+                sorts.push(SortField {
+                    column,
+                    descending,
+                    nulls_first,
+                });
+            }
+
+            let (input, width) = lower_input(&sort_rel.input, fn_map)?;
+            Ok((PhysicalPlan::Sort { input, sorts }, width))
+        }
+
+        substrait::proto::rel::RelType::Fetch(fetch_rel) => {
+            use substrait::proto::fetch_rel::{CountMode, OffsetMode};
+
+            #[allow(deprecated)]
+            let offset = match &fetch_rel.offset_mode {
+                Some(OffsetMode::Offset(o)) => *o,
+                Some(OffsetMode::OffsetExpr(e)) => literal_i64(e)?,
+                None => 0,
+            };
+            #[allow(deprecated)]
+            let count = match &fetch_rel.count_mode {
+                Some(CountMode::Count(c)) => *c,
+                Some(CountMode::CountExpr(e)) => literal_i64(e)?,
+                None => -1,
+            };
+
+            let (input, width) = lower_input(&fetch_rel.input, fn_map)?;
+            Ok((
+                PhysicalPlan::Fetch {
+                    input,
+                    offset: usize::try_from(offset)?,
+                    // -1 means ALL
+                    count: usize::try_from(count).ok(),
+                },
+                width,
+            ))
+        }
+
+        _ => anyhow::bail!("Unsupported relation type"),
+    }
+}
+
+fn function_name(reference: u32, fn_map: &HashMap<u32, String>) -> anyhow::Result<&str> {
+    fn_map
+        .get(&reference)
+        .map(|name| name.as_str())
+        .ok_or_else(|| anyhow::anyhow!("Unknown function: {}", reference))
+}
+
+// (expression, descending, nulls_first)
+fn lower_sort_field(
+    s: &substrait::proto::SortField,
+    fn_map: &HashMap<u32, String>,
+) -> anyhow::Result<(jit::Expression, bool, bool)> {
+    use substrait::proto::sort_field::{SortDirection, SortKind};
+
+    let expr = s
+        .expr
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Sort field has no expression"))?;
+    let direction = match s.sort_kind {
+        Some(SortKind::Direction(d)) => SortDirection::try_from(d)?,
+        None => SortDirection::AscNullsLast,
+        _ => anyhow::bail!("Custom sort comparison functions are not supported"),
+    };
+    let (descending, nulls_first) = match direction {
+        SortDirection::AscNullsFirst => (false, true),
+        SortDirection::AscNullsLast | SortDirection::Unspecified => (false, false),
+        SortDirection::DescNullsFirst => (true, true),
+        SortDirection::DescNullsLast => (true, false),
+        SortDirection::Clustered => anyhow::bail!("Clustered sort is not supported"),
+    };
+    Ok((lower_expression(expr, fn_map)?, descending, nulls_first))
+}
+
+fn value_argument(
+    argument: &substrait::proto::FunctionArgument,
+) -> Option<&substrait::proto::Expression> {
+    match &argument.arg_type {
+        Some(substrait::proto::function_argument::ArgType::Value(e)) => Some(e),
+        _ => None,
+    }
+}
+
+// Top level AND operands of a condition
+fn conjuncts<'a>(
+    expr: &'a substrait::proto::Expression,
+    fn_map: &HashMap<u32, String>,
+) -> Vec<&'a substrait::proto::Expression> {
+    if let Some(RexType::ScalarFunction(f)) = &expr.rex_type
+        && fn_map
+            .get(&f.function_reference)
+            .is_some_and(|n| n == "and")
+    {
+        return f
+            .arguments
+            .iter()
+            .filter_map(value_argument)
+            .flat_map(|a| conjuncts(a, fn_map))
+            .collect();
+    }
+    vec![expr]
+}
+
+// IN / EXISTS predicate, possibly under a NOT (returned as true)
+fn subquery_predicate<'a>(
+    expr: &'a substrait::proto::Expression,
+    fn_map: &HashMap<u32, String>,
+) -> Option<(bool, &'a substrait::proto::expression::Subquery)> {
+    match &expr.rex_type {
+        Some(RexType::Subquery(s)) if !matches!(s.subquery_type, Some(SubqueryType::Scalar(_))) => {
+            Some((false, s))
+        }
+        Some(RexType::ScalarFunction(f))
+            if fn_map
+                .get(&f.function_reference)
+                .is_some_and(|n| n == "not")
+                && f.arguments.len() == 1 =>
+        {
+            value_argument(&f.arguments[0])
+                .and_then(|a| subquery_predicate(a, fn_map))
+                .map(|(negated, s)| (!negated, s))
+        }
+        _ => None,
+    }
+}
+
+// Joins the filter input (`probe`, `width` fields) against an IN or EXISTS subquery
+fn lower_subquery_join(
+    probe: Box<PhysicalPlan>,
+    width: Option<usize>,
+    negated: bool,
+    subquery: &substrait::proto::expression::Subquery,
+    fn_map: &HashMap<u32, String>,
+) -> anyhow::Result<Box<PhysicalPlan>> {
+    let (probe_keys, build, kind) = match &subquery.subquery_type {
+        Some(SubqueryType::InPredicate(in_predicate)) => {
+            let haystack = in_predicate
+                .haystack
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("IN subquery has no haystack"))?;
+            let (build, build_width) = lower_rel(haystack, fn_map)?;
+            if build_width.is_some_and(|w| w != in_predicate.needles.len()) {
+                anyhow::bail!("IN subquery must return one column per needle");
+            }
+            let needles = in_predicate
+                .needles
+                .iter()
+                .map(|n| lower_expression(n, fn_map))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let kind = if negated {
+                JoinKind::NullAwareAnti
+            } else {
+                JoinKind::Semi
+            };
+            (needles, build, kind)
+        }
+        Some(SubqueryType::SetPredicate(set)) if set.predicate_op == PredicateOp::Exists as i32 => {
+            let tuples = set
+                .tuples
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("EXISTS subquery has no input"))?;
+            let (probe_keys, build) = decorrelate(tuples, fn_map)?;
+            let kind = if negated {
+                JoinKind::Anti
+            } else {
+                JoinKind::Semi
+            };
+            (probe_keys, build, kind)
+        }
+        _ => anyhow::bail!("Only IN and EXISTS subqueries are supported"),
+    };
+
+    let mut inputs = ComputedInputs {
+        width,
+        extra: Vec::new(),
+    };
+    let keys = probe_keys
+        .into_iter()
+        .map(|k| inputs.column(k))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let computed = !inputs.extra.is_empty();
+    let (probe, _) = inputs.project(probe);
+    let join = Box::new(PhysicalPlan::Join {
+        probe,
+        build: Box::new(build),
+        kind,
+        keys,
+    });
+
+    // Drop the computed key columns again
+    match width {
+        Some(width) if computed => Ok(Box::new(PhysicalPlan::Project {
+            input: join,
+            expressions: (0..width as u32).map(jit::Expression::Column).collect(),
+        })),
+        _ => Ok(join),
+    }
+}
+
+// Splits an EXISTS subquery into the outer columns it is correlated on and
+// a build plan returning the matching inner expressions. Only equalities
+// between an outer column and an inner expression can be correlated.
+fn decorrelate(
+    rel: &substrait::proto::Rel,
+    fn_map: &HashMap<u32, String>,
+) -> anyhow::Result<(Vec<jit::Expression>, PhysicalPlan)> {
+    use substrait::proto::rel::RelType;
+
+    match rel.rel_type.as_ref() {
+        // EXISTS ignores the columns, look through projections for the filter
+        Some(RelType::Project(project_rel)) => {
+            let input = project_rel
+                .input
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Relation has no input"))?;
+            decorrelate(input, fn_map)
+        }
+        Some(RelType::Filter(filter_rel)) => {
+            let condition = filter_rel
+                .condition
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Filter has no condition"))?;
+            let mut outer_keys = Vec::new();
+            let mut inner_keys = Vec::new();
+            let mut conditions = Vec::new();
+            for conjunct in conjuncts(condition, fn_map) {
+                if !has_outer_reference(conjunct) {
+                    conditions.push(lower_expression(conjunct, fn_map)?);
+                    continue;
                 }
-                current_rel = project_rel.input.as_ref().map(|b| b.as_ref());
+                let (outer, inner) = correlated_equality(conjunct, fn_map)?;
+                outer_keys.push(outer);
+                inner_keys.push(lower_expression(inner, fn_map)?);
+            }
+
+            let (mut input, _) = lower_input(&filter_rel.input, fn_map)?;
+            if let Some(condition) = conditions
+                .into_iter()
+                .reduce(|a, b| jit::Expression::And(Box::new(a), Box::new(b)))
+            {
+                input = Box::new(PhysicalPlan::Filter { input, condition });
+            }
+            Ok((
+                outer_keys,
+                PhysicalPlan::Project {
+                    input,
+                    expressions: inner_keys,
+                },
+            ))
+        }
+        // Uncorrelated, any row makes EXISTS true
+        _ => Ok((Vec::new(), lower_rel(rel, fn_map)?.0)),
+    }
+}
+
+fn has_outer_reference(expr: &substrait::proto::Expression) -> bool {
+    match &expr.rex_type {
+        Some(RexType::Selection(sel)) => {
+            matches!(
+                sel.root_type,
+                Some(field_reference::RootType::OuterReference(_))
+            )
+        }
+        Some(RexType::ScalarFunction(f)) => f
+            .arguments
+            .iter()
+            .filter_map(value_argument)
+            .any(has_outer_reference),
+        _ => false,
+    }
+}
+
+// (outer column, inner expression) of an `outer = inner` conjunct
+fn correlated_equality<'a>(
+    conjunct: &'a substrait::proto::Expression,
+    fn_map: &HashMap<u32, String>,
+) -> anyhow::Result<(jit::Expression, &'a substrait::proto::Expression)> {
+    let sides = match &conjunct.rex_type {
+        Some(RexType::ScalarFunction(f))
+            if fn_map
+                .get(&f.function_reference)
+                .is_some_and(|n| n == "equal") =>
+        {
+            f.arguments
+                .iter()
+                .filter_map(value_argument)
+                .collect::<Vec<_>>()
+        }
+        _ => Vec::new(),
+    };
+    let [a, b] = sides[..] else {
+        anyhow::bail!("Correlated EXISTS predicates must be equalities with an outer column");
+    };
+    let (outer, inner) = if has_outer_reference(a) {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    if has_outer_reference(inner) {
+        anyhow::bail!("Correlated EXISTS predicates must compare an outer column with inner rows");
+    }
+
+    let Some(RexType::Selection(sel)) = &outer.rex_type else {
+        anyhow::bail!("Correlated EXISTS predicates must use a plain outer column");
+    };
+    let Some(field_reference::RootType::OuterReference(outer_ref)) = &sel.root_type else {
+        anyhow::bail!("Correlated EXISTS predicates must use a plain outer column");
+    };
+    if outer_ref.steps_out != 1 {
+        anyhow::bail!("Only references to the directly enclosing query are supported");
+    }
+    match sel.reference_type.as_ref() {
+        Some(field_reference::ReferenceType::DirectReference(dr)) => {
+            match dr.reference_type.as_ref() {
+                Some(direct_reference::ReferenceType::StructField(sf)) => {
+                    Ok((jit::Expression::Column(sf.field as u32), inner))
+                }
+                _ => anyhow::bail!("Expected StructField"),
+            }
+        }
+        _ => anyhow::bail!("Expected DirectReference"),
+    }
+}
+
+// Window and join inputs that are not plain columns get computed by a
+// projection below the operator, so it only deals with column references
+struct ComputedInputs {
+    width: Option<usize>,
+    extra: Vec<jit::Expression>,
+}
+
+impl ComputedInputs {
+    fn column(&mut self, expr: jit::Expression) -> anyhow::Result<u32> {
+        if let jit::Expression::Column(idx) = expr {
+            return Ok(idx);
+        }
+        let width = self.width.ok_or_else(|| {
+            anyhow::anyhow!("Cannot compute derived inputs without the input's base_schema")
+        })?;
+        let pos = match self.extra.iter().position(|e| *e == expr) {
+            Some(pos) => pos,
+            None => {
+                self.extra.push(expr);
+                self.extra.len() - 1
+            }
+        };
+        Ok((width + pos) as u32)
+    }
+
+    // Input with the extra columns appended
+    fn project(self, input: Box<PhysicalPlan>) -> (Box<PhysicalPlan>, Option<usize>) {
+        let Some(width) = self.width.filter(|_| !self.extra.is_empty()) else {
+            return (input, self.width);
+        };
+        let mut expressions: Vec<_> = (0..width as u32).map(jit::Expression::Column).collect();
+        expressions.extend(self.extra);
+        let width = expressions.len();
+        (
+            Box::new(PhysicalPlan::Project { input, expressions }),
+            Some(width),
+        )
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn lower_window_function(
+    name: &str,
+    arguments: &[substrait::proto::FunctionArgument],
+    bounds_type: i32,
+    lower_bound: Option<&substrait::proto::expression::window_function::Bound>,
+    upper_bound: Option<&substrait::proto::expression::window_function::Bound>,
+    ordered: bool,
+    inputs: &mut ComputedInputs,
+    fn_map: &HashMap<u32, String>,
+) -> anyhow::Result<WindowFunction> {
+    use substrait::proto::expression::window_function::{BoundsType, bound::Kind};
+
+    let mut args = Vec::new();
+    for a in arguments {
+        match a.arg_type.as_ref() {
+            Some(substrait::proto::function_argument::ArgType::Value(v)) => {
+                args.push(lower_expression(v, fn_map)?)
             }
-            _ => anyhow::bail!("Unsupported relation type"),
+            _ => anyhow::bail!("Window function argument must be a value"),
         }
     }
-    Ok(PhysicalPlan {
-        projection: projection.ok_or_else(|| anyhow::anyhow!("No projection found"))?,
-        filter,
-        column_types,
-        is_aggregate,
+    let mut args = args.into_iter();
+    let mut column = || -> anyhow::Result<u32> {
+        let arg = args
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Missing argument for {}", name))?;
+        inputs.column(arg)
+    };
+
+    let kind = match name {
+        "row_number" => WindowKind::RowNumber,
+        "rank" => WindowKind::Rank,
+        "dense_rank" => WindowKind::DenseRank,
+        "sum" => WindowKind::Sum(column()?),
+        "avg" => WindowKind::Avg(column()?),
+        "lag" | "lead" => {
+            let column = column()?;
+            let offset = match args.next() {
+                None => 1,
+                Some(jit::Expression::Literal(jit::LiteralTypes::I32(v))) => usize::try_from(v)?,
+                Some(_) => anyhow::bail!("{} offset must be an integer literal", name),
+            };
+            if args.next().is_some() {
+                anyhow::bail!("{} default values are not supported", name);
+            }
+            if name == "lag" {
+                WindowKind::Lag { column, offset }
+            } else {
+                WindowKind::Lead { column, offset }
+            }
+        }
+        _ => anyhow::bail!("Unsupported window function: {}", name),
+    };
+
+    // SQL defaults: RANGE UNBOUNDED PRECEDING to CURRENT ROW when ordered, whole partition otherwise
+    let range = match BoundsType::try_from(bounds_type)? {
+        BoundsType::Rows => false,
+        BoundsType::Range => true,
+        BoundsType::Unspecified => ordered,
+    };
+    let preceding = match lower_bound.and_then(|b| b.kind.as_ref()) {
+        None | Some(Kind::Unbounded(_)) => None,
+        Some(Kind::Preceding(p)) if !range => Some(usize::try_from(p.offset)?),
+        Some(Kind::CurrentRow(_)) if !range => Some(0),
+        _ => anyhow::bail!("Unsupported window frame start"),
+    };
+    let unbounded_following = match upper_bound.and_then(|b| b.kind.as_ref()) {
+        None => !ordered,
+        Some(Kind::CurrentRow(_)) => false,
+        Some(Kind::Unbounded(_)) => true,
+        _ => anyhow::bail!("Unsupported window frame end"),
+    };
+
+    Ok(WindowFunction {
+        kind,
+        frame: WindowFrame {
+            preceding,
+            unbounded_following,
+            range,
+        },
     })
 }
 
+// Lower project expressions over `width` input fields. Window functions are
+// evaluated by Window operators below the projection, which then refers to
+// their output columns.
+fn lower_projection(
+    input: Box<PhysicalPlan>,
+    width: usize,
+    exprs: &[substrait::proto::Expression],
+    fn_map: &HashMap<u32, String>,
+) -> anyhow::Result<(Box<PhysicalPlan>, Vec<jit::Expression>)> {
+    let mut inputs = ComputedInputs {
+        width: Some(width),
+        extra: Vec::new(),
+    };
+    let mut windows: Vec<WindowSpec> = Vec::new();
+    // (position in the projection, window, function)
+    let mut window_outputs = Vec::new();
+
+    let mut expressions: Vec<_> = (0..width as u32).map(jit::Expression::Column).collect();
+    for e in exprs {
+        let Some(RexType::WindowFunction(f)) = &e.rex_type else {
+            expressions.push(lower_expression(e, fn_map)?);
+            continue;
+        };
+
+        let mut partition_by = Vec::new();
+        for p in &f.partitions {
+            partition_by.push(inputs.column(lower_expression(p, fn_map)?)?);
+        }
+        let mut order_by = Vec::new();
+        for s in &f.sorts {
+            let (expr, descending, nulls_first) = lower_sort_field(s, fn_map)?;
+            order_by.push(SortField {
+                column: inputs.column(expr)?,
+                descending,
+                nulls_first,
+            });
+        }
+        let function = lower_window_function(
+            function_name(f.function_reference, fn_map)?,
+            &f.arguments,
+            f.bounds_type,
+            f.lower_bound.as_ref(),
+            f.upper_bound.as_ref(),
+            !order_by.is_empty(),
+            &mut inputs,
+            fn_map,
+        )?;
+
+        // Functions over the same window share one sort
+        let w = match windows
+            .iter()
+            .position(|w| w.partition_by == partition_by && w.order_by == order_by)
+        {
+            Some(w) => w,
+            None => {
+                windows.push(WindowSpec {
+                    partition_by,
+                    order_by,
+                    functions: Vec::new(),
+                });
+                windows.len() - 1
+            }
+        };
+        windows[w].functions.push(function);
+        window_outputs.push((expressions.len(), w, windows[w].functions.len() - 1));
+        // placeholder until the window output positions are known
+        expressions.push(jit::Expression::Column(0));
+    }
+
+    if windows.is_empty() {
+        return Ok((input, expressions));
+    }
+
+    // Stack the windows, each appends its functions after the fields below it
+    let (mut input, below) = inputs.project(input);
+    let mut next = below.unwrap_or(width);
+    let mut offsets = Vec::new();
+    for window in windows {
+        offsets.push(next);
+        next += window.functions.len();
+        input = Box::new(PhysicalPlan::Window { input, window });
+    }
+    for (pos, w, f) in window_outputs {
+        expressions[pos] = jit::Expression::Column((offsets[w] + f) as u32);
+    }
+
+    Ok((input, expressions))
+}
+
+// Fetch offset/count are constant expressions
+fn literal_i64(expr: &substrait::proto::Expression) -> anyhow::Result<i64> {
+    use substrait::proto::expression::literal::LiteralType;
+
+    match &expr.rex_type {
+        Some(RexType::Literal(lit)) => match lit.literal_type {
+            Some(LiteralType::I64(v)) => Ok(v),
+            Some(LiteralType::I32(v)) => Ok(v as i64),
+            _ => anyhow::bail!("Expected an integer literal"),
+        },
+        _ => anyhow::bail!("Expected a literal expression"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_plan_detects_format() {
+        let json_plan = std::fs::read("tests/fixtures/simple_add.json").unwrap();
+        let plan = parse_plan(&json_plan).unwrap();
+        let bytes = plan.encode_to_vec();
+
+        assert_eq!(parse_plan(&bytes).unwrap(), plan);
+        assert!(parse_plan(b"{ not json").is_err());
+    }
+
     #[test]
     fn test_lower_add_expression() {
         let file = "tests/fixtures/simple_add.json";
@@ -304,4 +1824,140 @@ mod tests {
             _ => panic!("Expected And expression"),
         }
     }
+
+    #[test]
+    fn test_having_filter_runs_after_aggregate() {
+        let json_plan = std::fs::read_to_string("tests/fixtures/having_filter.json").unwrap();
+        let plan: substrait::proto::Plan = serde_json::from_str(&json_plan).unwrap();
+
+        let pipelines = lower_plan(&plan).unwrap().pipelines().unwrap();
+        assert_eq!(pipelines.len(), 2);
+
+        // Both scan filters are fused into the aggregate pipeline
+        match (&pipelines[0].filter, &pipelines[0].breaker) {
+            (Some(jit::Expression::And(l, r)), Breaker::Aggregate(aggregation)) => {
+                assert!(matches!(**l, jit::Expression::GreaterThan(_, _)));
+                assert!(matches!(**r, jit::Expression::LessThan(_, _)));
+                assert_eq!(aggregation.measures.len(), 1);
+            }
+            _ => panic!("Expected an aggregate pipeline with both filters"),
+        }
+
+        // HAVING filters the aggregated row instead of the scan
+        match &pipelines[1].filter {
+            Some(jit::Expression::GreaterThan(_, r)) => {
+                assert!(matches!(
+                    **r,
+                    jit::Expression::Literal(jit::LiteralTypes::F32(_))
+                ));
+            }
+            _ => panic!("Expected HAVING filter in the second pipeline"),
+        }
+        assert!(matches!(pipelines[1].breaker, Breaker::Materialize));
+    }
+
+    #[test]
+    fn test_emit_remaps_nested_projections() {
+        let json_plan = std::fs::read_to_string("tests/fixtures/emit_remap.json").unwrap();
+        let plan: substrait::proto::Plan = serde_json::from_str(&json_plan).unwrap();
+
+        let pipelines = lower_plan(&plan).unwrap().pipelines().unwrap();
+        let Breaker::Aggregate(aggregation) = &pipelines[0].breaker else {
+            panic!("Expected an aggregate pipeline");
+        };
+
+        // inner project emits [b + a, a], outer computes field0 * field1 and emits only that
+        match &aggregation.measures[0].argument {
+            jit::Expression::Multiply(l, r) => {
+                match &**l {
+                    jit::Expression::Add(a, b) => {
+                        assert!(matches!(**a, jit::Expression::Column(1)));
+                        assert!(matches!(**b, jit::Expression::Column(0)));
+                    }
+                    _ => panic!("Expected b + a on the left"),
+                }
+                assert!(matches!(**r, jit::Expression::Column(0)));
+            }
+            _ => panic!("Expected Multiply measure"),
+        }
+    }
+
+    #[test]
+    fn test_project_needs_input_width() {
+        let json_plan = std::fs::read_to_string("tests/fixtures/sum_project.json").unwrap();
+        let plan: substrait::proto::Plan = serde_json::from_str(&json_plan).unwrap();
+        assert!(lower_plan(&plan).is_ok());
+
+        // Field references of a Project index past the input fields, so
+        // without their count the plan cannot be lowered
+        let mut value: serde_json::Value = serde_json::from_str(&json_plan).unwrap();
+        strip_key(&mut value, "base_schema");
+        let plan: substrait::proto::Plan = serde_json::from_value(value).unwrap();
+        let err = lower_plan(&plan).unwrap_err();
+        assert!(err.to_string().contains("base_schema"));
+    }
+
+    fn strip_key(value: &mut serde_json::Value, key: &str) {
+        match value {
+            serde_json::Value::Object(map) => {
+                map.remove(key);
+                map.values_mut().for_each(|v| strip_key(v, key));
+            }
+            serde_json::Value::Array(items) => items.iter_mut().for_each(|v| strip_key(v, key)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_q6_reads_four_columns() {
+        let json_plan = std::fs::read_to_string("benches/queries/tpch_q6.json").unwrap();
+        let plan: substrait::proto::Plan = serde_json::from_str(&json_plan).unwrap();
+
+        let mut pipelines = lower_plan(&plan).unwrap().pipelines().unwrap();
+        let columns = pipelines[0].input_columns().unwrap();
+        assert_eq!(columns, vec![4, 5, 6, 10]);
+
+        // l_quantity and l_shipdate move to the first and last projected column
+        pipelines[0].project_input(&columns).unwrap();
+        let mut used = std::collections::BTreeSet::new();
+        jit::collect_columns(pipelines[0].filter.as_ref().unwrap(), &mut used);
+        assert_eq!(used.into_iter().collect::<Vec<_>>(), vec![0, 3]);
+    }
+
+    #[test]
+    fn test_exists_becomes_semi_and_anti_joins() {
+        let json_plan = std::fs::read_to_string("tests/fixtures/exists_subquery.json").unwrap();
+        let plan: substrait::proto::Plan = serde_json::from_str(&json_plan).unwrap();
+
+        let pipelines = lower_plan(&plan).unwrap().pipelines().unwrap();
+        assert_eq!(pipelines.len(), 5);
+
+        // Build sides run first, keeping only the uncorrelated filter and
+        // returning the inner key. The NOT EXISTS join is the outer one.
+        assert_eq!(pipelines[0].source, Source::Table(Some("lineitem".into())));
+        assert!(matches!(
+            pipelines[0].filter,
+            Some(jit::Expression::LessThan(_, _))
+        ));
+        assert_eq!(
+            pipelines[1].projection,
+            Some(vec![jit::Expression::Column(0)])
+        );
+
+        assert_eq!(pipelines[2].source, Source::Table(Some("orders".into())));
+        assert!(matches!(
+            &pipelines[2].breaker,
+            Breaker::Join { build: 1, kind: JoinKind::Semi, keys } if keys == &[0]
+        ));
+        assert_eq!(pipelines[3].source, Source::Pipeline(2));
+        assert!(matches!(
+            pipelines[3].breaker,
+            Breaker::Join {
+                build: 0,
+                kind: JoinKind::Anti,
+                ..
+            }
+        ));
+        assert_eq!(pipelines[4].source, Source::Pipeline(3));
+    }
 }
@@ -0,0 +1,344 @@
+use std::{ops::Range, sync::Arc};
+
+use arrow::{
+    array::{Array, ArrayRef, AsArray, Float32Array, Int32Array, UInt32Array},
+    datatypes::{
+        DataType, Decimal128Type, Field, Float32Type, Float64Type, Int32Type, Int64Type, Schema,
+        UInt32Type, UInt64Type,
+    },
+    record_batch::RecordBatch,
+};
+
+use crate::{executor::QueryExecutor, jit, sub};
+
+// Evaluates window functions over a materialized batch. Rows are sorted by
+// (partition, order) on the GPU, then every function is a segmented scan that
+// restarts at partition (or peer group) boundaries. Output rows stay in that
+// sorted order and carry one extra column per function.
+pub async fn evaluate(
+    executor: &QueryExecutor,
+    batch: RecordBatch,
+    window: &sub::WindowSpec,
+) -> anyhow::Result<RecordBatch> {
+    let batch = sort(executor, batch, window).await?;
+    let rows = batch.num_rows();
+
+    let column = |idx: u32| batch.column(idx as usize).clone();
+    let partition_keys: Vec<ArrayRef> = window.partition_by.iter().map(|c| column(*c)).collect();
+    let mut peer_keys = partition_keys.clone();
+    peer_keys.extend(window.order_by.iter().map(|s| column(s.column)));
+
+    let partitions = ranges(&partition_keys, rows)?;
+    let peers = ranges(&peer_keys, rows)?;
+    let partition_heads = heads(&partitions, rows);
+    let peer_heads = heads(&peers, rows);
+
+    // Range a row belongs to, by position
+    let owner = |ranges: &[Range<usize>]| {
+        let mut owner = vec![0..0; rows];
+        for r in ranges {
+            owner[r.clone()].fill(r.clone());
+        }
+        owner
+    };
+    let partition_of = owner(&partitions);
+    let peers_of = owner(&peers);
+
+    let ones = vec![1i32; rows];
+    let row_number = executor
+        .segmented_scan(
+            &ones,
+            &partition_heads,
+            jit::ScalarType::I32,
+            jit::ScanOp::Sum,
+        )
+        .await?;
+
+    let mut fields: Vec<Field> = batch
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| f.as_ref().clone())
+        .collect();
+    let mut columns = batch.columns().to_vec();
+
+    for (k, function) in window.functions.iter().enumerate() {
+        let result: ArrayRef = match &function.kind {
+            sub::WindowKind::RowNumber => Arc::new(Int32Array::from(row_number.clone())),
+            sub::WindowKind::Rank => {
+                let firsts: Vec<i32> = (0..rows)
+                    .map(|i| if peer_heads[i] == 1 { row_number[i] } else { 0 })
+                    .collect();
+                let rank = executor
+                    .segmented_scan(
+                        &firsts,
+                        &partition_heads,
+                        jit::ScalarType::I32,
+                        jit::ScanOp::Max,
+                    )
+                    .await?;
+                Arc::new(Int32Array::from(rank))
+            }
+            sub::WindowKind::DenseRank => {
+                let flags: Vec<i32> = peer_heads.iter().map(|h| *h as i32).collect();
+                let rank = executor
+                    .segmented_scan(
+                        &flags,
+                        &partition_heads,
+                        jit::ScalarType::I32,
+                        jit::ScanOp::Sum,
+                    )
+                    .await?;
+                Arc::new(Int32Array::from(rank))
+            }
+            sub::WindowKind::Lag { column: c, offset } => {
+                let indices: UInt32Array = (0..rows)
+                    .map(|i| {
+                        i.checked_sub(*offset)
+                            .filter(|j| *j >= partition_of[i].start)
+                            .map(|j| j as u32)
+                    })
+                    .collect();
+                arrow::compute::take(&column(*c), &indices, None)?
+            }
+            sub::WindowKind::Lead { column: c, offset } => {
+                let indices: UInt32Array = (0..rows)
+                    .map(|i| Some(i + offset).filter(|j| *j < partition_of[i].end))
+                    .map(|j| j.map(|j| j as u32))
+                    .collect();
+                arrow::compute::take(&column(*c), &indices, None)?
+            }
+            sub::WindowKind::Sum(c) | sub::WindowKind::Avg(c) => {
+                let values = arrow::compute::cast(&column(*c), &DataType::Float32)?;
+                let values = values.as_primitive::<Float32Type>();
+                let sums: Vec<f32> = (0..rows)
+                    .map(|i| {
+                        if values.is_valid(i) {
+                            values.value(i)
+                        } else {
+                            0.0
+                        }
+                    })
+                    .collect();
+                let valid: Vec<i32> = (0..rows).map(|i| values.is_valid(i) as i32).collect();
+                let sums = executor
+                    .segmented_scan(
+                        &sums,
+                        &partition_heads,
+                        jit::ScalarType::F32,
+                        jit::ScanOp::Sum,
+                    )
+                    .await?;
+                let counts = executor
+                    .segmented_scan(
+                        &valid,
+                        &partition_heads,
+                        jit::ScalarType::I32,
+                        jit::ScanOp::Sum,
+                    )
+                    .await?;
+
+                let frame = &function.frame;
+                let is_avg = matches!(function.kind, sub::WindowKind::Avg(_));
+                let result: Float32Array = (0..rows)
+                    .map(|i| {
+                        let partition = &partition_of[i];
+                        let end = if frame.unbounded_following {
+                            partition.end - 1
+                        } else if frame.range {
+                            peers_of[i].end - 1
+                        } else {
+                            i
+                        };
+                        let start = match frame.preceding {
+                            Some(n) => partition.start.max(i.saturating_sub(n)),
+                            None => partition.start,
+                        };
+                        // Prefix sums are inclusive, subtract everything before the frame
+                        let (mut sum, mut count) = (sums[end], counts[end]);
+                        if start > partition.start {
+                            sum -= sums[start - 1];
+                            count -= counts[start - 1];
+                        }
+                        match (count, is_avg) {
+                            (0, _) => None,
+                            (_, true) => Some(sum / count as f32),
+                            (_, false) => Some(sum),
+                        }
+                    })
+                    .collect();
+                Arc::new(result)
+            }
+        };
+        fields.push(Field::new(
+            format!("window_{k}"),
+            result.data_type().clone(),
+            true,
+        ));
+        columns.push(result);
+    }
+
+    Ok(RecordBatch::try_new(
+        Arc::new(Schema::new(fields)),
+        columns,
+    )?)
+}
+
+// Reorders the batch by the partition keys, then the order keys. One stable
+// GPU sort per key from the least significant one, like a radix sort.
+async fn sort(
+    executor: &QueryExecutor,
+    batch: RecordBatch,
+    window: &sub::WindowSpec,
+) -> anyhow::Result<RecordBatch> {
+    let mut keys: Vec<sub::SortField> = window
+        .partition_by
+        .iter()
+        .map(|c| sub::SortField {
+            column: *c,
+            descending: false,
+            nulls_first: false,
+        })
+        .collect();
+    keys.extend(window.order_by.iter().cloned());
+    if keys.is_empty() {
+        return Ok(batch);
+    }
+
+    let mut permutation = UInt32Array::from_iter_values(0..batch.num_rows() as u32);
+    for key in keys.iter().rev() {
+        for word in encode(batch.column(key.column as usize), key)?
+            .into_iter()
+            .rev()
+        {
+            // Words are in batch order, the sort sees them in the current one
+            let word = arrow::compute::take(&UInt32Array::from(word), &permutation, None)?;
+            let word = word.as_primitive::<UInt32Type>().values();
+            let order = UInt32Array::from(executor.sort_indices(word).await?);
+            permutation = arrow::compute::take(&permutation, &order, None)?
+                .as_primitive()
+                .clone();
+        }
+    }
+
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|c| arrow::compute::take(c, &permutation, None))
+        .collect::<Result<_, _>>()?;
+    Ok(RecordBatch::try_new(batch.schema(), columns)?)
+}
+
+// Maps a sort key to u32 words, most significant first, so that unsigned
+// order of the words matches the requested order. 64 bit keys take two words,
+// decimals four and strings are replaced by their rank among the distinct
+// values. Nulls are ordered by a flag word of their own in front.
+fn encode(column: &ArrayRef, key: &sub::SortField) -> anyhow::Result<Vec<Vec<u32>>> {
+    let rows = column.len();
+    let mut words: Vec<Vec<u32>> = match column.data_type() {
+        DataType::Boolean | DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => {
+            let column = match column.data_type() {
+                DataType::Utf8View => arrow::compute::cast(column, &DataType::Utf8)?,
+                _ => column.clone(),
+            };
+            vec![arrow::compute::rank(&column, None)?]
+        }
+        DataType::Int8 | DataType::Int16 | DataType::Int32 | DataType::Date32 => {
+            let column = arrow::compute::cast(column, &DataType::Int32)?;
+            let values = column.as_primitive::<Int32Type>().values();
+            vec![values.iter().map(|v| *v as u32 ^ 0x8000_0000).collect()]
+        }
+        DataType::UInt8 | DataType::UInt16 | DataType::UInt32 => {
+            let column = arrow::compute::cast(column, &DataType::UInt32)?;
+            vec![column.as_primitive::<UInt32Type>().values().to_vec()]
+        }
+        DataType::Int64 | DataType::Date64 | DataType::Timestamp(_, _) => {
+            let column = arrow::compute::cast(column, &DataType::Int64)?;
+            let values = column.as_primitive::<Int64Type>().values();
+            split(values.iter().map(|v| (*v as u64 ^ 1 << 63) as u128), 2)
+        }
+        DataType::UInt64 => {
+            let values = column.as_primitive::<UInt64Type>().values();
+            split(values.iter().map(|v| *v as u128), 2)
+        }
+        DataType::Float16 | DataType::Float32 => {
+            let column = arrow::compute::cast(column, &DataType::Float32)?;
+            let values = column.as_primitive::<Float32Type>().values();
+            let bits = values.iter().map(|v| {
+                let bits = v.to_bits();
+                if bits & 0x8000_0000 != 0 {
+                    !bits
+                } else {
+                    bits | 0x8000_0000
+                }
+            });
+            vec![bits.collect()]
+        }
+        DataType::Float64 => {
+            let values = column.as_primitive::<Float64Type>().values();
+            let bits = values.iter().map(|v| {
+                let bits = v.to_bits();
+                let bits = if bits & 1 << 63 != 0 {
+                    !bits
+                } else {
+                    bits | 1 << 63
+                };
+                bits as u128
+            });
+            split(bits, 2)
+        }
+        // Values of one column share the scale, so the unscaled integers order them
+        DataType::Decimal128(_, _) => {
+            let values = column.as_primitive::<Decimal128Type>().values();
+            split(values.iter().map(|v| *v as u128 ^ 1 << 127), 4)
+        }
+        data_type => anyhow::bail!("Window keys of type {} are not supported", data_type),
+    };
+
+    if key.descending {
+        for word in &mut words {
+            word.iter_mut().for_each(|v| *v = !*v);
+        }
+    }
+    if column.null_count() > 0 {
+        let (null, valid) = if key.nulls_first { (0, 1) } else { (1, 0) };
+        let flags = (0..rows)
+            .map(|i| if column.is_null(i) { null } else { valid })
+            .collect();
+        // Nulls are equal to each other whatever their value words hold
+        for word in &mut words {
+            for (i, v) in word.iter_mut().enumerate() {
+                if column.is_null(i) {
+                    *v = 0;
+                }
+            }
+        }
+        words.insert(0, flags);
+    }
+    Ok(words)
+}
+
+// The low `count` u32 words of every value, most significant first
+fn split(values: impl Iterator<Item = u128>, count: usize) -> Vec<Vec<u32>> {
+    let values: Vec<u128> = values.collect();
+    (0..count)
+        .rev()
+        .map(|w| values.iter().map(|v| (v >> (32 * w)) as u32).collect())
+        .collect()
+}
+
+// Runs of equal keys in an already sorted batch
+fn ranges(keys: &[ArrayRef], rows: usize) -> anyhow::Result<Vec<Range<usize>>> {
+    if keys.is_empty() {
+        return Ok(std::iter::once(0..rows).collect());
+    }
+    Ok(arrow::compute::partition(keys)?.ranges())
+}
+
+fn heads(ranges: &[Range<usize>], rows: usize) -> Vec<u32> {
+    let mut heads = vec![0; rows];
+    for r in ranges {
+        heads[r.start] = 1;
+    }
+    heads
+}
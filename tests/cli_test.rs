@@ -0,0 +1,185 @@
+use std::{io::Write, process::Command};
+
+fn wsql(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_wsql"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_cli_sql_csv() {
+    let output = wsql(&[
+        "sql",
+        "SELECT tinyint_col, SUM(id) AS total FROM alltypes GROUP BY 1 ORDER BY 1",
+        "--table",
+        "alltypes=tests/data/alltypes_plain.parquet",
+        "--format",
+        "csv",
+        "--timing",
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "tinyint_col,total\n0,12.0\n1,16.0\n");
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("Query time")
+    );
+}
+
+#[test]
+fn test_cli_sql_csv_table() {
+    let output = wsql(&[
+        "sql",
+        "SELECT column_2, COUNT(*) AS n FROM orders GROUP BY 1 ORDER BY 1",
+        "--table",
+        "orders=tests/data/orders_semicolon.csv",
+        "--csv-delimiter",
+        ";",
+        "--csv-no-header",
+        "--format",
+        "csv",
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "column_2,n\neast,3\nnorth,2\nwest,3\n");
+}
+
+#[test]
+fn test_cli_query_ipc_stdin() {
+    let arrow = wsql(&[
+        "sql",
+        "SELECT * FROM alltypes",
+        "--table",
+        "alltypes=tests/data/alltypes_plain.parquet",
+        "--format",
+        "arrow",
+    ]);
+    assert!(arrow.status.success());
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_wsql"))
+        .args([
+            "query",
+            "--plan",
+            "tests/fixtures/filter_project_sort.json",
+            "-",
+            "--format",
+            "csv",
+        ])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&arrow.stdout)
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "doubled_id\n14\n12\n10\n"
+    );
+}
+
+#[test]
+fn test_cli_query_explain() {
+    let output = wsql(&[
+        "query",
+        "--plan",
+        "tests/fixtures/filter_project_sort.json",
+        "tests/data/alltypes_plain.parquet",
+        "--explain",
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Physical plan:\n  Fetch offset 0 count Some(3)\n"));
+    assert!(stdout.contains("Pipeline 0: default input -> Sort"));
+    assert!(stdout.contains("Kernel output 0 -> i32 per row"));
+}
+
+#[test]
+fn test_cli_sql_analyze() {
+    let output = wsql(&[
+        "sql",
+        "SELECT tinyint_col, SUM(id) FROM alltypes GROUP BY 1",
+        "--table",
+        "alltypes=tests/data/alltypes_plain.parquet",
+        "--analyze",
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Execution time: "));
+    assert!(stdout.contains("analyze: 1 batches, 8 rows in, 2 rows out"));
+    assert!(stdout.contains("    batches: decode "));
+}
+
+#[test]
+fn test_cli_query_protobuf_plan() {
+    use prost::Message;
+
+    let json_plan = std::fs::read_to_string("tests/fixtures/filter_project_sort.json").unwrap();
+    let plan: substrait::proto::Plan = serde_json::from_str(&json_plan).unwrap();
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("filter_project_sort.pb");
+    std::fs::write(&path, plan.encode_to_vec()).unwrap();
+
+    let output = wsql(&[
+        "query",
+        "--plan",
+        path.to_str().unwrap(),
+        "tests/data/alltypes_plain.parquet",
+        "--format",
+        "csv",
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "doubled_id\n14\n12\n10\n");
+}
+
+#[test]
+fn test_cli_rejects_bad_table() {
+    let output = wsql(&["sql", "SELECT 1", "--table", "alltypes"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Expected NAME=PATH, got alltypes"));
+}
+
+#[test]
+fn test_cli_repl() {
+    use std::io::Write;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_wsql"))
+        .args([
+            "repl",
+            "--table",
+            "alltypes=tests/data/alltypes_plain.parquet",
+        ])
+        .env("HOME", env!("CARGO_TARGET_TMPDIR"))
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Statements span lines until the semicolon, the device stays up between them
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(
+            b".tables\n.mode csv\nSELECT id FROM alltypes\nWHERE id > 5 ORDER BY id;\n\
+              SELECT missing FROM alltypes;\nSELECT COUNT(*) AS n FROM alltypes;\n",
+        )
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("alltypes\n"));
+    assert!(stdout.contains("id\n6\n7\n"));
+    assert!(stdout.contains("n\n8\n"));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Error: Column missing does not exist"));
+}
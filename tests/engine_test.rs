@@ -1,23 +1,532 @@
+use arrow::array::AsArray;
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use wsql::source::TableSource;
+
+async fn executor() -> wsql::executor::QueryExecutor {
+    let gpu = wsql::gpu::Gpu::new().await;
+    wsql::executor::QueryExecutor::new(gpu)
+}
+
+async fn engine() -> wsql::engine::QueryEngine {
+    wsql::engine::QueryEngine::new(executor().await)
+}
+
+// alltypes_plain.parquet as a plain reader, 8 rows in batches of `batch_size`
+fn alltypes(batch_size: usize) -> ParquetRecordBatchReader {
+    let file = std::fs::File::open("tests/data/alltypes_plain.parquet").unwrap();
+    ParquetRecordBatchReaderBuilder::try_new(file)
+        .unwrap()
+        .with_batch_size(batch_size)
+        .build()
+        .unwrap()
+}
+
 #[tokio::test]
 async fn test_engine_streaming_aggregate() {
-    let gpu = wsql::gpu::Gpu::new().await;
-    let executor = wsql::executor::QueryExecutor::new(gpu);
-    let engine = wsql::engine::QueryEngine::new(executor);
+    let engine = engine().await;
+    let reader = alltypes(2); // forced streaming
+
+    let json_plan = std::fs::read_to_string("tests/fixtures/streaming_aggregate.json").unwrap();
+    let result = engine.run(reader, &json_plan).await.unwrap();
 
-    let dal_builder = opendal::services::Fs::default().root("tests");
-    let op = opendal::Operator::new(dal_builder).unwrap().finish();
+    assert_eq!(result.num_rows(), 1);
+    assert_eq!(result.schema().field(0).name(), "total_id_sum");
+    assert_eq!(
+        result
+            .column(0)
+            .as_primitive::<arrow::datatypes::Float32Type>()
+            .value(0),
+        28.0
+    );
+}
 
-    let file_path = "data/alltypes_plain.parquet";
-    let buffer = op.read(file_path).await.unwrap();
-    let reader =
-        parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(buffer.to_bytes())
-            .unwrap()
-            .with_batch_size(2) // forced streaming
-            .build()
-            .unwrap();
+#[tokio::test]
+async fn test_engine_dataset_scan() {
+    let engine = engine().await;
+    // Streamed through the OpenDAL async reader
+    let reader = wsql::dataset::DatasetTable::open("tests/data/alltypes_plain.parquet")
+        .await
+        .unwrap()
+        .with_batch_size(2)
+        .scan()
+        .unwrap();
 
     let json_plan = std::fs::read_to_string("tests/fixtures/streaming_aggregate.json").unwrap();
     let result = engine.run(reader, &json_plan).await.unwrap();
+    assert_eq!(
+        result
+            .column(0)
+            .as_primitive::<arrow::datatypes::Float32Type>()
+            .value(0),
+        28.0
+    );
+}
+
+#[tokio::test]
+async fn test_engine_filter_project_sort() {
+    let engine = engine().await;
+    let reader = alltypes(3);
+
+    // SELECT id * 2 AS doubled_id WHERE id > 1 ORDER BY doubled_id DESC LIMIT 3
+    let json_plan = std::fs::read_to_string("tests/fixtures/filter_project_sort.json").unwrap();
+    let result = engine.run(reader, &json_plan).await.unwrap();
+
+    assert_eq!(result.schema().field(0).name(), "doubled_id");
+    assert_eq!(
+        result
+            .column(0)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values(),
+        &[14, 12, 10]
+    );
+}
+
+#[tokio::test]
+async fn test_engine_protobuf_plan() {
+    use prost::Message;
+
+    let engine = engine().await;
+    let reader = alltypes(1024);
+
+    // Same plan as test_engine_filter_project_sort, handed over as protobuf bytes
+    let json_plan = std::fs::read_to_string("tests/fixtures/filter_project_sort.json").unwrap();
+    let plan: substrait::proto::Plan = serde_json::from_str(&json_plan).unwrap();
+    let result = engine
+        .run_protobuf(reader, &plan.encode_to_vec())
+        .await
+        .unwrap();
+
+    assert_eq!(result.schema().field(0).name(), "doubled_id");
+    assert_eq!(
+        result
+            .column(0)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values(),
+        &[14, 12, 10]
+    );
+}
+
+#[tokio::test]
+async fn test_engine_window_functions() {
+    let engine = engine().await;
+    let reader = alltypes(3);
+
+    // SELECT id, tinyint_col,
+    //   ROW_NUMBER() OVER (PARTITION BY tinyint_col ORDER BY id),
+    //   SUM(id) OVER (PARTITION BY tinyint_col ORDER BY id ROWS UNBOUNDED PRECEDING),
+    //   RANK() OVER (ORDER BY tinyint_col)
+    // ORDER BY id
+    let json_plan = std::fs::read_to_string("tests/fixtures/window_functions.json").unwrap();
+    let result = engine.run(reader, &json_plan).await.unwrap();
+
+    assert_eq!(result.schema().field(2).name(), "row_number");
+    assert_eq!(
+        result
+            .column(2)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values(),
+        &[1, 1, 2, 2, 3, 3, 4, 4]
+    );
+    assert_eq!(
+        result
+            .column(3)
+            .as_primitive::<arrow::datatypes::Float32Type>()
+            .values(),
+        &[0.0, 1.0, 2.0, 4.0, 6.0, 9.0, 12.0, 16.0]
+    );
+    assert_eq!(
+        result
+            .column(4)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values(),
+        &[1, 5, 1, 5, 1, 5, 1, 5]
+    );
+}
+
+#[tokio::test]
+async fn test_window_keys_keep_full_precision() {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{Int32Array, Int64Array, StringArray},
+        datatypes::{DataType, Field, Schema},
+        record_batch::RecordBatch,
+    };
+
+    let executor = executor().await;
+
+    // The two Int64 keys are the same value as f32, the strings are not numbers
+    let big = 1i64 << 40;
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("k", DataType::Int64, false),
+        Field::new("s", DataType::Utf8, true),
+        Field::new("v", DataType::Int32, false),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(Int64Array::from(vec![
+                big + 1,
+                big,
+                big + 1,
+                big,
+                big + 1,
+                big,
+            ])),
+            Arc::new(StringArray::from(vec![
+                Some("b"),
+                None,
+                Some("a"),
+                Some("b"),
+                None,
+                Some("a"),
+            ])),
+            Arc::new(Int32Array::from(vec![0, 1, 2, 3, 4, 5])),
+        ],
+    )
+    .unwrap();
+    let function = |kind| wsql::sub::WindowFunction {
+        kind,
+        frame: wsql::sub::WindowFrame {
+            preceding: None,
+            unbounded_following: false,
+            range: false,
+        },
+    };
+    let sort = |column, descending| wsql::sub::SortField {
+        column,
+        descending,
+        nulls_first: false,
+    };
+
+    // ROW_NUMBER() OVER (PARTITION BY k ORDER BY v DESC)
+    let window = wsql::sub::WindowSpec {
+        partition_by: vec![0],
+        order_by: vec![sort(2, true)],
+        functions: vec![function(wsql::sub::WindowKind::RowNumber)],
+    };
+    let result = wsql::window::evaluate(&executor, batch.clone(), &window)
+        .await
+        .unwrap();
+    assert_eq!(
+        result
+            .column(0)
+            .as_primitive::<arrow::datatypes::Int64Type>()
+            .values(),
+        &[big, big, big, big + 1, big + 1, big + 1]
+    );
+    assert_eq!(
+        result
+            .column(2)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values(),
+        &[5, 3, 1, 4, 2, 0]
+    );
+    assert_eq!(
+        result
+            .column(3)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values(),
+        &[1, 2, 3, 1, 2, 3]
+    );
+
+    // RANK() OVER (ORDER BY s), nulls last
+    let window = wsql::sub::WindowSpec {
+        partition_by: vec![],
+        order_by: vec![sort(1, false)],
+        functions: vec![function(wsql::sub::WindowKind::Rank)],
+    };
+    let result = wsql::window::evaluate(&executor, batch, &window)
+        .await
+        .unwrap();
+    assert_eq!(
+        result
+            .column(2)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values(),
+        &[2, 5, 0, 3, 1, 4]
+    );
+    assert_eq!(
+        result
+            .column(3)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values(),
+        &[1, 1, 3, 3, 5, 5]
+    );
+}
+
+#[tokio::test]
+async fn test_engine_count_distinct() {
+    let engine = engine().await;
+    let reader = alltypes(2);
+
+    // SELECT COUNT(DISTINCT tinyint_col), COUNT(*), SUM(DISTINCT tinyint_col + 1) WHERE id > 4
+    let json_plan = std::fs::read_to_string("tests/fixtures/count_distinct.json").unwrap();
+    let result = engine.run(reader, &json_plan).await.unwrap();
+
+    assert_eq!(result.num_rows(), 1);
+    let count = |i: usize| {
+        result
+            .column(i)
+            .as_primitive::<arrow::datatypes::Int64Type>()
+            .value(0)
+    };
+    assert_eq!((count(0), count(1)), (2, 3));
+    assert_eq!(
+        result
+            .column(2)
+            .as_primitive::<arrow::datatypes::Float32Type>()
+            .value(0),
+        3.0
+    );
+}
+
+#[tokio::test]
+async fn test_distinct_set_limit() {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::Int32Array,
+        datatypes::{DataType, Field, Schema},
+        record_batch::RecordBatch,
+    };
+
+    let executor = executor().await;
+    let schema = Arc::new(Schema::new(vec![Field::new("v", DataType::Int32, false)]));
+    let batch = |values: Vec<i32>| {
+        RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(values))]).unwrap()
+    };
+
+    // Duplicates merge away under the limit
+    let mut set = wsql::distinct::DistinctSet::new(schema.clone(), 2, 4);
+    for _ in 0..3 {
+        set.insert(&executor, batch(vec![1, 2, 3])).await.unwrap();
+    }
+    assert_eq!(set.finish(&executor).await.unwrap().num_rows(), 3);
+
+    let mut set = wsql::distinct::DistinctSet::new(schema.clone(), 2, 4);
+    set.insert(&executor, batch(vec![1, 2, 3])).await.unwrap();
+    let err = set
+        .insert(&executor, batch(vec![4, 5, 6]))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("more than 4 distinct rows"));
+}
+
+#[tokio::test]
+async fn test_engine_select_distinct() {
+    let engine = engine().await;
+    let reader = alltypes(3);
+
+    // SELECT DISTINCT tinyint_col ORDER BY tinyint_col
+    let json_plan = std::fs::read_to_string("tests/fixtures/select_distinct.json").unwrap();
+    let result = engine.run(reader, &json_plan).await.unwrap();
+
+    assert_eq!(result.schema().field(0).name(), "tinyint_col");
+    assert_eq!(
+        result
+            .column(0)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values(),
+        &[0, 1]
+    );
+}
+
+#[tokio::test]
+async fn test_engine_rollup() {
+    let engine = engine().await;
+    let reader = alltypes(3);
+
+    // SELECT tinyint_col, SUM(id), COUNT(*), GROUPING_ID GROUP BY ROLLUP(tinyint_col)
+    let json_plan = std::fs::read_to_string("tests/fixtures/rollup.json").unwrap();
+    let result = engine.run(reader, &json_plan).await.unwrap();
+
+    let keys = result
+        .column(0)
+        .as_primitive::<arrow::datatypes::Int32Type>();
+    assert_eq!(keys.iter().collect::<Vec<_>>(), [Some(0), Some(1), None]);
+    assert_eq!(
+        result
+            .column(1)
+            .as_primitive::<arrow::datatypes::Float32Type>()
+            .values(),
+        &[12.0, 16.0, 28.0]
+    );
+    assert_eq!(
+        result
+            .column(2)
+            .as_primitive::<arrow::datatypes::Int64Type>()
+            .values(),
+        &[4, 4, 8]
+    );
+    assert_eq!(result.schema().field(3).name(), "grouping_id");
+    assert_eq!(
+        result
+            .column(3)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values(),
+        &[0, 0, 1]
+    );
+}
+
+#[tokio::test]
+async fn test_engine_filtered_measures() {
+    let engine = engine().await;
+    let reader = alltypes(3);
+
+    // SELECT SUM(id) FILTER (WHERE tinyint_col > 0), COUNT(*) FILTER (WHERE id > 4),
+    //   COUNT(DISTINCT tinyint_col) FILTER (WHERE id > 6), SUM(id)
+    let json_plan = std::fs::read_to_string("tests/fixtures/filtered_measures.json").unwrap();
+    let result = engine.run(reader, &json_plan).await.unwrap();
+
+    // Counts are exact integers, sums Float32
+    let values: Vec<f64> = result
+        .columns()
+        .iter()
+        .map(|c| {
+            let c = arrow::compute::cast(c, &arrow::datatypes::DataType::Float64).unwrap();
+            c.as_primitive::<arrow::datatypes::Float64Type>().value(0)
+        })
+        .collect();
+    assert_eq!(values, [16.0, 3.0, 1.0, 28.0]);
+    assert_eq!(
+        result.schema().field(1).data_type(),
+        &arrow::datatypes::DataType::Int64
+    );
+}
+
+#[tokio::test]
+async fn test_engine_partial_and_final_aggregation() {
+    let engine = engine().await;
+
+    // Two workers emit AVG/COUNT/SUM state per tinyint_col over the same file
+    let json_plan = std::fs::read_to_string("tests/fixtures/partial_aggregate.json").unwrap();
+    let mut partials = Vec::new();
+    for _ in 0..2 {
+        let reader = alltypes(3);
+        partials.push(engine.run(reader, &json_plan).await.unwrap());
+    }
+    assert!(matches!(
+        partials[0].schema().field(1).data_type(),
+        arrow::datatypes::DataType::Struct(_)
+    ));
+    // Partial sums keep f64 precision until the final phase
+    assert_eq!(
+        partials[0].schema().field(3).data_type(),
+        &arrow::datatypes::DataType::Float64
+    );
+
+    let mut state = Vec::new();
+    let mut writer =
+        parquet::arrow::ArrowWriter::try_new(&mut state, partials[0].schema(), None).unwrap();
+    for partial in &partials {
+        writer.write(partial).unwrap();
+    }
+    writer.close().unwrap();
+
+    // The final phase merges both workers' state
+    let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(
+        opendal::Buffer::from(state).to_bytes(),
+    )
+    .unwrap()
+    .build()
+    .unwrap();
+    let json_plan = std::fs::read_to_string("tests/fixtures/final_aggregate.json").unwrap();
+    let result = engine.run(reader, &json_plan).await.unwrap();
+
+    let column = |i: usize| {
+        result
+            .column(i)
+            .as_primitive::<arrow::datatypes::Float32Type>()
+            .values()
+            .to_vec()
+    };
+    assert_eq!(column(1), [3.0, 4.0]);
+    assert_eq!(
+        result
+            .column(2)
+            .as_primitive::<arrow::datatypes::Int64Type>()
+            .values(),
+        &[8, 8]
+    );
+    assert_eq!(column(3), [24.0, 32.0]);
+}
+
+#[tokio::test]
+async fn test_engine_scalar_and_in_subqueries() {
+    let engine = engine().await;
+    let reader = alltypes(3);
+
+    // SELECT id WHERE id > (SELECT AVG(id)) AND id IN (SELECT id + id)
+    let json_plan = std::fs::read_to_string("tests/fixtures/scalar_in_subquery.json").unwrap();
+    let result = engine.run(reader, &json_plan).await.unwrap();
+
+    assert_eq!(result.schema().field(0).name(), "id");
+    assert_eq!(
+        result
+            .column(0)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values()
+            .to_vec(),
+        [4, 6]
+    );
+}
+
+#[tokio::test]
+async fn test_engine_exists_subqueries() {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::Int32Array,
+        datatypes::{DataType, Field, Schema},
+        record_batch::RecordBatch,
+    };
+
+    let mut engine = engine().await;
+
+    let table = |names: [&str; 2], a: Vec<i32>, b: Vec<i32>| {
+        let schema = Arc::new(Schema::new(
+            names
+                .map(|n| Field::new(n, DataType::Int32, false))
+                .to_vec(),
+        ));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(a)), Arc::new(Int32Array::from(b))],
+        )
+        .unwrap();
+        Arc::new(wsql::source::MemoryTable::new(schema, vec![batch]))
+    };
+    engine.register_table(
+        "orders",
+        table(
+            ["o_orderkey", "o_priority"],
+            vec![1, 2, 3, 4, 5],
+            vec![0; 5],
+        ),
+    );
+    engine.register_table(
+        "lineitem",
+        table(
+            ["l_orderkey", "l_late"],
+            vec![1, 1, 2, 2, 3, 5],
+            vec![1, 0, 1, 1, 0, 1],
+        ),
+    );
+
+    // Every table is named, the default input is never read
+    let reader = alltypes(1024);
+
+    // Orders with a late line item and no line item on time
+    let json_plan = std::fs::read_to_string("tests/fixtures/exists_subquery.json").unwrap();
+    let result = engine.run(reader, &json_plan).await.unwrap();
 
-    assert_eq!(result, wsql::executor::QueryResult::Aggregate(28.0));
+    assert_eq!(
+        result
+            .column(0)
+            .as_primitive::<arrow::datatypes::Int32Type>()
+            .values()
+            .to_vec(),
+        [2, 5]
+    );
 }
@@ -0,0 +1,118 @@
+use std::{
+    ffi::{CStr, CString},
+    sync::Arc,
+};
+
+use arrow::{
+    array::{AsArray, Float32Array, Int32Array, RecordBatch, RecordBatchIterator},
+    datatypes::{DataType, Field, Float32Type, Int32Type, Schema},
+    ffi_stream::{ArrowArrayStreamReader, FFI_ArrowArrayStream},
+};
+use prost::Message;
+use wsql::ffi::*;
+
+// Reads a result stream written by the engine
+fn collect(stream: &mut FFI_ArrowArrayStream) -> Vec<RecordBatch> {
+    let reader = unsafe { ArrowArrayStreamReader::from_raw(stream) }.unwrap();
+    reader.collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn test_ffi_register_and_execute() {
+    let engine = wsql_open();
+    assert!(!engine.is_null());
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("g", DataType::Int32, false),
+        Field::new("v", DataType::Float32, false),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema.clone(),
+        vec![
+            Arc::new(Int32Array::from(vec![1, 2, 1, 2, 1])),
+            Arc::new(Float32Array::from(vec![1.0, 2.0, 3.0, 4.0, 5.0])),
+        ],
+    )
+    .unwrap();
+    let reader = RecordBatchIterator::new([Ok(batch)], schema.clone());
+    let mut stream = FFI_ArrowArrayStream::new(Box::new(reader));
+    let name = CString::new("t").unwrap();
+    assert_eq!(
+        unsafe { wsql_register_stream(engine, name.as_ptr(), &mut stream) },
+        0
+    );
+
+    let sql = CString::new("SELECT g, SUM(v) AS total FROM t GROUP BY g ORDER BY g").unwrap();
+    let mut out = FFI_ArrowArrayStream::empty();
+    assert_eq!(
+        unsafe { wsql_execute_sql(engine, sql.as_ptr(), &mut out) },
+        0
+    );
+    let batches = collect(&mut out);
+    assert_eq!(batches[0].schema().field(1).name(), "total");
+    assert_eq!(
+        batches[0].column(0).as_primitive::<Int32Type>().values(),
+        &[1, 2]
+    );
+    assert_eq!(
+        batches[0].column(1).as_primitive::<Float32Type>().values(),
+        &[9.0, 6.0]
+    );
+
+    // The same query as binary Substrait
+    let plan = wsql::sql::plan("SELECT g FROM t WHERE v > 3.5", |_| Some(schema.clone()))
+        .unwrap()
+        .encode_to_vec();
+    let mut out = FFI_ArrowArrayStream::empty();
+    assert_eq!(
+        unsafe { wsql_execute_substrait(engine, plan.as_ptr(), plan.len(), &mut out) },
+        0
+    );
+    let batches = collect(&mut out);
+    assert_eq!(
+        batches[0].column(0).as_primitive::<Int32Type>().values(),
+        &[2, 1]
+    );
+
+    let sql = CString::new("SELECT missing FROM t").unwrap();
+    let mut out = FFI_ArrowArrayStream::empty();
+    assert_eq!(
+        unsafe { wsql_execute_sql(engine, sql.as_ptr(), &mut out) },
+        -1
+    );
+    let error = unsafe { CStr::from_ptr(wsql_last_error()) };
+    assert!(
+        error
+            .to_str()
+            .unwrap()
+            .contains("Column missing does not exist")
+    );
+
+    unsafe { wsql_close(engine) };
+}
+
+#[test]
+fn test_ffi_register_parquet() {
+    let engine = wsql_open();
+    let name = CString::new("alltypes").unwrap();
+    let path = CString::new("tests/data/alltypes_plain.parquet").unwrap();
+    assert_eq!(
+        unsafe { wsql_register_parquet(engine, name.as_ptr(), path.as_ptr()) },
+        0
+    );
+    let sql = CString::new("SELECT COUNT(*) AS n FROM alltypes").unwrap();
+    let mut out = FFI_ArrowArrayStream::empty();
+    assert_eq!(
+        unsafe { wsql_execute_sql(engine, sql.as_ptr(), &mut out) },
+        0
+    );
+    assert_eq!(collect(&mut out)[0].num_rows(), 1);
+
+    let missing = CString::new("missing.parquet").unwrap();
+    assert_eq!(
+        unsafe { wsql_register_parquet(engine, name.as_ptr(), missing.as_ptr()) },
+        -1
+    );
+    assert!(!wsql_last_error().is_null());
+    unsafe { wsql_close(engine) };
+}
@@ -0,0 +1,214 @@
+use std::{io::Cursor, sync::Arc};
+
+use arrow::{
+    array::{AsArray, RecordBatch},
+    datatypes::{Float32Type, Int32Type},
+    ipc::writer::{EncodedData, IpcWriteOptions},
+};
+use prost::Message;
+use tonic::{client::Grpc, codegen::http::uri::PathAndQuery, transport::Channel};
+use tonic_prost::ProstCodec;
+use wsql::flight::{self, proto};
+
+// Flight SQL server on a free localhost port, with alltypes_plain as `alltypes`
+async fn client() -> Grpc<Channel> {
+    let gpu = wsql::gpu::Gpu::new().await;
+    let mut engine = wsql::engine::QueryEngine::new(wsql::executor::QueryExecutor::new(gpu));
+    let table = wsql::source::ParquetTable::open("tests/data/alltypes_plain.parquet").unwrap();
+    engine.register_table("alltypes", Arc::new(table));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(flight::FlightSqlService::new(Arc::new(engine)))
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+    );
+    let channel = Channel::from_shared(format!("http://{addr}"))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    Grpc::new(channel)
+}
+
+async fn get_flight_info(
+    client: &mut Grpc<Channel>,
+    cmd: Vec<u8>,
+) -> Result<proto::FlightInfo, tonic::Status> {
+    client.ready().await.unwrap();
+    let descriptor = proto::FlightDescriptor {
+        r#type: proto::DescriptorType::Cmd as i32,
+        cmd,
+        path: Vec::new(),
+    };
+    let path = PathAndQuery::from_static("/arrow.flight.protocol.FlightService/GetFlightInfo");
+    let response = client
+        .unary(tonic::Request::new(descriptor), path, ProstCodec::default())
+        .await?;
+    Ok(response.into_inner())
+}
+
+// Fetches the first endpoint of `info` and decodes the stream
+async fn do_get(client: &mut Grpc<Channel>, info: &proto::FlightInfo) -> Vec<RecordBatch> {
+    client.ready().await.unwrap();
+    let ticket = info.endpoint[0].ticket.clone().unwrap();
+    let path = PathAndQuery::from_static("/arrow.flight.protocol.FlightService/DoGet");
+    let mut stream = client
+        .server_streaming(tonic::Request::new(ticket), path, ProstCodec::default())
+        .await
+        .unwrap()
+        .into_inner();
+
+    let mut ipc = Vec::new();
+    while let Some(data) = stream.message().await.unwrap() {
+        let data: proto::FlightData = data;
+        let encoded = EncodedData {
+            ipc_message: data.data_header,
+            arrow_data: data.data_body,
+        };
+        arrow::ipc::writer::write_message(&mut ipc, encoded, &IpcWriteOptions::default()).unwrap();
+    }
+    arrow::ipc::reader::StreamReader::try_new(Cursor::new(ipc), None)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_flight_sql_statement() {
+    let mut client = client().await;
+    let query = proto::CommandStatementQuery {
+        query: "SELECT tinyint_col, SUM(id) AS total FROM alltypes GROUP BY 1 ORDER BY 1".into(),
+        transaction_id: None,
+    };
+    let info = get_flight_info(&mut client, flight::pack("CommandStatementQuery", &query))
+        .await
+        .unwrap();
+    let schema = arrow::ipc::convert::try_schema_from_ipc_buffer(&info.schema).unwrap();
+
+    let batches = do_get(&mut client, &info).await;
+    assert_eq!(batches.len(), 1);
+    let batch = &batches[0];
+    assert_eq!(batch.schema().as_ref(), &schema);
+    assert_eq!(schema.field(1).name(), "total");
+    assert_eq!(
+        batch.column(0).as_primitive::<Int32Type>().values(),
+        &[0, 1]
+    );
+    assert_eq!(
+        batch.column(1).as_primitive::<Float32Type>().values(),
+        &[12.0, 16.0]
+    );
+
+    let query = proto::CommandStatementQuery {
+        query: "SELECT missing FROM alltypes".into(),
+        transaction_id: None,
+    };
+    let error = get_flight_info(&mut client, flight::pack("CommandStatementQuery", &query))
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), tonic::Code::InvalidArgument);
+    assert!(error.message().contains("Column missing does not exist"));
+}
+
+#[tokio::test]
+async fn test_flight_sql_substrait_and_prepared() {
+    let mut client = client().await;
+    let query = "SELECT id FROM alltypes WHERE id > 4 ORDER BY id DESC";
+
+    // A Substrait plan, as another producer would send it
+    let table = wsql::source::ParquetTable::open("tests/data/alltypes_plain.parquet").unwrap();
+    let schema = wsql::source::TableSource::schema(&table);
+    let plan = wsql::sql::plan(query, |_| Some(schema.clone())).unwrap();
+    let command = proto::CommandStatementSubstraitPlan {
+        plan: Some(proto::SubstraitPlan {
+            plan: plan.encode_to_vec(),
+            version: String::new(),
+        }),
+        transaction_id: None,
+    };
+    let info = get_flight_info(
+        &mut client,
+        flight::pack("CommandStatementSubstraitPlan", &command),
+    )
+    .await
+    .unwrap();
+    let batches = do_get(&mut client, &info).await;
+    assert_eq!(
+        batches[0].column(0).as_primitive::<Int32Type>().values(),
+        &[7, 6, 5]
+    );
+
+    // Prepared statements return the schema up front and run on every fetch
+    client.ready().await.unwrap();
+    let request = proto::ActionCreatePreparedStatementRequest {
+        query: query.into(),
+        transaction_id: None,
+    };
+    let action = proto::Action {
+        r#type: "CreatePreparedStatement".into(),
+        body: flight::pack("ActionCreatePreparedStatementRequest", &request),
+    };
+    let path = PathAndQuery::from_static("/arrow.flight.protocol.FlightService/DoAction");
+    let mut results = client
+        .server_streaming(tonic::Request::new(action), path, ProstCodec::default())
+        .await
+        .unwrap()
+        .into_inner();
+    let result: proto::Result = results.message().await.unwrap().unwrap();
+    let any = proto::Any::decode(result.body.as_slice()).unwrap();
+    let prepared =
+        proto::ActionCreatePreparedStatementResult::decode(any.value.as_slice()).unwrap();
+    let schema = arrow::ipc::convert::try_schema_from_ipc_buffer(&prepared.dataset_schema).unwrap();
+    assert_eq!(schema.field(0).name(), "id");
+
+    let command = proto::CommandPreparedStatementQuery {
+        prepared_statement_handle: prepared.prepared_statement_handle,
+    };
+    let info = get_flight_info(
+        &mut client,
+        flight::pack("CommandPreparedStatementQuery", &command),
+    )
+    .await
+    .unwrap();
+    let batches = do_get(&mut client, &info).await;
+    assert_eq!(batches[0].schema().as_ref(), &schema);
+    assert_eq!(
+        batches[0].column(0).as_primitive::<Int32Type>().values(),
+        &[7, 6, 5]
+    );
+}
+
+#[tokio::test]
+async fn test_flight_sql_get_tables() {
+    let mut client = client().await;
+    let command = proto::CommandGetTables {
+        table_name_filter_pattern: Some("all%".into()),
+        include_schema: true,
+        ..Default::default()
+    };
+    let info = get_flight_info(&mut client, flight::pack("CommandGetTables", &command))
+        .await
+        .unwrap();
+    let batches = do_get(&mut client, &info).await;
+    let batch = &batches[0];
+    assert_eq!(batch.num_rows(), 1);
+    assert_eq!(batch.column(2).as_string::<i32>().value(0), "alltypes");
+    let schema = arrow::ipc::convert::try_schema_from_ipc_buffer(
+        batch.column(4).as_binary::<i32>().value(0),
+    )
+    .unwrap();
+    assert_eq!(schema.field(0).name(), "id");
+
+    let command = proto::CommandGetTables {
+        table_name_filter_pattern: Some("other%".into()),
+        ..Default::default()
+    };
+    let info = get_flight_info(&mut client, flight::pack("CommandGetTables", &command))
+        .await
+        .unwrap();
+    // Empty results stream just the schema
+    let batches = do_get(&mut client, &info).await;
+    assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 0);
+}
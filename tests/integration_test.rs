@@ -62,8 +62,8 @@ async fn test_simple_substrait_lite() {
     let mut column_types = std::collections::HashMap::new();
     column_types.insert(0, arrow::datatypes::DataType::Int32);
 
-    // PhysicalPlan
-    let physical_plan = wsql::sub::PhysicalPlan {
+    // KernelPlan
+    let physical_plan = wsql::sub::KernelPlan {
         projection: query,
         filter: None,
         is_aggregate: false,
@@ -81,6 +81,42 @@ async fn test_simple_substrait_lite() {
     // println!("Result from GPU: {:?}", result);
 }
 
+#[tokio::test]
+async fn test_opendal_async_reader() {
+    // DATA, streamed with range reads through OpenDAL
+    let dal_op = opendal::Operator::new(opendal::services::Fs::default().root("tests"))
+        .expect("Unable to create new OpenDAL Operator")
+        .finish();
+    let file_reader = wsql::storage::OpendalReader::open(dal_op, "data/alltypes_plain.parquet")
+        .await
+        .expect("Unable to open file");
+    let parquet_stream = parquet::arrow::ParquetRecordBatchStreamBuilder::new(file_reader)
+        .await
+        .expect("Couldnt build parquet from file")
+        .with_batch_size(3)
+        .build()
+        .expect("Couldnt read file");
+
+    let batches: Vec<_> = futures::TryStreamExt::try_collect(parquet_stream)
+        .await
+        .unwrap();
+    assert_eq!(
+        batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>(),
+        vec![3, 3, 2]
+    );
+    let ids: Vec<i32> = batches
+        .iter()
+        .flat_map(|b| {
+            b.column_by_name("id")
+                .unwrap()
+                .as_primitive::<arrow::datatypes::Int32Type>()
+                .values()
+                .to_vec()
+        })
+        .collect();
+    assert_eq!(ids, vec![4, 5, 6, 7, 2, 3, 0, 1]);
+}
+
 #[tokio::test]
 async fn test_simple_filter_sparse() {
     // SELECT id WHERE id > 12
@@ -111,7 +147,7 @@ async fn test_simple_filter_sparse() {
 
     let mut column_types = std::collections::HashMap::new();
     column_types.insert(0, arrow::datatypes::DataType::Int32);
-    let physical_plan = wsql::sub::PhysicalPlan {
+    let physical_plan = wsql::sub::KernelPlan {
         projection,
         filter: Some(query),
         is_aggregate: false,
@@ -146,15 +182,14 @@ async fn test_gpu_aggregate_sum() {
     let json_plan = std::fs::read_to_string("tests/fixtures/sum_project.json").unwrap();
     let plan = serde_json::from_str(&json_plan).unwrap();
 
-    let mut physical_plan = wsql::sub::lower_plan(&plan).unwrap();
-    if physical_plan.column_types.is_empty() {
-        physical_plan
-            .column_types
-            .insert(0, arrow::datatypes::DataType::Float32);
-        physical_plan
-            .column_types
-            .insert(1, arrow::datatypes::DataType::Float32);
-    }
+    let pipelines = wsql::sub::lower_plan(&plan).unwrap().pipelines().unwrap();
+    let wsql::sub::Breaker::Aggregate(aggregation) = &pipelines[0].breaker else {
+        panic!("Expected an aggregate pipeline");
+    };
+    let mut column_types = std::collections::HashMap::new();
+    column_types.insert(0, arrow::datatypes::DataType::Float32);
+    column_types.insert(1, arrow::datatypes::DataType::Float32);
+    let physical_plan = pipelines[0].kernel(&aggregation.measures[0].summand(), &column_types);
     let schema = std::sync::Arc::new(Schema::new(vec![
         Field::new("price", DataType::Float32, false),
         Field::new("discount", DataType::Float32, false),
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use tokio_postgres::{NoTls, SimpleQueryMessage, error::SqlState};
+
+// Postgres endpoint on a free localhost port, with alltypes_plain as `alltypes`
+async fn client() -> tokio_postgres::Client {
+    let gpu = wsql::gpu::Gpu::new().await;
+    let mut engine = wsql::engine::QueryEngine::new(wsql::executor::QueryExecutor::new(gpu));
+    let table = wsql::source::ParquetTable::open("tests/data/alltypes_plain.parquet").unwrap();
+    engine.register_table("alltypes", Arc::new(table));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(wsql::pgwire::serve_listener(Arc::new(engine), listener));
+
+    let config = format!("host=127.0.0.1 port={port} user=wsql");
+    let (client, connection) = tokio_postgres::connect(&config, NoTls).await.unwrap();
+    tokio::spawn(connection);
+    client
+}
+
+#[tokio::test]
+async fn test_pgwire_simple_query() {
+    let client = client().await;
+    let messages = client
+        .simple_query(
+            "SET application_name = 'test'; \
+             SELECT id, bool_col, double_col, string_col FROM alltypes WHERE id > 5 ORDER BY id",
+        )
+        .await
+        .unwrap();
+
+    let rows: Vec<_> = messages
+        .iter()
+        .filter_map(|m| match m {
+            SimpleQueryMessage::Row(row) => Some(row),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].columns()[3].name(), "string_col");
+    let values: Vec<_> = (0..4).map(|i| rows[0].get(i).unwrap()).collect();
+    assert_eq!(values, ["6", "t", "0", "\\x30"]);
+    assert_eq!(rows[1].get(1), Some("f"));
+    assert!(matches!(
+        messages.last(),
+        Some(SimpleQueryMessage::CommandComplete(2))
+    ));
+
+    let error = client
+        .simple_query("SELECT missing FROM alltypes")
+        .await
+        .unwrap_err();
+    let error = error.as_db_error().unwrap();
+    assert_eq!(
+        error.code(),
+        &SqlState::SYNTAX_ERROR_OR_ACCESS_RULE_VIOLATION
+    );
+    assert!(error.message().contains("Column missing does not exist"));
+}
+
+#[tokio::test]
+async fn test_pgwire_extended_query() {
+    let client = client().await;
+    // Typed results come back in the binary format
+    let rows = client
+        .query(
+            "SELECT id, bigint_col, float_col, double_col, bool_col FROM alltypes \
+             WHERE id > $1 ORDER BY id DESC",
+            &[&"4"],
+        )
+        .await
+        .unwrap();
+    let ids: Vec<i32> = rows.iter().map(|row| row.get(0)).collect();
+    assert_eq!(ids, [7, 6, 5]);
+    assert_eq!(rows[0].get::<_, i64>(1), 10);
+    assert_eq!(rows[0].get::<_, f32>(2), 1.1);
+    assert_eq!(rows[0].get::<_, f64>(3), 10.1);
+    assert!(!rows[0].get::<_, bool>(4));
+
+    // A negative parameter after a minus is not read as a comment
+    let rows = client
+        .query(
+            "SELECT id FROM alltypes WHERE id-$1 > 10 ORDER BY id",
+            &[&"-5"],
+        )
+        .await
+        .unwrap();
+    let ids: Vec<i32> = rows.iter().map(|row| row.get(0)).collect();
+    assert_eq!(ids, [6, 7]);
+
+    // NULL parameters are bound as NULL, which the SQL frontend rejects
+    let error = client
+        .query("SELECT id FROM alltypes WHERE id > $1", &[&None::<&str>])
+        .await
+        .unwrap_err();
+    let error = error.as_db_error().unwrap();
+    assert!(error.message().contains("Unsupported literal: NULL"));
+
+    // A failed statement does not break the session
+    let error = client
+        .query("SELECT missing FROM alltypes", &[])
+        .await
+        .unwrap_err();
+    assert!(error.as_db_error().is_some());
+    let row = client
+        .query_one("SELECT COUNT(*) AS n FROM alltypes", &[])
+        .await
+        .unwrap();
+    assert_eq!(row.columns()[0].name(), "n");
+}
+
+#[tokio::test]
+async fn test_pgwire_portal_row_limit() {
+    let mut client = client().await;
+    let transaction = client.transaction().await.unwrap();
+    let statement = transaction
+        .prepare("SELECT id FROM alltypes ORDER BY id")
+        .await
+        .unwrap();
+    let portal = transaction.bind(&statement, &[]).await.unwrap();
+
+    let mut ids = Vec::new();
+    loop {
+        let rows = transaction.query_portal(&portal, 3).await.unwrap();
+        if rows.is_empty() {
+            break;
+        }
+        assert!(rows.len() <= 3);
+        ids.extend(rows.iter().map(|row| row.get::<_, i32>(0)));
+    }
+    assert_eq!(ids, (0..8).collect::<Vec<_>>());
+    transaction.commit().await.unwrap();
+}
@@ -0,0 +1,410 @@
+use std::sync::Arc;
+
+use arrow::{
+    array::{Array, AsArray},
+    datatypes::{DataType, Field, Float32Type, Int32Type, Int64Type, Schema},
+    record_batch::RecordBatch,
+};
+use wsql::{
+    dataset::DatasetTable,
+    jit::{Expression, LiteralTypes},
+    source::{CsvOptions, CsvTable, IpcTable, NdjsonTable, TableSource},
+    storage::Storage,
+};
+
+async fn engine() -> wsql::engine::QueryEngine {
+    let gpu = wsql::gpu::Gpu::new().await;
+    wsql::engine::QueryEngine::new(wsql::executor::QueryExecutor::new(gpu))
+}
+
+// alltypes_plain in batches of 3
+fn alltypes() -> Vec<RecordBatch> {
+    let file = std::fs::File::open("tests/data/alltypes_plain.parquet").unwrap();
+    parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(file)
+        .unwrap()
+        .with_batch_size(3)
+        .build()
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+fn int_column(batch: &RecordBatch, i: usize) -> Vec<i32> {
+    batch
+        .column(i)
+        .as_primitive::<Int32Type>()
+        .values()
+        .to_vec()
+}
+
+#[tokio::test]
+async fn test_csv_inferred_schema() {
+    let table = CsvTable::open("tests/data/orders.csv")
+        .unwrap()
+        .with_batch_size(3);
+    let types: Vec<_> = table
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| f.data_type().clone())
+        .collect();
+    // 64 bit numbers are narrowed for the kernels
+    assert_eq!(
+        types,
+        [
+            DataType::Int32,
+            DataType::Utf8,
+            DataType::Int32,
+            DataType::Float32,
+            DataType::Date32
+        ]
+    );
+    let sizes: Vec<_> = table
+        .scan()
+        .unwrap()
+        .map(|b| b.unwrap().num_rows())
+        .collect();
+    assert_eq!(sizes, [3, 3, 2]);
+
+    let mut engine = engine().await;
+    engine.register_table("orders", Arc::new(table));
+    let result = engine
+        .sql(
+            "SELECT region, SUM(quantity * price) AS revenue FROM orders \
+             WHERE order_date > DATE '2024-01-03' GROUP BY region ORDER BY region",
+        )
+        .await
+        .unwrap();
+    let regions: Vec<_> = result
+        .column(0)
+        .as_string::<i32>()
+        .iter()
+        .flatten()
+        .collect();
+    assert_eq!(regions, ["east", "north", "west"]);
+    assert_eq!(
+        result.column(1).as_primitive::<Float32Type>().values(),
+        &[16.0, 14.5, 24.0]
+    );
+}
+
+#[tokio::test]
+async fn test_csv_explicit_schema() {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Int32, false),
+        Field::new("region", DataType::Utf8, false),
+        Field::new("quantity", DataType::Int32, false),
+        Field::new("price", DataType::Float32, false),
+        Field::new("day", DataType::Utf8, false),
+    ]));
+    let options = CsvOptions {
+        header: false,
+        delimiter: b';',
+        schema: Some(schema.clone()),
+    };
+    let table = CsvTable::open_with("tests/data/orders_semicolon.csv", options).unwrap();
+    assert_eq!(table.schema(), schema);
+
+    let mut engine = engine().await;
+    engine.register_table("orders", Arc::new(table));
+    let result = engine
+        .sql("SELECT id, quantity FROM orders WHERE quantity > 3 ORDER BY id")
+        .await
+        .unwrap();
+    assert_eq!(int_column(&result, 0), [4, 5, 8]);
+    assert_eq!(int_column(&result, 1), [5, 4, 6]);
+}
+
+#[tokio::test]
+async fn test_ipc_file_is_mapped() {
+    let batches = alltypes();
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("alltypes.arrow");
+    let mut writer = arrow::ipc::writer::FileWriter::try_new(
+        std::fs::File::create(&path).unwrap(),
+        &batches[0].schema(),
+    )
+    .unwrap();
+    for batch in &batches {
+        writer.write(batch).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let table = IpcTable::open(&path).unwrap();
+    assert!(table.is_mapped());
+    assert_eq!(table.schema(), batches[0].schema());
+    let scanned: Vec<_> = table.scan().unwrap().map(|b| b.unwrap()).collect();
+    assert_eq!(scanned, batches);
+
+    let mut engine = engine().await;
+    engine.register_table("alltypes", Arc::new(table));
+    let result = engine
+        .sql("SELECT tinyint_col, SUM(id) AS total FROM alltypes GROUP BY 1 ORDER BY 1")
+        .await
+        .unwrap();
+    assert_eq!(int_column(&result, 0), [0, 1]);
+    assert_eq!(
+        result.column(1).as_primitive::<Float32Type>().values(),
+        &[12.0, 16.0]
+    );
+}
+
+#[tokio::test]
+async fn test_ipc_stream() {
+    let batches = alltypes();
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("alltypes.arrows");
+    let mut writer = arrow::ipc::writer::StreamWriter::try_new(
+        std::fs::File::create(&path).unwrap(),
+        &batches[0].schema(),
+    )
+    .unwrap();
+    for batch in &batches {
+        writer.write(batch).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let table = IpcTable::open(&path).unwrap();
+    assert!(!table.is_mapped());
+    let mut engine = engine().await;
+    engine.register_table("alltypes", Arc::new(table));
+    // The subquery scans the stream a second time
+    let result = engine
+        .sql(
+            "SELECT id FROM alltypes \
+             WHERE id IN (SELECT id FROM alltypes WHERE id > 5) ORDER BY id",
+        )
+        .await
+        .unwrap();
+    assert_eq!(int_column(&result, 0), [6, 7]);
+}
+
+#[tokio::test]
+async fn test_ndjson_events() {
+    let table = NdjsonTable::open("tests/data/events.ndjson")
+        .unwrap()
+        .with_batch_size(2);
+    let schema = table.schema();
+    let data_type = |name| schema.field_with_name(name).unwrap().data_type();
+    assert_eq!(schema.fields().len(), 5);
+    assert_eq!(data_type("user_id"), &DataType::Int32);
+    assert_eq!(data_type("kind"), &DataType::Utf8);
+    assert_eq!(data_type("latency"), &DataType::Float32);
+    // Nested objects become columns named by their path
+    assert_eq!(data_type("extra.page"), &DataType::Utf8);
+
+    let batches: Vec<_> = table.scan().unwrap().map(|b| b.unwrap()).collect();
+    assert_eq!(batches.len(), 3);
+    // Missing fields are null
+    let ok = schema.index_of("ok").unwrap();
+    assert_eq!(batches[0].column(ok).null_count(), 1);
+
+    let mut engine = engine().await;
+    engine.register_table("events", Arc::new(table));
+    let result = engine
+        .sql(
+            "SELECT kind, SUM(latency) AS latency FROM events \
+             WHERE user_id < 3 GROUP BY kind ORDER BY kind",
+        )
+        .await
+        .unwrap();
+    let kinds: Vec<_> = result
+        .column(0)
+        .as_string::<i32>()
+        .iter()
+        .flatten()
+        .collect();
+    assert_eq!(kinds, ["click", "view"]);
+    assert_eq!(
+        result.column(1).as_primitive::<Float32Type>().values(),
+        &[16.5, 10.5]
+    );
+}
+
+// Writes year=Y/region=R/part-0.parquet files with columns id and amount
+fn write_sales(root: &std::path::Path) {
+    let _ = std::fs::remove_dir_all(root);
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Int32, false),
+        Field::new("amount", DataType::Float32, false),
+    ]));
+    let partitions = [
+        ("2022", "east", 0),
+        ("2023", "east", 10),
+        ("2023", "west", 20),
+        ("2024", "north%2Fwest", 30),
+    ];
+    for (year, region, first) in partitions {
+        let dir = root.join(format!("year={year}/region={region}"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(arrow::array::Int32Array::from_iter_values(first..first + 3)),
+                Arc::new(arrow::array::Float32Array::from(vec![1.0, 2.0, 3.0])),
+            ],
+        )
+        .unwrap();
+        let file = std::fs::File::create(dir.join("part-0.parquet")).unwrap();
+        let mut writer = parquet::arrow::ArrowWriter::try_new(file, schema.clone(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+    }
+    // Markers are not data files
+    std::fs::write(root.join("_SUCCESS"), "").unwrap();
+}
+
+#[tokio::test]
+async fn test_partitioned_dataset() {
+    let root = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("sales");
+    write_sales(&root);
+
+    let table = DatasetTable::open(root.to_str().unwrap()).await.unwrap();
+    let schema = table.schema();
+    let names: Vec<_> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+    assert_eq!(names, ["id", "amount", "year", "region"]);
+    assert_eq!(schema.field(2).data_type(), &DataType::Int32);
+    assert_eq!(schema.field(3).data_type(), &DataType::Utf8);
+
+    // year > 2022 rules out the first partition before it is opened
+    let filter = Expression::GreaterThan(
+        Box::new(Expression::Column(2)),
+        Box::new(Expression::Literal(LiteralTypes::I32(2022))),
+    );
+    assert_eq!(table.files(None).len(), 4);
+    assert_eq!(
+        table.files(Some(&filter)),
+        [
+            "year=2023/region=east/part-0.parquet",
+            "year=2023/region=west/part-0.parquet",
+            "year=2024/region=north%2Fwest/part-0.parquet"
+        ]
+    );
+    // Only id and region are read
+    let batches: Vec<_> = table
+        .scan_with(Some(&filter), Some(&[0, 3]))
+        .unwrap()
+        .map(|b| b.unwrap())
+        .collect();
+    assert_eq!(batches.len(), 3);
+    assert_eq!(batches[0].num_columns(), 2);
+    assert_eq!(int_column(&batches[1], 0), [20, 21, 22]);
+    let regions: Vec<_> = batches
+        .iter()
+        .map(|b| b.column(1).as_string::<i32>().value(0).to_string())
+        .collect();
+    assert_eq!(regions, ["east", "west", "north/west"]);
+
+    let mut engine = engine().await;
+    engine.register_table("sales", Arc::new(table.with_concurrency(2)));
+    let result = engine
+        .sql(
+            "SELECT year, SUM(amount) AS total FROM sales \
+             WHERE year > 2022 AND id > 10 GROUP BY year ORDER BY year",
+        )
+        .await
+        .unwrap();
+    assert_eq!(int_column(&result, 0), [2023, 2024]);
+    assert_eq!(
+        result.column(1).as_primitive::<Float32Type>().values(),
+        &[11.0, 6.0]
+    );
+
+    // A glob selects some of the partitions
+    let pattern = format!("{}/year=2023/*/*.parquet", root.display());
+    let table = DatasetTable::open(&pattern).await.unwrap();
+    assert_eq!(table.files(None).len(), 2);
+    assert_eq!(table.schema().fields().len(), 4);
+    let table = DatasetTable::open(root.join("year=2024").to_str().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(
+        table.files(None),
+        ["year=2024/region=north%2Fwest/part-0.parquet"]
+    );
+}
+
+#[tokio::test]
+async fn test_fully_pruned_dataset() {
+    let root = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("pruned_sales");
+    write_sales(&root);
+
+    let table = DatasetTable::open(root.to_str().unwrap()).await.unwrap();
+    let mut engine = engine().await;
+    engine.register_table("sales", Arc::new(table));
+
+    // No partition is scanned, the aggregate still returns its empty result
+    let result = engine
+        .sql("SELECT COUNT(*), SUM(amount), SUM(id) FROM sales WHERE year = 2030")
+        .await
+        .unwrap();
+    assert_eq!(result.num_rows(), 1);
+    assert_eq!(result.column(0).as_primitive::<Int64Type>().value(0), 0);
+    assert!(result.column(1).is_null(0));
+    assert!(result.column(2).is_null(0));
+
+    for sql in [
+        "SELECT id FROM sales WHERE year = 2030 ORDER BY id",
+        "SELECT region, COUNT(*) FROM sales WHERE year = 2030 GROUP BY region",
+    ] {
+        assert_eq!(engine.sql(sql).await.unwrap().num_rows(), 0);
+    }
+}
+
+#[tokio::test]
+async fn test_analyze_table_prunes_partitions() {
+    let root = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("analyzed_sales");
+    write_sales(&root);
+    let table = Arc::new(DatasetTable::open(root.to_str().unwrap()).await.unwrap());
+
+    let mut planner = engine().await;
+    planner.register_table("sales", table.clone());
+    let plan = planner
+        .plan_sql("SELECT SUM(amount) FROM sales WHERE year = 2023")
+        .unwrap();
+
+    // The scan of an unregistered table reads the given one, like `run_table`
+    let analysis = engine().await.analyze_table(table, &plan).await.unwrap();
+    let profile = analysis.pipelines[0].profile.as_ref().unwrap();
+    assert_eq!(profile.batches.len(), 2);
+    assert_eq!(profile.rows_in(), 6);
+    assert_eq!(profile.rows_out, 1);
+}
+
+#[tokio::test]
+async fn test_dataset_in_memory_store() {
+    // Stands in for an object store, memory:// locations share its files
+    let storage = Storage::default();
+    for (day, batches) in [("2024-01-01", 0..2), ("2024-01-02", 2..3)] {
+        let all = alltypes();
+        let mut data = Vec::new();
+        let mut writer =
+            parquet::arrow::ArrowWriter::try_new(&mut data, all[0].schema(), None).unwrap();
+        for batch in &all[batches] {
+            writer.write(batch).unwrap();
+        }
+        writer.close().unwrap();
+        storage
+            .memory()
+            .write(&format!("warehouse/alltypes/day={day}/data.parquet"), data)
+            .await
+            .unwrap();
+    }
+
+    let table = DatasetTable::open_with(&storage, "memory://warehouse/alltypes")
+        .await
+        .unwrap();
+    let day = table.schema().index_of("day").unwrap();
+    assert_eq!(table.schema().field(day).data_type(), &DataType::Date32);
+    let mut engine = engine().await;
+    engine.register_table("alltypes", Arc::new(table));
+    let result = engine
+        .sql("SELECT id FROM alltypes WHERE day > DATE '2024-01-01' ORDER BY id")
+        .await
+        .unwrap();
+    assert_eq!(int_column(&result, 0), [0, 1]);
+
+    let error = DatasetTable::open_with(&storage, "memory://missing")
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.to_string(), "Dataset has no files");
+}
@@ -0,0 +1,345 @@
+use std::sync::Arc;
+
+use arrow::{
+    array::{Array, AsArray, Int32Array},
+    datatypes::{DataType, Field, Float32Type, Int32Type, Int64Type, Schema},
+    record_batch::RecordBatch,
+};
+use wsql::source::TableSource;
+
+// Engine with alltypes_plain registered as `alltypes`
+async fn engine() -> wsql::engine::QueryEngine {
+    let gpu = wsql::gpu::Gpu::new().await;
+    let executor = wsql::executor::QueryExecutor::new(gpu);
+    let mut engine = wsql::engine::QueryEngine::new(executor);
+
+    let reader = wsql::dataset::DatasetTable::open("tests/data/alltypes_plain.parquet")
+        .await
+        .unwrap()
+        .with_batch_size(3)
+        .scan()
+        .unwrap();
+    let table = wsql::source::MemoryTable::collect(reader).unwrap();
+    engine.register_table("alltypes", Arc::new(table));
+    engine
+}
+
+fn int_column(batch: &RecordBatch, i: usize) -> Vec<i32> {
+    batch
+        .column(i)
+        .as_primitive::<Int32Type>()
+        .values()
+        .to_vec()
+}
+
+#[tokio::test]
+async fn test_sql_filter_project_sort() {
+    let engine = engine().await;
+    let result = engine
+        .sql(
+            "SELECT id * 2 AS doubled FROM alltypes \
+             WHERE id >= 2 AND id <= 5 ORDER BY doubled DESC LIMIT 2",
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(result.schema().field(0).name(), "doubled");
+    assert_eq!(int_column(&result, 0), vec![10, 8]);
+}
+
+#[tokio::test]
+async fn test_sql_not_and_divide() {
+    let engine = engine().await;
+    let result = engine
+        .sql("SELECT id FROM alltypes WHERE NOT id > 3 ORDER BY id")
+        .await
+        .unwrap();
+    assert_eq!(int_column(&result, 0), vec![0, 1, 2, 3]);
+
+    // Integer division truncates, float division does not
+    let result = engine
+        .sql("SELECT id / 2, float_col / 2 FROM alltypes WHERE id > 4 ORDER BY id")
+        .await
+        .unwrap();
+    assert_eq!(int_column(&result, 0), vec![2, 3, 3]);
+    assert_eq!(
+        result
+            .column(1)
+            .as_primitive::<Float32Type>()
+            .values()
+            .to_vec(),
+        vec![0.55, 0.0, 0.55]
+    );
+}
+
+#[tokio::test]
+async fn test_sql_predicate_and_negated_projections() {
+    let engine = engine().await;
+    let result = engine
+        .sql(
+            "SELECT id >= 3, NOT id > 3 OR id = 0, -id, -float_col FROM alltypes \
+             WHERE id < 5 ORDER BY id",
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(result.column(0).data_type(), &DataType::Boolean);
+    assert_eq!(result.column(1).data_type(), &DataType::Boolean);
+    let booleans =
+        |i: usize| -> Vec<bool> { result.column(i).as_boolean().iter().flatten().collect() };
+    assert_eq!(booleans(0), vec![false, false, false, true, true]);
+    assert_eq!(booleans(1), vec![true, true, true, true, false]);
+    assert_eq!(int_column(&result, 2), vec![0, -1, -2, -3, -4]);
+    assert_eq!(
+        result
+            .column(3)
+            .as_primitive::<Float32Type>()
+            .values()
+            .to_vec(),
+        vec![-0.0, -1.1, -0.0, -1.1, -0.0]
+    );
+
+    // A predicate column can be filtered on again
+    let result = engine
+        .sql("SELECT id FROM (SELECT id, id >= 6 AS big FROM alltypes) WHERE big ORDER BY id")
+        .await
+        .unwrap();
+    assert_eq!(int_column(&result, 0), vec![6, 7]);
+}
+
+#[tokio::test]
+async fn test_sql_group_by() {
+    let engine = engine().await;
+    let result = engine
+        .sql(
+            "SELECT tinyint_col, SUM(id) AS total, COUNT(*) FROM alltypes \
+             WHERE id > 0 GROUP BY tinyint_col ORDER BY 1",
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(result.num_columns(), 3);
+    assert_eq!(result.schema().field(1).name(), "total");
+    let totals = result.column(1).as_primitive::<Float32Type>();
+    let counts = result.column(2).as_primitive::<Int64Type>();
+    assert_eq!(totals.values().to_vec(), vec![12.0, 16.0]);
+    assert_eq!(counts.values().to_vec(), vec![3, 4]);
+}
+
+#[tokio::test]
+async fn test_sql_sum_of_no_rows_is_null() {
+    let engine = engine().await;
+    let result = engine
+        .sql("SELECT SUM(id), COUNT(*) FROM alltypes WHERE id > 100")
+        .await
+        .unwrap();
+    assert!(result.column(0).is_null(0));
+    assert_eq!(result.column(1).as_primitive::<Int64Type>().value(0), 0);
+
+    let result = engine
+        .sql("SELECT AVG(DISTINCT id), SUM(DISTINCT id) FROM alltypes WHERE id > 100")
+        .await
+        .unwrap();
+    assert!(result.column(0).is_null(0));
+    assert!(result.column(1).is_null(0));
+
+    // A zero sum over rows that pass is not null
+    let result = engine
+        .sql("SELECT SUM(id) FROM alltypes WHERE id < 1")
+        .await
+        .unwrap();
+    assert_eq!(result.column(0).as_primitive::<Float32Type>().value(0), 0.0);
+    assert!(!result.column(0).is_null(0));
+}
+
+#[tokio::test]
+async fn test_sql_subqueries() {
+    let mut engine = engine().await;
+
+    let result = engine
+        .sql(
+            "SELECT id FROM alltypes \
+             WHERE id > (SELECT AVG(id) FROM alltypes) \
+             AND id IN (SELECT id + id FROM alltypes) ORDER BY id",
+        )
+        .await
+        .unwrap();
+    assert_eq!(int_column(&result, 0), vec![4, 6]);
+
+    let table = |names: [&str; 2], a: Vec<i32>, b: Vec<i32>| {
+        let schema = Arc::new(Schema::new(
+            names
+                .map(|n| Field::new(n, DataType::Int32, false))
+                .to_vec(),
+        ));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(a)), Arc::new(Int32Array::from(b))],
+        )
+        .unwrap();
+        Arc::new(wsql::source::MemoryTable::new(schema, vec![batch]))
+    };
+    engine.register_table(
+        "orders",
+        table(
+            ["o_orderkey", "o_priority"],
+            vec![1, 2, 3, 4, 5],
+            vec![0; 5],
+        ),
+    );
+    engine.register_table(
+        "lineitem",
+        table(
+            ["l_orderkey", "l_late"],
+            vec![1, 1, 2, 2, 3, 5],
+            vec![1, 0, 1, 1, 0, 1],
+        ),
+    );
+
+    // Orders with a late item and no item on time
+    let result = engine
+        .sql(
+            "SELECT o_orderkey FROM orders o \
+             WHERE EXISTS (SELECT * FROM lineitem WHERE l_orderkey = o.o_orderkey AND l_late > 0) \
+             AND NOT EXISTS (SELECT * FROM lineitem WHERE l_orderkey = o_orderkey AND l_late < 1) \
+             ORDER BY o_orderkey",
+        )
+        .await
+        .unwrap();
+    assert_eq!(int_column(&result, 0), vec![2, 5]);
+}
+
+#[tokio::test]
+async fn test_sql_null_scalar_subqueries() {
+    let engine = engine().await;
+    // A SUM over no rows and a subquery without rows are both NULL
+    let none = "(SELECT SUM(id) FROM alltypes WHERE id > 100)";
+    let empty = "(SELECT id FROM alltypes WHERE id > 100)";
+
+    for subquery in [none, empty] {
+        let result = engine
+            .sql(&format!("SELECT id FROM alltypes WHERE id > {subquery}"))
+            .await
+            .unwrap();
+        assert_eq!(result.num_rows(), 0);
+    }
+
+    // NULL comparisons are neither TRUE nor FALSE
+    let result = engine
+        .sql(&format!(
+            "SELECT id FROM alltypes WHERE NOT id > {empty} OR id < 2 ORDER BY id"
+        ))
+        .await
+        .unwrap();
+    assert_eq!(int_column(&result, 0), vec![0, 1]);
+
+    let result = engine
+        .sql(&format!(
+            "SELECT id + {none}, id FROM alltypes WHERE id < 3 ORDER BY id"
+        ))
+        .await
+        .unwrap();
+    assert_eq!(result.column(0).null_count(), 3);
+    assert_eq!(result.column(0).data_type(), &DataType::Float32);
+
+    let result = engine
+        .sql(&format!("SELECT SUM(id + {empty}), COUNT(*) FROM alltypes"))
+        .await
+        .unwrap();
+    assert!(result.column(0).is_null(0));
+    assert_eq!(result.column(1).as_primitive::<Int64Type>().value(0), 8);
+}
+
+#[tokio::test]
+async fn test_sql_errors() {
+    let engine = engine().await;
+
+    let err = engine.sql("SELECT nope FROM alltypes").await.unwrap_err();
+    assert_eq!(err.to_string(), "Column nope does not exist");
+
+    let err = engine
+        .sql("SELECT id, COUNT(*) FROM alltypes GROUP BY tinyint_col")
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Column id must appear in GROUP BY or be used in an aggregate function"
+    );
+
+    let err = engine.sql("SELECT id FROM missing").await.unwrap_err();
+    assert_eq!(err.to_string(), "Table missing does not exist");
+}
+
+#[tokio::test]
+async fn test_sql_explain() {
+    let engine = engine().await;
+    let explain = engine
+        .explain_sql("SELECT id * 2 FROM alltypes WHERE float_col > 0.5 ORDER BY 1")
+        .unwrap();
+
+    // Filter and projection are fused into the sorting pipeline
+    assert_eq!(explain.pipelines.len(), 1);
+    let pipeline = &explain.pipelines[0];
+    assert_eq!(
+        pipeline.source,
+        wsql::sub::Source::Table(Some("alltypes".into()))
+    );
+    assert_eq!(pipeline.output.field(0).data_type(), &DataType::Int32);
+
+    let roles: Vec<&str> = pipeline.kernels.iter().map(|k| k.role.as_str()).collect();
+    assert_eq!(roles, vec!["filter", "output 0"]);
+    let filter = &pipeline.kernels[0];
+    assert_eq!(filter.bindings.len(), 1);
+    assert_eq!(filter.bindings[0].column, 6);
+    assert_eq!(filter.bindings[0].data_type, DataType::Float32);
+    assert!(filter.wgsl.contains("in_col_0[idx] > 0.5f"));
+    assert_eq!(filter.workgroups(1000), 16);
+    // Input column, output and params
+    assert_eq!(filter.buffer_sizes(1000), vec![4000, 4000, 4]);
+
+    let text = explain.to_string();
+    assert!(text.contains("Read alltypes (11 columns)"));
+    assert!(text.contains("@binding(0) column 6 Float32 (4 B/row)"));
+
+    // Scalar subqueries are typed from their plan without running them
+    let explain = engine
+        .explain_sql("SELECT id + (SELECT COUNT(*) FROM alltypes) FROM alltypes")
+        .unwrap();
+    let pipeline = explain.pipelines.last().unwrap();
+    assert_eq!(pipeline.output.field(0).data_type(), &DataType::Int32);
+    assert_eq!(pipeline.subqueries.len(), 1);
+    let text = explain.to_string();
+    assert!(text.contains("Scalar subquery 0:"));
+    assert!(text.contains("Subquery(Project <- Project <- Aggregate <- Read alltypes)"));
+    assert!(!text.contains("0x"));
+}
+
+#[tokio::test]
+async fn test_sql_analyze() {
+    let engine = engine().await;
+    let query = "SELECT id * 2 FROM alltypes WHERE float_col > 0.5 ORDER BY 1";
+    let rows = engine.sql(query).await.unwrap().num_rows();
+    let analysis = engine.analyze_sql(query).await.unwrap();
+    assert!(analysis.elapsed.is_some());
+
+    let profile = analysis.pipelines[0].profile.as_ref().unwrap();
+    // alltypes_plain is registered in batches of 3
+    assert_eq!(
+        profile.batches.iter().map(|b| b.rows).collect::<Vec<_>>(),
+        vec![3, 3, 2]
+    );
+    assert_eq!(profile.rows_in(), 8);
+    assert_eq!(profile.rows_out, rows);
+
+    // Filter and output kernels run on every batch
+    for batch in &profile.batches {
+        assert!(batch.times.upload > std::time::Duration::ZERO);
+        assert!(batch.times.readback > std::time::Duration::ZERO);
+    }
+    assert!(profile.elapsed >= profile.total().readback);
+    assert!(
+        analysis
+            .to_string()
+            .contains("analyze: 3 batches, 8 rows in")
+    );
+}